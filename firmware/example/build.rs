@@ -1,9 +1,36 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use toml::Value;
 
+/// Must match secrets::CONTEXT in the firmware
+const SECRETS_CONTEXT: &[u8] = b"aws-iot-esp32 cfg secrets v1";
+
+/// cfg.toml settings left out of the config hash, which anyone who can send
+/// `version` reads: a hash of a secret allows guessing it offline. They are
+/// also not in the toml_cfg Config but sealed into sealed_settings.rs.
+const SECRET_SETTINGS: &[&str] = &[
+    "wifi_pass",
+    "aws_secret_access_key",
+    "aws_session_token",
+    "custom_auth_password",
+    "custom_auth_token",
+    "custom_auth_signature",
+    "console_token",
+];
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // esp-idf-svc can't create an MQTT 5 client; mqtt5.rs stands in for
+    // esp-mqtt's init to make one
+    if std::env::var_os("CARGO_FEATURE_MQTT5").is_some() {
+        println!("cargo:rustc-link-arg=-Wl,--wrap=esp_mqtt_client_init");
+    }
     
     // Validate that cfg.toml exists and contains required configuration
     let cfg_path = Path::new("cfg.toml");
@@ -23,8 +50,12 @@ fn main() {
         .expect("cfg.toml missing [led] section");
     
     // Basic validation - check for required fields
+    let client_id_from_cert = led_config.get("client_id_from_cert")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
     let required_fields = ["wifi_ssid", "wifi_pass", "mqtt_url", "mqtt_client_id"];
-    for field in &required_fields {
+    // With client_id_from_cert the device reads it from its certificate
+    for field in required_fields.iter().filter(|field| !client_id_from_cert || **field != "mqtt_client_id") {
         if !led_config.get(field).is_some() {
             panic!("cfg.toml is missing required field: {}", field);
         }
@@ -63,22 +94,141 @@ fn main() {
     let cert_ca_abs = Path::new(&manifest_dir).join(cert_ca);
     let cert_crt_abs = Path::new(&manifest_dir).join(cert_crt);
     let cert_key_abs = Path::new(&manifest_dir).join(cert_key);
+
+    // For JITP the device must present its certificate followed by the
+    // registered CA certificate, so bundle both into a single chain
+    let cert_jitp_ca = led_config.get("cert_jitp_ca")
+        .and_then(|v| v.as_str())
+        .filter(|path| !path.is_empty());
+    let cert_crt_abs = match cert_jitp_ca {
+        Some(jitp_ca) => {
+            if !Path::new(jitp_ca).exists() {
+                panic!("JITP CA certificate file not found at path: {}", jitp_ca);
+            }
+            let mut chain = fs::read_to_string(&cert_crt_abs)
+                .expect("Failed to read client certificate");
+            if !chain.ends_with('\n') {
+                chain.push('\n');
+            }
+            chain.push_str(&fs::read_to_string(jitp_ca)
+                .expect("Failed to read JITP CA certificate"));
+
+            println!("cargo:rerun-if-changed={}", jitp_ca);
+            let chain_path = Path::new(&out_dir).join("client_chain.pem.crt");
+            fs::write(&chain_path, chain)
+                .expect("Failed to write client certificate chain");
+            chain_path
+        }
+        None => cert_crt_abs,
+    };
     
+    // Private keys and secret settings are encrypted with a key derived from
+    // the device-class key when one is configured, see secrets.rs
+    let sealer = led_config.get("secrets_key")
+        .and_then(|v| v.as_str())
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            if !Path::new(path).exists() {
+                panic!("Secrets key file not found at path: {}", path);
+            }
+            println!("cargo:rerun-if-changed={}", path);
+            let key_block = led_config.get("secrets_key_block")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(0);
+            if !(0..=5).contains(&key_block) {
+                panic!("secrets_key_block must be 0-5, got {}", key_block);
+            }
+            Sealer::new(&fs::read(path).expect("Failed to read secrets key"), key_block as u32, &out_dir)
+        });
+    let seal = |name: &str, path: &Path| match &sealer {
+        Some(sealer) => sealer.seal(name, path),
+        None => format!("crate::secrets::Sealed::Plain(include_bytes!(\"{}\"))", path.to_string_lossy()),
+    };
+
+    // Optional second identity to fall back to when the primary one is
+    // rejected, e.g. the previous certificate during a rotation
+    let cert_backup_crt = led_config.get("cert_backup_crt")
+        .and_then(|v| v.as_str())
+        .filter(|path| !path.is_empty());
+    let cert_backup_key = led_config.get("cert_backup_key")
+        .and_then(|v| v.as_str())
+        .filter(|path| !path.is_empty());
+    let backup_code = match (cert_backup_crt, cert_backup_key) {
+        (Some(crt), Some(key)) => {
+            for (name, path) in [("Backup client certificate", crt), ("Backup private key", key)] {
+                if !Path::new(path).exists() {
+                    panic!("{} file not found at path: {}", name, path);
+                }
+                println!("cargo:rerun-if-changed={}", path);
+            }
+            format!(
+                "pub const BACKUP_CLIENT_CERT: Option<&[u8]> = Some(include_bytes!(\"{}\"));\n\
+                 pub const BACKUP_PRIVATE_KEY: Option<crate::secrets::Sealed> = Some({});\n",
+                Path::new(&manifest_dir).join(crt).to_string_lossy(),
+                seal("backup_private_key", &Path::new(&manifest_dir).join(key))
+            )
+        }
+        (None, None) => "pub const BACKUP_CLIENT_CERT: Option<&[u8]> = None;\n\
+                         pub const BACKUP_PRIVATE_KEY: Option<crate::secrets::Sealed> = None;\n"
+            .to_string(),
+        _ => panic!("cert_backup_crt and cert_backup_key must be set together"),
+    };
+
     let cert_code = format!(
         r#"// Auto-generated by build.rs from cfg.toml certificate paths
 // DO NOT EDIT THIS FILE MANUALLY
 
 pub const SERVER_CERT: &[u8] = include_bytes!("{}");
 pub const CLIENT_CERT: &[u8] = include_bytes!("{}");
-pub const PRIVATE_KEY: &[u8] = include_bytes!("{}");
-"#,
+pub const PRIVATE_KEY: crate::secrets::Sealed = {};
+{}"#,
         cert_ca_abs.to_string_lossy(),
         cert_crt_abs.to_string_lossy(),
-        cert_key_abs.to_string_lossy()
+        seal("private_key", &cert_key_abs),
+        backup_code
     );
     
     fs::write(&cert_file_path, cert_code)
         .expect("Failed to write certificates.rs");
+
+    // Generate sealed_settings.rs, the secret settings the firmware reads
+    // through secrets::settings
+    let mut settings_code = String::from("// Auto-generated by build.rs from cfg.toml\n// DO NOT EDIT THIS FILE MANUALLY\n\n");
+    for name in SECRET_SETTINGS {
+        let value = led_config.get(*name).and_then(|v| v.as_str()).unwrap_or("");
+        let sealed = match &sealer {
+            Some(sealer) if !value.is_empty() => sealer.seal_bytes(name, value.as_bytes()),
+            _ => format!("crate::secrets::Sealed::Plain({:?}.as_bytes())", value),
+        };
+        settings_code.push_str(&format!(
+            "pub static {}: crate::secrets::Setting = crate::secrets::Setting::new({});\n",
+            name.to_uppercase(),
+            sealed
+        ));
+    }
+    fs::write(Path::new(&out_dir).join("sealed_settings.rs"), settings_code)
+        .expect("Failed to write sealed_settings.rs");
+
+    // Public half of the tools/release signing key; without it the firmware
+    // refuses OTA jobs
+    let ota_public_key = led_config.get("ota_public_key")
+        .and_then(|v| v.as_str())
+        .filter(|path| !path.is_empty());
+    let ota_key_code = match ota_public_key {
+        Some(path) => {
+            if !Path::new(path).exists() {
+                panic!("OTA public key file not found at path: {}", path);
+            }
+            println!("cargo:rerun-if-changed={}", path);
+            format!(
+                "pub const OTA_PUBLIC_KEY: Option<&[u8]> = Some(include_bytes!(\"{}\"));\n",
+                Path::new(&manifest_dir).join(path).to_string_lossy()
+            )
+        }
+        None => "pub const OTA_PUBLIC_KEY: Option<&[u8]> = None;\n".to_string(),
+    };
+    fs::write(Path::new(&out_dir).join("ota_key.rs"), ota_key_code)
+        .expect("Failed to write ota_key.rs");
     
     println!("cargo:rerun-if-changed=cfg.toml");
     println!("cargo:rerun-if-changed={}", cert_ca);
@@ -90,4 +240,215 @@ pub const PRIVATE_KEY: &[u8] = include_bytes!("{}");
     println!("  CA: {}", cert_ca);
     println!("  Cert: {}", cert_crt);
     println!("  Key: {}", cert_key);
+
+    write_build_info(&out_dir, &manifest_dir, &cfg);
+    write_provenance(&out_dir, &manifest_dir);
+}
+
+// Encrypts embedded secrets with AES-256-GCM under
+// HMAC-SHA256(device-class key, SECRETS_CONTEXT), the key the device derives
+// with its HMAC peripheral from the same key burned into eFuse
+struct Sealer {
+    cipher: Aes256Gcm,
+    key_block: u32,
+    out_dir: PathBuf,
+}
+
+impl Sealer {
+    fn new(device_class_key: &[u8], key_block: u32, out_dir: &str) -> Self {
+        if device_class_key.len() != 32 {
+            panic!("The secrets key must be 32 raw bytes, e.g. from `openssl rand -out <file> 32`");
+        }
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(device_class_key).unwrap();
+        mac.update(SECRETS_CONTEXT);
+        let key = mac.finalize().into_bytes();
+        Self {
+            cipher: Aes256Gcm::new_from_slice(&key).unwrap(),
+            key_block,
+            out_dir: Path::new(out_dir).to_path_buf(),
+        }
+    }
+
+    // Encrypt the file at `path` into OUT_DIR and return the expression
+    // embedding it
+    fn seal(&self, name: &str, path: &Path) -> String {
+        let plaintext = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+        self.seal_bytes(name, &plaintext)
+    }
+
+    fn seal_bytes(&self, name: &str, plaintext: &[u8]) -> String {
+        // Deterministic, so rebuilds are reproducible; a nonce only repeats
+        // for the same secret under the same key
+        let digest = Sha256::new().chain_update(name).chain_update(plaintext).finalize();
+        let nonce: [u8; 12] = digest[..12].try_into().unwrap();
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("Failed to encrypt secret");
+
+        let sealed_path = self.out_dir.join(format!("{}.enc", name));
+        fs::write(&sealed_path, sealed).expect("Failed to write encrypted secret");
+        format!(
+            "crate::secrets::Sealed::Encrypted {{ key_block: {}, nonce: {:?}, data: include_bytes!(\"{}\") }}",
+            self.key_block,
+            nonce,
+            sealed_path.to_string_lossy()
+        )
+    }
+}
+
+// Generate build_info.rs: a JSON report of enabled features, configuration
+// hash and dependency versions, kept in its own section of the binary
+fn write_build_info(out_dir: &str, manifest_dir: &str, cfg: &Value) {
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+
+    let mut settings = cfg.get("example").and_then(Value::as_table).cloned().unwrap_or_default();
+    for key in SECRET_SETTINGS {
+        settings.remove(*key);
+    }
+    let settings = toml::to_string(&settings).expect("Failed to serialize cfg.toml settings");
+    // FNV-1a, stable across toolchains unlike DefaultHasher
+    let config_hash = settings.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+
+    let report = serde_json::json!({
+        "version": std::env::var("CARGO_PKG_VERSION").unwrap(),
+        "profile": std::env::var("PROFILE").unwrap_or_default(),
+        "target": std::env::var("TARGET").unwrap_or_default(),
+        "features": features,
+        "config_hash": format!("{:016x}", config_hash),
+        "dependencies": dependency_versions(manifest_dir),
+    });
+    let json = report.to_string();
+
+    let bytes: Vec<String> = json.bytes().map(|byte| byte.to_string()).collect();
+    let build_info_code = format!(
+        r#"// Auto-generated by build.rs
+// DO NOT EDIT THIS FILE MANUALLY
+
+#[used]
+#[link_section = ".rodata.build_info"]
+pub static BUILD_INFO: [u8; {}] = [{}];
+"#,
+        bytes.len(),
+        bytes.join(", ")
+    );
+
+    fs::write(Path::new(out_dir).join("build_info.rs"), build_info_code)
+        .expect("Failed to write build_info.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+// Generate provenance.rs: where this binary came from, for compliance
+// records. Kept in its own section like the build report
+fn write_provenance(out_dir: &str, manifest_dir: &str) {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(manifest_dir)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|status| !status.is_empty());
+    // Rebuild when the checked out commit moves
+    for path in ["HEAD", "index"] {
+        if let Some(path) = git(&["rev-parse", "--git-path", path]) {
+            println!("cargo:rerun-if-changed={}", Path::new(manifest_dir).join(path).display());
+        }
+    }
+    if let Some(path) = git(&["symbolic-ref", "-q", "HEAD"]).and_then(|head| git(&["rev-parse", "--git-path", &head])) {
+        println!("cargo:rerun-if-changed={}", Path::new(manifest_dir).join(path).display());
+    }
+
+    // CI names itself; a local build is whoever ran it
+    let builder = std::env::var("BUILDER")
+        .ok()
+        .or_else(|| {
+            let repository = std::env::var("GITHUB_REPOSITORY").ok()?;
+            let run = std::env::var("GITHUB_RUN_ID").ok()?;
+            Some(format!("github-actions:{}/{}", repository, run))
+        })
+        .or_else(|| std::env::var("USER").ok())
+        .or_else(|| std::env::var("USERNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    for var in ["BUILDER", "GITHUB_REPOSITORY", "GITHUB_RUN_ID", "USER", "USERNAME"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+
+    let version_of = |tool: String| {
+        Command::new(tool)
+            .arg("-V")
+            .output()
+            .ok()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    let lockfile_sha256 = fs::read(Path::new(manifest_dir).join("Cargo.lock"))
+        .map(|lock| format!("{:x}", Sha256::digest(lock)))
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    let record = serde_json::json!({
+        "version": std::env::var("CARGO_PKG_VERSION").unwrap(),
+        "commit": commit,
+        "dirty": dirty,
+        "builder": builder,
+        "rustc": version_of(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string())),
+        "cargo": version_of(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string())),
+        "esp_idf": std::env::var("ESP_IDF_VERSION").unwrap_or_else(|_| "unknown".to_string()),
+        "target": std::env::var("TARGET").unwrap_or_default(),
+        "lockfile_sha256": lockfile_sha256,
+    });
+    let json = record.to_string();
+
+    let bytes: Vec<String> = json.bytes().map(|byte| byte.to_string()).collect();
+    let provenance_code = format!(
+        r#"// Auto-generated by build.rs
+// DO NOT EDIT THIS FILE MANUALLY
+
+#[used]
+#[link_section = ".rodata.provenance"]
+pub static PROVENANCE: [u8; {}] = [{}];
+"#,
+        bytes.len(),
+        bytes.join(", ")
+    );
+
+    fs::write(Path::new(out_dir).join("provenance.rs"), provenance_code)
+        .expect("Failed to write provenance.rs");
+}
+
+// Resolved versions of the direct dependencies, from Cargo.lock
+fn dependency_versions(manifest_dir: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut versions = serde_json::Map::new();
+
+    let manifest: Value = fs::read_to_string(Path::new(manifest_dir).join("Cargo.toml"))
+        .ok()
+        .and_then(|content| content.parse().ok())
+        .unwrap_or(Value::Table(Default::default()));
+    let lock: Value = fs::read_to_string(Path::new(manifest_dir).join("Cargo.lock"))
+        .ok()
+        .and_then(|content| content.parse().ok())
+        .unwrap_or(Value::Table(Default::default()));
+
+    let packages = lock.get("package").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+    if let Some(dependencies) = manifest.get("dependencies").and_then(|d| d.as_table()) {
+        for name in dependencies.keys() {
+            let version = packages
+                .iter()
+                .find(|package| package.get("name").and_then(|n| n.as_str()) == Some(name.as_str()))
+                .and_then(|package| package.get("version"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("unknown");
+            versions.insert(name.clone(), version.into());
+        }
+    }
+
+    versions
 }
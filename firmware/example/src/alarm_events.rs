@@ -0,0 +1,286 @@
+//! Alarm events and their suppression windows, apart from the NVS registry
+//! in [`crate::alarms`], so the windows run in host tests (see
+//! `firmware/host-tests`).
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Transitions kept while they can't be published; the oldest go first
+const MAX_PENDING_EVENTS: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            other => Err(format!("Unknown alarm severity \"{}\" (info, warning or critical)", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alarm {
+    pub id: String,
+    pub severity: Severity,
+    /// Subsystem that raised it
+    pub source: String,
+    pub message: String,
+    /// When it was raised, in milliseconds since the Unix epoch, once SNTP has synced
+    pub raised_at: Option<u64>,
+    /// The condition is still present
+    pub active: bool,
+    pub requires_ack: bool,
+    pub acknowledged: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    Raised,
+    Cleared,
+    Acknowledged,
+}
+
+/// One step in the life of an alarm, published as an `alarm` event.
+#[derive(Serialize, Debug, Clone)]
+pub struct AlarmEvent {
+    pub event: &'static str,
+    pub transition: Transition,
+    #[serde(flatten)]
+    pub alarm: Alarm,
+    /// The alarm left the registry: cleared and, if it needed one, acknowledged
+    pub closed: bool,
+    /// Raises and clears held back since the alarm's previous event
+    #[serde(skip_serializing_if = "is_zero")]
+    pub suppressed: u32,
+}
+
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+/// Suppression window of one alarm.
+struct Window {
+    /// When the alarm's last raise or clear was published
+    since: Instant,
+    published: Transition,
+    /// Latest transition held back, published when the window ends
+    held: Option<AlarmEvent>,
+    suppressed: u32,
+}
+
+/// Events waiting to be published, with the raises and clears held back by
+/// suppression windows.
+pub struct Suppression {
+    /// Zero publishes every transition
+    window: Duration,
+    windows: BTreeMap<String, Window>,
+    pending: VecDeque<AlarmEvent>,
+}
+
+impl Suppression {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            windows: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queue `event`, or hold it back while its alarm's window lasts.
+    pub fn record(&mut self, mut event: AlarmEvent, now: Instant) {
+        if self.window.is_zero() {
+            queue(&mut self.pending, event);
+            return;
+        }
+        let id = event.alarm.id.clone();
+        let closed = event.closed;
+        let window = self.windows.get_mut(&id);
+        match (event.transition, window) {
+            (Transition::Acknowledged, Some(window)) => {
+                // Whatever was held comes first, so the order stays right
+                if let Some(mut held) = window.held.take() {
+                    held.suppressed = window.suppressed - 1;
+                    window.published = held.transition;
+                    window.suppressed = 0;
+                    queue(&mut self.pending, held);
+                }
+                if closed {
+                    self.windows.remove(&id);
+                }
+                queue(&mut self.pending, event);
+            }
+            (Transition::Acknowledged, None) => queue(&mut self.pending, event),
+            (_, Some(window)) if now.duration_since(window.since) < self.window => {
+                window.suppressed += 1;
+                window.held = Some(event);
+            }
+            (transition, window) => {
+                event.suppressed = window.map_or(0, |window| window.suppressed);
+                if !closed {
+                    self.windows.insert(
+                        id,
+                        Window {
+                            since: now,
+                            published: transition,
+                            held: None,
+                            suppressed: 0,
+                        },
+                    );
+                } else {
+                    self.windows.remove(&id);
+                }
+                queue(&mut self.pending, event);
+            }
+        }
+    }
+
+    /// Events not yet published, oldest first, including those held back
+    /// by windows that have ended by `now`.
+    pub fn take_events(&mut self, now: Instant) -> Vec<AlarmEvent> {
+        let window = self.window;
+        let mut released = Vec::new();
+        self.windows.retain(|_, state| {
+            if now.duration_since(state.since) < window {
+                return true;
+            }
+            match state.held.take() {
+                // Back where it was published: nothing to tell yet
+                Some(held) if !held.closed && held.transition == state.published => true,
+                Some(mut held) => {
+                    held.suppressed = state.suppressed - 1;
+                    state.since = now;
+                    state.published = held.transition;
+                    state.suppressed = 0;
+                    let closed = held.closed;
+                    released.push(held);
+                    !closed
+                }
+                // Keep the count for the alarm's next event
+                None => state.suppressed > 0,
+            }
+        });
+        for event in released {
+            queue(&mut self.pending, event);
+        }
+        self.pending.drain(..).collect()
+    }
+}
+
+fn queue(pending: &mut VecDeque<AlarmEvent>, event: AlarmEvent) {
+    if pending.len() >= MAX_PENDING_EVENTS {
+        pending.pop_front();
+    }
+    pending.push_back(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn event(transition: Transition, active: bool, acknowledged: bool, closed: bool) -> AlarmEvent {
+        AlarmEvent {
+            event: "alarm",
+            transition,
+            alarm: Alarm {
+                id: "temp".to_string(),
+                severity: Severity::Critical,
+                source: "sensor".to_string(),
+                message: "Too hot".to_string(),
+                raised_at: None,
+                active,
+                requires_ack: true,
+                acknowledged,
+            },
+            closed,
+            suppressed: 0,
+        }
+    }
+
+    fn raised() -> AlarmEvent {
+        event(Transition::Raised, true, false, false)
+    }
+
+    fn cleared() -> AlarmEvent {
+        event(Transition::Cleared, false, false, false)
+    }
+
+    fn summary(events: &[AlarmEvent]) -> Vec<(Transition, u32)> {
+        events.iter().map(|event| (event.transition, event.suppressed)).collect()
+    }
+
+    #[test]
+    fn flapping_publishes_the_latest_transition_once_per_window() {
+        let start = Instant::now();
+        let mut suppression = Suppression::new(WINDOW);
+        suppression.record(raised(), start);
+        for second in 1..=4 {
+            let at = start + Duration::from_secs(second);
+            suppression.record(if second % 2 == 1 { cleared() } else { raised() }, at);
+        }
+        suppression.record(cleared(), start + Duration::from_secs(5));
+        assert_eq!(summary(&suppression.take_events(start + Duration::from_secs(10))), [(Transition::Raised, 0)]);
+
+        let events = suppression.take_events(start + WINDOW);
+        assert_eq!(summary(&events), [(Transition::Cleared, 4)]);
+        assert!(suppression.take_events(start + WINDOW * 3).is_empty());
+    }
+
+    #[test]
+    fn zero_window_publishes_every_transition() {
+        let now = Instant::now();
+        let mut suppression = Suppression::new(Duration::ZERO);
+        suppression.record(raised(), now);
+        suppression.record(cleared(), now);
+        suppression.record(raised(), now);
+        assert_eq!(
+            summary(&suppression.take_events(now)),
+            [(Transition::Raised, 0), (Transition::Cleared, 0), (Transition::Raised, 0)]
+        );
+    }
+
+    #[test]
+    fn an_ack_in_a_window_flushes_the_held_event_first() {
+        let start = Instant::now();
+        let mut suppression = Suppression::new(WINDOW);
+        suppression.record(raised(), start);
+        suppression.record(cleared(), start + Duration::from_secs(1));
+        suppression.record(event(Transition::Acknowledged, false, true, true), start + Duration::from_secs(2));
+
+        let events = suppression.take_events(start + Duration::from_secs(3));
+        assert_eq!(
+            summary(&events),
+            [(Transition::Raised, 0), (Transition::Cleared, 0), (Transition::Acknowledged, 0)]
+        );
+        assert!(events[2].closed);
+        // Closed with the ack, so the next raise starts a window of its own
+        suppression.record(raised(), start + Duration::from_secs(4));
+        assert_eq!(summary(&suppression.take_events(start + Duration::from_secs(5))), [(Transition::Raised, 0)]);
+    }
+
+    #[test]
+    fn a_window_ending_where_it_was_published_stays_quiet() {
+        let start = Instant::now();
+        let mut suppression = Suppression::new(WINDOW);
+        suppression.record(raised(), start);
+        suppression.record(cleared(), start + Duration::from_secs(1));
+        suppression.record(raised(), start + Duration::from_secs(2));
+        assert_eq!(summary(&suppression.take_events(start + WINDOW)), [(Transition::Raised, 0)]);
+
+        // The flapping still counts towards the alarm's next event
+        suppression.record(cleared(), start + WINDOW * 2);
+        assert_eq!(summary(&suppression.take_events(start + WINDOW * 2)), [(Transition::Cleared, 2)]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Registry of active alarms. Subsystems raise an alarm while a condition
+//! lasts and clear it when the condition goes away. Alarms at or above the
+//! acknowledgment severity also need an `alarm_ack` from the cloud: a
+//! cleared alarm stays in the registry until it is acknowledged, so an
+//! operator can't miss one that came and went while nobody was looking.
+//!
+//! The registry is kept in NVS, so active alarms survive a reboot. Every
+//! transition is queued as an [`AlarmEvent`] for the main loop to publish
+//! and mirror in the `alarms` named shadow once it can.
+//!
+//! A flapping sensor would raise and clear the same alarm many times a
+//! minute. With a suppression window, an alarm publishes at most one raise
+//! or clear per window: later ones are held, and when the window ends only
+//! the latest is published if it changed anything, with the number of
+//! transitions left out in `suppressed`. Acknowledgments always go out.
+
+use crate::alarm_events::Suppression;
+use crate::clock;
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+pub use crate::alarm_events::{Alarm, AlarmEvent, Severity, Transition};
+
+const ALARMS_KEY: &str = "alarms";
+/// NVS strings top out at 4000 bytes
+const MAX_ALARMS: usize = 12;
+const MAX_MESSAGE_CHARS: usize = 96;
+
+pub struct Alarms {
+    nvs: EspNvs<NvsDefault>,
+    /// Alarms from this severity up need acknowledging; `None` for none
+    ack_from: Option<Severity>,
+    alarms: BTreeMap<String, Alarm>,
+    suppression: Suppression,
+}
+
+impl Alarms {
+    /// Load the alarms stored before the last reboot.
+    pub fn open(
+        partition: EspDefaultNvsPartition,
+        ack_from: Option<Severity>,
+        suppress_window: Duration,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = vec![0u8; 4000];
+        let stored: Vec<Alarm> = match nvs.get_str(ALARMS_KEY, &mut buf)? {
+            Some(json) => serde_json::from_str(json).unwrap_or_else(|e| {
+                log::warn!("Dropping unreadable stored alarms: {}", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        if !stored.is_empty() {
+            log::info!("{} alarm(s) still open from before the reboot", stored.len());
+        }
+        Ok(Self {
+            nvs,
+            ack_from,
+            alarms: stored.into_iter().map(|alarm| (alarm.id.clone(), alarm)).collect(),
+            suppression: Suppression::new(suppress_window),
+        })
+    }
+
+    /// Every alarm in the registry, active or waiting for acknowledgment.
+    pub fn iter(&self) -> impl Iterator<Item = &Alarm> {
+        self.alarms.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.alarms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alarms.is_empty()
+    }
+
+    pub fn is_active(&self, id: &str) -> bool {
+        self.alarms.get(id).is_some_and(|alarm| alarm.active)
+    }
+
+    /// Transitions not yet published, oldest first, including those held
+    /// back by suppression windows that have ended.
+    pub fn take_events(&mut self) -> Vec<AlarmEvent> {
+        self.suppression.take_events(Instant::now())
+    }
+
+    /// Raise alarm `id`, or update it if it is already in the registry.
+    /// Nothing happens when it is already active as described.
+    pub fn raise(
+        &mut self,
+        id: &str,
+        severity: Severity,
+        source: &str,
+        message: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let message: String = message.chars().take(MAX_MESSAGE_CHARS).collect();
+        let requires_ack = self.ack_from.is_some_and(|ack_from| severity >= ack_from);
+        match self.alarms.get_mut(id) {
+            Some(alarm) if alarm.active && alarm.severity == severity && alarm.message == message => {
+                return Ok(());
+            }
+            Some(alarm) => {
+                // Coming back before it was acknowledged needs a fresh acknowledgment
+                if !alarm.active {
+                    alarm.acknowledged = false;
+                    alarm.raised_at = clock::now_ms();
+                }
+                alarm.active = true;
+                alarm.severity = severity;
+                alarm.source = source.to_string();
+                alarm.message = message;
+                alarm.requires_ack = requires_ack;
+            }
+            None => {
+                if self.alarms.len() >= MAX_ALARMS {
+                    return Err(format!("Alarm registry full, can't raise \"{}\"", id).into());
+                }
+                self.alarms.insert(
+                    id.to_string(),
+                    Alarm {
+                        id: id.to_string(),
+                        severity,
+                        source: source.to_string(),
+                        message,
+                        raised_at: clock::now_ms(),
+                        active: true,
+                        requires_ack,
+                        acknowledged: false,
+                    },
+                );
+            }
+        }
+        log::warn!("Alarm \"{}\" raised ({:?}, {})", id, severity, source);
+        self.transition(id, Transition::Raised)
+    }
+
+    /// The condition behind alarm `id` went away. Nothing happens when it
+    /// isn't active.
+    pub fn clear(&mut self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(alarm) = self.alarms.get_mut(id).filter(|alarm| alarm.active) else {
+            return Ok(());
+        };
+        alarm.active = false;
+        log::info!("Alarm \"{}\" cleared", id);
+        self.transition(id, Transition::Cleared)
+    }
+
+    /// The cloud acknowledged alarm `id`.
+    pub fn acknowledge(&mut self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let alarm = self.alarms.get_mut(id).ok_or_else(|| format!("No alarm \"{}\"", id))?;
+        if alarm.acknowledged {
+            return Ok(());
+        }
+        alarm.acknowledged = true;
+        log::info!("Alarm \"{}\" acknowledged", id);
+        self.transition(id, Transition::Acknowledged)
+    }
+
+    /// Close the alarm if it is done with, store the registry and queue the
+    /// event describing what happened.
+    fn transition(&mut self, id: &str, transition: Transition) -> Result<(), Box<dyn std::error::Error>> {
+        let alarm = self.alarms[id].clone();
+        let closed = !alarm.active && (alarm.acknowledged || !alarm.requires_ack);
+        if closed {
+            self.alarms.remove(id);
+        }
+        let stored: Vec<&Alarm> = self.alarms.values().collect();
+        self.nvs.set_str(ALARMS_KEY, &serde_json::to_string(&stored)?)?;
+
+        let event = AlarmEvent {
+            event: "alarm",
+            transition,
+            alarm,
+            closed,
+            suppressed: 0,
+        };
+        self.suppression.record(event, Instant::now());
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use crossbeam_channel::{bounded, Receiver, Sender};
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2s::config::{
+    Config as ChannelConfig, DataBitWidth, SlotMode, StdClkConfig, StdConfig, StdGpioConfig, StdSlotConfig,
+};
+use esp_idf_svc::hal::i2s::{I2sDriver, I2sRx, I2S0};
+use serde::Serialize;
+use std::thread;
+
+/// Levels are computed over windows of this many milliseconds.
+const WINDOW_MS: u32 = 1000;
+/// The level must fall this far below the threshold before another alert.
+const HYSTERESIS_DB: f32 = 3.0;
+/// Floor for silence so an all-zero window isn't -inf.
+const MIN_DB: f32 = -120.0;
+
+/// Sound level over one window, in dB relative to full scale.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Level {
+    pub rms_dbfs: f32,
+    pub peak_dbfs: f32,
+}
+
+/// Level statistics since the previous telemetry report.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct LevelStats {
+    pub windows: u32,
+    pub min_rms_dbfs: f32,
+    pub avg_rms_dbfs: f32,
+    pub max_rms_dbfs: f32,
+    pub max_peak_dbfs: f32,
+}
+
+/// I2S MEMS microphone (INMP441 or similar, left channel). Samples are
+/// reduced to RMS and peak levels on a background thread; raw audio never
+/// leaves it.
+pub struct Microphone {
+    levels: Receiver<Level>,
+    threshold_dbfs: f32,
+    alerting: bool,
+    windows: u32,
+    rms_sum: f32,
+    min_rms: f32,
+    max_rms: f32,
+    max_peak: f32,
+}
+
+impl Microphone {
+    pub fn start(
+        i2s: I2S0,
+        bclk_pin: i32,
+        ws_pin: i32,
+        din_pin: i32,
+        sample_rate: u32,
+        threshold_dbfs: f32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = StdConfig::new(
+            ChannelConfig::default(),
+            StdClkConfig::from_sample_rate_hz(sample_rate),
+            StdSlotConfig::philips_slot_default(DataBitWidth::Bits32, SlotMode::Mono),
+            StdGpioConfig::default(),
+        );
+        let mut driver = I2sDriver::new_std_rx(
+            i2s,
+            &config,
+            unsafe { AnyIOPin::new(bclk_pin) },
+            unsafe { AnyIOPin::new(din_pin) },
+            Option::<AnyIOPin>::None,
+            unsafe { AnyIOPin::new(ws_pin) },
+        )?;
+        driver.rx_enable()?;
+
+        let (tx, rx) = bounded(4);
+        let window_samples = (sample_rate * WINDOW_MS / 1000) as usize;
+        thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || measure(driver, window_samples, tx))
+            .map_err(|e| format!("Failed to spawn microphone thread: {}", e))?;
+
+        log::info!("Microphone started at {} Hz", sample_rate);
+        Ok(Self {
+            levels: rx,
+            threshold_dbfs,
+            alerting: false,
+            windows: 0,
+            rms_sum: 0.0,
+            min_rms: 0.0,
+            max_rms: MIN_DB,
+            max_peak: MIN_DB,
+        })
+    }
+
+    /// Fold in the levels measured since the last call. Returns the level
+    /// that crossed the alert threshold, once per excursion above it.
+    pub fn poll(&mut self) -> Option<Level> {
+        let mut alert = None;
+        while let Ok(level) = self.levels.try_recv() {
+            self.min_rms = if self.windows == 0 { level.rms_dbfs } else { self.min_rms.min(level.rms_dbfs) };
+            self.max_rms = self.max_rms.max(level.rms_dbfs);
+            self.max_peak = self.max_peak.max(level.peak_dbfs);
+            self.rms_sum += level.rms_dbfs;
+            self.windows += 1;
+
+            if !self.alerting && level.rms_dbfs >= self.threshold_dbfs {
+                self.alerting = true;
+                alert = Some(level);
+            } else if self.alerting && level.rms_dbfs < self.threshold_dbfs - HYSTERESIS_DB {
+                self.alerting = false;
+            }
+        }
+        alert
+    }
+
+    /// Statistics since the previous call, or `None` if nothing was measured.
+    pub fn take_stats(&mut self) -> Option<LevelStats> {
+        if self.windows == 0 {
+            return None;
+        }
+        let stats = LevelStats {
+            windows: self.windows,
+            min_rms_dbfs: self.min_rms,
+            avg_rms_dbfs: self.rms_sum / self.windows as f32,
+            max_rms_dbfs: self.max_rms,
+            max_peak_dbfs: self.max_peak,
+        };
+        self.windows = 0;
+        self.rms_sum = 0.0;
+        self.max_rms = MIN_DB;
+        self.max_peak = MIN_DB;
+        Some(stats)
+    }
+}
+
+fn measure(mut driver: I2sDriver<'static, I2sRx>, window_samples: usize, levels: Sender<Level>) {
+    let mut buf = [0u8; 1024];
+    let mut sum_squares = 0f64;
+    let mut peak = 0f32;
+    let mut count = 0;
+    loop {
+        let read = match driver.read(&mut buf, BLOCK) {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Microphone read failed: {}", e);
+                continue;
+            }
+        };
+        for sample in buf[..read].chunks_exact(4) {
+            // 24-bit samples, left-justified in 32-bit slots
+            let value = (i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) >> 8) as f32 / 8_388_608.0;
+            sum_squares += (value * value) as f64;
+            peak = peak.max(value.abs());
+            count += 1;
+
+            if count == window_samples {
+                let level = Level {
+                    rms_dbfs: to_dbfs((sum_squares / count as f64).sqrt() as f32),
+                    peak_dbfs: to_dbfs(peak),
+                };
+                // The main loop is busy; losing a window is fine
+                let _ = levels.try_send(level);
+                sum_squares = 0.0;
+                peak = 0.0;
+                count = 0;
+            }
+        }
+    }
+}
+
+fn to_dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 {
+        return MIN_DB;
+    }
+    (20.0 * amplitude.log10()).max(MIN_DB)
+}
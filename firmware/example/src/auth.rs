@@ -0,0 +1,465 @@
+use crate::client::{
+    self, convert_certificate, BACKUP_CLIENT_CERT, BACKUP_PRIVATE_KEY, CLIENT_CERT, PRIVATE_KEY,
+};
+use crate::migrations::NAMESPACE;
+use crate::secrets::{settings, Sealed};
+use crate::sigv4::{self, Credentials};
+use crate::startup::Config;
+use crate::{clock, keygen};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::mqtt::client::MqttClientConfiguration;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for SNTP before giving up on signing.
+const CLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Position in the fallback chain to use on the next boot.
+const FALLBACK_KEY: &str = "auth_fallback";
+
+/// Turns a broker URL into the one to connect to, see [`AuthProvider::url_signer`].
+pub type UrlSigner = Arc<dyn Fn(&str) -> Result<String, Box<dyn std::error::Error>> + Send + Sync>;
+
+/// How the device authenticates to the broker.
+///
+/// `Client::new` only deals with the transport; each provider fills in the
+/// credential-related parts of the MQTT configuration and may rewrite the
+/// broker URL (e.g. for WebSocket or custom authorizer modes).
+pub trait AuthProvider {
+    fn name(&self) -> &'static str;
+
+    /// Set the credentials on the MQTT configuration.
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// URL to connect to, given the configured `mqtt_url`.
+    fn broker_url(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(url.to_string())
+    }
+
+    /// PEM certificate and private key presented in the TLS handshake, for
+    /// connections to the broker besides esp-mqtt's. `None` when the broker
+    /// sees no client certificate.
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+        Ok(None)
+    }
+
+    /// PEM CA the broker's certificate chains to, when it isn't Amazon's.
+    fn server_ca(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Makes a fresh [`broker_url`](Self::broker_url) for each reconnect, for
+    /// providers whose URL carries credentials that expire. `None` when the
+    /// URL stays valid.
+    fn url_signer(&self) -> Option<UrlSigner> {
+        None
+    }
+}
+
+/// Client certificate and key compiled into the firmware from cfg.toml paths.
+pub struct EmbeddedX509;
+
+impl AuthProvider for EmbeddedX509 {
+    fn name(&self) -> &'static str {
+        "x509_embedded"
+    }
+
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("Client cert size: {} bytes", CLIENT_CERT.len());
+        log::info!("Private key size: {} bytes", PRIVATE_KEY.len());
+        conf.client_certificate = Some(convert_certificate(CLIENT_CERT.to_vec()));
+        conf.private_key = Some(convert_certificate(PRIVATE_KEY.open()?.into_owned()));
+        Ok(())
+    }
+
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+        Ok(Some((CLIENT_CERT.to_vec(), PRIVATE_KEY.open()?.into_owned())))
+    }
+}
+
+/// Certificate and key stored in NVS, e.g. issued for the on-device key.
+pub struct NvsX509 {
+    certificate_pem: String,
+    private_key_pem: String,
+    /// The certificate replaced by the last install
+    previous: bool,
+}
+
+impl NvsX509 {
+    pub fn new(certificate_pem: String, private_key_pem: String) -> Self {
+        Self {
+            certificate_pem,
+            private_key_pem,
+            previous: false,
+        }
+    }
+}
+
+impl AuthProvider for NvsX509 {
+    fn name(&self) -> &'static str {
+        if self.previous {
+            "x509_nvs_previous"
+        } else {
+            "x509_nvs"
+        }
+    }
+
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        conf.client_certificate = Some(convert_certificate(self.certificate_pem.clone().into_bytes()));
+        conf.private_key = Some(convert_certificate(self.private_key_pem.clone().into_bytes()));
+        Ok(())
+    }
+
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+        let certificate = self.certificate_pem.clone().into_bytes();
+        Ok(Some((certificate, self.private_key_pem.clone().into_bytes())))
+    }
+}
+
+/// Second certificate and key compiled in from `cert_backup_crt` and
+/// `cert_backup_key`.
+pub struct BackupX509 {
+    certificate: &'static [u8],
+    private_key: Sealed,
+}
+
+impl AuthProvider for BackupX509 {
+    fn name(&self) -> &'static str {
+        "x509_backup"
+    }
+
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        conf.client_certificate = Some(convert_certificate(self.certificate.to_vec()));
+        conf.private_key = Some(convert_certificate(self.private_key.open()?.into_owned()));
+        Ok(())
+    }
+
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+        Ok(Some((self.certificate.to_vec(), self.private_key.open()?.into_owned())))
+    }
+}
+
+/// Where the SigV4 provider gets its keys.
+#[derive(Clone)]
+enum CredentialSource {
+    /// Keys from cfg.toml
+    Static(Credentials),
+    /// Temporary keys from the AWS IoT credentials provider, fetched with the
+    /// embedded certificate over HTTPS on 443
+    RoleAlias {
+        endpoint: &'static str,
+        role_alias: &'static str,
+        thing_name: &'static str,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct CredentialsResponse {
+    credentials: Credentials,
+}
+
+/// MQTT over WebSockets on port 443 with a SigV4-presigned URL, for
+/// networks that block 8883. No client certificate is sent to the broker.
+/// Temporary credentials expire within hours, and so does the signed URL,
+/// so it is signed again, with fresh credentials, before every reconnect.
+#[derive(Clone)]
+pub struct SigV4WebSocket {
+    source: CredentialSource,
+}
+
+impl SigV4WebSocket {
+    fn credentials(&self) -> Result<Credentials, Box<dyn std::error::Error>> {
+        let (endpoint, role_alias, thing_name) = match &self.source {
+            CredentialSource::Static(credentials) => return Ok(credentials.clone()),
+            CredentialSource::RoleAlias {
+                endpoint,
+                role_alias,
+                thing_name,
+            } => (*endpoint, *role_alias, *thing_name),
+        };
+
+        let mut connection = EspHttpConnection::new(&HttpConfiguration {
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            client_certificate: Some(convert_certificate(CLIENT_CERT.to_vec())),
+            private_key: Some(convert_certificate(PRIVATE_KEY.open()?.into_owned())),
+            ..Default::default()
+        })?;
+        let url = format!("https://{}/role-aliases/{}/credentials", endpoint, role_alias);
+        connection.initiate_request(Method::Get, &url, &[("x-amzn-iot-thingname", thing_name)])?;
+        connection.initiate_response()?;
+        if connection.status() != 200 {
+            return Err(format!("Credentials provider answered HTTP {}", connection.status()).into());
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            let read = connection.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..read]);
+        }
+        let response: CredentialsResponse = serde_json::from_slice(&body)?;
+        log::info!("Fetched temporary credentials for role alias \"{}\"", role_alias);
+        Ok(response.credentials)
+    }
+}
+
+impl AuthProvider for SigV4WebSocket {
+    fn name(&self) -> &'static str {
+        "sigv4_websocket"
+    }
+
+    fn apply(&self, _conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        // The server certificate check stays; the identity is in the URL
+        Ok(())
+    }
+
+    fn broker_url(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let host = url
+            .split("://")
+            .last()
+            .and_then(|rest| rest.split(['/', ':']).next())
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| format!("No host in mqtt_url \"{}\"", url))?;
+        let region = sigv4::region_of(host).ok_or_else(|| format!("No AWS region in \"{}\"", host))?;
+
+        // The signature is only accepted close to its timestamp
+        let deadline = Instant::now() + CLOCK_TIMEOUT;
+        let now_ms = loop {
+            if let Some(now_ms) = clock::now_ms() {
+                break now_ms;
+            }
+            if Instant::now() >= deadline {
+                return Err("Clock not set, can't sign the broker URL".into());
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        };
+
+        let credentials = self.credentials()?;
+        Ok(sigv4::presign_mqtt_url(host, region, &credentials, now_ms / 1000))
+    }
+
+    fn url_signer(&self) -> Option<UrlSigner> {
+        let provider = self.clone();
+        Some(Arc::new(move |url| provider.broker_url(url)))
+    }
+}
+
+/// MQTT on port 443 authenticated by an AWS IoT custom authorizer, for
+/// devices holding a token from an existing backend instead of a client
+/// certificate. The authorizer name, token and signature travel as query
+/// parameters of the MQTT username; the password goes to the authorizer's
+/// Lambda as is. AWS IoT only accepts this with the `mqtt` ALPN protocol.
+pub struct CustomAuthorizer {
+    /// Username with the authorizer parameters appended
+    username: &'static str,
+    password: Option<&'static str>,
+}
+
+impl CustomAuthorizer {
+    /// `token_key` is the token key name configured on the authorizer;
+    /// `signature` is only needed when token signing is enabled on it.
+    pub fn new(
+        authorizer_name: &str,
+        username: &str,
+        password: &'static str,
+        token_key: &str,
+        token: &str,
+        signature: &str,
+    ) -> Self {
+        let mut query = format!("x-amz-customauthorizer-name={}", sigv4::uri_encode(authorizer_name));
+        if !token_key.is_empty() {
+            query.push_str(&format!("&{}={}", sigv4::uri_encode(token_key), sigv4::uri_encode(token)));
+        }
+        if !signature.is_empty() {
+            query.push_str(&format!("&x-amz-customauthorizer-signature={}", sigv4::uri_encode(signature)));
+        }
+        // Made once per boot and borrowed by the MQTT configuration
+        let username = Box::leak(format!("{}?{}", username, query).into_boxed_str());
+        Self {
+            username,
+            password: Some(password).filter(|password| !password.is_empty()),
+        }
+    }
+}
+
+impl AuthProvider for CustomAuthorizer {
+    fn name(&self) -> &'static str {
+        "custom_authorizer"
+    }
+
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        // The server certificate check stays; no client certificate is sent
+        conf.username = Some(self.username);
+        conf.password = self.password;
+        conf.alpn_protos = Some(&[client::ALPN_MQTT]);
+        Ok(())
+    }
+
+    fn broker_url(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        if !url.starts_with("mqtts://") {
+            return Err(format!("custom_authorizer needs an mqtts:// mqtt_url, not \"{}\"", url).into());
+        }
+        Ok(client::with_default_port(url, 443))
+    }
+}
+
+/// Build the provider selected by `auth_mode` in cfg.toml.
+pub fn from_config(
+    config: &Config,
+    nvs: EspDefaultNvsPartition,
+) -> Result<Box<dyn AuthProvider>, Box<dyn std::error::Error>> {
+    match config.auth_mode {
+        "x509_embedded" => Ok(Box::new(EmbeddedX509)),
+        "x509_nvs" => match keygen::load_credentials(nvs)? {
+            Some((certificate_pem, private_key_pem)) => Ok(Box::new(NvsX509 {
+                certificate_pem,
+                private_key_pem,
+                previous: false,
+            })),
+            None => {
+                // Needed to bootstrap: the CSR flow runs over the embedded identity
+                log::warn!("No certificate in NVS yet, falling back to the embedded certificate");
+                Ok(Box::new(EmbeddedX509))
+            }
+        },
+        "sigv4_websocket" if !config.aws_access_key_id.is_empty() => Ok(Box::new(SigV4WebSocket {
+            source: CredentialSource::Static(Credentials {
+                access_key_id: config.aws_access_key_id.to_string(),
+                secret_access_key: settings::AWS_SECRET_ACCESS_KEY.open()?.to_string(),
+                session_token: Some(settings::AWS_SESSION_TOKEN.open()?)
+                    .filter(|token| !token.is_empty())
+                    .map(str::to_string),
+            }),
+        })),
+        "sigv4_websocket" if !config.credentials_endpoint.is_empty() => Ok(Box::new(SigV4WebSocket {
+            source: CredentialSource::RoleAlias {
+                endpoint: config.credentials_endpoint,
+                role_alias: config.credentials_role_alias,
+                thing_name: config.thing_name(),
+            },
+        })),
+        "sigv4_websocket" => Err("sigv4_websocket needs aws_access_key_id or credentials_endpoint".into()),
+        "custom_authorizer" if config.custom_authorizer_name.is_empty() => {
+            Err("custom_authorizer needs custom_authorizer_name".into())
+        }
+        "custom_authorizer" => Ok(Box::new(CustomAuthorizer::new(
+            config.custom_authorizer_name,
+            config.custom_auth_username,
+            settings::CUSTOM_AUTH_PASSWORD.open()?,
+            config.custom_auth_token_key,
+            settings::CUSTOM_AUTH_TOKEN.open()?,
+            settings::CUSTOM_AUTH_SIGNATURE.open()?,
+        ))),
+        other => Err(format!("Unsupported auth_mode \"{}\"", other).into()),
+    }
+}
+
+/// PEM of the client certificate `auth_mode` connects with, for reading
+/// the identity out of it.
+pub fn client_certificate(config: &Config, nvs: EspDefaultNvsPartition) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match config.auth_mode {
+        "x509_embedded" => Ok(CLIENT_CERT.to_vec()),
+        "x509_nvs" => match keygen::load_certificate(nvs)? {
+            Some(certificate_pem) => Ok(certificate_pem.into_bytes()),
+            None => Ok(CLIENT_CERT.to_vec()),
+        },
+        other => Err(format!("auth_mode \"{}\" connects without a client certificate", other).into()),
+    }
+}
+
+/// Identity picked for this boot out of the fallback chain.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// 0 for the identity selected by `auth_mode`
+    pub index: usize,
+    pub name: &'static str,
+    /// Length of the chain
+    pub count: usize,
+}
+
+impl Identity {
+    pub fn is_fallback(&self) -> bool {
+        self.index > 0
+    }
+}
+
+/// Identities to try after the primary one: the certificate replaced by the
+/// last `install_cert`, the embedded bootstrap certificate for `x509_nvs`,
+/// and the `cert_backup_crt` pair. SigV4 and custom authorizers have no
+/// fallback.
+fn backups(
+    config: &Config,
+    nvs: EspDefaultNvsPartition,
+) -> Result<Vec<Box<dyn AuthProvider>>, Box<dyn std::error::Error>> {
+    let mut chain: Vec<Box<dyn AuthProvider>> = Vec::new();
+    if config.auth_mode == "x509_nvs" {
+        if let Some((certificate_pem, private_key_pem)) = keygen::load_previous_credentials(nvs)? {
+            chain.push(Box::new(NvsX509 {
+                certificate_pem,
+                private_key_pem,
+                previous: true,
+            }));
+        }
+        chain.push(Box::new(EmbeddedX509));
+    }
+    if config.auth_mode.starts_with("x509_") {
+        if let (Some(certificate), Some(private_key)) = (BACKUP_CLIENT_CERT, BACKUP_PRIVATE_KEY) {
+            chain.push(Box::new(BackupX509 {
+                certificate,
+                private_key,
+            }));
+        }
+    }
+    Ok(chain)
+}
+
+/// The provider for this boot: normally the one selected by `auth_mode`, or
+/// the backup chosen by [`fall_back`] before the last restart. That choice
+/// holds for one boot only, so the next restart tries the primary again.
+pub fn select(
+    config: &Config,
+    nvs: EspDefaultNvsPartition,
+) -> Result<(Box<dyn AuthProvider>, Identity), Box<dyn std::error::Error>> {
+    let mut chain = vec![from_config(config, nvs.clone())?];
+    if config.cert_fallback_after > 0 {
+        for backup in backups(config, nvs.clone())? {
+            // x509_nvs already falls back to the embedded identity itself
+            if chain.iter().all(|provider| provider.name() != backup.name()) {
+                chain.push(backup);
+            }
+        }
+    }
+
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    let index = (storage.get_u8(FALLBACK_KEY)?.unwrap_or(0) as usize).min(chain.len() - 1);
+    if index > 0 {
+        storage.remove(FALLBACK_KEY)?;
+    }
+
+    let count = chain.len();
+    let provider = chain.swap_remove(index);
+    let identity = Identity {
+        index,
+        name: provider.name(),
+        count,
+    };
+    Ok((provider, identity))
+}
+
+/// Give up on `identity`: the next boot uses the one after it in the chain,
+/// or the primary again after the last one.
+pub fn fall_back(nvs: EspDefaultNvsPartition, identity: &Identity) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    let next = (identity.index + 1) % identity.count;
+    if next > 0 {
+        storage.set_u8(FALLBACK_KEY, next as u8)?;
+    } else {
+        storage.remove(FALLBACK_KEY)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Plumbing shared by the battery-powered profiles: bring the network up
+//! only when there is something to send, make sure it arrived, sleep again.
+
+use crate::envelope;
+use crate::events::Event;
+use crate::rtc::RtcSlot;
+use crate::startup::App;
+use crossbeam_channel::Receiver;
+use esp_idf_svc::mqtt::client::QoS;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Envelope numbering, carried over deep sleep so the cloud's dedup by
+/// `device_id` and `sequence` doesn't see each wake's messages as repeats
+#[link_section = ".rtc.data"]
+static SEQUENCE: RtcSlot<u64, 32> = RtcSlot::new();
+
+/// A connected [`App`] for the length of one wake cycle.
+pub struct Session {
+    pub app: App,
+    /// Dropping the receiver would stop the listener, and with it the acks
+    _messages: Receiver<(String, Vec<u8>)>,
+}
+
+impl Session {
+    /// Connect WiFi and MQTT and wait for the broker to accept us.
+    pub fn connect(nvs: EspDefaultNvsPartition) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(last) = SEQUENCE.load() {
+            envelope::resume_sequence(last);
+        }
+        let mut app = App::with_partition(nvs)?;
+        let events = app.events.subscribe(8);
+        let messages = app.client.lock().start_message_listener()?;
+
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(remaining) {
+                Ok(Event::MqttConnected) => break,
+                Ok(_) => {}
+                Err(_) => return Err("Timed out waiting for the broker".into()),
+            }
+        }
+        Ok(Self {
+            app,
+            _messages: messages,
+        })
+    }
+
+    /// Publish `payloads` to the publish topic at QoS 1 and wait until the
+    /// broker has acknowledged every one, so it is safe to sleep.
+    pub fn publish_confirmed(&mut self, payloads: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = self.app.client.lock();
+        let acks = client.watch_acks(payloads.len().max(1));
+        let topic = client.pub_topic.clone();
+        let mut pending = Vec::new();
+        for payload in payloads {
+            pending.push(client.publish_with_qos(&topic, payload, QoS::AtLeastOnce)?);
+        }
+
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        let result = loop {
+            if pending.is_empty() {
+                break Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match acks.recv_timeout(remaining) {
+                Ok(id) => pending.retain(|pending| *pending != id),
+                Err(_) => break Err(format!("{} publishes not acknowledged", pending.len()).into()),
+            }
+        };
+        client.stop_watching_acks();
+        result
+    }
+}
+
+/// Enter deep sleep, waking after `timer` if given, and on whatever other
+/// sources the caller has armed.
+pub fn deep_sleep(timer: Option<Duration>) -> ! {
+    log::info!("Deep sleep, timer wakeup {:?}", timer);
+    if let Err(e) = SEQUENCE.store(&envelope::last_sequence()) {
+        log::warn!("Failed to keep the sequence number: {}", e);
+    }
+    unsafe {
+        if let Some(timer) = timer {
+            sys::esp_sleep_enable_timer_wakeup(timer.as_micros() as u64);
+        }
+        sys::esp_deep_sleep_start()
+    }
+}
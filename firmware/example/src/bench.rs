@@ -0,0 +1,135 @@
+use crate::client::Client;
+use esp_idf_svc::mqtt::client::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const MAX_COUNT: u32 = 1000;
+const MAX_SIZE: usize = 8192;
+/// How long to wait for outstanding QoS 1 acknowledgements.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+pub struct BenchCommand {
+    #[serde(default = "default_count")]
+    pub count: u32,
+    #[serde(default = "default_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub qos: u8,
+}
+
+fn default_count() -> u32 {
+    100
+}
+
+fn default_size() -> usize {
+    256
+}
+
+/// Min/avg/max of a set of latencies, in microseconds.
+#[derive(Serialize, Debug, Default)]
+pub struct Latency {
+    pub min_us: u64,
+    pub avg_us: u64,
+    pub max_us: u64,
+    #[serde(skip)]
+    total_us: u64,
+    #[serde(skip)]
+    samples: u64,
+}
+
+impl Latency {
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.min_us = if self.samples == 0 { us } else { self.min_us.min(us) };
+        self.max_us = self.max_us.max(us);
+        self.total_us += us;
+        self.samples += 1;
+        self.avg_us = self.total_us / self.samples;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct BenchReport {
+    pub count: u32,
+    pub size: usize,
+    pub qos: u8,
+    pub sent: u32,
+    /// Publishes the client refused, typically because the outbox was full
+    pub dropped: u32,
+    pub acked: u32,
+    pub duration_ms: u64,
+    pub msgs_per_sec: u32,
+    pub enqueue: Latency,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack: Option<Latency>,
+}
+
+/// Publish `count` messages of `size` bytes to `topic` back to back,
+/// measuring how long each enqueue takes and, for QoS 1, how long the
+/// broker takes to acknowledge it.
+pub fn run(client: &mut Client, topic: &str, command: &BenchCommand) -> Result<BenchReport, Box<dyn std::error::Error>> {
+    let count = command.count.min(MAX_COUNT);
+    let size = command.size.min(MAX_SIZE);
+    let qos = crate::client::qos(command.qos)?;
+    log::info!("Benchmark: {} messages of {} bytes at QoS {}", count, size, command.qos);
+
+    let payload = "x".repeat(size);
+    let acks = (qos == QoS::AtLeastOnce).then(|| client.watch_acks(count as usize));
+    let mut pending = HashMap::new();
+    let mut enqueue = Latency::default();
+    let mut sent = 0;
+    let mut dropped = 0;
+
+    let started = Instant::now();
+    for _ in 0..count {
+        let before = Instant::now();
+        match client.publish_with_qos(topic, &payload, qos) {
+            Ok(id) => {
+                enqueue.record(before.elapsed());
+                pending.insert(id, before);
+                sent += 1;
+            }
+            Err(e) => {
+                log::debug!("Benchmark publish dropped: {}", e);
+                dropped += 1;
+            }
+        }
+    }
+
+    let mut ack = None;
+    if let Some(acks) = acks {
+        let mut latency = Latency::default();
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        while !pending.is_empty() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            match acks.recv_timeout(remaining) {
+                Ok(id) => {
+                    if let Some(sent_at) = pending.remove(&id) {
+                        latency.record(sent_at.elapsed());
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client.stop_watching_acks();
+        ack = Some(latency);
+    }
+    let duration = started.elapsed();
+
+    Ok(BenchReport {
+        count,
+        size,
+        qos: command.qos,
+        sent,
+        dropped,
+        acked: ack.as_ref().map_or(0, |latency| latency.samples as u32),
+        duration_ms: duration.as_millis() as u64,
+        msgs_per_sec: (sent as f32 / duration.as_secs_f32().max(0.001)) as u32,
+        enqueue,
+        ack,
+    })
+}
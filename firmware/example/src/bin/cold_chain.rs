@@ -0,0 +1,10 @@
+//! Cold-chain monitor: the battery profile on its own, regardless of
+//! `cold_chain_enabled`.
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use example::{cold_chain, startup};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    startup::init_runtime();
+    cold_chain::run(EspDefaultNvsPartition::take()?, &startup::config())
+}
@@ -0,0 +1,10 @@
+//! Door/window sensor: the battery profile on its own, regardless of
+//! `contact_enabled`.
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use example::{contact, startup};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    startup::init_runtime();
+    contact::run(EspDefaultNvsPartition::take()?, &startup::config())
+}
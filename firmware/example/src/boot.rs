@@ -0,0 +1,124 @@
+//! Startup as a dependency graph. Each step names the steps it needs, and
+//! steps that don't need each other run at the same time: WiFi and SNTP come
+//! up on their own thread while storage and sensors initialize, so the first
+//! publish doesn't wait for a slow sensor or key generation.
+//!
+//! ```text
+//! nvs ─┬─ wifi ── sntp ─┬─ mqtt
+//!      ├─ storage ──────┘
+//!      └─ sensors
+//! ```
+//!
+//! How long each step took goes into the startup report, published once the
+//! broker is first reached.
+
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Every step, with the steps it needs.
+pub const GRAPH: &[(&str, &[&str])] = &[
+    // Factory settings, eFuse, config validation, migrations
+    ("nvs", &[]),
+    ("wifi", &["nvs"]),
+    ("sntp", &["wifi"]),
+    // Device id, device key, feature flags, templates, alarms
+    ("storage", &["nvs"]),
+    // Sensors and actuators
+    ("sensors", &["nvs"]),
+    ("console", &["wifi"]),
+    // Identity, broker selection, MQTT client
+    ("mqtt", &["sntp", "storage"]),
+];
+
+/// When a step ran, in milliseconds since startup began.
+#[derive(Serialize, Debug, Clone)]
+pub struct StepTiming {
+    pub name: &'static str,
+    pub start_ms: u32,
+    pub duration_ms: u32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StartupReport {
+    /// Until every step was done
+    pub total_ms: u32,
+    /// The steps one after another would have taken this long
+    pub sequential_ms: u32,
+    /// In the order they finished
+    pub steps: Vec<StepTiming>,
+}
+
+/// The steps done so far. Clones share them, for the threads of parallel
+/// branches.
+#[derive(Clone)]
+pub struct Boot {
+    started: Instant,
+    done: Arc<Mutex<Vec<StepTiming>>>,
+}
+
+impl Default for Boot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Boot {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            done: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Begin step `name`. Fails if it isn't in [`GRAPH`] or a step it needs
+    /// isn't done, which means startup runs the graph wrongly.
+    pub fn start(&self, name: &'static str) -> Result<Step<'_>, String> {
+        let (_, needs) = GRAPH
+            .iter()
+            .find(|(step, _)| *step == name)
+            .ok_or_else(|| format!("Unknown startup step \"{}\"", name))?;
+        let done = self.done.lock().unwrap();
+        if let Some(missing) = needs.iter().find(|need| !done.iter().any(|step| step.name == **need)) {
+            return Err(format!("Startup step \"{}\" started before \"{}\"", name, missing));
+        }
+        Ok(Step {
+            boot: self,
+            name,
+            started: Instant::now(),
+        })
+    }
+
+    /// Milliseconds since startup began.
+    pub fn elapsed_ms(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    pub fn report(&self) -> StartupReport {
+        let steps = self.done.lock().unwrap().clone();
+        StartupReport {
+            total_ms: steps.iter().map(|step| step.start_ms + step.duration_ms).max().unwrap_or(0),
+            sequential_ms: steps.iter().map(|step| step.duration_ms).sum(),
+            steps,
+        }
+    }
+}
+
+/// A step in progress. Only steps that get to [`Step::finish`] count as done.
+pub struct Step<'a> {
+    boot: &'a Boot,
+    name: &'static str,
+    started: Instant,
+}
+
+impl Step<'_> {
+    pub fn finish(self) {
+        let timing = StepTiming {
+            name: self.name,
+            start_ms: self.started.duration_since(self.boot.started).as_millis() as u32,
+            duration_ms: self.started.elapsed().as_millis() as u32,
+        };
+        log::info!("Startup step \"{}\" took {} ms", timing.name, timing.duration_ms);
+        self.boot.done.lock().unwrap().push(timing);
+    }
+}
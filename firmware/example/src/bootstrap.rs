@@ -0,0 +1,26 @@
+//! Zero-touch onboarding without Fleet Provisioning templates: the backend
+//! publishes a device's initial configuration, retained, to a topic of its
+//! own such as `devices/<id>/bootstrap`. The broker hands the retained
+//! message over as soon as the device subscribes, so a new device gets its
+//! full configuration on its first connection, before anything is in its
+//! shadow. The main loop applies it like a shadow delta, once per boot.
+
+use crate::client::{Client, SharedClient};
+use crate::events::{Event, EventBus};
+use std::error::Error;
+
+/// Subscribe to `topic` and announce its configuration on `events` as
+/// [`Event::BootstrapConfig`]. Payloads that aren't a JSON object, such as
+/// the empty one clearing the retained message, are ignored.
+pub fn subscribe(client: &SharedClient, topic: &str, events: EventBus) -> Result<(), Box<dyn Error>> {
+    let handler = move |_: &mut Client, topic: &str, payload: &[u8]| {
+        if payload.is_empty() {
+            return;
+        }
+        match serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(payload) {
+            Ok(config) => events.publish(Event::BootstrapConfig(serde_json::Value::Object(config).to_string())),
+            Err(e) => log::warn!("Invalid bootstrap configuration on \"{}\": {}", topic, e),
+        }
+    };
+    client.lock().subscribe_with_handler(topic, handler)
+}
@@ -0,0 +1,176 @@
+//! Failover to a local MQTT bridge (a Greengrass core, a mosquitto bridge,
+//! ...) advertised over mDNS as `_mqtt._tcp`, for when the AWS IoT endpoint
+//! is unreachable. esp-mqtt can't change brokers on the fly, so switching
+//! either way goes through a restart with the choice kept in NVS.
+
+use crate::auth::AuthProvider;
+use crate::migrations::NAMESPACE;
+use crate::startup::App;
+use crate::{client, tls_observer};
+use esp_idf_svc::mdns::{EspMdns, QueryResult};
+use esp_idf_svc::mqtt::client::MqttClientConfiguration;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Broker URL of the bridge in use, while failed over.
+const BRIDGE_KEY: &str = "bridge_url";
+const SERVICE: &str = "_mqtt";
+const PROTO: &str = "_tcp";
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_RESULTS: usize = 4;
+/// Reachability check of the AWS endpoint while bridged.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to the bridge's plain `mqtt://` listener: no client certificate,
+/// and the URL replaces `mqtt_url`.
+pub struct Bridge {
+    url: String,
+}
+
+impl Bridge {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string() }
+    }
+}
+
+impl AuthProvider for Bridge {
+    fn name(&self) -> &'static str {
+        "bridge"
+    }
+
+    fn apply(&self, _conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn broker_url(&self, _url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.url.clone())
+    }
+}
+
+/// The bridge selected before the last restart, if failed over.
+pub fn selected(nvs: EspDefaultNvsPartition) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    let mut buf = [0u8; 128];
+    Ok(storage.get_str(BRIDGE_KEY, &mut buf)?.map(str::to_string))
+}
+
+fn select(nvs: EspDefaultNvsPartition, url: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    match url {
+        Some(url) => storage.set_str(BRIDGE_KEY, url)?,
+        None => {
+            storage.remove(BRIDGE_KEY)?;
+        }
+    }
+    Ok(())
+}
+
+/// Browse for an MQTT broker on the LAN, optionally only the service
+/// instance named `instance`, and return its `mqtt://` URL.
+pub fn discover(instance: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mdns = EspMdns::take()?;
+    let mut results: [QueryResult; MAX_RESULTS] = Default::default();
+    let found = mdns.query_ptr(SERVICE, PROTO, QUERY_TIMEOUT, MAX_RESULTS, &mut results)?;
+
+    let url = results[..found.min(MAX_RESULTS)]
+        .iter()
+        .filter(|result| instance.is_empty() || result.instance_name.as_deref() == Some(instance))
+        .find_map(|result| {
+            let addr = result.addr.iter().find(|addr| addr.is_ipv4())?;
+            log::info!(
+                "Found MQTT broker \"{}\" at {}:{}",
+                result.instance_name.as_deref().unwrap_or_default(),
+                addr,
+                result.port
+            );
+            Some(format!("mqtt://{}:{}", addr, result.port))
+        });
+    Ok(url)
+}
+
+/// Whether a TCP connection to the broker behind `url` goes through.
+pub(crate) fn reachable(url: &str) -> bool {
+    let Some((host, port)) = tls_observer::endpoint_host_port(url) else {
+        return false;
+    };
+    let Ok(mut addrs) = (host, port).to_socket_addrs() else {
+        return false;
+    };
+    addrs.next().is_some_and(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+}
+
+/// Decides when to move to a bridge and when to go back to AWS IoT.
+pub struct Failover {
+    nvs: EspDefaultNvsPartition,
+    failover_after: u32,
+    instance: &'static str,
+    direct_url: String,
+    bridged: bool,
+    check_interval: Duration,
+    next_check: Instant,
+}
+
+impl Failover {
+    pub fn new(app: &App) -> Self {
+        let config = &app.config;
+        // Both reach AWS IoT on 443
+        let direct_url = if config.use_alpn || config.auth_mode == "sigv4_websocket" {
+            client::with_default_port(config.mqtt_url, 443)
+        } else {
+            config.mqtt_url.to_string()
+        };
+        let check_interval = Duration::from_secs(config.bridge_direct_check_secs);
+        let bridged = app.bridge.is_some();
+        Self {
+            nvs: app.nvs.clone(),
+            failover_after: config.bridge_failover_after,
+            instance: config.bridge_instance,
+            direct_url,
+            bridged,
+            check_interval,
+            // Look for a bridge as soon as AWS IoT is unreachable, but stay
+            // on a new bridge for a full interval before probing AWS IoT
+            next_check: if bridged { Instant::now() + check_interval } else { Instant::now() },
+        }
+    }
+
+    /// Call from the main loop. Returns true when the device should restart
+    /// to switch brokers.
+    pub fn poll(&mut self, failures_since_connect: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let unreachable = failures_since_connect >= self.failover_after;
+        if self.bridged && unreachable {
+            log::warn!("Bridge failed {} connection attempts, going back to AWS IoT", failures_since_connect);
+            select(self.nvs.clone(), None)?;
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if now < self.next_check || (!self.bridged && !unreachable) {
+            return Ok(false);
+        }
+        self.next_check = now + self.check_interval;
+
+        if self.bridged {
+            if !reachable(&self.direct_url) {
+                return Ok(false);
+            }
+            log::info!("AWS IoT endpoint reachable again, leaving the bridge");
+            select(self.nvs.clone(), None)?;
+            return Ok(true);
+        }
+
+        log::warn!("AWS IoT unreachable for {} attempts, looking for a local bridge", failures_since_connect);
+        match discover(self.instance)? {
+            Some(url) => {
+                log::warn!("Failing over to bridge {}", url);
+                select(self.nvs.clone(), Some(&url))?;
+                Ok(true)
+            }
+            None => {
+                log::info!("No bridge found, retrying in {:?}", self.check_interval);
+                Ok(false)
+            }
+        }
+    }
+}
@@ -0,0 +1,8 @@
+// Include the generated build report from build.rs
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// JSON report of how this binary was built: version, enabled cargo
+/// features, cfg.toml hash and resolved dependency versions.
+pub fn report() -> &'static str {
+    std::str::from_utf8(&BUILD_INFO).unwrap_or("{}")
+}
@@ -0,0 +1,122 @@
+use crate::envelope::Chunk;
+use base64::Engine;
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::sys::camera;
+use serde::Deserialize;
+use std::sync::Mutex;
+
+/// Raw JPEG bytes per MQTT chunk, before base64.
+const CHUNK_SIZE: usize = 4096;
+
+static INITIALIZED: Mutex<bool> = Mutex::new(false);
+
+#[derive(Deserialize, Debug)]
+pub struct SnapshotCommand {
+    /// Presigned S3 PUT URL. Without one the image is sent over MQTT.
+    #[serde(default)]
+    pub upload_url: Option<String>,
+    /// Object key the URL was presigned for, echoed in the response
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+/// Frame buffer borrowed from the driver, handed back on drop.
+pub struct Frame(*mut camera::camera_fb_t);
+
+impl Frame {
+    pub fn jpeg(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts((*self.0).buf, (*self.0).len) }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        unsafe { camera::esp_camera_fb_return(self.0) };
+    }
+}
+
+/// Start the OV2640 with the ESP32-S3-EYE pinout, once.
+fn init() -> Result<(), Box<dyn std::error::Error>> {
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if *initialized {
+        return Ok(());
+    }
+
+    let config = camera::camera_config_t {
+        pin_pwdn: -1,
+        pin_reset: -1,
+        pin_xclk: 15,
+        __bindgen_anon_1: camera::camera_config_t__bindgen_ty_1 { pin_sccb_sda: 4 },
+        __bindgen_anon_2: camera::camera_config_t__bindgen_ty_2 { pin_sccb_scl: 5 },
+        pin_d7: 16,
+        pin_d6: 17,
+        pin_d5: 18,
+        pin_d4: 12,
+        pin_d3: 10,
+        pin_d2: 8,
+        pin_d1: 9,
+        pin_d0: 11,
+        pin_vsync: 6,
+        pin_href: 7,
+        pin_pclk: 13,
+        xclk_freq_hz: 20_000_000,
+        ledc_timer: esp_idf_svc::sys::ledc_timer_t_LEDC_TIMER_0,
+        ledc_channel: esp_idf_svc::sys::ledc_channel_t_LEDC_CHANNEL_0,
+        pixel_format: camera::pixformat_t_PIXFORMAT_JPEG,
+        frame_size: camera::framesize_t_FRAMESIZE_SVGA,
+        jpeg_quality: 12,
+        fb_count: 1,
+        fb_location: camera::camera_fb_location_t_CAMERA_FB_IN_PSRAM,
+        grab_mode: camera::camera_grab_mode_t_CAMERA_GRAB_LATEST,
+        ..Default::default()
+    };
+    esp_idf_svc::sys::esp!(unsafe { camera::esp_camera_init(&config) })?;
+    log::info!("Camera initialised");
+    *initialized = true;
+    Ok(())
+}
+
+pub fn capture() -> Result<Frame, Box<dyn std::error::Error>> {
+    init()?;
+    let frame = unsafe { camera::esp_camera_fb_get() };
+    if frame.is_null() {
+        return Err("Camera returned no frame".into());
+    }
+    Ok(Frame(frame))
+}
+
+/// PUT the image to a presigned S3 URL.
+pub fn upload(url: &str, jpeg: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+
+    let content_length = jpeg.len().to_string();
+    let headers = [("Content-Type", "image/jpeg"), ("Content-Length", content_length.as_str())];
+    let mut request = client.request(Method::Put, url, &headers)?;
+    request.write_all(jpeg)?;
+    request.flush()?;
+    let response = request.submit()?;
+
+    match response.status() {
+        200..=299 => Ok(()),
+        status => Err(format!("S3 upload failed with HTTP {}", status).into()),
+    }
+}
+
+/// Split the image into base64 chunks for the MQTT fallback.
+pub fn chunks<'a>(key: &'a str, jpeg: &'a [u8]) -> impl Iterator<Item = Chunk<'a>> + 'a {
+    let total = jpeg.len().div_ceil(CHUNK_SIZE);
+    jpeg.chunks(CHUNK_SIZE).enumerate().map(move |(seq, chunk)| Chunk {
+        event: "snapshot_chunk",
+        key,
+        seq,
+        total,
+        data: base64::engine::general_purpose::STANDARD.encode(chunk),
+    })
+}
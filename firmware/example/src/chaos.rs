@@ -0,0 +1,103 @@
+use crate::middleware::Middleware;
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Faults the `chaos` command can inject to exercise recovery paths on
+/// real hardware. Only available in debug builds with `chaos_enabled`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Disconnect WiFi, then reconnect through the regular retry path
+    DropWifi,
+    /// Block the MQTT listener thread on the next received message
+    StallListener,
+    /// Leak heap until allocation fails and the device aborts
+    Oom,
+    /// Hold every publish for a second
+    DelayPublish,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChaosCommand {
+    pub fault: Fault,
+    /// How long the fault lasts, where it has a duration
+    #[serde(default = "default_duration_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_duration_secs() -> u64 {
+    10
+}
+
+#[derive(Default)]
+struct Armed {
+    stall_listener: Option<Duration>,
+    delay_publish_until: Option<Instant>,
+}
+
+/// Faults that act on the client's publish/receive paths, injected through
+/// the middleware chain. Register a clone and keep the original to arm them.
+#[derive(Clone, Default)]
+pub struct Chaos {
+    armed: Arc<Mutex<Armed>>,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stall_listener(&self, duration: Duration) {
+        self.armed.lock().unwrap().stall_listener = Some(duration);
+    }
+
+    pub fn delay_publish(&self, duration: Duration) {
+        self.armed.lock().unwrap().delay_publish_until = Some(Instant::now() + duration);
+    }
+}
+
+impl Middleware for Chaos {
+    fn name(&self) -> &'static str {
+        "chaos"
+    }
+
+    fn on_publish(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let delaying = {
+            let mut armed = self.armed.lock().unwrap();
+            match armed.delay_publish_until {
+                Some(until) if Instant::now() < until => true,
+                Some(_) => {
+                    armed.delay_publish_until = None;
+                    false
+                }
+                None => false,
+            }
+        };
+        if delaying {
+            log::warn!("chaos: delaying publish");
+            thread::sleep(Duration::from_secs(1));
+        }
+        Ok(payload)
+    }
+
+    fn on_receive(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let stall = self.armed.lock().unwrap().stall_listener.take();
+        if let Some(duration) = stall {
+            log::warn!("chaos: stalling listener for {:?}", duration);
+            thread::sleep(duration);
+        }
+        Ok(payload)
+    }
+}
+
+/// Leak heap in small chunks until the allocator gives up. Never returns:
+/// the allocation failure aborts and the device restarts.
+pub fn exhaust_heap() -> ! {
+    log::warn!("chaos: exhausting heap");
+    loop {
+        std::mem::forget(vec![0xA5u8; 4096]);
+    }
+}
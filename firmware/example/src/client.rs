@@ -1,11 +1,34 @@
 use esp_idf_svc::{
-    mqtt::client::{EspMqttClient, EspMqttConnection, MqttClientConfiguration, QoS},
+    handle::RawHandle,
+    mqtt::client::{Details, EspMqttClient, EspMqttConnection, LwtConfiguration, MqttClientConfiguration, QoS},
     tls::X509,
 };
-use embedded_svc::mqtt::client::EventPayload::Received;
+use embedded_svc::mqtt::client::EventPayload;
+use crate::auth::{AuthProvider, UrlSigner};
+use crate::clock;
+use crate::delivery::{Confirmation, Confirmations, Delivery, DeliveryTracker, Outcome};
+use crate::events::{Event, EventBus};
+use crate::middleware::MiddlewareChain;
+#[cfg(feature = "mqtt5")]
+use crate::mqtt5::{self, PublishProperties, ReasonCode};
+use crate::netstats::ConnectionStats;
+use crate::offline_queue::OfflineQueue;
+use crate::publish_queue::{Overflow, Pending, PublishQueue};
+use crate::reassembly::{Fragment, Reassembler};
+use crate::reconnect::{self, LinkEvent, ReconnectStatus, StatusCallbacks};
+use crate::request::{Reply, Requests};
+use crate::retry::{RetryPolicy, Subsystem};
+use crate::router::{Handler, Router};
+use crate::tls_observer;
+use crate::topics::{self, TopicAliases};
+use crate::traffic::{TrafficCounters, TrafficStats};
 use crossbeam_channel::{bounded, Receiver, Sender};
-use std::time::Duration;
-use std::{mem, slice, thread};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{mem, thread};
 use log::*;
 
 pub struct Client {
@@ -13,9 +36,161 @@ pub struct Client {
     pub mqtt_connection: Option<EspMqttConnection>,
     pub pub_topic: String,
     pub sub_topic: String,
-    message_sender: Option<Sender<Vec<u8>>>,
+    jitp: bool,
+    session: Session,
+    presence_topic: Option<String>,
+    broadcast_topic: Option<String>,
+    publish_qos: QoS,
+    subscribe_qos: QoS,
+    retry_policy: RetryPolicy,
+    reconnect_policy: Option<RetryPolicy>,
+    reconnect_callbacks: StatusCallbacks,
+    events: EventBus,
+    middleware: MiddlewareChain,
+    aliases: TopicAliases,
+    broker_url: String,
+    /// Broker URLs to rotate through, `broker_url` first
+    endpoints: Vec<String>,
+    /// Signs each endpoint before esp-mqtt connects to it, for providers
+    /// whose URL carries expiring credentials. The endpoints are then kept
+    /// unsigned
+    url_signer: Option<UrlSigner>,
+    /// Endpoint the reconnect manager signs before each attempt
+    current_endpoint: Arc<Mutex<String>>,
+    endpoint: usize,
+    rotate_after: u32,
+    /// `failures_since_connect` at the last rotation
+    rotated_at: u32,
+    stats: ConnectionStats,
+    traffic: TrafficCounters,
+    /// Largest incoming message reassembled, see [`Client::with_max_message_bytes`]
+    max_message_bytes: usize,
+    failed_attempts: Arc<AtomicU32>,
+    failures_since_connect: Arc<AtomicU32>,
+    message_sender: Option<Sender<(String, Vec<u8>)>>,
+    ack_sender: Arc<Mutex<Option<Sender<u32>>>>,
+    deliveries: DeliveryTracker,
+    /// Publishes from [`Client::publish_blocking`] waiting for their PUBACK
+    confirmations: Confirmations,
+    /// Requests from [`Client::request`] waiting for their reply
+    requests: Requests,
+    reserved_receiver: Option<Receiver<(String, Vec<u8>)>>,
+    router: Router,
+    routed_receiver: Option<Receiver<(String, Vec<u8>)>>,
+    /// Active subscriptions by wire topic, restored after a reconnect
+    subscriptions: BTreeMap<String, QoS>,
+    resubscribe_pending: Arc<AtomicBool>,
+    connection: ConnectionWatch,
+    offline_queue: Option<OfflineQueue>,
+    publish_queue: Option<PublishQueue>,
 }
 
+/// Whether the client has a broker connection, as reported to
+/// [`Client::on_connection_change`] callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    Connected,
+    Disconnected,
+}
+
+/// What the broker keeps of the connection after it drops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Session {
+    /// Every connection starts without subscriptions; messages published
+    /// while disconnected are lost
+    #[default]
+    Clean,
+    /// The broker keeps subscriptions and queues QoS 1 messages for them
+    /// while disconnected, delivering them after reconnect. MQTT 3.1.1 has
+    /// no session expiry on the wire, so `expiry` must match the broker's
+    /// setting (AWS IoT: one hour by default); the client uses it to tell
+    /// whether an outage outlived the session.
+    Persistent { expiry: Duration },
+}
+
+/// Connection settings esp-mqtt takes when the client is created, passed to
+/// [`Client::new`]. The defaults: no JITP, no ALPN, no presence, esp-mqtt's
+/// own reconnects and a clean session.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectOptions<'a> {
+    jitp: bool,
+    alpn: bool,
+    presence_topic: Option<&'a str>,
+    reconnect_policy: Option<RetryPolicy>,
+    session: Session,
+}
+
+impl<'a> ConnectOptions<'a> {
+    /// Expect the first connection to be dropped while AWS IoT registers the
+    /// certificate, and subscribe only after it has
+    pub fn with_jitp(mut self, jitp: bool) -> Self {
+        self.jitp = jitp;
+        self
+    }
+
+    /// Negotiate AWS IoT's ALPN protocol to use MQTT over TLS on port 443
+    pub fn with_alpn(mut self, alpn: bool) -> Self {
+        self.alpn = alpn;
+        self
+    }
+
+    /// Publish `online`, retained, on `topic` after connecting, with
+    /// `offline` as the last will
+    pub fn with_presence_topic(mut self, topic: Option<&'a str>) -> Self {
+        self.presence_topic = topic;
+        self
+    }
+
+    /// Reconnect on `policy` rather than on esp-mqtt's fixed timeout
+    pub fn with_reconnect_policy(mut self, policy: Option<RetryPolicy>) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+}
+
+type ConnectionCallback = Box<dyn FnMut(ConnState) + Send>;
+
+/// Connection state shared with the listener thread, which calls the
+/// callbacks on every change.
+#[derive(Clone, Default)]
+struct ConnectionWatch {
+    connected: Arc<AtomicBool>,
+    callbacks: Arc<Mutex<Vec<ConnectionCallback>>>,
+}
+
+impl ConnectionWatch {
+    fn set(&self, state: ConnState) {
+        let connected = state == ConnState::Connected;
+        if self.connected.swap(connected, Ordering::Relaxed) != connected {
+            for callback in self.callbacks.lock().unwrap().iter_mut() {
+                callback(state);
+            }
+        }
+    }
+}
+
+/// Stored publishes sent per `poll`, so flushing a long outage doesn't
+/// stall the main loop.
+const OFFLINE_FLUSH_BATCH: usize = 8;
+
+/// Longest a publish waits for room with the `Block` overflow policy.
+const PUBLISH_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ALPN protocol that lets AWS IoT accept X.509-authenticated MQTT on 443.
+pub const ALPN_MQTT_CA: &str = "x-amzn-mqtt-ca";
+/// ALPN protocol for MQTT on 443 authenticated by a custom authorizer.
+pub const ALPN_MQTT: &str = "mqtt";
+
+/// Retained on the presence topic: the broker publishes `offline` as the
+/// last will when the connection drops without a DISCONNECT.
+const PRESENCE_ONLINE: &str = r#"{"status":"online"}"#;
+const PRESENCE_OFFLINE: &str = r#"{"status":"offline"}"#;
+
 // Include the generated certificate constants from build.rs
 include!(concat!(env!("OUT_DIR"), "/certificates.rs"));
 
@@ -25,79 +200,459 @@ impl Client {
         client_id: &str,
         pub_topic: &str,
         sub_topic: &str,
+        options: ConnectOptions,
+        auth: &dyn AuthProvider,
     ) -> Result<Client, Box<dyn std::error::Error>> {
+        let ConnectOptions {
+            jitp,
+            alpn,
+            presence_topic,
+            reconnect_policy,
+            session,
+        } = options;
+        let url_signer = auth.url_signer();
+        // A signed URL expires, so only reconnects the manager signs anew
+        // can outlast it
+        let reconnect_policy = reconnect_policy.or(url_signer.as_ref().map(|_| RetryPolicy::default()));
         log::info!("Loading certificates...");
         log::info!("Server cert size: {} bytes", SERVER_CERT.len());
-        log::info!("Client cert size: {} bytes", CLIENT_CERT.len());
-        log::info!("Private key size: {} bytes", PRIVATE_KEY.len());
 
         log::info!("Converting server certificate...");
         let server_cert: X509 = convert_certificate(SERVER_CERT.to_vec());
         log::info!("Server certificate converted successfully");
-        
-        log::info!("Converting client certificate...");
-        let client_cert: X509 = convert_certificate(CLIENT_CERT.to_vec());
-        log::info!("Client certificate converted successfully");
-        
-        log::info!("Converting private key...");
-        let private_key: X509 = convert_certificate(PRIVATE_KEY.to_vec());
-        log::info!("Private key converted successfully");
 
         log::info!("Creating MQTT client configuration...");
         
-        // AWS IoT requires client certificates for authentication
-        let mqtt_client_config = MqttClientConfiguration {
+        let mut mqtt_client_config = MqttClientConfiguration {
             client_id: Some(client_id),
             crt_bundle_attach: Some(esp_idf_svc::hal::sys::esp_crt_bundle_attach),
             keep_alive_interval: Some(Duration::from_secs(60)),
+            reconnect_timeout: match &reconnect_policy {
+                // Only a backstop, the reconnect manager comes first
+                Some(policy) => Some(policy.max_delay * 2),
+                // JITP drops the very first connection while it registers the
+                // certificate, so retry sooner than the esp-mqtt default
+                None if jitp => Some(Duration::from_secs(3)),
+                None => None,
+            },
+            disable_clean_session: matches!(session, Session::Persistent { .. }),
             server_certificate: Some(server_cert),
-            client_certificate: Some(client_cert),
-            private_key: Some(private_key),
+            lwt: presence_topic.map(|topic| LwtConfiguration {
+                topic,
+                payload: PRESENCE_OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
             ..Default::default()
         };
+
+        log::info!("Applying {} authentication...", auth.name());
+        auth.apply(&mut mqtt_client_config)?;
+        let endpoint = url;
+        let mut url = auth.broker_url(endpoint)?;
+        // Only for TLS: the WebSocket modes already use 443, and a provider
+        // that negotiates its own protocol keeps it
+        if alpn && url.starts_with("mqtts://") && mqtt_client_config.alpn_protos.is_none() {
+            log::info!("Negotiating ALPN {}", ALPN_MQTT_CA);
+            mqtt_client_config.alpn_protos = Some(&[ALPN_MQTT_CA]);
+            url = with_default_port(&url, 443);
+        }
         log::info!("MQTT client configuration created successfully");
 
-        log::info!("MQTT URL: {}", url);
+        log::info!("MQTT URL: {}", redact_url(&url));
         log::info!("Creating MQTT client instance...");
-        let (mqtt_client, mqtt_connection) = EspMqttClient::new(url, &mqtt_client_config)?;
+        #[cfg(feature = "mqtt5")]
+        mqtt5::set_session_expiry(match session {
+            Session::Clean => None,
+            Session::Persistent { expiry } => Some(expiry),
+        });
+        let (mqtt_client, mqtt_connection) = EspMqttClient::new(&url, &mqtt_client_config)?;
         log::info!("MQTT client created successfully");
+        // Signed again for every connection, see `url_signer`
+        let url = if url_signer.is_some() { endpoint.to_string() } else { url };
 
         Ok(Self {
             mqtt_client,
             mqtt_connection: Some(mqtt_connection),
             pub_topic: pub_topic.to_string(),
             sub_topic: sub_topic.to_string(),
+            jitp,
+            session,
+            presence_topic: presence_topic.map(str::to_string),
+            broadcast_topic: None,
+            publish_qos: QoS::AtMostOnce,
+            subscribe_qos: QoS::AtMostOnce,
+            retry_policy: RetryPolicy::default(),
+            reconnect_policy,
+            reconnect_callbacks: StatusCallbacks::default(),
+            events: EventBus::new(),
+            middleware: MiddlewareChain::new(),
+            aliases: TopicAliases::default(),
+            stats: ConnectionStats::new(&url, 0),
+            endpoints: vec![url.clone()],
+            endpoint: 0,
+            rotate_after: 0,
+            rotated_at: 0,
+            url_signer,
+            current_endpoint: Arc::new(Mutex::new(url.clone())),
+            broker_url: url,
+            traffic: TrafficCounters::default(),
+            max_message_bytes: 0,
+            failed_attempts: Arc::new(AtomicU32::new(0)),
+            failures_since_connect: Arc::new(AtomicU32::new(0)),
             message_sender: None,
+            ack_sender: Arc::new(Mutex::new(None)),
+            deliveries: DeliveryTracker::default(),
+            confirmations: Confirmations::default(),
+            requests: Requests::default(),
+            reserved_receiver: None,
+            router: Router::default(),
+            routed_receiver: None,
+            subscriptions: BTreeMap::new(),
+            resubscribe_pending: Arc::new(AtomicBool::new(false)),
+            connection: ConnectionWatch::default(),
+            offline_queue: None,
+            publish_queue: None,
         })
     }
 
-    /// Start non-blocking message listener and return a receiver for raw message data
-    pub fn start_message_listener(&mut self) -> Result<Receiver<Vec<u8>>, Box<dyn std::error::Error>> {
-        let (tx, rx) = bounded::<Vec<u8>>(10);
+    /// Announce connection changes on `events`
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Run published and received payloads through `middleware`
+    pub fn with_middleware(mut self, middleware: MiddlewareChain) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Publish and subscribe on the short wire topics of `aliases`
+    pub fn with_topic_aliases(mut self, aliases: TopicAliases) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Deliver messages on the fleet-wide `topic` with the reserved topics,
+    /// where the topic is known, rather than as commands
+    pub fn with_broadcast_topic(mut self, topic: Option<&str>) -> Self {
+        self.broadcast_topic = topic.map(str::to_string);
+        self
+    }
+
+    pub fn topic_aliases(&self) -> &TopicAliases {
+        &self.aliases
+    }
+
+    /// Keep statistics for the last `capacity` connection attempts
+    pub fn with_connection_history(mut self, capacity: usize) -> Self {
+        self.stats = ConnectionStats::new(&self.broker_url, capacity);
+        self
+    }
+
+    /// Move on to the next of `backups` after `rotate_after` connection
+    /// attempts in a row failed on the current endpoint, and from the last
+    /// back to the first. They connect with the same credentials and TLS
+    /// settings as the first endpoint
+    pub fn with_backup_endpoints(mut self, backups: Vec<String>, rotate_after: u32) -> Self {
+        self.endpoints.truncate(1);
+        self.endpoints.extend(backups);
+        self.rotate_after = rotate_after.max(1);
+        self
+    }
+
+    /// Broker URL the client connects to, or last tried
+    pub fn endpoint(&self) -> &str {
+        &self.broker_url
+    }
+
+    /// `host:port` of [`Client::endpoint`], for logs and reports that
+    /// shouldn't carry the rest of the URL
+    pub fn endpoint_address(&self) -> String {
+        match tls_observer::endpoint_host_port(&self.broker_url) {
+            Some((host, port)) => format!("{}:{}", host, port),
+            None => redact_url(&self.broker_url).to_string(),
+        }
+    }
+
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Count traffic in `traffic`, shared with e.g. the metrics exporter
+    pub fn with_traffic_counters(mut self, traffic: TrafficCounters) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// Drop incoming messages over `max_bytes` (`0`: AWS IoT's limit) while
+    /// reassembling them from esp-mqtt's pieces, before buffering them whole
+    pub fn with_max_message_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_message_bytes = max_bytes;
+        self
+    }
+
+    /// Messages and bytes on the wire, reconnects and subscribe failures
+    /// since boot
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Connection attempts that failed before the first one succeeded
+    pub fn failed_attempts(&self) -> u32 {
+        self.failed_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Connection attempts that failed since the last successful one
+    pub fn failures_since_connect(&self) -> u32 {
+        self.failures_since_connect.load(Ordering::Relaxed)
+    }
+
+    /// QoS of `publish`/`publish_to` and of `subscribe`/`subscribe_topic`
+    pub fn with_qos(mut self, publish: QoS, subscribe: QoS) -> Self {
+        self.publish_qos = publish;
+        self.subscribe_qos = subscribe;
+        self
+    }
+
+    /// Store publishes made while disconnected in `queue` and send them once
+    /// connected again, instead of failing them
+    pub fn with_offline_queue(mut self, queue: OfflineQueue) -> Self {
+        self.offline_queue = Some(queue);
+        self
+    }
+
+    /// Hold publishes in `queue` while the esp-mqtt outbox is over the
+    /// queue's byte limit, rather than letting the outbox grow
+    pub fn with_publish_queue(mut self, queue: PublishQueue) -> Self {
+        self.publish_queue = Some(queue);
+        self
+    }
+
+    /// Publishes the publish queue dropped on overflow, if there is one
+    pub fn publish_queue_dropped(&self) -> Option<u64> {
+        self.publish_queue.as_ref().map(PublishQueue::dropped)
+    }
+
+    /// Use `policy` for operations the client retries, such as subscribing
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Start non-blocking message listener and return a receiver for the
+    /// messages on the subscribed topics, with the topic each arrived on
+    pub fn start_message_listener(&mut self) -> Result<Receiver<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
+        let (tx, rx) = bounded::<(String, Vec<u8>)>(10);
         self.message_sender = Some(tx.clone());
 
+        // AWS reserved topics ($aws/...) and the broadcast topic are kept
+        // apart from application messages
+        let (reserved_tx, reserved_rx) = bounded::<(String, Vec<u8>)>(10);
+        self.reserved_receiver = Some(reserved_rx);
+
+        // Topics with a handler, for `dispatch`
+        let (routed_tx, routed_rx) = bounded::<(String, Vec<u8>)>(10);
+        self.routed_receiver = Some(routed_rx);
+
         // Take the connection from the Option
         let connection = self.mqtt_connection.take()
             .ok_or("MQTT connection already taken")?;
 
+        let jitp = self.jitp;
+        let session = self.session;
+        let events = self.events.clone();
+        let middleware = self.middleware.clone();
+        let ack_sender = self.ack_sender.clone();
+        let deliveries = self.deliveries.clone();
+        let confirmations = self.confirmations.clone();
+        let requests = self.requests.clone();
+        let stats = self.stats.clone();
+        let traffic = self.traffic.clone();
+        let max_message_bytes = self.max_message_bytes;
+        let failed_attempts = self.failed_attempts.clone();
+        let failures_since_connect = self.failures_since_connect.clone();
+        let resubscribe_pending = self.resubscribe_pending.clone();
+        let connection_watch = self.connection.clone();
+        let link = match self.reconnect_policy {
+            Some(policy) => {
+                let (link_tx, link_rx) = bounded(8);
+                let signer = self.url_signer.clone().map(|signer| {
+                    let endpoint = self.current_endpoint.clone();
+                    Box::new(move || signer(&endpoint.lock().unwrap())) as reconnect::Signer
+                });
+                reconnect::spawn(self.mqtt_client.handle(), policy, link_rx, signer, self.reconnect_callbacks.clone())?;
+                Some(link_tx)
+            }
+            None => None,
+        };
+        let report_link = move |event: LinkEvent| {
+            if let Some(link) = &link {
+                let _ = link.try_send(event);
+            }
+        };
+        let broadcast = self
+            .broadcast_topic
+            .clone()
+            .map(|topic| (self.aliases.wire(&topic).to_string(), topic));
+        let aliases = self.aliases.clone();
+        let routes = self.router.matcher();
+
         thread::Builder::new()
             .stack_size(6000)
             .spawn(move || {
                 info!("MQTT message listener started");
                 let mut connection = connection;
+                let mut connected_once = false;
+                let mut attempt_pending = false;
+                let mut disconnected_at: Option<Instant> = None;
+                let mut reassembler = Reassembler::new(max_message_bytes);
 
                 while let Ok(event) = connection.next() {
-                    if let Received {
-                        id: _,
-                        topic: _,
-                        data,
-                        details: _,
-                    } = event.payload()
-                    {
-                        if let Err(e) = tx.send(data.to_vec()) {
-                            error!("Failed to send message to channel: {}", e);
-                            break;
+                    match event.payload() {
+                        EventPayload::Received { topic, data, details, .. } => {
+                            // Large messages arrive in pieces; only whole ones go on
+                            let fragment = match details {
+                                Details::Complete => Fragment::Complete,
+                                Details::InitialChunk(chunk) => Fragment::First {
+                                    total: chunk.total_data_size,
+                                },
+                                Details::SubsequentChunk(chunk) => Fragment::Next {
+                                    offset: chunk.current_data_offset,
+                                    total: chunk.total_data_size,
+                                },
+                            };
+                            let (topic, data) = match reassembler.push(topic, data, fragment) {
+                                Ok(Some(message)) => message,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    match topic {
+                                        Some(topic) => warn!("Dropping message on \"{}\": {}", topic, e),
+                                        None => warn!("Dropping incoming message: {}", e),
+                                    }
+                                    continue;
+                                }
+                            };
+                            traffic.received(data.len());
+                            let data = match middleware.receive(&topic, data) {
+                                Ok(data) => data,
+                                Err(e) => {
+                                    warn!("Dropping message on \"{}\": {}", topic, e);
+                                    continue;
+                                }
+                            };
+                            let logical = aliases.logical(&topic);
+                            if routes.is_routed(logical) {
+                                if let Err(e) = routed_tx.send((logical.to_string(), data)) {
+                                    error!("Failed to send message to channel: {}", e);
+                                    break;
+                                }
+                            } else if topic.starts_with("$aws/")
+                                || broadcast.as_ref().is_some_and(|(wire, _)| *wire == topic)
+                            {
+                                // Broadcasts under their logical topic
+                                let topic = match &broadcast {
+                                    Some((wire, logical)) if *wire == topic => logical.clone(),
+                                    _ => topic,
+                                };
+                                if let Err(e) = reserved_tx.send((topic, data)) {
+                                    error!("Failed to send message to channel: {}", e);
+                                    break;
+                                }
+                            } else {
+                                // Replies to `request` go to whoever is waiting
+                                if requests.resolve(&data) {
+                                    continue;
+                                }
+                                if let Err(e) = tx.send((logical.to_string(), data)) {
+                                    error!("Failed to send message to channel: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        EventPayload::Published(id) => {
+                            if let Some(acks) = ack_sender.lock().unwrap().as_ref() {
+                                let _ = acks.try_send(id);
+                            }
+                            deliveries.complete(id, Outcome::Acked);
+                            confirmations.complete(id, Outcome::Acked);
+                        }
+                        EventPayload::Deleted(id) => {
+                            warn!("Message {} dropped from the outbox", id);
+                            deliveries.complete(id, Outcome::Dropped);
+                            confirmations.complete(id, Outcome::Dropped);
+                        }
+                        EventPayload::BeforeConnect => {
+                            stats.begin();
+                            attempt_pending = true;
+                        }
+                        EventPayload::Connected(session_present) => {
+                            info!("MQTT connected");
+                            stats.connected();
+                            attempt_pending = false;
+                            let outage = disconnected_at.take().map(|at| at.elapsed());
+                            if let (Session::Persistent { expiry }, Some(outage)) = (session, outage) {
+                                if session_present {
+                                    info!("MQTT session resumed after {:?} offline", outage);
+                                } else if outage >= expiry {
+                                    warn!(
+                                        "Offline for {:?}, past the session expiry: messages sent meanwhile are lost",
+                                        outage
+                                    );
+                                } else {
+                                    warn!("Broker discarded the MQTT session after {:?} offline", outage);
+                                }
+                            }
+                            if connected_once {
+                                traffic.reconnected();
+                            }
+                            // A new session starts without subscriptions
+                            if connected_once && !session_present {
+                                resubscribe_pending.store(true, Ordering::Relaxed);
+                            }
+                            connected_once = true;
+                            failures_since_connect.store(0, Ordering::Relaxed);
+                            report_link(LinkEvent::Connected);
+                            connection_watch.set(ConnState::Connected);
+                            events.publish(Event::MqttConnected);
                         }
+                        EventPayload::Error(e) => {
+                            stats.failed(Some(e.to_string()));
+                            report_link(LinkEvent::Lost);
+                            if mem::take(&mut attempt_pending) {
+                                failures_since_connect.fetch_add(1, Ordering::Relaxed);
+                                if !connected_once {
+                                    failed_attempts.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                        EventPayload::Disconnected if jitp && !connected_once => {
+                            stats.failed(None);
+                            report_link(LinkEvent::Lost);
+                            if mem::take(&mut attempt_pending) {
+                                failures_since_connect.fetch_add(1, Ordering::Relaxed);
+                                failed_attempts.fetch_add(1, Ordering::Relaxed);
+                            }
+                            info!("MQTT connection dropped while JITP registers the certificate, retrying...");
+                        }
+                        EventPayload::Disconnected => {
+                            warn!("MQTT disconnected");
+                            if connected_once && disconnected_at.is_none() {
+                                disconnected_at = Some(Instant::now());
+                            }
+                            stats.failed(None);
+                            report_link(LinkEvent::Lost);
+                            connection_watch.set(ConnState::Disconnected);
+                            if mem::take(&mut attempt_pending) {
+                                failures_since_connect.fetch_add(1, Ordering::Relaxed);
+                                if !connected_once {
+                                    failed_attempts.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            events.publish(Event::MqttDisconnected);
+                        }
+                        _ => {}
                     }
                 }
 
@@ -108,49 +663,639 @@ impl Client {
         Ok(rx)
     }
 
+    /// Whether the broker connection is up. Without an offline queue,
+    /// publishes while it is down fail or wait in the esp-mqtt outbox, so
+    /// periodic reports can be skipped instead (see `can_publish`)
+    pub fn is_connected(&self) -> bool {
+        self.connection.connected.load(Ordering::Relaxed)
+    }
+
+    /// Tear down the broker connection and connect again, for a connection
+    /// that looks up but no longer carries traffic. Blocks until esp-mqtt's
+    /// task has stopped, at most its network timeout on a half-open socket
+    pub fn force_reconnect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let handle = self.mqtt_client.handle();
+        esp_idf_svc::sys::esp!(unsafe { esp_idf_svc::sys::esp_mqtt_client_stop(handle) })?;
+        // Stopping doesn't report a disconnect; connecting again reports as usual
+        self.connection.set(ConnState::Disconnected);
+        self.events.publish(Event::MqttDisconnected);
+        esp_idf_svc::sys::esp!(unsafe { esp_idf_svc::sys::esp_mqtt_client_start(handle) })?;
+        Ok(())
+    }
+
+    /// Call `callback` whenever the broker connection comes up or goes
+    /// down, e.g. to drive a status LED. It runs on the listener thread, so
+    /// keep it short and don't call back into the client
+    pub fn on_connection_change(&mut self, callback: impl FnMut(ConnState) + Send + 'static) {
+        self.connection.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Call `callback` on every change of the reconnect state. It runs on
+    /// the reconnect manager's thread, so keep it short. Only called when
+    /// the client was created with a reconnect policy
+    pub fn on_reconnect_status(&mut self, callback: impl Fn(&ReconnectStatus) + Send + 'static) {
+        self.reconnect_callbacks.register(Box::new(callback));
+    }
+
+    /// Receive the message ids of QoS 1 publishes as the broker acknowledges
+    /// them, until `stop_watching_acks`
+    pub fn watch_acks(&mut self, capacity: usize) -> Receiver<u32> {
+        let (tx, rx) = bounded(capacity);
+        *self.ack_sender.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub fn stop_watching_acks(&mut self) {
+        *self.ack_sender.lock().unwrap() = None;
+    }
+
+    /// Track every QoS 1 publish from now on and report whether it was
+    /// acknowledged, dropped by the outbox, or timed out (see
+    /// `expire_deliveries`)
+    pub fn track_deliveries(&mut self, capacity: usize) -> Receiver<Delivery> {
+        self.deliveries.start(capacity)
+    }
+
+    /// Report tracked publishes unacknowledged after `timeout` as timed out.
+    /// Call periodically, e.g. from the main loop
+    pub fn expire_deliveries(&self, timeout: Duration) {
+        self.deliveries.expire(timeout);
+    }
+
+    /// Receiver for messages on AWS reserved topics (shadow, jobs, ...) and
+    /// the broadcast topic, available once the listener is started
+    pub fn take_reserved_receiver(&mut self) -> Option<Receiver<(String, Vec<u8>)>> {
+        self.reserved_receiver.take()
+    }
+
+    /// Subscribe to `topic`, which may be a filter with `+` and `#`, and have
+    /// `dispatch` pass its messages to `handler` rather than deliver them as
+    /// commands or on the reserved receiver. Register before the listener
+    /// starts when the subscription may already have messages waiting
+    pub fn subscribe_with_handler(
+        &mut self,
+        topic: &str,
+        handler: impl Handler + 'static,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.router.register(topic, handler)?;
+        self.subscribe_topic(topic)
+    }
+
+    /// Call from the main loop: restores the subscriptions after a
+    /// reconnect, sends publishes stored while offline, then runs the
+    /// handlers of routed messages
+    pub fn poll(&mut self) {
+        if self.resubscribe_pending.swap(false, Ordering::Relaxed) {
+            self.resubscribe();
+        }
+        self.rotate_endpoint();
+        self.drain_publish_queue();
+        self.flush_offline_queue();
+        self.dispatch();
+    }
+
+    /// Point esp-mqtt at the next endpoint once the current one failed
+    /// `rotate_after` attempts in a row. The next attempt goes there; a
+    /// connection stays on whichever endpoint took it
+    fn rotate_endpoint(&mut self) {
+        if self.endpoints.len() < 2 {
+            return;
+        }
+        let failures = self.failures_since_connect();
+        // A connection since the last rotation started the count over
+        if self.is_connected() || failures < self.rotated_at {
+            self.rotated_at = 0;
+            return;
+        }
+        if failures - self.rotated_at < self.rotate_after {
+            return;
+        }
+        self.rotated_at = failures;
+
+        let next = (self.endpoint + 1) % self.endpoints.len();
+        let url = &self.endpoints[next];
+        let signed = match &self.url_signer {
+            Some(signer) => match signer(url) {
+                Ok(signed) => signed,
+                Err(e) => {
+                    error!("Failed to sign the URL of {}: {}", url, e);
+                    return;
+                }
+            },
+            None => url.clone(),
+        };
+        let Ok(uri) = CString::new(signed) else {
+            error!("Invalid broker URL {}", url);
+            return;
+        };
+        // Waits for esp-mqtt to finish a connection attempt in progress
+        let handle = self.mqtt_client.handle();
+        if let Err(e) = esp_idf_svc::sys::esp!(unsafe { esp_idf_svc::sys::esp_mqtt_client_set_uri(handle, uri.as_ptr()) }) {
+            error!("Failed to switch to {}: {}", url, e);
+            return;
+        }
+        warn!("{} failed {} connection attempts, switching to {}", self.broker_url, self.rotate_after, url);
+        self.endpoint = next;
+        self.broker_url = url.clone();
+        *self.current_endpoint.lock().unwrap() = url.clone();
+        self.stats.set_endpoint(url);
+    }
+
+    /// Move queued publishes into the esp-mqtt outbox while it has room
+    fn drain_publish_queue(&mut self) {
+        let Some(mut queue) = self.publish_queue.take() else {
+            return;
+        };
+        while !queue.is_empty() && self.outbox_size() < queue.outbox_limit() {
+            let Some(publish) = queue.pop_front() else {
+                break;
+            };
+            if publish.expires.is_some_and(|expires| expires <= Instant::now()) {
+                info!("Dropping expired queued publish to \"{}\"", publish.topic);
+                continue;
+            }
+            if let Err(e) = self.enqueue(&publish.topic, &publish.payload, publish.qos, publish.retain) {
+                warn!("Failed to send queued publish to \"{}\": {}", publish.topic, e);
+                queue.push_front(publish);
+                break;
+            }
+        }
+        self.publish_queue = Some(queue);
+    }
+
+    /// Bytes waiting in the esp-mqtt outbox
+    fn outbox_size(&self) -> usize {
+        let size = unsafe { esp_idf_svc::sys::esp_mqtt_client_get_outbox_size(self.mqtt_client.handle()) };
+        size.max(0) as usize
+    }
+
+    /// Send up to a batch of the publishes stored while offline, oldest
+    /// first. A record is only dropped from the queue once esp-mqtt took it
+    fn flush_offline_queue(&mut self) {
+        if !self.is_connected() {
+            return;
+        }
+        let Some(mut queue) = self.offline_queue.take() else {
+            return;
+        };
+        for _ in 0..OFFLINE_FLUSH_BATCH {
+            let record = match queue.front() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read the offline queue: {}", e);
+                    break;
+                }
+            };
+            let now_ms = clock::now_ms();
+            let expires = match (record.expires_at_ms, now_ms) {
+                (Some(deadline), Some(now)) if deadline <= now => {
+                    info!("Dropping expired stored publish to \"{}\"", record.topic);
+                    if let Err(e) = queue.pop_front() {
+                        error!("Failed to update the offline queue: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+                (Some(deadline), Some(now)) => Some(Instant::now() + Duration::from_millis(deadline - now)),
+                _ => None,
+            };
+            if let Err(e) = self.send(&record.topic, &record.payload, record.qos, record.retain, expires) {
+                warn!("Failed to send stored publish to \"{}\": {}", record.topic, e);
+                break;
+            }
+            if let Err(e) = queue.pop_front() {
+                error!("Failed to update the offline queue: {}", e);
+                break;
+            }
+            if queue.is_empty() {
+                info!("Sent every publish stored while offline");
+            }
+        }
+        self.offline_queue = Some(queue);
+    }
+
+    /// Subscribe again to every active subscription. Failures are logged
+    /// and don't stop the others
+    pub fn resubscribe(&mut self) {
+        info!("Restoring {} subscription(s)", self.subscriptions.len());
+        for (topic, qos) in &self.subscriptions {
+            if let Err(e) = self.mqtt_client.subscribe(topic, *qos) {
+                self.traffic.subscribe_failed();
+                error!("Failed to resubscribe to topic \"{}\": {}", topic, e);
+            }
+        }
+    }
+
+    /// Run the handlers for the messages received on their topics since the
+    /// last call. Returns the number handled
+    pub fn dispatch(&mut self) -> usize {
+        let Some(receiver) = self.routed_receiver.clone() else {
+            return 0;
+        };
+        // Handlers get the client, so the routes are taken out meanwhile
+        let mut router = self.router.take();
+        let mut handled = 0;
+        while let Ok((topic, payload)) = receiver.try_recv() {
+            if router.dispatch(self, &topic, &payload) {
+                handled += 1;
+            } else {
+                debug!("No handler for message on \"{}\"", topic);
+            }
+        }
+        self.router.restore(router);
+        handled
+    }
+
     /// Subscribe to the configured topic
     pub fn subscribe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let topic = self.sub_topic.clone();
+        self.subscribe_topic(&topic)
+    }
+
+    /// Subscribe to an arbitrary topic at the default QoS, retrying per the
+    /// retry policy
+    pub fn subscribe_topic(&mut self, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.subscribe_with_qos(topic, self.subscribe_qos)
+    }
+
+    /// Subscribe to an arbitrary topic at `qos`, retrying per the retry policy
+    pub fn subscribe_with_qos(&mut self, topic: &str, qos: QoS) -> Result<(), Box<dyn std::error::Error>> {
+        let topic = self.aliases.wire(topic).to_string();
+        let topic = topic.as_str();
+        let mut backoff = self.retry_policy.backoff(Subsystem::Subscribe);
         loop {
-            match self.mqtt_client.subscribe(&self.sub_topic, QoS::AtMostOnce) {
+            match self.mqtt_client.subscribe(topic, qos) {
                 Ok(_) => {
-                    info!("Subscribed to topic \"{}\"", self.sub_topic);
+                    info!("Subscribed to topic \"{}\" at {:?}", topic, qos);
+                    self.subscriptions.insert(topic.to_string(), qos);
                     break;
                 }
-                Err(e) => {
-                    error!("Failed to subscribe to topic \"{}\": {}, retrying...", self.sub_topic, e);
-                    thread::sleep(Duration::from_millis(500));
-                }
+                Err(e) => match backoff.next_delay() {
+                    Some(delay) => {
+                        self.traffic.subscribe_failed();
+                        error!("Failed to subscribe to topic \"{}\": {}, retrying in {:?}...", topic, e, delay);
+                        thread::sleep(delay);
+                    }
+                    None => {
+                        self.traffic.subscribe_failed();
+                        return Err(format!("Failed to subscribe to topic \"{}\": {}", topic, e).into());
+                    }
+                },
             }
         }
         Ok(())
     }
 
-    /// Publish a message to the configured publish topic
-    pub fn publish(&mut self, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
-        self.mqtt_client.enqueue(
-            &self.pub_topic,
-            QoS::AtMostOnce,
-            false,
-            payload.as_bytes(),
+    /// Stop receiving `topic`: unsubscribe, stop restoring the subscription
+    /// after a reconnect and drop its handler, if it was subscribed with one.
+    /// Messages for the handler that are still queued are dropped
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.router.unregister(topic) {
+            info!("Removed the handler for \"{}\"", topic);
+        }
+        let topic = self.aliases.wire(topic).to_string();
+        self.subscriptions.remove(&topic);
+        self.mqtt_client.unsubscribe(&topic)?;
+        info!("Unsubscribed from topic \"{}\"", topic);
+        Ok(())
+    }
+
+    /// Publish a message to the configured publish topic, returning its
+    /// message id
+    pub fn publish(&mut self, payload: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        let topic = self.pub_topic.clone();
+        self.publish_to(&topic, payload)
+    }
+
+    /// Publish `payload`, a JSON object, as a request on the publish topic
+    /// and wait up to `timeout` for the reply with the same `request_id` on
+    /// the subscribe topic. The request gets a fresh `request_id` and
+    /// `reply_to`, the topic to answer on. Needs the message listener
+    pub fn request(&mut self, payload: &str, timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.send_request(payload)?.wait(timeout)
+    }
+
+    /// Publish a message to the configured publish topic at QoS 1 and wait up
+    /// to `timeout` for the broker to acknowledge it, returning its message
+    /// id. Unlike `publish`, it never goes to the offline or publish queue:
+    /// while disconnected, or without a PUBACK in time, it is an error. The
+    /// message may still reach the broker later from the esp-mqtt outbox
+    pub fn publish_blocking(&mut self, payload: &str, timeout: Duration) -> Result<u32, Box<dyn std::error::Error>> {
+        self.send_blocking(payload)?.wait(timeout)
+    }
+
+    /// Publish like `publish_blocking` without waiting for the PUBACK
+    pub fn send_blocking(&mut self, payload: &str) -> Result<Confirmation, Box<dyn std::error::Error>> {
+        if !self.is_connected() {
+            return Err("Not connected to the broker".into());
+        }
+        let topic = self.pub_topic.clone();
+        let payload = self.middleware.publish(&topic, payload.as_bytes().to_vec())?;
+        let confirmation = self.confirmations.register();
+        let id = self.mqtt_client.publish(self.aliases.wire(&topic), QoS::AtLeastOnce, false, &payload)?;
+        self.traffic.sent(payload.len());
+        self.deliveries.track(id, &topic);
+        Ok(confirmation.for_id(id))
+    }
+
+    /// Publish a request like `request` without waiting for the reply
+    pub fn send_request(&mut self, payload: &str) -> Result<Reply, Box<dyn std::error::Error>> {
+        let mut request: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(payload).map_err(|e| format!("A request must be a JSON object: {}", e))?;
+        let id = self.requests.next_id();
+        request.insert("request_id".to_string(), id.clone().into());
+        request.insert("reply_to".to_string(), self.aliases.wire(&self.sub_topic).into());
+        let request = serde_json::to_string(&request)?;
+
+        let reply = self.requests.register(&id, request.as_bytes());
+        let topic = self.pub_topic.clone();
+        self.publish_to(&topic, &request)?;
+        Ok(reply)
+    }
+
+    /// Publish a message to an arbitrary topic at the default QoS, returning
+    /// its message id
+    pub fn publish_to(&mut self, topic: &str, payload: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        self.publish_with_qos(topic, payload, self.publish_qos)
+    }
+
+    /// Publish a message on the wire topic of `topic` through the IoT rule
+    /// `rule_name` with Basic Ingest, returning its message id. The rule
+    /// gets it as usual; subscribers to `topic` don't. `expiry` is as for
+    /// [`Client::publish_opts`]
+    pub fn publish_ingest(
+        &mut self,
+        rule_name: &str,
+        topic: &str,
+        payload: &str,
+        expiry: Option<Duration>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let topic = topics::basic_ingest(rule_name, self.aliases.wire(topic))?;
+        self.publish_opts(&topic, payload, self.publish_qos, false, expiry)
+    }
+
+    /// Publish a message after the middleware chain, returning its message id
+    pub fn publish_with_qos(&mut self, topic: &str, payload: &str, qos: QoS) -> Result<u32, Box<dyn std::error::Error>> {
+        self.publish_opts(topic, payload, qos, false, None)
+    }
+
+    /// Like `publish_with_qos`, optionally retained so late subscribers get
+    /// the last state published on `topic`. An empty retained payload clears it.
+    /// While offline with an offline queue, the publish is stored for later
+    /// and the message id is 0.
+    ///
+    /// A publish with an `expiry` that is still in the offline queue or the
+    /// publish queue when it runs out is dropped instead of sent. In the
+    /// offline queue this needs the clock to be set when publishing
+    pub fn publish_opts(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        retain: bool,
+        expiry: Option<Duration>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        if !self.is_connected() {
+            if let Some(queue) = self.offline_queue.as_mut() {
+                let expires_at_ms = expiry.zip(clock::now_ms()).map(|(expiry, now)| now + expiry.as_millis() as u64);
+                queue.push(topic, payload, qos, retain, expires_at_ms)?;
+                return Ok(0);
+            }
+        }
+        self.send(topic, payload, qos, retain, expiry.map(|expiry| Instant::now() + expiry))
+    }
+
+    /// Publish with MQTT 5 `properties`, e.g. a correlation id for the
+    /// receiver, after the middleware chain, returning the message id. It
+    /// goes to the esp-mqtt outbox even while offline: the offline and
+    /// publish queues don't keep properties
+    #[cfg(feature = "mqtt5")]
+    pub fn publish_with_properties(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        properties: &PublishProperties,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let payload = self.middleware.publish(topic, payload.as_bytes().to_vec())?;
+        let handle = self.mqtt_client.handle();
+        mqtt5::with_properties(handle, properties, || self.enqueue(topic, &payload, qos, false))
+    }
+
+    /// Reason code of the last connection the broker refused, e.g. "Not
+    /// authorized"
+    #[cfg(feature = "mqtt5")]
+    pub fn last_refusal(&self) -> Option<ReasonCode> {
+        mqtt5::last_refusal()
+    }
+
+    /// Whether a publish now goes out or is stored for later
+    pub fn can_publish(&self) -> bool {
+        self.is_connected() || self.offline_queue.is_some()
+    }
+
+    /// Hand a publish to esp-mqtt after the middleware chain, or to the
+    /// publish queue while the outbox is over its limit. A queued publish
+    /// has message id 0
+    fn send(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        retain: bool,
+        expires: Option<Instant>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let payload = self.middleware.publish(topic, payload.as_bytes().to_vec())?;
+        let Some((overflow, outbox_limit)) = self
+            .publish_queue
+            .as_ref()
+            .map(|queue| (queue.overflow(), queue.outbox_limit()))
+        else {
+            return self.enqueue(topic, &payload, qos, retain);
+        };
+
+        // Queued publishes go first, so a new one only skips the queue when it is empty
+        self.drain_publish_queue();
+        if self.publish_queue.as_ref().is_some_and(PublishQueue::is_empty) && self.outbox_size() < outbox_limit {
+            return self.enqueue(topic, &payload, qos, retain);
+        }
+
+        if overflow == Overflow::Block {
+            let deadline = Instant::now() + PUBLISH_BLOCK_TIMEOUT;
+            while self.publish_queue.as_ref().is_some_and(PublishQueue::is_full) {
+                if Instant::now() >= deadline {
+                    return Err(format!("Publish queue still full after {:?}", PUBLISH_BLOCK_TIMEOUT).into());
+                }
+                thread::sleep(Duration::from_millis(20));
+                self.drain_publish_queue();
+            }
+        }
+        if let Some(queue) = self.publish_queue.as_mut() {
+            queue.push(Pending {
+                topic: topic.to_string(),
+                payload,
+                qos,
+                retain,
+                expires,
+            });
+        }
+        Ok(0)
+    }
+
+    /// Put a publish in the esp-mqtt outbox, tracking its delivery
+    fn enqueue(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<u32, Box<dyn std::error::Error>> {
+        let id = self.mqtt_client.enqueue(
+            self.aliases.wire(topic),
+            qos,
+            retain,
+            payload,
         )?;
+        self.traffic.sent(payload.len());
+        if qos == QoS::AtLeastOnce {
+            self.deliveries.track(id, topic);
+        }
+        Ok(id)
+    }
+
+    /// Replace the retained `offline` last will with `online`. Call after
+    /// every connect; does nothing without a presence topic.
+    pub fn publish_online(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Not aliased: the will was registered before the aliases were set
+        if let Some(topic) = &self.presence_topic {
+            self.mqtt_client
+                .enqueue(topic, QoS::AtLeastOnce, true, PRESENCE_ONLINE.as_bytes())?;
+            self.traffic.sent(PRESENCE_ONLINE.len());
+        }
         Ok(())
     }
 }
 
-fn convert_certificate(mut certificate_bytes: Vec<u8>) -> X509<'static> {
-    // append NUL
-    certificate_bytes.push(0);
+/// Reference-counted handle to the one [`Client`], so the shadow, jobs, OTA
+/// and telemetry can each keep their own instead of borrowing `&mut Client`
+/// from the main loop. Clones share the connection. Every call locks the
+/// client for its duration, so publishes and subscribes from different
+/// subsystems never interleave.
+///
+/// Router handlers already get `&mut Client` and must use that one: the
+/// client is locked while `poll` runs them.
+#[derive(Clone)]
+pub struct SharedClient(Arc<Mutex<Client>>);
+
+impl SharedClient {
+    pub fn new(client: Client) -> Self {
+        Self(Arc::new(Mutex::new(client)))
+    }
 
-    // convert the certificate
-    let certificate_slice: &[u8] = unsafe {
-        let ptr: *const u8 = certificate_bytes.as_ptr();
-        let len: usize = certificate_bytes.len();
-        mem::forget(certificate_bytes);
+    /// Exclusive access for anything without a shortcut below. Don't hold
+    /// the guard across calls into subsystems that have their own handle
+    pub fn lock(&self) -> MutexGuard<'_, Client> {
+        self.0.lock().unwrap()
+    }
 
-        slice::from_raw_parts(ptr, len)
-    };
+    pub fn is_connected(&self) -> bool {
+        self.lock().is_connected()
+    }
+
+    pub fn can_publish(&self) -> bool {
+        self.lock().can_publish()
+    }
 
-    // return the certificate file in the correct format
-    X509::pem_until_nul(certificate_slice)
+    pub fn subscribe_topic(&self, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.lock().subscribe_topic(topic)
+    }
+
+    pub fn unsubscribe(&self, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.lock().unsubscribe(topic)
+    }
+
+    pub fn publish(&self, payload: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish(payload)
+    }
+
+    pub fn publish_to(&self, topic: &str, payload: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish_to(topic, payload)
+    }
+
+    pub fn publish_ingest(
+        &self,
+        rule_name: &str,
+        topic: &str,
+        payload: &str,
+        expiry: Option<Duration>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish_ingest(rule_name, topic, payload, expiry)
+    }
+
+    pub fn publish_with_qos(&self, topic: &str, payload: &str, qos: QoS) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish_with_qos(topic, payload, qos)
+    }
+
+    #[cfg(feature = "mqtt5")]
+    pub fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        properties: &PublishProperties,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish_with_properties(topic, payload, qos, properties)
+    }
+
+    /// Like [`Client::request`], with the client locked only to publish
+    pub fn request(&self, payload: &str, timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let reply = self.lock().send_request(payload)?;
+        reply.wait(timeout)
+    }
+
+    /// Like [`Client::publish_blocking`], with the client locked only to publish
+    pub fn publish_blocking(&self, payload: &str, timeout: Duration) -> Result<u32, Box<dyn std::error::Error>> {
+        let confirmation = self.lock().send_blocking(payload)?;
+        confirmation.wait(timeout)
+    }
+
+    pub fn publish_opts(
+        &self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        retain: bool,
+        expiry: Option<Duration>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        self.lock().publish_opts(topic, payload, qos, retain, expiry)
+    }
+}
+
+/// QoS for a level from cfg.toml or a command. AWS IoT doesn't support QoS 2.
+pub fn qos(level: u8) -> Result<QoS, String> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        other => Err(format!("Unsupported QoS {}", other)),
+    }
+}
+
+/// `url` with `port` appended unless it names one already.
+pub fn with_default_port(url: &str, port: u16) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if authority.contains(':') {
+        url.to_string()
+    } else {
+        format!("{}://{}:{}{}", scheme, authority, port, path)
+    }
+}
+
+/// `url` without its query string, which for SigV4 holds the signature and
+/// session token. For logs.
+pub fn redact_url(url: &str) -> &str {
+    url.split('?').next().unwrap_or(url)
+}
+
+/// Leak `certificate_bytes`, NUL-terminated, for the lifetime of the
+/// esp-mqtt configuration that points at them.
+pub(crate) fn convert_certificate(mut certificate_bytes: Vec<u8>) -> X509<'static> {
+    certificate_bytes.push(0);
+    X509::pem_until_nul(certificate_bytes.leak())
 }
 
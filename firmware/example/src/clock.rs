@@ -0,0 +1,205 @@
+//! Wall-clock time from SNTP, optionally corrected for the drift of the
+//! local clock between syncs.
+//!
+//! Between syncs the system clock runs free on the device's own oscillator,
+//! and through deep sleep on the RTC clock, which can be off by hundreds of
+//! ppm: minutes a day on a device that sleeps for hours. With drift
+//! compensation, every sync compares NTP time with what the local clock
+//! read when SNTP replaced it, and [`now_ms`] scales the time since the last
+//! sync by the drift measured so far. The estimate and the time of the last
+//! sync are kept in NVS, so a device waking from deep sleep corrects its
+//! timestamps before it has synced again.
+
+use crate::retry::{self, RetryPolicy, Subsystem};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Anything before this (2024-01-01) means the clock was never set.
+const MIN_VALID_EPOCH_MS: u64 = 1_704_067_200_000;
+
+const NAMESPACE: &str = "clock";
+const SYNCED_KEY: &str = "synced_ms";
+const DRIFT_KEY: &str = "drift_ppb";
+const RESIDUAL_KEY: &str = "residual_ppb";
+
+/// Shorter intervals between syncs drown the drift in network latency
+const MIN_MEASURE_MS: u64 = 10 * 60 * 1000;
+/// An offset this large (10%) means the clock was set some other way
+const MAX_DRIFT_PPB: i64 = 100_000_000;
+/// Assumed error of the local clock until two syncs have tested the estimate
+const UNMEASURED_PPB: u64 = 500_000;
+
+/// How long one SNTP start waits for the first sync
+const SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+/// SNTP restarts before giving up on a sync at startup
+const SYNC_ATTEMPTS: u32 = 3;
+
+/// Drift of the local clock, measured across SNTP syncs.
+struct Drift {
+    nvs: EspNvs<NvsDefault>,
+    /// NTP time of the last sync; the local clock has run free since
+    synced_ms: Option<u64>,
+    /// How much faster NTP time runs than the local clock, in parts per billion
+    drift_ppb: Option<i64>,
+    /// How far the last sync found the drift estimate off
+    residual_ppb: Option<u64>,
+    /// What the local clock read at an instant since it was last set, to
+    /// tell what it read when SNTP replaces it
+    reference: Option<(u64, Instant)>,
+}
+
+static DRIFT: Mutex<Option<Drift>> = Mutex::new(None);
+
+/// Start measuring and correcting clock drift, with what earlier boots
+/// measured. Call before [`start_sntp`].
+pub fn compensate_drift(partition: EspDefaultNvsPartition) -> Result<(), Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let drift = Drift {
+        synced_ms: nvs.get_u64(SYNCED_KEY)?,
+        drift_ppb: nvs.get_i64(DRIFT_KEY)?,
+        residual_ppb: nvs.get_u64(RESIDUAL_KEY)?,
+        reference: local_ms().map(|ms| (ms, Instant::now())),
+        nvs,
+    };
+    if let Some(ppb) = drift.drift_ppb {
+        log::info!("Correcting the clock for {:.1} ppm of drift", ppb as f64 / 1000.0);
+    }
+    *DRIFT.lock().unwrap() = Some(drift);
+    Ok(())
+}
+
+/// Start SNTP and wait for the clock to be set, restarting SNTP with the
+/// backoff of `policy` (at most [`SYNC_ATTEMPTS`] times) when a sync doesn't
+/// come. If none does, SNTP keeps trying in the background.
+pub fn start_sntp(policy: &RetryPolicy) -> Result<EspSntp<'static>, Box<dyn std::error::Error>> {
+    let policy = policy.with_max_attempts(SYNC_ATTEMPTS);
+    let synced = retry::retry(Subsystem::Sntp, &policy, || -> Result<_, Box<dyn std::error::Error>> {
+        let sntp = EspSntp::new_with_callback(&SntpConf::default(), on_sync)?;
+        wait_for_sync(&sntp, SYNC_TIMEOUT)?;
+        Ok(sntp)
+    });
+    match synced {
+        Ok(sntp) => {
+            log::info!("SNTP synced");
+            Ok(sntp)
+        }
+        Err(e) => {
+            log::warn!("{}, syncing in the background", e);
+            let sntp = EspSntp::new_with_callback(&SntpConf::default(), on_sync)?;
+            log::info!("SNTP started, sync status: {:?}", sntp.get_sync_status());
+            Ok(sntp)
+        }
+    }
+}
+
+fn wait_for_sync(sntp: &EspSntp<'_>, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    while !is_synced(sntp) {
+        if started.elapsed() >= timeout {
+            return Err(format!("No SNTP sync within {} seconds", timeout.as_secs()).into());
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    Ok(())
+}
+
+pub fn is_synced(sntp: &EspSntp<'_>) -> bool {
+    sntp.get_sync_status() == SyncStatus::Completed || now_ms().is_some()
+}
+
+/// Wall-clock time in milliseconds since the Unix epoch, or `None` until
+/// the clock has been set. Corrected for drift with [`compensate_drift`].
+pub fn now_ms() -> Option<u64> {
+    let now = local_ms()?;
+    Some(DRIFT.lock().unwrap().as_ref().map_or(now, |drift| drift.correct(now)))
+}
+
+/// How far off [`now_ms`] may be, in milliseconds: the time since the last
+/// sync times the error of the drift estimate. `None` without drift
+/// compensation or a sync to go by.
+pub fn error_ms() -> Option<u64> {
+    let now = local_ms()?;
+    let drift = DRIFT.lock().unwrap();
+    let drift = drift.as_ref()?;
+    let elapsed = now.checked_sub(drift.synced_ms?)?;
+    let error_ppb = drift.residual_ppb.unwrap_or(UNMEASURED_PPB);
+    Some((elapsed as u128 * error_ppb as u128 / 1_000_000_000) as u64)
+}
+
+/// The system clock, uncorrected.
+fn local_ms() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+    (now >= MIN_VALID_EPOCH_MS).then_some(now)
+}
+
+/// Called by SNTP with the time it just set.
+fn on_sync(time: Duration) {
+    let synced_ms = time.as_millis() as u64;
+    let mut drift = DRIFT.lock().unwrap();
+    let Some(drift) = drift.as_mut() else {
+        return;
+    };
+    let local_ms = drift.reference.map(|(ms, at)| ms + at.elapsed().as_millis() as u64);
+    if let (Some(previous), Some(local)) = (drift.synced_ms, local_ms) {
+        drift.measure(previous, local, synced_ms);
+    }
+    drift.synced_ms = Some(synced_ms);
+    drift.reference = Some((synced_ms, Instant::now()));
+    if let Err(e) = drift.store() {
+        log::warn!("Failed to store the clock drift: {}", e);
+    }
+}
+
+impl Drift {
+    /// `now` on the local clock, plus the drift since the last sync.
+    fn correct(&self, now: u64) -> u64 {
+        let (Some(synced), Some(ppb)) = (self.synced_ms, self.drift_ppb) else {
+            return now;
+        };
+        let Some(elapsed) = now.checked_sub(synced) else {
+            return now;
+        };
+        let correction = elapsed as i128 * ppb as i128 / 1_000_000_000;
+        (now as i128 + correction) as u64
+    }
+
+    /// Update the estimate with a sync that found the local clock at
+    /// `local` when NTP said `synced`, `previous` being the last sync.
+    fn measure(&mut self, previous: u64, local: u64, synced: u64) {
+        let Some(elapsed) = local.checked_sub(previous).filter(|&elapsed| elapsed >= MIN_MEASURE_MS) else {
+            return;
+        };
+        let offset = synced as i64 - local as i64;
+        let measured = offset as i128 * 1_000_000_000 / elapsed as i128;
+        if measured.abs() > MAX_DRIFT_PPB as i128 {
+            log::warn!("Clock off by {} ms after {} s, not counting it as drift", offset, elapsed / 1000);
+            return;
+        }
+        let measured = measured as i64;
+        log::info!(
+            "Clock off by {} ms after {} s of running free, {:.1} ppm",
+            offset,
+            elapsed / 1000,
+            measured as f64 / 1000.0
+        );
+        // The estimate in use since the last sync, so how wrong timestamps got
+        self.residual_ppb = self.drift_ppb.map(|estimate| estimate.abs_diff(measured));
+        self.drift_ppb = Some(self.drift_ppb.map_or(measured, |estimate| (3 * estimate + measured) / 4));
+    }
+
+    fn store(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(synced_ms) = self.synced_ms {
+            self.nvs.set_u64(SYNCED_KEY, synced_ms)?;
+        }
+        if let Some(drift_ppb) = self.drift_ppb {
+            self.nvs.set_i64(DRIFT_KEY, drift_ppb)?;
+        }
+        if let Some(residual_ppb) = self.residual_ppb {
+            self.nvs.set_u64(RESIDUAL_KEY, residual_ppb)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+//! Cold-chain monitoring profile: the battery-powered sensor pattern.
+//!
+//! Each wake-up samples a DS18B20 before the radio is touched, appends the
+//! reading to a history kept in NVS and goes straight back to deep sleep.
+//! The network is only brought up every `cold_chain_upload_every` wakes, or
+//! when an alarm escalates past the local buzzer.
+
+use crate::battery::{self, Session};
+use crate::envelope;
+use crate::migrations::NAMESPACE;
+use crate::rtc::RtcSlot;
+use crate::shadow::Shadow;
+use crate::startup::Config;
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::hal::gpio::{AnyIOPin, InputOutput, PinDriver};
+use esp_idf_svc::hal::interrupt;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const STATE_KEY: &str = "cold_state";
+const HISTORY_KEY: &str = "cold_hist";
+/// A day of readings at the default 5 minute interval.
+const MAX_HISTORY: usize = 288;
+/// Bytes per stored reading: u32 seconds since the epoch, i16 centi-degrees.
+const ENTRY_SIZE: usize = 6;
+/// Readings per uploaded message.
+const UPLOAD_BATCH: usize = 48;
+/// Failed uploads stretch the upload interval up to this many times
+const MAX_UPLOAD_BACKOFF: u32 = 16;
+
+/// Alarm escalation. Each level is reached after `cold_chain_escalate_after`
+/// further out-of-range readings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmLevel {
+    #[default]
+    Normal,
+    /// Local buzzer only
+    Buzzer,
+    /// MQTT alert, connecting early if needed
+    Alert,
+    /// Alarm flag set in the shadow
+    Shadow,
+}
+
+/// Kept in RTC memory across deep sleep, and in NVS whenever the alarm
+/// level or an upload changed it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    wakes: u32,
+    out_of_range: u32,
+    level: AlarmLevel,
+    /// Level already announced over MQTT / the shadow
+    announced: AlarmLevel,
+    /// Uploads failed in a row, so an outage doesn't drain the battery on
+    /// connection attempts
+    #[serde(default)]
+    failed_uploads: u32,
+}
+
+#[link_section = ".rtc.data"]
+static RTC_STATE: RtcSlot<State, 128> = RtcSlot::new();
+
+#[derive(Serialize, Debug, Clone, Copy)]
+struct Reading {
+    /// Seconds since the epoch, 0 if the clock wasn't set
+    t: u32,
+    c: f32,
+}
+
+#[derive(Serialize, Debug)]
+struct HistoryUpload<'a> {
+    event: &'static str,
+    readings: &'a [Reading],
+}
+
+#[derive(Serialize, Debug)]
+struct ColdChainAlarm {
+    event: &'static str,
+    level: AlarmLevel,
+    /// `None` when the sensor couldn't be read
+    temperature_c: Option<f32>,
+    min_c: f32,
+    max_c: f32,
+}
+
+#[derive(Serialize, Debug)]
+struct ColdChainShadow {
+    alarm: bool,
+    level: AlarmLevel,
+    temperature_c: Option<f32>,
+}
+
+/// One wake cycle. Never returns: ends in deep sleep, also when the wake
+/// failed, so the next one can try again.
+pub fn run(nvs: EspDefaultNvsPartition, config: &Config) -> ! {
+    if let Err(e) = wake(nvs, config) {
+        log::error!("Cold chain wake failed: {}", e);
+    }
+    battery::deep_sleep(Some(Duration::from_secs(config.cold_chain_sample_secs)))
+}
+
+fn wake(nvs: EspDefaultNvsPartition, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut state = match RTC_STATE.load() {
+        Some(state) => state,
+        None => load_state(&storage),
+    };
+    let level = state.level;
+    state.wakes += 1;
+
+    // A sensor that can't be read can't vouch for the goods either, so it
+    // counts as out of range, without a history entry
+    let temperature = match read_ds18b20(config.cold_chain_sensor_pin) {
+        Ok(temperature) => {
+            log::info!("Cold chain wake {}: {:.2} C", state.wakes, temperature);
+            Some(temperature)
+        }
+        Err(e) => {
+            log::error!("Cold chain wake {}: sensor fault: {}", state.wakes, e);
+            None
+        }
+    };
+    let in_range = temperature.is_some_and(|c| (config.cold_chain_min_c..=config.cold_chain_max_c).contains(&c));
+
+    state.out_of_range = if in_range { 0 } else { state.out_of_range + 1 };
+    let step = config.cold_chain_escalate_after.max(1);
+    state.level = match state.out_of_range {
+        0 => AlarmLevel::Normal,
+        n if n < 1 + step => AlarmLevel::Buzzer,
+        n if n < 1 + 2 * step => AlarmLevel::Alert,
+        _ => AlarmLevel::Shadow,
+    };
+    set_buzzer(config.cold_chain_buzzer_pin, state.level >= AlarmLevel::Buzzer)?;
+
+    let mut history = load_history(&storage)?;
+    if history.len() >= MAX_HISTORY {
+        history.remove(0);
+    }
+    if let Some(c) = temperature {
+        let now = crate::clock::now_ms().map_or(0, |ms| (ms / 1000) as u32);
+        history.push(Reading { t: now, c });
+        store_history(&mut storage, &history)?;
+    }
+
+    let escalated = state.level >= AlarmLevel::Alert && state.level != state.announced;
+    let recovered = state.level == AlarmLevel::Normal && state.announced >= AlarmLevel::Alert;
+    let backoff = 1 << state.failed_uploads.min(MAX_UPLOAD_BACKOFF.ilog2());
+    let upload_due = state.wakes % (config.cold_chain_upload_every.max(1) * backoff) == 0;
+    let connect = upload_due || escalated || recovered;
+    if connect {
+        match upload(nvs, config, &history, &state, temperature) {
+            Ok(()) => {
+                history.clear();
+                store_history(&mut storage, &history)?;
+                state.announced = state.level;
+                state.failed_uploads = 0;
+            }
+            // Keep the history for the next attempt
+            Err(e) => {
+                log::error!("Cold chain upload failed: {}", e);
+                state.failed_uploads += 1;
+            }
+        }
+    }
+
+    RTC_STATE.store(&state)?;
+    // The wake count alone isn't worth a flash write every wake
+    if connect || state.level != level {
+        storage.set_str(STATE_KEY, &serde_json::to_string(&state)?)?;
+    }
+    Ok(())
+}
+
+/// The state kept in NVS, after a power cycle cleared RTC memory. One that
+/// can't be read starts over rather than keep the device from sampling.
+fn load_state(storage: &EspNvs<esp_idf_svc::nvs::NvsDefault>) -> State {
+    let mut buf = [0u8; 128];
+    match storage.get_str(STATE_KEY, &mut buf) {
+        Ok(Some(json)) => serde_json::from_str(json).unwrap_or_else(|e| {
+            log::warn!("Dropping unreadable cold chain state: {}", e);
+            State::default()
+        }),
+        Ok(None) => State::default(),
+        Err(e) => {
+            log::warn!("Failed to read the cold chain state: {}", e);
+            State::default()
+        }
+    }
+}
+
+/// Publish the history and any alarm change, and wait until the broker has
+/// acknowledged them.
+fn upload(
+    nvs: EspDefaultNvsPartition,
+    config: &Config,
+    history: &[Reading],
+    state: &State,
+    temperature: Option<f32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut session = Session::connect(nvs)?;
+    let device_id = session.app.device_id.clone();
+
+    let mut payloads = Vec::new();
+    for batch in history.chunks(UPLOAD_BATCH) {
+        let upload = HistoryUpload {
+            event: "cold_chain_log",
+            readings: batch,
+        };
+        payloads.push(envelope::to_json(&device_id, &upload)?);
+    }
+    if state.level >= AlarmLevel::Alert || state.announced >= AlarmLevel::Alert {
+        let alarm = ColdChainAlarm {
+            event: "cold_chain_alarm",
+            level: state.level,
+            temperature_c: temperature,
+            min_c: config.cold_chain_min_c,
+            max_c: config.cold_chain_max_c,
+        };
+        payloads.push(envelope::to_json(&device_id, &alarm)?);
+    }
+
+    if state.level >= AlarmLevel::Shadow || state.announced >= AlarmLevel::Shadow {
+        let app = &session.app;
+        let shadow = Shadow::new(config.thing_name(), Duration::ZERO, app.client.clone(), app.events.clone());
+        let reported = serde_json::json!({
+            "cold_chain": ColdChainShadow {
+                alarm: state.level >= AlarmLevel::Shadow,
+                level: state.level,
+                temperature_c: temperature,
+            }
+        });
+        shadow.report(&reported)?;
+    }
+
+    session.publish_confirmed(&payloads)?;
+    log::info!("Uploaded {} readings", history.len());
+    Ok(())
+}
+
+fn load_history(storage: &EspNvs<esp_idf_svc::nvs::NvsDefault>) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    let mut buf = vec![0u8; MAX_HISTORY * ENTRY_SIZE];
+    let Some(raw) = storage.get_raw(HISTORY_KEY, &mut buf)? else {
+        return Ok(Vec::new());
+    };
+    Ok(raw
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| Reading {
+            t: u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            c: i16::from_le_bytes([entry[4], entry[5]]) as f32 / 100.0,
+        })
+        .collect())
+}
+
+fn store_history(
+    storage: &mut EspNvs<esp_idf_svc::nvs::NvsDefault>,
+    history: &[Reading],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut raw = Vec::with_capacity(history.len() * ENTRY_SIZE);
+    for reading in history {
+        raw.extend_from_slice(&reading.t.to_le_bytes());
+        raw.extend_from_slice(&((reading.c * 100.0).round() as i16).to_le_bytes());
+    }
+    storage.set_raw(HISTORY_KEY, &raw)?;
+    Ok(())
+}
+
+/// Drive the buzzer and hold the level through deep sleep.
+fn set_buzzer(pin: i32, on: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if pin < 0 {
+        return Ok(());
+    }
+    unsafe {
+        sys::esp!(sys::gpio_hold_dis(pin))?;
+        sys::esp!(sys::gpio_set_direction(pin, sys::gpio_mode_t_GPIO_MODE_OUTPUT))?;
+        sys::esp!(sys::gpio_set_level(pin, on as u32))?;
+        sys::esp!(sys::gpio_hold_en(pin))?;
+        sys::gpio_deep_sleep_hold_en();
+    }
+    Ok(())
+}
+
+/// Single DS18B20 on a bit-banged 1-Wire bus (4.7k pull-up required).
+fn read_ds18b20(pin: i32) -> Result<f32, Box<dyn std::error::Error>> {
+    const SKIP_ROM: u8 = 0xCC;
+    const CONVERT_T: u8 = 0x44;
+    const READ_SCRATCHPAD: u8 = 0xBE;
+
+    let mut bus = PinDriver::input_output_od(unsafe { AnyIOPin::new(pin) })?;
+    bus.set_high()?;
+
+    if !reset(&mut bus)? {
+        return Err("No DS18B20 presence pulse".into());
+    }
+    write_byte(&mut bus, SKIP_ROM)?;
+    write_byte(&mut bus, CONVERT_T)?;
+    // 12-bit conversion time
+    std::thread::sleep(Duration::from_millis(750));
+
+    if !reset(&mut bus)? {
+        return Err("DS18B20 vanished during conversion".into());
+    }
+    write_byte(&mut bus, SKIP_ROM)?;
+    write_byte(&mut bus, READ_SCRATCHPAD)?;
+    let mut scratchpad = [0u8; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = read_byte(&mut bus)?;
+    }
+    if crc8(&scratchpad[..8]) != scratchpad[8] {
+        return Err("DS18B20 scratchpad CRC mismatch".into());
+    }
+    Ok(i16::from_le_bytes([scratchpad[0], scratchpad[1]]) as f32 / 16.0)
+}
+
+type Bus = PinDriver<'static, AnyIOPin, InputOutput>;
+
+/// Reset pulse; true if a device answered with a presence pulse.
+fn reset(bus: &mut Bus) -> Result<bool, Box<dyn std::error::Error>> {
+    let present = interrupt::free(|| -> Result<bool, sys::EspError> {
+        bus.set_low()?;
+        Ets::delay_us(480);
+        bus.set_high()?;
+        Ets::delay_us(70);
+        let present = bus.is_low();
+        Ets::delay_us(410);
+        Ok(present)
+    })?;
+    Ok(present)
+}
+
+fn write_byte(bus: &mut Bus, byte: u8) -> Result<(), Box<dyn std::error::Error>> {
+    for bit in 0..8 {
+        let one = byte >> bit & 1 == 1;
+        interrupt::free(|| -> Result<(), sys::EspError> {
+            bus.set_low()?;
+            Ets::delay_us(if one { 6 } else { 60 });
+            bus.set_high()?;
+            Ets::delay_us(if one { 64 } else { 10 });
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn read_byte(bus: &mut Bus) -> Result<u8, Box<dyn std::error::Error>> {
+    let mut byte = 0;
+    for bit in 0..8 {
+        let high = interrupt::free(|| -> Result<bool, sys::EspError> {
+            bus.set_low()?;
+            Ets::delay_us(3);
+            bus.set_high()?;
+            Ets::delay_us(10);
+            let high = bus.is_high();
+            Ets::delay_us(53);
+            Ok(high)
+        })?;
+        byte |= (high as u8) << bit;
+    }
+    Ok(byte)
+}
+
+/// Dallas/Maxim CRC-8.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold((crc, byte), |(crc, byte), _| {
+            let mix = (crc ^ byte) & 1;
+            let crc = if mix == 1 { (crc >> 1) ^ 0x8C } else { crc >> 1 };
+            (crc, byte >> 1)
+        })
+        .0
+    })
+}
@@ -0,0 +1,204 @@
+//! LAN debug console: a WebSocket endpoint on the local HTTP server that
+//! streams the firmware's log lines and accepts the same JSON commands as
+//! the command topic, for debugging without a serial cable.
+//!
+//! Only records logged through the `log` crate are streamed; ESP-IDF
+//! component logs (WiFi, esp-mqtt, ...) stay on the serial console.
+//!
+//! With an [`Exporter`], the server also answers Prometheus scrapes on
+//! `/metrics`.
+
+use crate::prometheus::{self, Exporter};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use esp_idf_svc::http::{Headers, Method};
+use esp_idf_svc::io::Write;
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::sys::{EspError, ESP_FAIL};
+use esp_idf_svc::ws::FrameType;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+/// Commands waiting for the main loop.
+const COMMAND_QUEUE: usize = 4;
+/// Longest command frame accepted.
+const MAX_FRAME: usize = 2048;
+
+const INDEX_HTML: &str = r#"<!doctype html>
+<title>ESP32 console</title>
+<style>body{font-family:monospace;margin:0}#log{height:90vh;overflow:auto;white-space:pre-wrap}input{width:99%}</style>
+<div id="log"></div>
+<input id="cmd" placeholder='token first if one is set, then e.g. {"message": "ping"}'>
+<script>
+const log = document.getElementById("log"), cmd = document.getElementById("cmd");
+const ws = new WebSocket(`ws://${location.host}/ws`);
+ws.onmessage = (e) => { log.textContent += e.data + "\n"; log.scrollTop = log.scrollHeight; };
+ws.onclose = () => { log.textContent += "-- disconnected --\n"; };
+cmd.onkeydown = (e) => { if (e.key === "Enter") { ws.send(cmd.value); cmd.value = ""; } };
+</script>
+"#;
+
+/// Connected consoles receiving log lines, by session id.
+static SINKS: Mutex<Vec<(i32, EspHttpWsDetachedSender)>> = Mutex::new(Vec::new());
+
+/// Logger installed by `startup::init_runtime`: everything still goes to
+/// the ESP-IDF log, and is copied to connected consoles.
+pub struct ConsoleLogger(EspLogger);
+
+pub static LOGGER: ConsoleLogger = ConsoleLogger(EspLogger::new());
+
+impl ConsoleLogger {
+    pub fn initialize(&'static self) {
+        ::log::set_logger(self).expect("Logger already set");
+        self.0.initialize();
+    }
+}
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // try_lock: a send that logs must not deadlock on itself
+        let Ok(mut sinks) = SINKS.try_lock() else {
+            return;
+        };
+        if sinks.is_empty() {
+            return;
+        }
+        let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+        sinks.retain_mut(|(_, sender)| sender.send(FrameType::Text(false), line.as_bytes()).is_ok());
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// The running console. Dropping it stops the server.
+pub struct Console {
+    _server: EspHttpServer<'static>,
+    commands: Receiver<Vec<u8>>,
+}
+
+impl Console {
+    /// Serve the console page on `/` and the WebSocket on `/ws`, and with
+    /// `exporter` the metrics on `/metrics`. With a non-empty `token`, a
+    /// client must send it as its first frame before it gets logs or may
+    /// send commands, and a scrape must carry it as a bearer token.
+    pub fn start(port: u16, token: &'static str, exporter: Option<Exporter>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut server = EspHttpServer::new(&Configuration {
+            http_port: port,
+            ..Default::default()
+        })?;
+
+        server.fn_handler("/", Method::Get, |request| {
+            request
+                .into_response(200, None, &[("Content-Type", "text/html")])?
+                .write_all(INDEX_HTML.as_bytes())
+        })?;
+
+        if let Some(exporter) = exporter {
+            server.fn_handler("/metrics", Method::Get, move |request| {
+                let authorized = token.is_empty()
+                    || request.header("Authorization").and_then(|value| value.strip_prefix("Bearer ")) == Some(token);
+                if !authorized {
+                    return request.into_status_response(401)?.write_all(b"Unauthorized");
+                }
+                request
+                    .into_response(200, None, &[("Content-Type", prometheus::CONTENT_TYPE)])?
+                    .write_all(exporter.render().as_bytes())
+            })?;
+        }
+
+        let (tx, commands) = bounded(COMMAND_QUEUE);
+        let authenticated = Mutex::new(BTreeSet::new());
+        server.ws_handler("/ws", move |ws: &mut EspHttpWsConnection| -> Result<(), EspError> {
+            handle_frame(ws, token, &authenticated, &tx)
+        })?;
+
+        log::info!("Console listening on port {}", port);
+        Ok(Self {
+            _server: server,
+            commands,
+        })
+    }
+
+    /// Next command received from a console, if any.
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        self.commands.try_recv().ok()
+    }
+}
+
+impl Drop for Console {
+    /// The connections go with the server; stop streaming logs to them.
+    fn drop(&mut self) {
+        SINKS.lock().unwrap().clear();
+    }
+}
+
+fn handle_frame(
+    ws: &mut EspHttpWsConnection,
+    token: &str,
+    authenticated: &Mutex<BTreeSet<i32>>,
+    commands: &Sender<Vec<u8>>,
+) -> Result<(), EspError> {
+    let session = ws.session();
+    if ws.is_new() {
+        log::info!("Console {} connected", session);
+        if token.is_empty() {
+            attach(ws)?;
+        } else {
+            ws.send(FrameType::Text(false), b"Send the console token")?;
+        }
+        return Ok(());
+    }
+    if ws.is_closed() {
+        log::info!("Console {} disconnected", session);
+        authenticated.lock().unwrap().remove(&session);
+        SINKS.lock().unwrap().retain(|(id, _)| *id != session);
+        return Ok(());
+    }
+
+    let (_, len) = ws.recv(&mut [])?;
+    if len > MAX_FRAME {
+        ws.send(FrameType::Text(false), b"Frame too large")?;
+        return Err(EspError::from_infallible::<ESP_FAIL>());
+    }
+    let mut frame = vec![0u8; len];
+    ws.recv(&mut frame)?;
+    // Text frames carry a trailing NUL
+    while frame.last() == Some(&0) {
+        frame.pop();
+    }
+
+    if !token.is_empty() && !authenticated.lock().unwrap().contains(&session) {
+        if frame == token.as_bytes() {
+            authenticated.lock().unwrap().insert(session);
+            attach(ws)?;
+        } else {
+            log::warn!("Console {} sent a wrong token", session);
+            ws.send(FrameType::Text(false), b"Wrong token")?;
+        }
+        return Ok(());
+    }
+
+    log::info!("Console {} command: {}", session, String::from_utf8_lossy(&frame));
+    if commands.try_send(frame).is_err() {
+        ws.send(FrameType::Text(false), b"Busy, try again")?;
+    }
+    Ok(())
+}
+
+/// Start streaming log lines to this connection.
+fn attach(ws: &mut EspHttpWsConnection) -> Result<(), EspError> {
+    let sender = ws.create_detached_sender()?;
+    SINKS.lock().unwrap().push((ws.session(), sender));
+    ws.send(FrameType::Text(false), b"Console attached, streaming logs")
+}
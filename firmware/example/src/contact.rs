@@ -0,0 +1,199 @@
+//! Door/window sensor profile: the minimal-resource reference. The device
+//! sleeps until the reed switch changes state, the tamper switch trips or
+//! the heartbeat timer expires, reports what happened and sleeps again.
+
+use crate::battery::{self, Session};
+use crate::envelope;
+use crate::migrations::NAMESPACE;
+use crate::rtc::RtcSlot;
+use crate::startup::Config;
+use esp_idf_svc::hal::gpio::{AnyInputPin, PinDriver, Pull};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::Duration;
+
+const STATE_KEY: &str = "contact";
+/// Spacing of the samples that make up a debounced read.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Kept in NVS across deep sleep; only updated once the broker confirmed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    open: Option<bool>,
+    tampered: bool,
+}
+
+/// [`State`] as of the last wake, so a wake that has nothing to report
+/// doesn't touch NVS
+#[link_section = ".rtc.data"]
+static RTC_STATE: RtcSlot<State, 64> = RtcSlot::new();
+
+#[derive(Serialize, Debug)]
+struct ContactEvent {
+    event: &'static str,
+    state: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct TamperEvent {
+    event: &'static str,
+    tampered: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Heartbeat {
+    event: &'static str,
+    state: &'static str,
+    tampered: bool,
+}
+
+fn state_name(open: bool) -> &'static str {
+    if open {
+        "open"
+    } else {
+        "closed"
+    }
+}
+
+/// One wake cycle. Never returns: ends in deep sleep, also when the wake
+/// failed. The heartbeat timer then brings the next try.
+pub fn run(nvs: EspDefaultNvsPartition, config: &Config) -> ! {
+    if let Err(e) = wake(nvs, config) {
+        log::error!("Contact wake failed: {}", e);
+    }
+    battery::deep_sleep(Some(Duration::from_secs(config.contact_heartbeat_secs)))
+}
+
+fn wake(nvs: EspDefaultNvsPartition, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let cause = unsafe { sys::esp_sleep_get_wakeup_cause() };
+    let mut state = match RTC_STATE.load() {
+        Some(state) => state,
+        // Starting over only makes the next report go out as a change
+        None => load_state(nvs.clone()).unwrap_or_else(|e| {
+            log::warn!("Dropping unreadable contact state: {}", e);
+            State::default()
+        }),
+    };
+
+    // Reed switch to ground: the magnet closes it while the door is shut
+    let debounce = Duration::from_millis(config.contact_debounce_ms);
+    let open = read_debounced(config.contact_pin, debounce)?;
+    let tampered = config.contact_tamper_pin >= 0 && read_debounced(config.contact_tamper_pin, debounce)?;
+    log::info!("Contact wake ({}): {}, tampered {}", cause, state_name(open), tampered);
+
+    let heartbeat_due = cause == sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER;
+    let changed = state.open != Some(open) || state.tampered != tampered;
+    if changed || heartbeat_due {
+        match report(nvs.clone(), &state, open, tampered, heartbeat_due) {
+            Ok(()) if changed => {
+                state.open = Some(open);
+                state.tampered = tampered;
+                // Still arm the wakeups below; at worst the change is reported twice
+                if let Err(e) = save_state(nvs, &state) {
+                    log::error!("Failed to save the contact state: {}", e);
+                }
+            }
+            Ok(()) => {}
+            // The next wake compares against the old state and retries
+            Err(e) => log::error!("Contact report failed: {}", e),
+        }
+    }
+
+    arm_wakeups(config, open, tampered)
+}
+
+/// The state kept in NVS, after a power cycle cleared RTC memory.
+fn load_state(nvs: EspDefaultNvsPartition) -> Result<State, Box<dyn std::error::Error>> {
+    let storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    let mut buf = [0u8; 64];
+    let state = match storage.get_str(STATE_KEY, &mut buf)? {
+        Some(json) => serde_json::from_str(json)?,
+        None => State::default(),
+    };
+    RTC_STATE.store(&state)?;
+    Ok(state)
+}
+
+fn save_state(nvs: EspDefaultNvsPartition, state: &State) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    storage.set_str(STATE_KEY, &serde_json::to_string(state)?)?;
+    RTC_STATE.store(state)
+}
+
+/// Publish whatever changed since `state`, plus the heartbeat if due, and
+/// wait until the broker has acknowledged it.
+fn report(
+    nvs: EspDefaultNvsPartition,
+    state: &State,
+    open: bool,
+    tampered: bool,
+    heartbeat_due: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut session = Session::connect(nvs)?;
+    let device_id = session.app.device_id.clone();
+
+    let mut payloads = Vec::new();
+    if state.open != Some(open) {
+        let event = ContactEvent {
+            event: "contact",
+            state: state_name(open),
+        };
+        payloads.push(envelope::to_json(&device_id, &event)?);
+    }
+    if state.tampered != tampered {
+        let event = TamperEvent {
+            event: "tamper",
+            tampered,
+        };
+        payloads.push(envelope::to_json(&device_id, &event)?);
+    }
+    if heartbeat_due {
+        let heartbeat = Heartbeat {
+            event: "heartbeat",
+            state: state_name(open),
+            tampered,
+        };
+        payloads.push(envelope::to_json(&device_id, &heartbeat)?);
+    }
+
+    session.publish_confirmed(&payloads)
+}
+
+/// Sample `pin` (pulled up, high = open) until it reads the same for `debounce`.
+fn read_debounced(pin: i32, debounce: Duration) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut input = PinDriver::input(unsafe { AnyInputPin::new(pin) })?;
+    input.set_pull(Pull::Up)?;
+    let needed = (debounce.as_millis() / SAMPLE_INTERVAL.as_millis()).max(1);
+
+    let mut level = input.is_high();
+    let mut stable = 0;
+    while stable < needed {
+        thread::sleep(SAMPLE_INTERVAL);
+        let now = input.is_high();
+        stable = if now == level { stable + 1 } else { 0 };
+        level = now;
+    }
+    Ok(level)
+}
+
+/// Wake when the contact leaves its current state, and when the tamper
+/// switch trips (not while it is still tripped, or it would never sleep).
+fn arm_wakeups(config: &Config, open: bool, tampered: bool) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        sys::esp!(sys::esp_sleep_enable_ext0_wakeup(config.contact_pin, if open { 0 } else { 1 }))?;
+        sys::esp!(sys::rtc_gpio_pullup_en(config.contact_pin))?;
+        sys::esp!(sys::rtc_gpio_pulldown_dis(config.contact_pin))?;
+
+        if config.contact_tamper_pin >= 0 && !tampered {
+            sys::esp!(sys::esp_sleep_enable_ext1_wakeup(
+                1u64 << config.contact_tamper_pin,
+                sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_HIGH,
+            ))?;
+            sys::esp!(sys::rtc_gpio_pullup_en(config.contact_tamper_pin))?;
+            sys::esp!(sys::rtc_gpio_pulldown_dis(config.contact_tamper_pin))?;
+        }
+    }
+    Ok(())
+}
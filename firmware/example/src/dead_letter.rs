@@ -0,0 +1,73 @@
+use crate::client::SharedClient;
+use crate::envelope;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Bytes of the offending payload kept in a dead-letter record.
+const MAX_PAYLOAD_BYTES: usize = 256;
+
+#[derive(Serialize, Debug)]
+struct DeadLetterRecord<'a> {
+    event: &'static str,
+    topic: &'a str,
+    error: &'a str,
+    payload: String,
+    payload_len: usize,
+    /// Records dropped by the rate limit since the previous one was sent
+    suppressed: u32,
+}
+
+/// Publishes messages that failed processing to a dead-letter topic, so
+/// failures are visible without serial access. Limited to `max_per_minute`
+/// records; the overflow is counted and reported with the next record.
+pub struct DeadLetter {
+    topic: String,
+    max_per_minute: u32,
+    window_start: Instant,
+    sent_in_window: u32,
+    suppressed: u32,
+}
+
+impl DeadLetter {
+    pub fn new(topic: String, max_per_minute: u32) -> Self {
+        Self {
+            topic,
+            max_per_minute,
+            window_start: Instant::now(),
+            sent_in_window: 0,
+            suppressed: 0,
+        }
+    }
+
+    pub fn record(&mut self, client: &SharedClient, device_id: &str, topic: &str, payload: &[u8], error: &str) {
+        if self.window_start.elapsed() >= Duration::from_secs(60) {
+            self.window_start = Instant::now();
+            self.sent_in_window = 0;
+        }
+        if self.sent_in_window >= self.max_per_minute {
+            self.suppressed += 1;
+            return;
+        }
+
+        let truncated = &payload[..payload.len().min(MAX_PAYLOAD_BYTES)];
+        let record = DeadLetterRecord {
+            event: "dead_letter",
+            topic,
+            error,
+            payload: String::from_utf8_lossy(truncated).into_owned(),
+            payload_len: payload.len(),
+            suppressed: self.suppressed,
+        };
+
+        let result = envelope::to_json(device_id, &record)
+            .map_err(|e| e.into())
+            .and_then(|json| client.publish_to(&self.topic, &json));
+        match result {
+            Ok(_) => {
+                self.sent_in_window += 1;
+                self.suppressed = 0;
+            }
+            Err(e) => log::error!("Failed to publish dead letter to \"{}\": {}", self.topic, e),
+        }
+    }
+}
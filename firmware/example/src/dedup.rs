@@ -0,0 +1,72 @@
+//! Duplicate suppression. At QoS 1 the broker delivers again whatever it
+//! doesn't see acknowledged, e.g. across a reconnect, so the same command
+//! can arrive twice and open a valve twice. Messages that identify
+//! themselves, by `request_id` or by sender and `seq`/`sequence`, are
+//! remembered in a sliding window; a repeat within it is dropped before
+//! dispatch and counted in the metrics. Messages without either pass. The
+//! window is short on purpose: a sender whose count restarted at boot only
+//! collides with the messages it sent just before.
+
+use crate::middleware::{Metrics, Middleware};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::error::Error;
+
+#[derive(Deserialize)]
+struct Identity {
+    request_id: Option<String>,
+    device_id: Option<String>,
+    #[serde(alias = "sequence")]
+    seq: Option<u64>,
+    /// Only on the device's own requests, see `request`
+    reply_to: Option<String>,
+}
+
+pub struct DedupFilter {
+    /// Most recent last
+    seen: VecDeque<String>,
+    window: usize,
+    metrics: Metrics,
+}
+
+impl DedupFilter {
+    /// Remember the last `window` messages, counting repeats in `metrics`.
+    pub fn new(window: usize, metrics: Metrics) -> Self {
+        Self {
+            seen: VecDeque::with_capacity(window),
+            window,
+            metrics,
+        }
+    }
+}
+
+impl Middleware for DedupFilter {
+    fn name(&self) -> &'static str {
+        "dedup"
+    }
+
+    fn on_receive(&mut self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = match serde_json::from_slice::<Identity>(&payload) {
+            // An echoed request would shadow its reply, which has the same id
+            Ok(Identity { reply_to: Some(_), .. }) => return Ok(payload),
+            Ok(Identity {
+                request_id: Some(id), ..
+            }) => format!("{} request {}", topic, id),
+            Ok(Identity {
+                device_id,
+                seq: Some(seq),
+                ..
+            }) => format!("{} {} seq {}", topic, device_id.unwrap_or_default(), seq),
+            _ => return Ok(payload),
+        };
+        if self.seen.contains(&key) {
+            self.metrics.record_duplicate();
+            return Err(format!("duplicate of {}", key).into());
+        }
+        if self.seen.len() >= self.window {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key);
+        Ok(payload)
+    }
+}
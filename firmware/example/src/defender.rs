@@ -0,0 +1,281 @@
+//! AWS IoT Device Defender detect metrics. Every period the device lists its
+//! open sockets (listening TCP/UDP ports, established TCP connections) and
+//! its MQTT traffic counters, and publishes them as a device-side metrics
+//! report in the Defender JSON format. Security profiles in the cloud then flag a
+//! device whose behaviour changes, e.g. a port nobody opened on purpose.
+
+use crate::client::SharedClient;
+use crate::clock;
+use crate::middleware::MessageStats;
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ffi::{c_int, c_void};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Defender rejects reports sent more often than this
+pub const MIN_INTERVAL_SECS: u64 = 300;
+
+/// Topics of a thing's metrics reports.
+pub struct DefenderTopics {
+    pub publish: String,
+    pub accepted: String,
+    pub rejected: String,
+}
+
+impl DefenderTopics {
+    pub fn new(thing_name: &str) -> Self {
+        let publish = format!("$aws/things/{}/defender/metrics/json", thing_name);
+        Self {
+            accepted: format!("{}/accepted", publish),
+            rejected: format!("{}/rejected", publish),
+            publish,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Header {
+    report_id: u64,
+    version: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct Port {
+    port: u16,
+}
+
+#[derive(Serialize, Debug)]
+struct Ports {
+    ports: Vec<Port>,
+    total: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Connection {
+    local_port: u16,
+    remote_addr: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Connections {
+    connections: Vec<Connection>,
+    total: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct TcpConnections {
+    established_connections: Connections,
+}
+
+/// MQTT payload bytes since boot: the firmware's own traffic, without the
+/// protocol and TLS overhead
+#[derive(Serialize, Debug)]
+struct NetworkStats {
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct Metrics {
+    listening_tcp_ports: Ports,
+    listening_udp_ports: Ports,
+    network_stats: NetworkStats,
+    tcp_connections: TcpConnections,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    header: Header,
+    metrics: Metrics,
+}
+
+/// Payload of `accepted` and `rejected`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    report_id: u64,
+    #[serde(default)]
+    status_details: Option<StatusDetails>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct StatusDetails {
+    #[serde(rename = "ErrorCode", default)]
+    error_code: String,
+    #[serde(rename = "ErrorMessage", default)]
+    error_message: String,
+}
+
+/// Publishes metrics reports and follows what Defender made of them.
+pub struct Defender {
+    pub topics: DefenderTopics,
+    last_report_id: u64,
+    client: SharedClient,
+}
+
+impl Defender {
+    pub fn new(thing_name: &str, client: SharedClient) -> Self {
+        Self {
+            topics: DefenderTopics::new(thing_name),
+            last_report_id: 0,
+            client,
+        }
+    }
+
+    /// Subscribe to the report responses. Call on every connect.
+    pub fn bootstrap(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut client = self.client.lock();
+        client.subscribe_topic(&self.topics.accepted)?;
+        client.subscribe_topic(&self.topics.rejected)?;
+        Ok(())
+    }
+
+    /// Collect the metrics and publish a report. `traffic` supplies the
+    /// byte counters. Returns the report id, or `None` before SNTP has
+    /// synced: report ids are timestamps, so they keep increasing across
+    /// reboots as Defender requires.
+    pub fn publish(&mut self, traffic: &MessageStats) -> Result<Option<u64>, Box<dyn Error>> {
+        let Some(now_ms) = clock::now_ms() else {
+            log::info!("Clock not set, skipping Device Defender report");
+            return Ok(None);
+        };
+        let report_id = (now_ms / 1000).max(self.last_report_id + 1);
+
+        let sockets = open_sockets();
+        let listening = |kind: c_int| {
+            let ports: Vec<Port> = sockets
+                .iter()
+                .filter(|socket| socket.kind == kind && socket.remote.is_none() && socket.listening)
+                .map(|socket| Port {
+                    port: socket.local.port(),
+                })
+                .collect();
+            Ports { total: ports.len(), ports }
+        };
+        let connections: Vec<Connection> = sockets
+            .iter()
+            .filter(|socket| socket.kind == sys::SOCK_STREAM as c_int)
+            .filter_map(|socket| {
+                socket.remote.map(|remote| Connection {
+                    local_port: socket.local.port(),
+                    remote_addr: remote.to_string(),
+                })
+            })
+            .collect();
+
+        let report = Report {
+            header: Header {
+                report_id,
+                version: "1.0",
+            },
+            metrics: Metrics {
+                listening_tcp_ports: listening(sys::SOCK_STREAM as c_int),
+                listening_udp_ports: listening(sys::SOCK_DGRAM as c_int),
+                network_stats: NetworkStats {
+                    bytes_in: traffic.received_bytes,
+                    bytes_out: traffic.published_bytes,
+                },
+                tcp_connections: TcpConnections {
+                    established_connections: Connections {
+                        total: connections.len(),
+                        connections,
+                    },
+                },
+            },
+        };
+        self.client.publish_to(&self.topics.publish, &serde_json::to_string(&report)?)?;
+        self.last_report_id = report_id;
+        Ok(Some(report_id))
+    }
+
+    /// Handle a message on a Defender topic. Returns false if the topic
+    /// isn't ours.
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let accepted = if topic == self.topics.accepted {
+            true
+        } else if topic == self.topics.rejected {
+            false
+        } else {
+            return false;
+        };
+        match serde_json::from_slice::<Response>(payload) {
+            Ok(response) if accepted => log::info!("Device Defender accepted report {}", response.report_id),
+            Ok(response) => {
+                let details = response.status_details.unwrap_or_default();
+                log::warn!(
+                    "Device Defender rejected report {}: {} {}",
+                    response.report_id,
+                    details.error_code,
+                    details.error_message
+                );
+            }
+            Err(e) => log::warn!("Invalid Device Defender response: {}", e),
+        }
+        true
+    }
+}
+
+/// An open lwIP socket.
+struct Socket {
+    kind: c_int,
+    local: SocketAddrV4,
+    /// Peer of a connected socket
+    remote: Option<SocketAddrV4>,
+    /// Accepting connections (TCP), or bound to a port (UDP)
+    listening: bool,
+}
+
+/// Every open IPv4 socket, found by asking each lwIP descriptor for its
+/// type and addresses. Descriptors that aren't open fail the first call.
+fn open_sockets() -> Vec<Socket> {
+    let first = sys::LWIP_SOCKET_OFFSET as c_int;
+    let last = first + sys::CONFIG_LWIP_MAX_SOCKETS as c_int;
+    (first..last)
+        .filter_map(|fd| {
+            let kind = socket_option(fd, sys::SO_TYPE as c_int)?;
+            let local = socket_address(fd, sys::lwip_getsockname)?;
+            let listening = if kind == sys::SOCK_STREAM as c_int {
+                socket_option(fd, sys::SO_ACCEPTCONN as c_int).is_some_and(|accepting| accepting != 0)
+            } else {
+                local.port() != 0
+            };
+            Some(Socket {
+                kind,
+                local,
+                remote: socket_address(fd, sys::lwip_getpeername),
+                listening,
+            })
+        })
+        .collect()
+}
+
+fn socket_option(fd: c_int, option: c_int) -> Option<c_int> {
+    let mut value: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as sys::socklen_t;
+    let ret = unsafe {
+        sys::lwip_getsockopt(
+            fd,
+            sys::SOL_SOCKET as c_int,
+            option,
+            &mut value as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(value)
+}
+
+type AddressFn = unsafe extern "C" fn(c_int, *mut sys::sockaddr, *mut sys::socklen_t) -> c_int;
+
+fn socket_address(fd: c_int, get: AddressFn) -> Option<SocketAddrV4> {
+    let mut address: sys::sockaddr_in = unsafe { core::mem::zeroed() };
+    let mut len = std::mem::size_of::<sys::sockaddr_in>() as sys::socklen_t;
+    let ret = unsafe { get(fd, &mut address as *mut sys::sockaddr_in as *mut sys::sockaddr, &mut len) };
+    if ret != 0 || address.sin_family as u32 != sys::AF_INET {
+        return None;
+    }
+    Some(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+        u16::from_be(address.sin_port),
+    ))
+}
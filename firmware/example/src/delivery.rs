@@ -0,0 +1,197 @@
+//! Delivery tracking for QoS 1 publishes. `enqueue` only hands a message to
+//! the esp-mqtt outbox; the outcome arrives later as a PUBACK, as the outbox
+//! dropping the message, or not at all.
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Publishes awaiting their PUBACK; more aren't tracked.
+const MAX_PENDING: usize = 64;
+
+/// Outcomes a confirmation buffers while it looks for its own
+const CONFIRMATION_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The broker acknowledged it
+    Acked,
+    /// esp-mqtt gave up on it, e.g. its outbox entry expired
+    Dropped,
+    /// No acknowledgement within the timeout
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub id: u32,
+    pub topic: String,
+    pub outcome: Outcome,
+    /// Time from enqueue to the outcome
+    pub elapsed: Duration,
+}
+
+struct Pending {
+    topic: String,
+    enqueued: Instant,
+}
+
+/// Shared by the client, which registers publishes, and its listener, which
+/// completes them. Tracks nothing until `start`.
+#[derive(Clone, Default)]
+pub struct DeliveryTracker {
+    pending: Arc<Mutex<BTreeMap<u32, Pending>>>,
+    sender: Arc<Mutex<Option<Sender<Delivery>>>>,
+}
+
+impl DeliveryTracker {
+    /// Report outcomes on the returned receiver. Reports that don't fit in
+    /// `capacity` are logged and discarded.
+    pub fn start(&self, capacity: usize) -> Receiver<Delivery> {
+        let (tx, rx) = bounded(capacity);
+        *self.sender.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.sender.lock().unwrap().is_some()
+    }
+
+    pub fn track(&self, id: u32, topic: &str) {
+        if !self.is_started() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            log::warn!("Too many unacknowledged publishes, not tracking {} on \"{}\"", id, topic);
+            return;
+        }
+        pending.insert(
+            id,
+            Pending {
+                topic: topic.to_string(),
+                enqueued: Instant::now(),
+            },
+        );
+    }
+
+    /// Called by the listener on `Published` and `Deleted`.
+    pub fn complete(&self, id: u32, outcome: Outcome) {
+        let Some(pending) = self.pending.lock().unwrap().remove(&id) else {
+            return;
+        };
+        self.report(id, pending, outcome);
+    }
+
+    /// Report publishes pending for longer than `timeout` as timed out.
+    pub fn expire(&self, timeout: Duration) {
+        let expired: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<u32> = pending
+                .iter()
+                .filter(|(_, pending)| pending.enqueued.elapsed() >= timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter().filter_map(|id| Some((id, pending.remove(&id)?))).collect()
+        };
+        for (id, pending) in expired {
+            self.report(id, pending, Outcome::TimedOut);
+        }
+    }
+
+    fn report(&self, id: u32, pending: Pending, outcome: Outcome) {
+        let delivery = Delivery {
+            id,
+            topic: pending.topic,
+            outcome,
+            elapsed: pending.enqueued.elapsed(),
+        };
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            if let Err(TrySendError::Full(delivery)) = sender.try_send(delivery) {
+                log::warn!("Delivery report queue full, dropping {:?}", delivery);
+            }
+        }
+    }
+}
+
+/// Publishes whose sender waits for the outcome, see
+/// `Client::publish_blocking`. The PUBACK can arrive before the publish call
+/// returns the message id, so a waiter registers before publishing and sees
+/// every outcome until it finds its own.
+#[derive(Clone, Default)]
+pub struct Confirmations {
+    waiters: Arc<Mutex<(u64, BTreeMap<u64, Sender<(u32, Outcome)>>)>>,
+}
+
+impl Confirmations {
+    /// Start watching outcomes, before publishing.
+    pub fn register(&self) -> Confirmation {
+        let (tx, rx) = bounded(CONFIRMATION_CAPACITY);
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters.0 += 1;
+        let token = waiters.0;
+        waiters.1.insert(token, tx);
+        Confirmation {
+            token,
+            id: 0,
+            receiver: rx,
+            confirmations: self.clone(),
+            registered: Instant::now(),
+        }
+    }
+
+    /// Called by the listener on `Published` and `Deleted`.
+    pub fn complete(&self, id: u32, outcome: Outcome) {
+        for sender in self.waiters.lock().unwrap().1.values() {
+            let _ = sender.try_send((id, outcome));
+        }
+    }
+}
+
+/// The outcome of one publish, once the broker acknowledges it.
+pub struct Confirmation {
+    token: u64,
+    id: u32,
+    receiver: Receiver<(u32, Outcome)>,
+    confirmations: Confirmations,
+    registered: Instant,
+}
+
+impl Confirmation {
+    /// The message id of the publish, once sent.
+    pub fn for_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Block until the broker acknowledges the publish, at most `timeout`
+    /// after registering. Returns the message id.
+    pub fn wait(self, timeout: Duration) -> Result<u32, Box<dyn Error>> {
+        let deadline = self.registered + timeout;
+        loop {
+            match self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((id, Outcome::Acked)) if id == self.id => return Ok(id),
+                Ok((id, _)) if id == self.id => {
+                    return Err(format!("Message {} dropped before the broker acknowledged it", id).into())
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!("Message {} not acknowledged within {:?}", self.id, timeout).into())
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(format!("Message {} abandoned", self.id).into()),
+            }
+        }
+    }
+}
+
+impl Drop for Confirmation {
+    fn drop(&mut self) {
+        self.confirmations.waiters.lock().unwrap().1.remove(&self.token);
+    }
+}
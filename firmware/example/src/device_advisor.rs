@@ -0,0 +1,58 @@
+//! Test mode for AWS IoT Device Advisor, to qualify firmware changes before
+//! they reach production. With `device_advisor_endpoint` set, the device
+//! connects to the account's Device Advisor test endpoint instead of
+//! `mqtt_url`, with the same certificate, and behaves the way the suites
+//! expect: it subscribes to the test topic and publishes the same payload to
+//! it at QoS 1 on a fixed period. Connect, keep-alive, reconnect backoff,
+//! TLS and last-will checks exercise the client as it runs in production.
+//!
+//! The endpoint replaces the broker for the whole boot, so the failovers that
+//! would move the device elsewhere (backup endpoints, bridge, Greengrass core)
+//! are off while testing.
+
+use crate::client::{Client, SharedClient};
+use crate::timer::PeriodicTimer;
+use esp_idf_svc::mqtt::client::QoS;
+use std::error::Error;
+use std::time::Duration;
+
+/// Published unchanged every period, so suites can match it exactly.
+pub const PAYLOAD: &str = r#"{"message":"device advisor test"}"#;
+
+pub struct DeviceAdvisor {
+    topic: String,
+    timer: PeriodicTimer,
+}
+
+impl DeviceAdvisor {
+    pub fn new(topic: String, period: Duration, client_id: &str) -> Self {
+        Self {
+            topic,
+            timer: PeriodicTimer::new(period, client_id, "device_advisor"),
+        }
+    }
+
+    /// Subscribe to the test topic, unless it is the command topic the
+    /// client subscribes to anyway. Messages on it are only logged.
+    pub fn subscribe(&self, client: &SharedClient) -> Result<(), Box<dyn Error>> {
+        let mut client = client.lock();
+        if client.sub_topic == self.topic {
+            return Ok(());
+        }
+        let handler = |_: &mut Client, topic: &str, payload: &[u8]| {
+            log::info!("Device Advisor message on \"{}\": {}", topic, String::from_utf8_lossy(payload));
+        };
+        client.subscribe_with_handler(&self.topic, handler)
+    }
+
+    /// Publish the test payload when due. Call once per main loop iteration.
+    pub fn poll(&mut self, client: &SharedClient) {
+        if !self.timer.poll() || !client.is_connected() {
+            return;
+        }
+        match client.publish_with_qos(&self.topic, PAYLOAD, QoS::AtLeastOnce) {
+            Ok(id) => log::info!("Device Advisor publish {} on \"{}\"", id, self.topic),
+            Err(e) => log::warn!("Device Advisor publish failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use esp_idf_svc::sys;
+use serde::Serialize;
+use std::ffi::CStr;
+
+/// One FreeRTOS task, as reported by `tasks.list`.
+#[derive(Serialize, Debug)]
+pub struct TaskInfo {
+    pub name: String,
+    pub priority: u32,
+    pub state: &'static str,
+    /// Least free stack the task has had so far, in bytes
+    pub stack_high_water: u32,
+}
+
+/// Snapshot of every task. Needs `CONFIG_FREERTOS_USE_TRACE_FACILITY`.
+pub fn tasks() -> Vec<TaskInfo> {
+    unsafe {
+        // Headroom for tasks created between counting and the snapshot
+        let capacity = sys::uxTaskGetNumberOfTasks() as usize + 4;
+        let mut status: Vec<sys::TaskStatus_t> = Vec::with_capacity(capacity);
+        let filled = sys::uxTaskGetSystemState(status.as_mut_ptr(), capacity as _, core::ptr::null_mut());
+        status.set_len(filled as usize);
+
+        let mut tasks: Vec<TaskInfo> = status
+            .iter()
+            .map(|task| TaskInfo {
+                name: CStr::from_ptr(task.pcTaskName).to_string_lossy().into_owned(),
+                priority: task.uxCurrentPriority as u32,
+                state: state_name(task.eCurrentState),
+                stack_high_water: task.usStackHighWaterMark as u32,
+            })
+            .collect();
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+        tasks
+    }
+}
+
+fn state_name(state: sys::eTaskState) -> &'static str {
+    match state {
+        sys::eTaskState_eRunning => "running",
+        sys::eTaskState_eReady => "ready",
+        sys::eTaskState_eBlocked => "blocked",
+        sys::eTaskState_eSuspended => "suspended",
+        sys::eTaskState_eDeleted => "deleted",
+        _ => "invalid",
+    }
+}
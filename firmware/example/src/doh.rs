@@ -0,0 +1,216 @@
+//! DNS-over-HTTPS fallback for networks whose resolver hijacks or breaks
+//! lookups of unusual names such as IoT endpoints. Once the broker connection
+//! has failed `doh_after` attempts in a row and the system resolver can't
+//! resolve the broker's host either, the host is resolved through the
+//! configured DoH provider and the answer pinned in lwIP's resolver, so
+//! esp-mqtt's next attempt, and every other lookup of that name, gets it.
+//! A connection that fails although DNS works is left alone. TLS still
+//! checks the certificate against the host name, so a bad answer fails the
+//! handshake rather than reaching the wrong broker.
+//!
+//! lwIP asks [`lwip_hook_netconn_external_resolve`] before its own DNS,
+//! which needs `CONFIG_LWIP_HOOK_NETCONN_EXT_RESOLVE_CUSTOM` (set in
+//! sdkconfig.defaults). A pinned answer expires with its DNS TTL, after
+//! which lookups go to the system resolver again.
+
+use crate::client::SharedClient;
+use crate::tls_observer;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::sys;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, CStr};
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// A DNS JSON answer for one name is a few hundred bytes
+const MAX_RESPONSE_BYTES: usize = 4096;
+/// Resource record type of an IPv4 address
+const TYPE_A: u16 = 1;
+/// `addrtype` of a lookup that only wants IPv6 (lwIP's NETCONN_DNS_IPV6)
+const NETCONN_DNS_IPV6: u8 = 1;
+/// Shortest a pinned answer is kept, so a TTL of 0 doesn't mean a DoH
+/// lookup on every attempt
+const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// Names answered from DoH instead of the system resolver, until the
+/// instant they expire.
+static PINNED: Mutex<BTreeMap<String, (Ipv4Addr, Instant)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<Answer>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Answer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    /// Seconds the answer may be cached
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    data: String,
+}
+
+/// Look up the IPv4 address of `host`, and how long it may be cached, with
+/// the JSON API of the DoH provider at `provider`, e.g.
+/// `https://1.1.1.1/dns-query`.
+pub fn resolve(provider: &str, host: &str) -> Result<(Ipv4Addr, Duration), Box<dyn std::error::Error>> {
+    let url = format!("{}?name={}&type=A", provider, host);
+    let mut connection = EspHttpConnection::new(&HttpConfiguration {
+        crt_bundle_attach: Some(sys::esp_crt_bundle_attach),
+        timeout: Some(TIMEOUT),
+        ..Default::default()
+    })?;
+    connection.initiate_request(Method::Get, &url, &[("accept", "application/dns-json")])?;
+    connection.initiate_response()?;
+    if connection.status() != 200 {
+        return Err(format!("DoH provider answered HTTP {}", connection.status()).into());
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let read = connection.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > MAX_RESPONSE_BYTES {
+            return Err(format!("DoH response over {} bytes", MAX_RESPONSE_BYTES).into());
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    let response: Response = serde_json::from_slice(&body)?;
+    if response.status != 0 {
+        return Err(format!("DoH lookup of {} failed with DNS status {}", host, response.status).into());
+    }
+    // CNAMEs come first, the address records of the name they point to after
+    let (address, ttl) = response
+        .answer
+        .iter()
+        .filter(|answer| answer.record_type == TYPE_A)
+        .find_map(|answer| Some((answer.data.parse().ok()?, answer.ttl)))
+        .ok_or_else(|| format!("DoH has no IPv4 address for {}", host))?;
+    Ok((address, Duration::from_secs(ttl.into())))
+}
+
+/// Answer lookups of `host` with `address` for `ttl`.
+pub fn pin(host: &str, address: Ipv4Addr, ttl: Duration) {
+    PINNED.lock().unwrap().insert(host.to_string(), (address, Instant::now() + ttl));
+}
+
+/// Whether lookups of `host` are answered from DoH, dropping its pin once
+/// expired.
+fn is_pinned(host: &str) -> bool {
+    let mut pinned = PINNED.lock().unwrap();
+    match pinned.get(host) {
+        Some((_, expires)) if *expires > Instant::now() => true,
+        Some(_) => {
+            pinned.remove(host);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Called by lwIP ahead of every DNS lookup; returns 1 when it answered.
+#[no_mangle]
+pub unsafe extern "C" fn lwip_hook_netconn_external_resolve(
+    name: *const c_char,
+    addr: *mut sys::ip_addr_t,
+    addrtype: u8,
+    err: *mut sys::err_t,
+) -> c_int {
+    if name.is_null() || addrtype == NETCONN_DNS_IPV6 {
+        return 0;
+    }
+    let Ok(name) = CStr::from_ptr(name).to_str() else {
+        return 0;
+    };
+    // Never block lwIP on a lock held elsewhere
+    let Ok(pinned) = PINNED.try_lock() else {
+        return 0;
+    };
+    let Some((address, _)) = pinned.get(name).filter(|(_, expires)| *expires > Instant::now()) else {
+        return 0;
+    };
+    (*addr).u_addr.ip4.addr = u32::from_ne_bytes(address.octets());
+    (*addr).type_ = sys::lwip_ip_addr_type_IPADDR_TYPE_V4 as u8;
+    *err = sys::err_enum_t_ERR_OK as sys::err_t;
+    1
+}
+
+/// Resolves the broker through DoH when connecting keeps failing because
+/// the system resolver does.
+pub struct DohFallback {
+    provider: &'static str,
+    after: u32,
+    /// `failures_since_connect` at the last lookup
+    tried_at: u32,
+}
+
+impl DohFallback {
+    pub fn new(provider: &'static str, after: u32) -> Self {
+        Self {
+            provider,
+            after: after.max(1),
+            tried_at: 0,
+        }
+    }
+
+    /// Call from the main loop. Every `after` failed connection attempts in a
+    /// row, looks up the current endpoint through DoH if the system resolver
+    /// can't and no DoH answer is pinned.
+    pub fn poll(&mut self, client: &SharedClient) {
+        let (connected, failures, endpoint) = {
+            let client = client.lock();
+            (client.is_connected(), client.failures_since_connect(), client.endpoint().to_string())
+        };
+        // A connection since the last lookup started the count over
+        if connected || failures < self.tried_at {
+            self.tried_at = 0;
+        }
+        if failures - self.tried_at < self.after {
+            return;
+        }
+        self.tried_at = failures;
+
+        let Some((host, port)) = tls_observer::endpoint_host_port(&endpoint) else {
+            return;
+        };
+        if host.parse::<IpAddr>().is_ok() {
+            return;
+        }
+        // Once pinned, lookups no longer reach the system resolver
+        if is_pinned(host) {
+            return;
+        }
+        match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => {
+                log::info!("System DNS resolves {} to {}, not falling back to DoH", host, addr.ip());
+                return;
+            }
+            Ok(None) | Err(_) => log::warn!("System DNS can't resolve {}", host),
+        }
+        match resolve(self.provider, host) {
+            Ok((address, ttl)) => {
+                let ttl = ttl.max(MIN_TTL);
+                log::warn!(
+                    "{} failed attempts, connecting to {} at {} from DoH for {} s",
+                    failures,
+                    host,
+                    address,
+                    ttl.as_secs()
+                );
+                pin(host, address, ttl);
+            }
+            Err(e) => log::warn!("DoH lookup of {} failed: {}", host, e),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Echo detection. When a subscription overlaps the device's own publish
+//! topics, e.g. a wildcard over `<mqtt_topic_pub>`, the broker delivers the
+//! device's publishes back to it. Every envelope carries the device id and
+//! a per-boot `sequence`, so an incoming message with both, and our id, is
+//! one of ours: it is dropped before dispatch and counted in the metrics.
+
+use crate::middleware::{Metrics, Middleware};
+use serde::Deserialize;
+use std::error::Error;
+
+#[derive(Deserialize)]
+struct Origin {
+    device_id: Option<String>,
+    sequence: Option<u64>,
+}
+
+pub struct EchoFilter {
+    device_id: String,
+    metrics: Metrics,
+}
+
+impl EchoFilter {
+    /// Drop envelopes of `device_id`, counting them in `metrics`.
+    pub fn new(device_id: &str, metrics: Metrics) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            metrics,
+        }
+    }
+}
+
+impl Middleware for EchoFilter {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn on_receive(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        // Only messages that mention our id are worth parsing
+        let id = self.device_id.as_bytes();
+        if !payload.windows(id.len()).any(|window| window == id) {
+            return Ok(payload);
+        }
+        match serde_json::from_slice::<Origin>(&payload) {
+            Ok(Origin {
+                device_id: Some(device_id),
+                sequence: Some(sequence),
+            }) if device_id == self.device_id => {
+                self.metrics.record_echo();
+                Err(format!("own message {} echoed back", sequence).into())
+            }
+            _ => Ok(payload),
+        }
+    }
+}
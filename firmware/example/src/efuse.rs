@@ -0,0 +1,70 @@
+//! Hardware facts burned into the user data eFuse block (BLOCK3,
+//! `BLOCK_USR_DATA`) during manufacturing by `cargo xtask efuse`. Unlike NVS
+//! they survive erasing the flash, and can't be changed once burned.
+//!
+//! Layout, 32 bytes, unburned bits read as zero:
+//!
+//! | Bytes  | Field                                           |
+//! |--------|-------------------------------------------------|
+//! | 0      | Layout version, 1; 0 means nothing was burned   |
+//! | 1..8   | Hardware revision, ASCII, NUL-padded            |
+//! | 8..24  | Serial number, ASCII, NUL-padded                |
+//! | 24..28 | Manufacture date as `YYYYMMDD`, little endian   |
+//! | 28..32 | Reserved                                        |
+
+use esp_idf_svc::sys;
+use serde::Serialize;
+
+const LAYOUT_VERSION: u8 = 1;
+const BLOCK_BYTES: usize = 32;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HardwareInfo {
+    /// Board revision, as matched against an OTA manifest's `hardware_revision`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub revision: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub serial: String,
+    /// `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufactured: Option<String>,
+}
+
+/// The burned fields, or `None` on a device that has none.
+pub fn read() -> Result<Option<HardwareInfo>, Box<dyn std::error::Error>> {
+    let mut block = [0u8; BLOCK_BYTES];
+    sys::esp!(unsafe {
+        sys::esp_efuse_read_block(
+            sys::esp_efuse_block_t_EFUSE_BLK3,
+            block.as_mut_ptr() as *mut core::ffi::c_void,
+            0,
+            BLOCK_BYTES * 8,
+        )
+    })?;
+    parse(&block)
+}
+
+fn parse(block: &[u8; BLOCK_BYTES]) -> Result<Option<HardwareInfo>, Box<dyn std::error::Error>> {
+    match block[0] {
+        0 => return Ok(None),
+        LAYOUT_VERSION => {}
+        other => return Err(format!("Unknown eFuse layout version {}", other).into()),
+    }
+
+    let date = u32::from_le_bytes([block[24], block[25], block[26], block[27]]);
+    let manufactured = (date != 0).then(|| format!("{:04}-{:02}-{:02}", date / 10000, date / 100 % 100, date % 100));
+    Ok(Some(HardwareInfo {
+        revision: ascii(&block[1..8])?,
+        serial: ascii(&block[8..24])?,
+        manufactured,
+    }))
+}
+
+fn ascii(field: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    let text = std::str::from_utf8(&field[..end])?;
+    if !text.is_ascii() {
+        return Err("eFuse text field is not ASCII".into());
+    }
+    Ok(text.to_string())
+}
@@ -0,0 +1,253 @@
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C1};
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART2};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+const CALIBRATION_KEY: &str = "energy_cal";
+
+/// One measurement, after calibration.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Reading {
+    pub voltage_v: f32,
+    pub current_a: f32,
+    pub power_w: f32,
+    /// Cumulative energy
+    pub energy_wh: f64,
+}
+
+/// Gains applied to raw readings, managed through the shadow's
+/// `energy_calibration` key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Calibration {
+    pub voltage_gain: f32,
+    pub current_gain: f32,
+    pub power_gain: f32,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            voltage_gain: 1.0,
+            current_gain: 1.0,
+            power_gain: 1.0,
+        }
+    }
+}
+
+/// A metering front-end.
+pub trait EnergyMeter: Send {
+    fn name(&self) -> &'static str;
+
+    /// Read the front-end before calibration.
+    fn read(&mut self) -> Result<Reading, Box<dyn std::error::Error>>;
+}
+
+/// Peacefair PZEM-004T v3 over Modbus RTU.
+pub struct Pzem004t {
+    uart: UartDriver<'static>,
+}
+
+impl Pzem004t {
+    /// Broadcast slave address, answered by whichever PZEM is on the bus.
+    const ADDRESS: u8 = 0xF8;
+    const READ_INPUT_REGISTERS: u8 = 0x04;
+    const REGISTER_COUNT: u16 = 10;
+
+    pub fn new(uart: UART2, tx_pin: i32, rx_pin: i32) -> Result<Self, Box<dyn std::error::Error>> {
+        let uart = UartDriver::new(
+            uart,
+            unsafe { AnyIOPin::new(tx_pin) },
+            unsafe { AnyIOPin::new(rx_pin) },
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::default().baudrate(Hertz(9600)),
+        )?;
+        Ok(Self { uart })
+    }
+}
+
+impl EnergyMeter for Pzem004t {
+    fn name(&self) -> &'static str {
+        "pzem004t"
+    }
+
+    fn read(&mut self) -> Result<Reading, Box<dyn std::error::Error>> {
+        let mut request = vec![Self::ADDRESS, Self::READ_INPUT_REGISTERS, 0x00, 0x00];
+        request.extend_from_slice(&Self::REGISTER_COUNT.to_be_bytes());
+        request.extend_from_slice(&modbus_crc(&request).to_le_bytes());
+        self.uart.clear_rx()?;
+        self.uart.write(&request)?;
+
+        // Address, function, byte count, 10 registers, CRC
+        let mut response = [0u8; 25];
+        let mut received = 0;
+        let deadline = Instant::now() + Duration::from_millis(500);
+        while received < response.len() && Instant::now() < deadline {
+            received += self.uart.read(&mut response[received..], 10)?;
+        }
+        if received < response.len() {
+            return Err(format!("PZEM answered {} of {} bytes", received, response.len()).into());
+        }
+        let (frame, crc) = response.split_at(23);
+        if modbus_crc(frame).to_le_bytes() != crc {
+            return Err("PZEM response CRC mismatch".into());
+        }
+
+        let register = |i: usize| u16::from_be_bytes([frame[3 + 2 * i], frame[4 + 2 * i]]) as u32;
+        // 32-bit values are sent low word first
+        let long = |i: usize| register(i) | register(i + 1) << 16;
+        Ok(Reading {
+            voltage_v: register(0) as f32 * 0.1,
+            current_a: long(1) as f32 * 0.001,
+            power_w: long(3) as f32 * 0.1,
+            energy_wh: long(5) as f64,
+        })
+    }
+}
+
+/// Analog Devices ADE7953 over I2C, channel A. Energy is integrated from
+/// active power between reads.
+pub struct Ade7953 {
+    i2c: I2cDriver<'static>,
+    energy_wh: f64,
+    last_read: Option<Instant>,
+}
+
+impl Ade7953 {
+    const ADDRESS: u8 = 0x38;
+    const UNLOCK: u16 = 0x00FE;
+    const RESERVED: u16 = 0x0120;
+    const AWATT: u16 = 0x0312;
+    const IRMSA: u16 = 0x031A;
+    const VRMS: u16 = 0x031C;
+    /// Scale of a typical Shelly-style shunt and divider; fine-tune with
+    /// the calibration gains
+    const LSB_PER_VOLT: f32 = 26_000.0;
+    const LSB_PER_AMP: f32 = 100_000.0;
+    const LSB_PER_WATT: f32 = 154.0;
+
+    pub fn new(i2c: I2C1, sda_pin: i32, scl_pin: i32) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut i2c = I2cDriver::new(
+            i2c,
+            unsafe { AnyIOPin::new(sda_pin) },
+            unsafe { AnyIOPin::new(scl_pin) },
+            &I2cConfig::new().baudrate(Hertz(100_000)),
+        )?;
+        // Required start-up sequence from the datasheet
+        i2c.write(Self::ADDRESS, &[0x00, Self::UNLOCK as u8, 0xAD], BLOCK)?;
+        let [hi, lo] = Self::RESERVED.to_be_bytes();
+        i2c.write(Self::ADDRESS, &[hi, lo, 0x00, 0x30], BLOCK)?;
+        Ok(Self {
+            i2c,
+            energy_wh: 0.0,
+            last_read: None,
+        })
+    }
+
+    fn read_register(&mut self, register: u16) -> Result<i32, Box<dyn std::error::Error>> {
+        let mut value = [0u8; 4];
+        self.i2c
+            .write_read(Self::ADDRESS, &register.to_be_bytes(), &mut value, BLOCK)?;
+        Ok(i32::from_be_bytes(value))
+    }
+}
+
+impl EnergyMeter for Ade7953 {
+    fn name(&self) -> &'static str {
+        "ade7953"
+    }
+
+    fn read(&mut self) -> Result<Reading, Box<dyn std::error::Error>> {
+        let voltage_v = self.read_register(Self::VRMS)? as f32 / Self::LSB_PER_VOLT;
+        let current_a = self.read_register(Self::IRMSA)? as f32 / Self::LSB_PER_AMP;
+        let power_w = (self.read_register(Self::AWATT)? as f32 / Self::LSB_PER_WATT).abs();
+
+        let now = Instant::now();
+        if let Some(last) = self.last_read.replace(now) {
+            self.energy_wh += power_w as f64 * (now - last).as_secs_f64() / 3600.0;
+        }
+        Ok(Reading {
+            voltage_v,
+            current_a,
+            power_w,
+            energy_wh: self.energy_wh,
+        })
+    }
+}
+
+/// A meter with its calibration and latest reading.
+pub struct EnergyMonitor {
+    meter: Box<dyn EnergyMeter>,
+    calibration: Calibration,
+    latest: Option<Reading>,
+}
+
+impl EnergyMonitor {
+    /// Use `meter`, with the calibration last stored in NVS.
+    pub fn new(meter: Box<dyn EnergyMeter>, partition: EspDefaultNvsPartition) -> Result<Self, Box<dyn std::error::Error>> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = [0u8; 128];
+        let calibration = match nvs.get_str(CALIBRATION_KEY, &mut buf)? {
+            Some(json) => serde_json::from_str(json)?,
+            None => Calibration::default(),
+        };
+        log::info!("Energy meter {} with calibration {:?}", meter.name(), calibration);
+        Ok(Self {
+            meter,
+            calibration,
+            latest: None,
+        })
+    }
+
+    pub fn read(&mut self) -> Result<Reading, Box<dyn std::error::Error>> {
+        let raw = self.meter.read()?;
+        let reading = Reading {
+            voltage_v: raw.voltage_v * self.calibration.voltage_gain,
+            current_a: raw.current_a * self.calibration.current_gain,
+            power_w: raw.power_w * self.calibration.power_gain,
+            energy_wh: raw.energy_wh * self.calibration.power_gain as f64,
+        };
+        self.latest = Some(reading);
+        Ok(reading)
+    }
+
+    pub fn latest(&self) -> Option<Reading> {
+        self.latest
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    /// Apply and persist new calibration gains.
+    pub fn set_calibration(
+        &mut self,
+        calibration: Calibration,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        nvs.set_str(CALIBRATION_KEY, &serde_json::to_string(&calibration)?)?;
+        log::info!("Energy calibration updated: {:?}", calibration);
+        self.calibration = calibration;
+        Ok(())
+    }
+}
+
+/// Modbus RTU CRC-16.
+fn modbus_crc(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
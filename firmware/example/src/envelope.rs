@@ -0,0 +1,63 @@
+use crate::clock;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Set while connected through a local bridge instead of AWS IoT directly.
+static BRIDGED: AtomicBool = AtomicBool::new(false);
+/// Last sequence number handed out
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps every outgoing message with the canonical device identity and,
+/// once SNTP has synced, the time it was sent.
+#[derive(Serialize, Debug)]
+struct Envelope<'a, T: Serialize> {
+    device_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    /// Counts up from 1 every boot, and on across deep sleep in the battery
+    /// profiles; with `device_id` it identifies the message, so the device
+    /// recognizes its own when they come back
+    sequence: u64,
+    /// Lets the cloud dedupe messages a bridge forwards late, by
+    /// `device_id` and `timestamp`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    bridged: bool,
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+/// One piece of a payload too large for a single message; the cloud
+/// reassembles `total` chunks of `key` in `seq` order.
+#[derive(Serialize, Debug)]
+pub struct Chunk<'a> {
+    pub event: &'static str,
+    pub key: &'a str,
+    pub seq: usize,
+    pub total: usize,
+    pub data: String,
+}
+
+pub fn to_json<T: Serialize>(device_id: &str, body: &T) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Envelope {
+        device_id,
+        timestamp: clock::now_ms(),
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1,
+        bridged: BRIDGED.load(Ordering::Relaxed),
+        body,
+    })
+}
+
+/// The sequence number of the last message built.
+pub fn last_sequence() -> u64 {
+    SEQUENCE.load(Ordering::Relaxed)
+}
+
+/// Continue numbering after `last`, e.g. from before deep sleep.
+pub fn resume_sequence(last: u64) {
+    SEQUENCE.store(last, Ordering::Relaxed);
+}
+
+/// Mark every message from now on as sent through a local bridge.
+pub fn set_bridged(bridged: bool) {
+    BRIDGED.store(bridged, Ordering::Relaxed);
+}
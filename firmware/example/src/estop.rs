@@ -0,0 +1,156 @@
+//! Hardware emergency stop for the actuator profiles. A high-priority task
+//! samples the e-stop input and, once it trips, holds every actuator output
+//! low on its own, whatever MQTT or the command handlers are doing. The trip
+//! is latched, in NVS too so a reboot doesn't clear it, until an explicit
+//! `estop_reset` command arrives while the input is released.
+//!
+//! Wire the normally closed contact between the pin and ground: pressing the
+//! button, or a broken wire, lets the pull-up take the pin high and trips.
+
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const LATCH_KEY: &str = "estop";
+/// How often the task samples the input and drives the outputs low again
+const SCAN_PERIOD: Duration = Duration::from_millis(10);
+/// Above the MQTT task, lwIP and the application; below WiFi and esp_timer
+const TASK_PRIORITY: u8 = 20;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EStopEvent {
+    EstopTripped,
+    EstopReset,
+}
+
+/// Read-only view of the trip, for actuators that must refuse to start.
+#[derive(Clone)]
+pub struct Latch(Arc<AtomicBool>);
+
+impl Latch {
+    pub fn is_tripped(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+pub struct EStop {
+    pin: i32,
+    latch: Latch,
+    nvs: EspNvs<NvsDefault>,
+    /// The current trip is stored in NVS
+    persisted: bool,
+    /// The current trip hasn't been announced to the broker yet
+    alarm_pending: bool,
+}
+
+impl EStop {
+    /// Watch `pin` and hold the `outputs` pins low while tripped.
+    pub fn start(
+        pin: i32,
+        outputs: Vec<i32>,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = sys::gpio_config_t {
+            pin_bit_mask: 1u64 << pin,
+            mode: sys::gpio_mode_t_GPIO_MODE_INPUT,
+            pull_up_en: sys::gpio_pullup_t_GPIO_PULLUP_ENABLE,
+            pull_down_en: sys::gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+            intr_type: sys::gpio_int_type_t_GPIO_INTR_DISABLE,
+            ..Default::default()
+        };
+        unsafe { sys::esp!(sys::gpio_config(&config))? };
+
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let latched = nvs.get_u8(LATCH_KEY)?.unwrap_or(0) != 0;
+        if latched {
+            log::warn!("E-stop still latched from before the reboot, send estop_reset to clear it");
+        }
+        let latch = Latch(Arc::new(AtomicBool::new(latched)));
+
+        let watched = latch.clone();
+        ThreadSpawnConfiguration {
+            name: Some(b"estop\0"),
+            priority: TASK_PRIORITY,
+            ..Default::default()
+        }
+        .set()?;
+        let spawned = thread::Builder::new()
+            .stack_size(3072)
+            .spawn(move || watch(pin, outputs, watched));
+        // Threads spawned after this one get the defaults again
+        ThreadSpawnConfiguration::default().set()?;
+        spawned.map_err(|e| format!("Failed to spawn e-stop thread: {}", e))?;
+
+        log::info!("E-stop armed on GPIO {}", pin);
+        Ok(Self {
+            pin,
+            latch,
+            nvs,
+            persisted: latched,
+            alarm_pending: latched,
+        })
+    }
+
+    pub fn latch(&self) -> Latch {
+        self.latch.clone()
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.latch.is_tripped()
+    }
+
+    /// Persist a new trip. Call once per main loop iteration; returns
+    /// whether the tripped alarm still has to be published.
+    pub fn poll(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.is_tripped() && !self.persisted {
+            self.persisted = true;
+            self.alarm_pending = true;
+            self.nvs.set_u8(LATCH_KEY, 1)?;
+        }
+        Ok(self.alarm_pending)
+    }
+
+    /// The tripped alarm reached the client.
+    pub fn alarm_sent(&mut self) {
+        self.alarm_pending = false;
+    }
+
+    /// Clear the trip. Fails while the input is still active.
+    pub fn reset(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if input_active(self.pin) {
+            return Err("E-stop input is still active".into());
+        }
+        self.nvs.set_u8(LATCH_KEY, 0)?;
+        self.latch.0.store(false, Ordering::Release);
+        self.persisted = false;
+        self.alarm_pending = false;
+        log::info!("E-stop reset");
+        Ok(())
+    }
+}
+
+fn watch(pin: i32, outputs: Vec<i32>, latch: Latch) {
+    loop {
+        if input_active(pin) && !latch.0.swap(true, Ordering::AcqRel) {
+            log::error!("E-stop tripped, forcing {} output(s) off", outputs.len());
+        }
+        // Every scan, so nothing can switch an output back on while tripped
+        if latch.is_tripped() {
+            for &output in &outputs {
+                unsafe { sys::gpio_set_level(output, 0) };
+            }
+        }
+        thread::sleep(SCAN_PERIOD);
+    }
+}
+
+fn input_active(pin: i32) -> bool {
+    unsafe { sys::gpio_get_level(pin) != 0 }
+}
@@ -0,0 +1,56 @@
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// Events subsystems announce to each other through the [`EventBus`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    NetworkUp,
+    NetworkDown,
+    MqttConnected,
+    MqttDisconnected,
+    /// Desired-state delta received from the device shadow (JSON `state` object)
+    ShadowDelta(String),
+    /// Delta from a named shadow without its own callback
+    NamedShadowDelta { name: String, delta: String },
+    /// Initial configuration from the retained bootstrap topic (JSON object)
+    BootstrapConfig(String),
+    OtaProgress { percent: u8 },
+    ButtonPressed,
+}
+
+/// In-process publish/subscribe bus.
+///
+/// Every subscriber gets its own bounded queue so a slow listener (an LED
+/// indicator, a display) can't block the publisher: when a queue is full the
+/// event is dropped for that subscriber only. Cloning the bus is cheap and
+/// all clones share the same subscribers.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a listener buffering up to `capacity` events.
+    pub fn subscribe(&self, capacity: usize) -> Receiver<Event> {
+        let (tx, rx) = bounded(capacity);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Event subscriber queue full, dropping {:?}", event);
+                true
+            }
+            // The receiver is gone, forget about it
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
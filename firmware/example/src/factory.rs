@@ -0,0 +1,91 @@
+//! Per-device settings written on the production line by `tools/provision`,
+//! so one generic firmware image serves every device. They live in their own
+//! NVS namespace, next to the device certificate in [`NAMESPACE`], and
+//! override the cfg.toml values at boot.
+//!
+//! [`NAMESPACE`]: crate::migrations::NAMESPACE
+
+use crate::startup::Config;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::ESP_ERR_NVS_NOT_FOUND;
+
+pub const FACTORY_NAMESPACE: &str = "factory";
+
+/// Config fields that can be provisioned, by NVS key. NVS keys are at most
+/// 15 characters.
+const WIFI_SSID_KEY: &str = "wifi_ssid";
+const WIFI_PASS_KEY: &str = "wifi_pass";
+const MQTT_URL_KEY: &str = "mqtt_url";
+const CLIENT_ID_KEY: &str = "client_id";
+const THING_NAME_KEY: &str = "thing_name";
+const AUTH_MODE_KEY: &str = "auth_mode";
+const HARDWARE_REV_KEY: &str = "hw_rev";
+
+/// `config` with the provisioned values in place of the compiled-in ones.
+/// Devices flashed without a factory image keep cfg.toml as it is.
+pub fn apply(nvs: EspDefaultNvsPartition, mut config: Config) -> Result<Config, Box<dyn std::error::Error>> {
+    let storage = match EspNvs::new(nvs, FACTORY_NAMESPACE, false) {
+        Ok(storage) => storage,
+        Err(e) if e.code() == ESP_ERR_NVS_NOT_FOUND => return Ok(config),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut provisioned = Vec::new();
+    // Read by `wifi_pass`
+    if storage.contains(WIFI_PASS_KEY)? {
+        provisioned.push(WIFI_PASS_KEY);
+    }
+    let fields: [(&str, &mut &'static str); 6] = [
+        (WIFI_SSID_KEY, &mut config.wifi_ssid),
+        (MQTT_URL_KEY, &mut config.mqtt_url),
+        (CLIENT_ID_KEY, &mut config.mqtt_client_id),
+        (THING_NAME_KEY, &mut config.thing_name),
+        (AUTH_MODE_KEY, &mut config.auth_mode),
+        (HARDWARE_REV_KEY, &mut config.hardware_revision),
+    ];
+    for (key, field) in fields {
+        if let Some(value) = get_string(&storage, key)? {
+            // Read once at boot and kept for the life of the firmware
+            *field = Box::leak(value.into_boxed_str());
+            provisioned.push(key);
+        }
+    }
+
+    if !provisioned.is_empty() {
+        log::info!("Factory provisioning overrides: {}", provisioned.join(", "));
+    }
+    Ok(config)
+}
+
+/// The provisioned WiFi password. It has no [`Config`] field to override:
+/// cfg.toml's is sealed apart, see [`crate::secrets::settings`].
+pub fn wifi_pass(nvs: EspDefaultNvsPartition) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let storage = match EspNvs::new(nvs, FACTORY_NAMESPACE, false) {
+        Ok(storage) => storage,
+        Err(e) if e.code() == ESP_ERR_NVS_NOT_FOUND => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    get_string(&storage, WIFI_PASS_KEY)
+}
+
+/// Point the device at another broker and thing from the next boot on,
+/// authenticating with the certificate in NVS. Used when a device moves to
+/// another account.
+pub fn store_endpoint(
+    nvs: EspDefaultNvsPartition,
+    mqtt_url: &str,
+    client_id: &str,
+    thing_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, FACTORY_NAMESPACE, true)?;
+    storage.set_str(MQTT_URL_KEY, mqtt_url)?;
+    storage.set_str(CLIENT_ID_KEY, client_id)?;
+    storage.set_str(THING_NAME_KEY, thing_name)?;
+    storage.set_str(AUTH_MODE_KEY, "x509_nvs")?;
+    Ok(())
+}
+
+fn get_string(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut buf = vec![0u8; 256];
+    Ok(nvs.get_str(key, &mut buf)?.map(str::to_string))
+}
@@ -0,0 +1,110 @@
+//! Feature flags for staged rollouts of new behaviour without an OTA. Each
+//! flag has a default from cfg.toml (`feature_flags`), which the cloud can
+//! override per device through `desired.flags` in the shadow. Overrides are
+//! kept in NVS, so a device keeps its flags while offline and across
+//! reboots. Code checks a flag with [`FeatureFlags::enabled`]:
+//!
+//! ```ignore
+//! if app.flags.enabled("new_telemetry_v2") {
+//!     // new behaviour
+//! }
+//! ```
+
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+const FLAGS_KEY: &str = "flags";
+
+pub struct FeatureFlags {
+    defaults: BTreeMap<String, bool>,
+    /// Values set through the shadow that differ from the default
+    overrides: BTreeMap<String, bool>,
+    /// Counts the changes, so users can tell when to look again
+    generation: u32,
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl FeatureFlags {
+    /// Flags with the `defaults` of cfg.toml, `name=true` or `name=false`
+    /// separated by commas, and the overrides stored before the last reboot.
+    pub fn load(partition: EspDefaultNvsPartition, defaults: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let defaults = parse_defaults(defaults)?;
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = [0u8; 1024];
+        let overrides: BTreeMap<String, bool> = match nvs.get_str(FLAGS_KEY, &mut buf)? {
+            Some(json) => serde_json::from_str(json)?,
+            None => BTreeMap::new(),
+        };
+        if !overrides.is_empty() {
+            log::info!("Feature flag overrides: {:?}", overrides);
+        }
+        Ok(Self {
+            defaults,
+            overrides,
+            generation: 0,
+            nvs,
+        })
+    }
+
+    /// Whether flag `name` is on. Unknown flags are off.
+    pub fn enabled(&self, name: &str) -> bool {
+        self.get(name).unwrap_or(false)
+    }
+
+    /// Value of flag `name`, or `None` for a flag nobody set.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.overrides.get(name).or_else(|| self.defaults.get(name)).copied()
+    }
+
+    /// Changes with every [`FeatureFlags::apply`].
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Every known flag with its current value, as reported to the shadow.
+    pub fn values(&self) -> BTreeMap<&str, bool> {
+        self.defaults
+            .keys()
+            .chain(self.overrides.keys())
+            .map(|name| (name.as_str(), self.enabled(name)))
+            .collect()
+    }
+
+    /// Apply `desired.flags` from a shadow delta and store the overrides.
+    /// Nothing changes if any value isn't a boolean.
+    pub fn apply(&mut self, desired: &Map<String, Value>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut overrides = self.overrides.clone();
+        for (name, value) in desired {
+            let enabled = value
+                .as_bool()
+                .ok_or_else(|| format!("Feature flag \"{}\" must be true or false", name))?;
+            if self.defaults.get(name) == Some(&enabled) {
+                overrides.remove(name);
+            } else {
+                overrides.insert(name.clone(), enabled);
+            }
+        }
+        self.nvs.set_str(FLAGS_KEY, &serde_json::to_string(&overrides)?)?;
+        log::info!("Feature flags updated: {:?}", overrides);
+        self.overrides = overrides;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+}
+
+fn parse_defaults(spec: &str) -> Result<BTreeMap<String, bool>, String> {
+    let mut defaults = BTreeMap::new();
+    for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Feature flag \"{}\" is not name=true or name=false", pair))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Feature flag \"{}\" is not name=true or name=false", pair))?;
+        defaults.insert(name.trim().to_string(), value);
+    }
+    Ok(defaults)
+}
@@ -0,0 +1,238 @@
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Mean Earth radius used for distances between fixes.
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Latest position reported by the receiver.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude_m: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub satellites: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_kmh: Option<f32>,
+}
+
+impl Fix {
+    /// Great-circle distance to `other` in metres.
+    pub fn distance_m(&self, other: &Fix) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+    }
+}
+
+/// NMEA receiver on UART1, parsed on a background thread.
+pub struct Gnss {
+    latest: Arc<Mutex<Option<Fix>>>,
+}
+
+impl Gnss {
+    pub fn start(uart: UART1, tx_pin: i32, rx_pin: i32, baud: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = UartConfig::default().baudrate(Hertz(baud));
+        let driver = UartDriver::new(
+            uart,
+            unsafe { AnyIOPin::new(tx_pin) },
+            unsafe { AnyIOPin::new(rx_pin) },
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &config,
+        )?;
+
+        let latest = Arc::new(Mutex::new(None));
+        let shared = latest.clone();
+        thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || read_sentences(driver, shared))
+            .map_err(|e| format!("Failed to spawn GNSS reader thread: {}", e))?;
+
+        log::info!("GNSS receiver started on UART1 at {} baud", baud);
+        Ok(Self { latest })
+    }
+
+    /// The current fix, or `None` while the receiver has no position.
+    pub fn latest(&self) -> Option<Fix> {
+        *self.latest.lock().unwrap()
+    }
+}
+
+fn read_sentences(driver: UartDriver<'static>, latest: Arc<Mutex<Option<Fix>>>) {
+    let mut line = Vec::with_capacity(96);
+    let mut buf = [0u8; 64];
+    loop {
+        let read = match driver.read(&mut buf, BLOCK) {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("GNSS UART read failed: {}", e);
+                continue;
+            }
+        };
+        for &byte in &buf[..read] {
+            match byte {
+                b'\n' => {
+                    if let Ok(sentence) = std::str::from_utf8(&line) {
+                        let mut latest = latest.lock().unwrap();
+                        *latest = apply_sentence(*latest, sentence.trim_end());
+                    }
+                    line.clear();
+                }
+                // Noise or a receiver stuck mid-sentence; start over
+                _ if line.len() >= 96 => line.clear(),
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+/// Fold one NMEA sentence into the current fix. GGA carries position,
+/// altitude and satellites; RMC carries position and speed. Sentences with a
+/// bad checksum or of other types leave the fix unchanged.
+pub fn apply_sentence(current: Option<Fix>, sentence: &str) -> Option<Fix> {
+    let Some(body) = checked_body(sentence) else {
+        return current;
+    };
+    let fields: Vec<&str> = body.split(',').collect();
+    let kind = fields[0];
+
+    if kind.ends_with("GGA") && fields.len() >= 10 {
+        if fields[6] == "0" || fields[6].is_empty() {
+            return None;
+        }
+        let (latitude, longitude) = position(fields[2], fields[3], fields[4], fields[5])?;
+        Some(Fix {
+            latitude,
+            longitude,
+            altitude_m: fields[9].parse().ok(),
+            satellites: fields[7].parse().ok(),
+            speed_kmh: current.and_then(|fix| fix.speed_kmh),
+        })
+    } else if kind.ends_with("RMC") && fields.len() >= 8 {
+        if fields[2] != "A" {
+            return None;
+        }
+        let (latitude, longitude) = position(fields[3], fields[4], fields[5], fields[6])?;
+        Some(Fix {
+            latitude,
+            longitude,
+            altitude_m: current.and_then(|fix| fix.altitude_m),
+            satellites: current.and_then(|fix| fix.satellites),
+            speed_kmh: fields[7].parse::<f32>().ok().map(|knots| knots * 1.852),
+        })
+    } else {
+        current
+    }
+}
+
+/// The part between `$` and `*`, if the checksum matches.
+fn checked_body(sentence: &str) -> Option<&str> {
+    let (body, checksum) = sentence.strip_prefix('$')?.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    let actual = body.bytes().fold(0u8, |acc, byte| acc ^ byte);
+    (actual == expected).then_some(body)
+}
+
+fn position(lat: &str, ns: &str, lon: &str, ew: &str) -> Option<(f64, f64)> {
+    let mut latitude = degrees(lat)?;
+    let mut longitude = degrees(lon)?;
+    if ns == "S" {
+        latitude = -latitude;
+    }
+    if ew == "W" {
+        longitude = -longitude;
+    }
+    Some((latitude, longitude))
+}
+
+/// NMEA `dddmm.mmmm` to decimal degrees.
+fn degrees(value: &str) -> Option<f64> {
+    let raw: f64 = value.parse().ok()?;
+    let whole = (raw / 100.0).trunc();
+    Some(whole + (raw - whole * 100.0) / 60.0)
+}
+
+/// Reports a fix only once the device has moved far enough from the last
+/// reported one, so a parked device doesn't publish GPS jitter.
+pub struct MovementFilter {
+    min_distance_m: f64,
+    last_reported: Option<Fix>,
+}
+
+impl MovementFilter {
+    pub fn new(min_distance_m: f64) -> Self {
+        Self {
+            min_distance_m,
+            last_reported: None,
+        }
+    }
+
+    pub fn should_report(&mut self, fix: &Fix) -> bool {
+        let moved = self
+            .last_reported
+            .map_or(true, |last| last.distance_m(fix) >= self.min_distance_m);
+        if moved {
+            self.last_reported = Some(*fix);
+        }
+        moved
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeofenceTransition {
+    Entered,
+    Exited,
+}
+
+impl GeofenceTransition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeofenceTransition::Entered => "entered",
+            GeofenceTransition::Exited => "exited",
+        }
+    }
+}
+
+/// Circular geofence reporting when the device crosses its boundary.
+pub struct Geofence {
+    center: Fix,
+    radius_m: f64,
+    inside: Option<bool>,
+}
+
+impl Geofence {
+    pub fn new(latitude: f64, longitude: f64, radius_m: f64) -> Self {
+        Self {
+            center: Fix {
+                latitude,
+                longitude,
+                altitude_m: None,
+                satellites: None,
+                speed_kmh: None,
+            },
+            radius_m,
+            inside: None,
+        }
+    }
+
+    /// The transition `fix` causes, if any. The first fix only establishes
+    /// which side the device starts on.
+    pub fn update(&mut self, fix: &Fix) -> Option<GeofenceTransition> {
+        let inside = self.center.distance_m(fix) <= self.radius_m;
+        let previous = self.inside.replace(inside)?;
+        match (previous, inside) {
+            (false, true) => Some(GeofenceTransition::Entered),
+            (true, false) => Some(GeofenceTransition::Exited),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Connection through a Greengrass core, for sites whose internet link comes
+//! and goes. At boot the device asks the Greengrass discovery API which core
+//! serves its thing and connects to that core's local broker instead of AWS
+//! IoT; the core relays to the cloud whenever it can. The answer is cached
+//! in NVS, so a boot during an outage still finds the core. When the core
+//! can't be reached either, the device restarts onto the cloud endpoint for
+//! one boot.
+
+use crate::auth::AuthProvider;
+use crate::client::{convert_certificate, CLIENT_CERT, PRIVATE_KEY};
+use crate::migrations::NAMESPACE;
+use crate::{bridge, sigv4, tls_observer};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::mqtt::client::MqttClientConfiguration;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::Deserialize;
+
+const DISCOVERY_PORT: u16 = 8443;
+/// Broker URL and CA of the core found by the last discovery.
+const URL_KEY: &str = "gg_url";
+const CA_KEY: &str = "gg_ca";
+/// Set for one boot after the core failed.
+const CLOUD_KEY: &str = "gg_cloud";
+/// A discovery answer carries a few CA certificates; anything much larger is wrong
+const MAX_RESPONSE_BYTES: usize = 16 * 1024;
+/// NVS strings top out at 4000 bytes
+const MAX_CACHED_CA_BYTES: usize = 4000;
+
+#[derive(Deserialize, Debug)]
+struct DiscoverResponse {
+    #[serde(rename = "GGGroups")]
+    groups: Vec<GroupInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct GroupInfo {
+    #[serde(rename = "GGGroupId")]
+    group_id: String,
+    cores: Vec<CoreInfo>,
+    #[serde(rename = "CAs")]
+    cas: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct CoreInfo {
+    thing_arn: String,
+    connectivity: Vec<Connectivity>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Connectivity {
+    host_address: String,
+    port_number: u16,
+}
+
+/// A core's local broker and the CA that signed its server certificate.
+#[derive(Debug, Clone)]
+pub struct Core {
+    pub url: String,
+    pub ca_pem: String,
+}
+
+/// Ask the discovery API of the region of `mqtt_url` for the cores serving
+/// `thing_name`, and return the first endpoint that accepts connections.
+/// The request authenticates with the embedded certificate.
+pub fn discover(mqtt_url: &str, thing_name: &str) -> Result<Option<Core>, Box<dyn std::error::Error>> {
+    let (host, _) = tls_observer::endpoint_host_port(mqtt_url).ok_or_else(|| format!("No host in \"{}\"", mqtt_url))?;
+    let region = sigv4::region_of(host).ok_or_else(|| format!("No AWS region in \"{}\"", host))?;
+    let url = format!(
+        "https://greengrass-ats.iot.{}.amazonaws.com:{}/greengrass/discover/thing/{}",
+        region, DISCOVERY_PORT, thing_name
+    );
+
+    let mut connection = EspHttpConnection::new(&HttpConfiguration {
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        client_certificate: Some(convert_certificate(CLIENT_CERT.to_vec())),
+        private_key: Some(convert_certificate(PRIVATE_KEY.open()?.into_owned())),
+        ..Default::default()
+    })?;
+    connection.initiate_request(Method::Get, &url, &[])?;
+    connection.initiate_response()?;
+    match connection.status() {
+        200 => {}
+        404 => {
+            log::info!("Thing \"{}\" isn't associated with a Greengrass core", thing_name);
+            return Ok(None);
+        }
+        status => return Err(format!("Greengrass discovery answered HTTP {}", status).into()),
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let read = connection.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > MAX_RESPONSE_BYTES {
+            return Err(format!("Greengrass discovery response over {} bytes", MAX_RESPONSE_BYTES).into());
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    let response: DiscoverResponse = serde_json::from_slice(&body)?;
+
+    for group in &response.groups {
+        for core in &group.cores {
+            for endpoint in &core.connectivity {
+                let url = format!("mqtts://{}:{}", endpoint.host_address, endpoint.port_number);
+                if bridge::reachable(&url) {
+                    log::info!("Greengrass core {} of group {} at {}", core.thing_arn, group.group_id, url);
+                    return Ok(Some(Core {
+                        url,
+                        ca_pem: group.cas.join("\n"),
+                    }));
+                }
+                log::info!("Greengrass core endpoint {} unreachable", url);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The core to connect to this boot: freshly discovered, or the cached one
+/// when discovery fails. `None` means AWS IoT directly, which is also the
+/// case for one boot after [`fall_back`].
+pub fn select(
+    nvs: EspDefaultNvsPartition,
+    mqtt_url: &str,
+    thing_name: &str,
+) -> Result<Option<Core>, Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    if storage.get_u8(CLOUD_KEY)?.is_some() {
+        storage.remove(CLOUD_KEY)?;
+        log::warn!("Greengrass core was unreachable, connecting to AWS IoT for this boot");
+        return Ok(None);
+    }
+
+    match discover(mqtt_url, thing_name) {
+        Ok(Some(core)) => {
+            if let Err(e) = store(&mut storage, &core) {
+                log::warn!("Failed to cache the Greengrass core: {}", e);
+            }
+            Ok(Some(core))
+        }
+        Ok(None) => {
+            storage.remove(URL_KEY)?;
+            storage.remove(CA_KEY)?;
+            Ok(None)
+        }
+        Err(e) => {
+            log::warn!("Greengrass discovery failed: {}, trying the cached core", e);
+            cached(&storage)
+        }
+    }
+}
+
+/// Give up on the core: the next boot connects to AWS IoT.
+pub fn fall_back(nvs: EspDefaultNvsPartition) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    storage.set_u8(CLOUD_KEY, 1)?;
+    Ok(())
+}
+
+fn store(storage: &mut EspNvs<NvsDefault>, core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    if core.ca_pem.len() >= MAX_CACHED_CA_BYTES {
+        return Err(format!("CA certificates too large to cache ({} bytes)", core.ca_pem.len()).into());
+    }
+    storage.set_str(URL_KEY, &core.url)?;
+    storage.set_str(CA_KEY, &core.ca_pem)?;
+    Ok(())
+}
+
+fn cached(storage: &EspNvs<NvsDefault>) -> Result<Option<Core>, Box<dyn std::error::Error>> {
+    let mut url_buf = [0u8; 128];
+    let mut ca_buf = vec![0u8; MAX_CACHED_CA_BYTES];
+    let url = storage.get_str(URL_KEY, &mut url_buf)?.map(str::to_string);
+    let ca_pem = storage.get_str(CA_KEY, &mut ca_buf)?.map(str::to_string);
+    Ok(url.zip(ca_pem).map(|(url, ca_pem)| Core { url, ca_pem }))
+}
+
+/// Connects to the core's broker with the identity of `inner`, trusting only
+/// the group CA.
+pub struct GreengrassCore {
+    core: Core,
+    inner: Box<dyn AuthProvider>,
+}
+
+impl GreengrassCore {
+    pub fn new(core: Core, inner: Box<dyn AuthProvider>) -> Self {
+        Self { core, inner }
+    }
+}
+
+impl AuthProvider for GreengrassCore {
+    fn name(&self) -> &'static str {
+        "greengrass"
+    }
+
+    fn apply(&self, conf: &mut MqttClientConfiguration<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.apply(conf)?;
+        conf.crt_bundle_attach = None;
+        conf.server_certificate = Some(convert_certificate(self.core.ca_pem.clone().into_bytes()));
+        Ok(())
+    }
+
+    fn broker_url(&self, _url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.core.url.clone())
+    }
+
+    fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+        self.inner.client_identity()
+    }
+
+    fn server_ca(&self) -> Option<Vec<u8>> {
+        Some(self.core.ca_pem.clone().into_bytes())
+    }
+}
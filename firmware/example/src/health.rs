@@ -0,0 +1,110 @@
+//! Connection health check by loopback. The device subscribes to a topic of
+//! its own and publishes a ping to it every period; the broker delivering it
+//! back proves the connection carries traffic both ways, and the time it
+//! took is the round trip. esp-mqtt's keep-alive only notices a half-open
+//! TLS connection after one and a half keep-alive intervals, which hangs
+//! the device for minutes. Without the ping back within the timeout,
+//! [`HealthMonitor::poll`] asks the main loop to reconnect.
+//!
+//! The round trip is taken when the main loop handles the ping, so it
+//! includes up to one main loop pass.
+
+use crate::client::{Client, SharedClient};
+use crate::timer::PeriodicTimer;
+use esp_idf_svc::mqtt::client::QoS;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Deliberately not an envelope: the echo filter would drop it.
+#[derive(Serialize, Deserialize, Debug)]
+struct Ping {
+    ping: u32,
+}
+
+pub struct HealthMonitor {
+    topic: String,
+    timer: PeriodicTimer,
+    timeout: Duration,
+    nonce: u32,
+    /// Ping on its way, and when it was sent
+    outstanding: Option<(u32, Instant)>,
+    /// Pings the handler saw come back, and when
+    returned: Arc<Mutex<Option<(u32, Instant)>>>,
+    rtt: Option<Duration>,
+}
+
+impl HealthMonitor {
+    pub fn new(topic: String, period: Duration, timeout: Duration, client_id: &str) -> Self {
+        Self {
+            topic,
+            timer: PeriodicTimer::new(period, client_id, "health"),
+            timeout,
+            nonce: 0,
+            outstanding: None,
+            returned: Arc::new(Mutex::new(None)),
+            rtt: None,
+        }
+    }
+
+    /// Subscribe to the loopback topic. Once is enough, the client restores
+    /// the subscription after reconnects.
+    pub fn subscribe(&self, client: &SharedClient) -> Result<(), Box<dyn Error>> {
+        let returned = self.returned.clone();
+        let handler = move |_: &mut Client, _: &str, payload: &[u8]| {
+            match serde_json::from_slice::<Ping>(payload) {
+                Ok(ping) => *returned.lock().unwrap() = Some((ping.ping, Instant::now())),
+                Err(e) => log::warn!("Invalid loopback ping: {}", e),
+            }
+        };
+        client.lock().subscribe_with_handler(&self.topic, handler)
+    }
+
+    /// Round trip of the last ping that came back.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Send a ping when one is due and check on the one outstanding. Call
+    /// once per main loop iteration, after `Client::poll`; returns true when
+    /// a ping didn't come back in time although the client reports being
+    /// connected.
+    pub fn poll(&mut self, client: &SharedClient) -> bool {
+        if let Some((nonce, arrived)) = self.returned.lock().unwrap().take() {
+            match self.outstanding {
+                Some((sent_nonce, sent)) if sent_nonce == nonce => {
+                    let rtt = arrived.saturating_duration_since(sent);
+                    log::debug!("Loopback ping {} back after {:?}", nonce, rtt);
+                    self.rtt = Some(rtt);
+                    self.outstanding = None;
+                }
+                _ => log::debug!("Ignoring late loopback ping {}", nonce),
+            }
+        }
+
+        // A connection known to be down is the reconnect logic's business
+        if !client.is_connected() {
+            self.outstanding = None;
+            return false;
+        }
+        if let Some((nonce, sent)) = self.outstanding {
+            if sent.elapsed() < self.timeout {
+                return false;
+            }
+            self.outstanding = None;
+            log::warn!("Loopback ping {} not back within {:?}", nonce, self.timeout);
+            return true;
+        }
+
+        if self.timer.poll() {
+            self.nonce = self.nonce.wrapping_add(1);
+            let ping = serde_json::to_string(&Ping { ping: self.nonce }).unwrap_or_default();
+            match client.publish_with_qos(&self.topic, &ping, QoS::AtMostOnce) {
+                Ok(_) => self.outstanding = Some((self.nonce, Instant::now())),
+                Err(e) => log::warn!("Failed to publish loopback ping: {}", e),
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,125 @@
+//! Leak hunting with the ESP-IDF standalone heap tracer (`heap-trace`
+//! feature). Allocations made between `start` and `stop` that were never
+//! freed are grouped by call site; the summary is uploaded in chunks and the
+//! return addresses symbolicated offline against the ELF.
+
+use crate::envelope::Chunk;
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Allocations the tracer can hold at once; later ones are not recorded.
+const MAX_RECORDS: usize = 300;
+/// Summary bytes per MQTT chunk.
+const CHUNK_SIZE: usize = 4096;
+
+struct Records {
+    _buffer: Box<[sys::heap_trace_record_t]>,
+}
+
+// Only touched by the tracer, between start and stop
+unsafe impl Send for Records {}
+
+static RECORDS: Mutex<Option<Records>> = Mutex::new(None);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeapTraceAction {
+    Start,
+    Stop,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HeapTraceCommand {
+    pub action: HeapTraceAction,
+}
+
+/// Unfreed allocations from one call site.
+#[derive(Serialize, Debug)]
+pub struct LeakSite {
+    /// Return addresses, innermost first, as hex
+    pub backtrace: Vec<String>,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LeakSummary {
+    pub leaked_bytes: usize,
+    pub leaked_allocations: usize,
+    /// Allocations dropped because the record buffer was full
+    pub overflowed: bool,
+    pub sites: Vec<LeakSite>,
+}
+
+/// Begin recording allocations that aren't freed again.
+pub fn start() -> Result<(), Box<dyn std::error::Error>> {
+    let mut records = RECORDS.lock().unwrap();
+    if records.is_none() {
+        // The tracer keeps the buffer for good, so it is allocated once
+        let mut buffer: Box<[sys::heap_trace_record_t]> =
+            (0..MAX_RECORDS).map(|_| unsafe { core::mem::zeroed() }).collect();
+        unsafe { sys::esp!(sys::heap_trace_init_standalone(buffer.as_mut_ptr(), MAX_RECORDS))? };
+        *records = Some(Records { _buffer: buffer });
+    }
+    unsafe { sys::esp!(sys::heap_trace_start(sys::heap_trace_mode_t_HEAP_TRACE_LEAKS))? };
+    log::info!("Heap tracing started");
+    Ok(())
+}
+
+/// Stop recording and summarize what is still allocated, largest site first.
+pub fn stop() -> Result<LeakSummary, Box<dyn std::error::Error>> {
+    unsafe { sys::esp!(sys::heap_trace_stop())? };
+
+    let mut sites: BTreeMap<Vec<usize>, (usize, usize)> = BTreeMap::new();
+    let count = unsafe { sys::heap_trace_get_count() };
+    for i in 0..count {
+        let mut record: sys::heap_trace_record_t = unsafe { core::mem::zeroed() };
+        unsafe { sys::esp!(sys::heap_trace_get(i, &mut record))? };
+        let backtrace: Vec<usize> = record
+            .alloced_by
+            .iter()
+            .map(|address| *address as usize)
+            .take_while(|address| *address != 0)
+            .collect();
+        let site = sites.entry(backtrace).or_default();
+        site.0 += 1;
+        site.1 += record.size;
+    }
+
+    let mut summary = LeakSummary {
+        leaked_bytes: sites.values().map(|(_, bytes)| bytes).sum(),
+        leaked_allocations: count,
+        overflowed: count >= MAX_RECORDS,
+        sites: sites
+            .into_iter()
+            .map(|(backtrace, (count, bytes))| LeakSite {
+                backtrace: backtrace.iter().map(|address| format!("0x{:08x}", address)).collect(),
+                count,
+                bytes,
+            })
+            .collect(),
+    };
+    summary.sites.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+    log::info!(
+        "Heap tracing stopped: {} bytes in {} allocations from {} sites",
+        summary.leaked_bytes,
+        summary.leaked_allocations,
+        summary.sites.len()
+    );
+    Ok(summary)
+}
+
+/// Split the summary JSON into chunks for upload.
+pub fn chunks<'a>(key: &'a str, json: &'a str) -> impl Iterator<Item = Chunk<'a>> + 'a {
+    let total = json.len().div_ceil(CHUNK_SIZE);
+    // The summary is ASCII, so byte boundaries are character boundaries
+    json.as_bytes().chunks(CHUNK_SIZE).enumerate().map(move |(seq, chunk)| Chunk {
+        event: "heap_trace_chunk",
+        key,
+        seq,
+        total,
+        data: String::from_utf8_lossy(chunk).into_owned(),
+    })
+}
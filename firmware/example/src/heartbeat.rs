@@ -0,0 +1,106 @@
+//! Heartbeat on its own topic, for cloud-side liveness checks that don't
+//! depend on telemetry. Heartbeats go out at QoS 1, and the device watches
+//! their PUBACKs itself: a connection can look up while nothing leaves it,
+//! e.g. behind a NAT that silently dropped the session. After
+//! `max_missed` unacknowledged heartbeats in a row, [`Heartbeat::poll`]
+//! asks the main loop to recover the connection.
+
+use crate::client::SharedClient;
+use crate::delivery::{Delivery, Outcome};
+use crate::envelope;
+use crate::timer::PeriodicTimer;
+use esp_idf_svc::mqtt::client::QoS;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug)]
+struct HeartbeatMessage {
+    event: &'static str,
+    /// Counts up from 1 every boot, so gaps show lost heartbeats
+    seq: u64,
+    uptime_secs: u64,
+}
+
+pub struct Heartbeat {
+    topic: String,
+    timer: PeriodicTimer,
+    max_missed: u32,
+    started: Instant,
+    seq: u64,
+    /// Message id of the last heartbeat until its PUBACK arrives
+    awaiting: Option<u32>,
+    missed: u32,
+}
+
+impl Heartbeat {
+    pub fn new(topic: String, period: Duration, client_id: &str, max_missed: u32) -> Self {
+        Self {
+            topic,
+            timer: PeriodicTimer::new(period, client_id, "heartbeat"),
+            max_missed: max_missed.max(1),
+            started: Instant::now(),
+            seq: 0,
+            awaiting: None,
+            missed: 0,
+        }
+    }
+
+    /// Publish a heartbeat when one is due. Call once per main loop
+    /// iteration; returns true when `max_missed` heartbeats in a row went
+    /// unacknowledged although the client reports being connected.
+    pub fn poll(&mut self, client: &SharedClient, device_id: &str) -> bool {
+        if !self.timer.poll() {
+            return false;
+        }
+        // A connection known to be down is the reconnect logic's business
+        if !client.is_connected() {
+            self.awaiting = None;
+            self.missed = 0;
+            return false;
+        }
+        if let Some(id) = self.awaiting.take() {
+            self.missed += 1;
+            log::warn!("Heartbeat {} not acknowledged ({} missed in a row)", id, self.missed);
+        }
+
+        self.seq += 1;
+        let heartbeat = HeartbeatMessage {
+            event: "heartbeat",
+            seq: self.seq,
+            uptime_secs: self.started.elapsed().as_secs(),
+        };
+        let sent = envelope::to_json(device_id, &heartbeat)
+            .map_err(Into::into)
+            .and_then(|payload| client.publish_with_qos(&self.topic, &payload, QoS::AtLeastOnce));
+        match sent {
+            Ok(0) => {
+                // Held back by the publish queue: the outbox isn't draining
+                self.missed += 1;
+                log::warn!("Heartbeat {} queued, outbox full ({} missed in a row)", self.seq, self.missed);
+            }
+            Ok(id) => self.awaiting = Some(id),
+            Err(e) => {
+                self.missed += 1;
+                log::warn!("Failed to publish heartbeat {}: {} ({} missed in a row)", self.seq, e, self.missed);
+            }
+        }
+
+        if self.missed >= self.max_missed {
+            self.missed = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Feed every delivery report; the heartbeat picks out its own.
+    pub fn delivered(&mut self, delivery: &Delivery) {
+        if self.awaiting != Some(delivery.id) || delivery.outcome != Outcome::Acked {
+            return;
+        }
+        self.awaiting = None;
+        if self.missed > 0 {
+            log::info!("Heartbeats acknowledged again after {} missed", self.missed);
+        }
+        self.missed = 0;
+    }
+}
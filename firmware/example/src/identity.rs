@@ -0,0 +1,97 @@
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys;
+
+/// OID 2.5.4.3, commonName
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// CN of every certificate AWS IoT creates itself, e.g. with
+/// `CreateKeysAndCertificate`; it doesn't name the thing
+const AWS_GENERATED_CN: &str = "AWS IoT Certificate";
+
+const DEVICE_ID_KEY: &str = "device_id";
+
+/// Load the canonical device identity from NVS, generating and persisting a
+/// random UUID on first boot.
+///
+/// Unlike the MQTT client id or thing name, this never changes for the life
+/// of the flash contents, so device history can be joined across renames.
+/// Call it after WiFi has started: the hardware RNG is only a true entropy
+/// source while the radio is enabled.
+pub fn load_or_create(partition: EspDefaultNvsPartition) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(device_id) = load(partition.clone())? {
+        return Ok(device_id);
+    }
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+    let device_id = generate_uuid();
+    nvs.set_str(DEVICE_ID_KEY, &device_id)?;
+    log::info!("Generated new device id {}", device_id);
+    Ok(device_id)
+}
+
+/// The device id stored by an earlier boot, if any. Safe to call before WiFi.
+pub fn load(partition: EspDefaultNvsPartition) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = [0u8; 37];
+    Ok(nvs.get_str(DEVICE_ID_KEY, &mut buf)?.map(str::to_string))
+}
+
+/// Subject CN of the first certificate in `certificate` (PEM or DER), e.g.
+/// the thing name a device certificate was issued for.
+pub fn certificate_common_name(certificate: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    // mbedtls wants PEM with its terminating NUL counted
+    let mut buf = certificate.to_vec();
+    if buf.starts_with(b"-----") && buf.last() != Some(&0) {
+        buf.push(0);
+    }
+
+    let common_name = unsafe {
+        let mut crt: sys::mbedtls_x509_crt = std::mem::zeroed();
+        sys::mbedtls_x509_crt_init(&mut crt);
+        let result = match sys::mbedtls_x509_crt_parse(&mut crt, buf.as_ptr(), buf.len()) {
+            // A positive return counts certificates of a chain that failed
+            // to parse; the device certificate comes first
+            ret if ret < 0 => Err(format!("Failed to parse the certificate: -0x{:04x}", -ret)),
+            _ => {
+                let mut name: *const sys::mbedtls_x509_name = &crt.subject;
+                let mut common_name = None;
+                while !name.is_null() {
+                    let oid = std::slice::from_raw_parts((*name).oid.p, (*name).oid.len);
+                    if oid == OID_COMMON_NAME {
+                        let value = std::slice::from_raw_parts((*name).val.p, (*name).val.len);
+                        common_name = Some(String::from_utf8_lossy(value).into_owned());
+                    }
+                    name = (*name).next;
+                }
+                common_name.ok_or_else(|| "The certificate's subject has no CN".to_string())
+            }
+        };
+        sys::mbedtls_x509_crt_free(&mut crt);
+        result?
+    };
+
+    if common_name == AWS_GENERATED_CN {
+        return Err(format!("The certificate's CN is the generic \"{}\"", AWS_GENERATED_CN).into());
+    }
+    Ok(common_name)
+}
+
+/// Random (version 4) UUID in its hyphenated text form.
+fn generate_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    unsafe {
+        esp_idf_svc::sys::esp_fill_random(bytes.as_mut_ptr() as *mut core::ffi::c_void, bytes.len());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
@@ -0,0 +1,351 @@
+//! Irrigation controller profile: a cloud-managed valve scheduler composed
+//! from relays with an interlock, a pulse flow meter, the wall clock and the
+//! device shadow (schedule and limits under `desired.irrigation.config`).
+
+use crate::clock;
+use crate::estop::Latch;
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const CONFIG_KEY: &str = "irrigation";
+/// Size of the buffer the stored config is read back into at boot
+const MAX_CONFIG_BYTES: usize = 2048;
+/// Flow seen with every valve closed for this long is reported as a leak.
+const LEAK_WINDOW: Duration = Duration::from_secs(60);
+/// Litres per leak window above which idle flow counts as a leak.
+const LEAK_LITERS: f32 = 0.5;
+
+static FLOW_PULSES: AtomicU32 = AtomicU32::new(0);
+
+/// Water a zone at `start` (local "HH:MM") for `minutes` on the given
+/// weekdays (0 = Sunday; empty = every day).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleEntry {
+    pub zone: usize,
+    pub start: String,
+    pub minutes: u32,
+    #[serde(default)]
+    pub days: Vec<u8>,
+}
+
+impl ScheduleEntry {
+    fn start_minute(&self) -> Option<u32> {
+        let (hours, minutes) = self.start.split_once(':')?;
+        let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    }
+}
+
+/// Settings managed through the shadow and kept in NVS.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct IrrigationConfig {
+    pub schedule: Vec<ScheduleEntry>,
+    /// Offset of local time from UTC, for schedule start times
+    pub utc_offset_mins: i32,
+    /// Upper bound for any run, scheduled or manual
+    pub max_minutes: u32,
+    /// Close the valve if it hasn't flowed after this long (0 disables)
+    pub no_flow_secs: u32,
+}
+
+impl Default for IrrigationConfig {
+    fn default() -> Self {
+        Self {
+            schedule: Vec::new(),
+            utc_offset_mins: 0,
+            max_minutes: 60,
+            no_flow_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum IrrigationEvent {
+    ZoneStarted { zone: usize, minutes: u32, source: &'static str },
+    ZoneStopped { zone: usize, liters: f32, reason: &'static str },
+    Leak { liters: f32 },
+}
+
+/// State reported to the shadow.
+#[derive(Serialize, Debug)]
+pub struct IrrigationState<'a> {
+    pub active_zone: Option<usize>,
+    pub config: &'a IrrigationConfig,
+}
+
+struct Run {
+    zone: usize,
+    started: Instant,
+    duration: Duration,
+    pulses_at_start: u32,
+}
+
+pub struct Irrigation {
+    valves: Vec<PinDriver<'static, AnyOutputPin, Output>>,
+    pump: Option<PinDriver<'static, AnyOutputPin, Output>>,
+    pulses_per_liter: Option<f32>,
+    config: IrrigationConfig,
+    active: Option<Run>,
+    /// Local minute (since the epoch) schedules were last evaluated for
+    last_schedule_minute: Option<i64>,
+    leak_window: (Instant, u32),
+    estop: Option<Latch>,
+}
+
+impl Irrigation {
+    /// `valve_pins` is a comma-separated list, one relay per zone. Negative
+    /// pump and flow pins mean not fitted.
+    pub fn new(
+        valve_pins: &str,
+        pump_pin: i32,
+        flow_pin: i32,
+        pulses_per_liter: f32,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut valves = Vec::new();
+        for pin in valve_pins.split(',').map(str::trim).filter(|pin| !pin.is_empty()) {
+            let pin: i32 = pin.parse().map_err(|_| format!("Invalid valve pin \"{}\"", pin))?;
+            let mut valve = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+            valve.set_low()?;
+            valves.push(valve);
+        }
+        if valves.is_empty() {
+            return Err("Irrigation needs at least one valve pin".into());
+        }
+
+        let pump = if pump_pin >= 0 {
+            let mut pump = PinDriver::output(unsafe { AnyOutputPin::new(pump_pin) })?;
+            pump.set_low()?;
+            Some(pump)
+        } else {
+            None
+        };
+
+        if flow_pin >= 0 {
+            start_flow_counter(flow_pin)?;
+        }
+
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = [0u8; MAX_CONFIG_BYTES];
+        // A config that can't be read must not keep the device from booting
+        let config = match nvs.get_str(CONFIG_KEY, &mut buf) {
+            Ok(Some(json)) => match serde_json::from_str(json) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::warn!("Dropping unreadable irrigation config: {}", e);
+                    IrrigationConfig::default()
+                }
+            },
+            Ok(None) => IrrigationConfig::default(),
+            Err(e) => {
+                log::warn!("Failed to read the irrigation config, using the defaults: {}", e);
+                IrrigationConfig::default()
+            }
+        };
+        log::info!("Irrigation: {} zones, {} schedule entries", valves.len(), config.schedule.len());
+
+        Ok(Self {
+            valves,
+            pump,
+            pulses_per_liter: (flow_pin >= 0).then_some(pulses_per_liter),
+            config,
+            active: None,
+            last_schedule_minute: None,
+            leak_window: (Instant::now(), FLOW_PULSES.load(Ordering::Relaxed)),
+            estop: None,
+        })
+    }
+
+    pub fn state(&self) -> IrrigationState<'_> {
+        IrrigationState {
+            active_zone: self.active.as_ref().map(|run| run.zone),
+            config: &self.config,
+        }
+    }
+
+    /// GPIO numbers of the valve and pump relays, for the e-stop to force off.
+    pub fn output_pins(&self) -> Vec<i32> {
+        self.valves.iter().chain(self.pump.as_ref()).map(|driver| driver.pin()).collect()
+    }
+
+    /// Refuse to open a zone, and close the running one, while `latch` is tripped.
+    pub fn set_estop(&mut self, latch: Latch) {
+        self.estop = Some(latch);
+    }
+
+    fn estop_tripped(&self) -> bool {
+        self.estop.as_ref().is_some_and(Latch::is_tripped)
+    }
+
+    pub fn config(&self) -> &IrrigationConfig {
+        &self.config
+    }
+
+    /// Apply and persist settings received through the shadow.
+    pub fn set_config(
+        &mut self,
+        config: IrrigationConfig,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(entry) = config.schedule.iter().find(|entry| entry.zone >= self.valves.len()) {
+            return Err(format!("Schedule uses zone {} but only {} are fitted", entry.zone, self.valves.len()).into());
+        }
+        if let Some(entry) = config.schedule.iter().find(|entry| entry.start_minute().is_none()) {
+            return Err(format!("Invalid schedule start \"{}\"", entry.start).into());
+        }
+        let json = serde_json::to_string(&config)?;
+        if json.len() >= MAX_CONFIG_BYTES {
+            return Err(format!("Irrigation config over {} bytes", MAX_CONFIG_BYTES).into());
+        }
+        let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        nvs.set_str(CONFIG_KEY, &json)?;
+        log::info!("Irrigation config updated: {:?}", config);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Open `zone` for `minutes`, closing whichever zone is running first.
+    pub fn start(
+        &mut self,
+        zone: usize,
+        minutes: u32,
+        source: &'static str,
+    ) -> Result<Vec<IrrigationEvent>, Box<dyn std::error::Error>> {
+        if zone >= self.valves.len() {
+            return Err(format!("No zone {}", zone).into());
+        }
+        if self.estop_tripped() {
+            return Err("E-stop is tripped, send estop_reset first".into());
+        }
+        let minutes = minutes.min(self.config.max_minutes);
+        let mut events = Vec::new();
+        events.extend(self.stop("preempted")?);
+
+        // Interlock: valve first, then the pump, so it never runs dead-headed
+        self.valves[zone].set_high()?;
+        if let Some(pump) = self.pump.as_mut() {
+            pump.set_high()?;
+        }
+        self.active = Some(Run {
+            zone,
+            started: Instant::now(),
+            duration: Duration::from_secs(minutes as u64 * 60),
+            pulses_at_start: FLOW_PULSES.load(Ordering::Relaxed),
+        });
+        log::info!("Irrigation zone {} open for {} min ({})", zone, minutes, source);
+        events.push(IrrigationEvent::ZoneStarted { zone, minutes, source });
+        Ok(events)
+    }
+
+    /// Close the running zone, if any.
+    pub fn stop(&mut self, reason: &'static str) -> Result<Option<IrrigationEvent>, Box<dyn std::error::Error>> {
+        let Some(run) = self.active.take() else {
+            return Ok(None);
+        };
+        // Reverse of start: pump off before the valve closes
+        if let Some(pump) = self.pump.as_mut() {
+            pump.set_low()?;
+        }
+        self.valves[run.zone].set_low()?;
+        self.leak_window = (Instant::now(), FLOW_PULSES.load(Ordering::Relaxed));
+
+        let liters = self.liters_since(run.pulses_at_start);
+        log::info!("Irrigation zone {} closed after {:.1} L ({})", run.zone, liters, reason);
+        Ok(Some(IrrigationEvent::ZoneStopped {
+            zone: run.zone,
+            liters,
+            reason,
+        }))
+    }
+
+    /// Run the schedule and the flow checks. Call once per main loop iteration.
+    pub fn poll(&mut self) -> Result<Vec<IrrigationEvent>, Box<dyn std::error::Error>> {
+        let mut events = Vec::new();
+
+        // The e-stop task has already forced the outputs off; this brings the state in line
+        if self.estop_tripped() {
+            events.extend(self.stop("estop")?);
+            return Ok(events);
+        }
+
+        if let Some(run) = &self.active {
+            let elapsed = run.started.elapsed();
+            let no_flow = self.config.no_flow_secs > 0
+                && self.pulses_per_liter.is_some()
+                && elapsed >= Duration::from_secs(self.config.no_flow_secs as u64)
+                && FLOW_PULSES.load(Ordering::Relaxed) == run.pulses_at_start;
+            if elapsed >= run.duration {
+                events.extend(self.stop("completed")?);
+            } else if no_flow {
+                events.extend(self.stop("no_flow")?);
+            }
+        } else if self.pulses_per_liter.is_some() && self.leak_window.0.elapsed() >= LEAK_WINDOW {
+            let liters = self.liters_since(self.leak_window.1);
+            if liters >= LEAK_LITERS {
+                log::warn!("Irrigation: {:.1} L flowed with every valve closed", liters);
+                events.push(IrrigationEvent::Leak { liters });
+            }
+            self.leak_window = (Instant::now(), FLOW_PULSES.load(Ordering::Relaxed));
+        }
+
+        // Schedules need the wall clock; each local minute is evaluated once
+        if let Some(now_ms) = clock::now_ms() {
+            let local_minute = (now_ms / 60_000) as i64 + self.config.utc_offset_mins as i64;
+            if self.last_schedule_minute != Some(local_minute) {
+                self.last_schedule_minute = Some(local_minute);
+                let minute_of_day = local_minute.rem_euclid(24 * 60) as u32;
+                // 1970-01-01 was a Thursday
+                let weekday = (local_minute.div_euclid(24 * 60) + 4).rem_euclid(7) as u8;
+                let due = self.config.schedule.iter().find(|entry| {
+                    entry.start_minute() == Some(minute_of_day)
+                        && (entry.days.is_empty() || entry.days.contains(&weekday))
+                });
+                if let Some(entry) = due.cloned() {
+                    events.extend(self.start(entry.zone, entry.minutes, "schedule")?);
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn liters_since(&self, pulses_at_start: u32) -> f32 {
+        match self.pulses_per_liter {
+            Some(per_liter) => FLOW_PULSES.load(Ordering::Relaxed).wrapping_sub(pulses_at_start) as f32 / per_liter,
+            None => 0.0,
+        }
+    }
+}
+
+/// Count rising edges on `pin` from a GPIO interrupt that stays armed.
+fn start_flow_counter(pin: i32) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe extern "C" fn on_pulse(_: *mut core::ffi::c_void) {
+        FLOW_PULSES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let config = sys::gpio_config_t {
+        pin_bit_mask: 1u64 << pin,
+        mode: sys::gpio_mode_t_GPIO_MODE_INPUT,
+        pull_up_en: sys::gpio_pullup_t_GPIO_PULLUP_ENABLE,
+        pull_down_en: sys::gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+        intr_type: sys::gpio_int_type_t_GPIO_INTR_POSEDGE,
+        ..Default::default()
+    };
+    unsafe {
+        sys::esp!(sys::gpio_config(&config))?;
+        // Already installed by another driver is fine
+        let installed = sys::gpio_install_isr_service(0);
+        if installed != sys::ESP_OK && installed != sys::ESP_ERR_INVALID_STATE as sys::esp_err_t {
+            sys::esp!(installed)?;
+        }
+        sys::esp!(sys::gpio_isr_handler_add(pin, Some(on_pulse), core::ptr::null_mut()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use crate::client::SharedClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::error::Error;
+
+/// Runs one kind of job, selected by the `operation` field of the job document.
+pub trait JobExecutor: Send {
+    fn operation(&self) -> &'static str;
+
+    /// Carry out the job. `Ok` is reported as SUCCEEDED, `Err` as FAILED
+    /// with the error as the reason. `client` is there for progress reports
+    /// while the job runs.
+    fn execute(&mut self, client: &SharedClient, document: &Value) -> Result<JobOutcome, Box<dyn Error>>;
+}
+
+#[derive(Debug, Default)]
+pub struct JobOutcome {
+    /// Reported as the execution's `statusDetails` (string values only)
+    pub details: Map<String, Value>,
+    /// Restart once the result has been reported
+    pub restart: bool,
+}
+
+/// `{"operation": "reboot"}`
+pub struct Reboot;
+
+impl JobExecutor for Reboot {
+    fn operation(&self) -> &'static str {
+        "reboot"
+    }
+
+    fn execute(&mut self, _client: &SharedClient, _document: &Value) -> Result<JobOutcome, Box<dyn Error>> {
+        Ok(JobOutcome {
+            restart: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// Topics of a thing's jobs.
+pub struct JobTopics {
+    prefix: String,
+    pub notify_next: String,
+    pub start_next: String,
+    pub start_next_accepted: String,
+    pub start_next_rejected: String,
+    pub update_rejected: String,
+}
+
+impl JobTopics {
+    pub fn new(thing_name: &str) -> Self {
+        let prefix = format!("$aws/things/{}/jobs", thing_name);
+        Self {
+            notify_next: format!("{}/notify-next", prefix),
+            start_next: format!("{}/start-next", prefix),
+            start_next_accepted: format!("{}/start-next/accepted", prefix),
+            start_next_rejected: format!("{}/start-next/rejected", prefix),
+            update_rejected: format!("{}/+/update/rejected", prefix),
+            prefix,
+        }
+    }
+
+    pub fn update(&self, job_id: &str) -> String {
+        format!("{}/{}/update", self.prefix, job_id)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JobExecution {
+    job_id: String,
+    #[serde(default)]
+    job_document: Value,
+}
+
+/// Payload of `notify-next` and `start-next/accepted`; no execution means
+/// the queue is empty.
+#[derive(Deserialize, Debug)]
+struct NextJob {
+    #[serde(default)]
+    execution: Option<JobExecution>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StatusUpdate<'a> {
+    status: &'static str,
+    status_details: &'a Map<String, Value>,
+}
+
+/// AWS IoT Jobs client: takes queued jobs one at a time through
+/// `start-next` (which marks them IN_PROGRESS), runs the executor registered
+/// for their operation and reports SUCCEEDED or FAILED.
+pub struct Jobs {
+    pub topics: JobTopics,
+    executors: Vec<Box<dyn JobExecutor>>,
+    restart_pending: bool,
+    client: SharedClient,
+}
+
+impl Jobs {
+    pub fn new(thing_name: &str, client: SharedClient) -> Self {
+        Self {
+            topics: JobTopics::new(thing_name),
+            executors: Vec::new(),
+            restart_pending: false,
+            client,
+        }
+    }
+
+    pub fn register(&mut self, executor: impl JobExecutor + 'static) {
+        log::info!("Registered \"{}\" job executor", executor.operation());
+        self.executors.push(Box::new(executor));
+    }
+
+    /// Subscribe to the jobs topics and ask for the next queued job. Call on
+    /// every connect. The client restores subscriptions after a reconnect
+    /// too, but not necessarily before the request goes out.
+    pub fn bootstrap(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut client = self.client.lock();
+        client.subscribe_topic(&self.topics.notify_next)?;
+        client.subscribe_topic(&self.topics.start_next_accepted)?;
+        client.subscribe_topic(&self.topics.start_next_rejected)?;
+        client.subscribe_topic(&self.topics.update_rejected)?;
+        client.publish_to(&self.topics.start_next, "{}")?;
+        Ok(())
+    }
+
+    /// True once a job asked for a restart and its result has been reported.
+    pub fn restart_pending(&self) -> bool {
+        self.restart_pending
+    }
+
+    /// Handle a message on a jobs topic. Returns false if the topic isn't ours.
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> bool {
+        if topic == self.topics.notify_next {
+            match serde_json::from_slice::<NextJob>(payload) {
+                Ok(NextJob { execution: Some(job) }) => {
+                    log::info!("Job {} queued", job.job_id);
+                    if let Err(e) = self.client.publish_to(&self.topics.start_next, "{}") {
+                        log::error!("Failed to start job {}: {}", job.job_id, e);
+                    }
+                }
+                Ok(_) => log::info!("No jobs queued"),
+                Err(e) => log::warn!("Invalid jobs notification: {}", e),
+            }
+        } else if topic == self.topics.start_next_accepted {
+            match serde_json::from_slice::<NextJob>(payload) {
+                Ok(NextJob { execution: Some(job) }) => {
+                    if let Err(e) = self.run(&job) {
+                        log::error!("Failed to report job {}: {}", job.job_id, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Invalid job execution: {}", e),
+            }
+        } else if topic == self.topics.start_next_rejected {
+            log::warn!("Job start rejected: {}", String::from_utf8_lossy(payload));
+        } else if topic.starts_with(&self.topics.prefix) && topic.ends_with("/update/rejected") {
+            log::warn!("Job update rejected: {}", String::from_utf8_lossy(payload));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn run(&mut self, job: &JobExecution) -> Result<(), Box<dyn Error>> {
+        let operation = job.job_document.get("operation").and_then(Value::as_str).unwrap_or("");
+        log::info!("Running job {} ({})", job.job_id, operation);
+
+        let result = match self.executors.iter_mut().find(|executor| executor.operation() == operation) {
+            Some(executor) => executor.execute(&self.client, &job.job_document),
+            None => Err(format!("Unsupported operation \"{}\"", operation).into()),
+        };
+        let (status, outcome) = match result {
+            Ok(outcome) => ("SUCCEEDED", outcome),
+            Err(e) => {
+                log::error!("Job {} failed: {}", job.job_id, e);
+                let mut details = Map::new();
+                details.insert("reason".to_string(), Value::String(e.to_string()));
+                ("FAILED", JobOutcome { details, restart: false })
+            }
+        };
+
+        let update = StatusUpdate {
+            status,
+            status_details: &outcome.details,
+        };
+        self.client.publish_to(&self.topics.update(&job.job_id), &serde_json::to_string(&update)?)?;
+        log::info!("Job {} {}", job.job_id, status);
+        self.restart_pending |= outcome.restart;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! Payload limits, apart from the [`crate::middleware::Middleware`] in
+//! [`crate::limits`], so the scanner runs in host tests (see
+//! `firmware/host-tests`).
+
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+
+/// Bounds on incoming payloads, checked before anything deserializes them,
+/// so a malicious or buggy publisher can't exhaust the heap with a huge or
+/// deeply nested document. Registered right after the echo and dedup
+/// filters; the receive path runs the chain in reverse, so the limits check
+/// a payload before those filters parse it. `0` disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonLimits {
+    pub max_bytes: usize,
+    /// Objects and arrays open at the same time
+    pub max_depth: usize,
+    pub max_array_len: usize,
+}
+
+/// Why a payload was rejected.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "violation", rename_all = "snake_case")]
+pub enum LimitViolation {
+    TooLarge { size: usize, limit: usize },
+    TooDeep { limit: usize },
+    ArrayTooLong { limit: usize },
+}
+
+impl fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitViolation::TooLarge { size, limit } => {
+                write!(f, "payload of {} bytes exceeds the {}-byte limit", size, limit)
+            }
+            LimitViolation::TooDeep { limit } => write!(f, "JSON nested deeper than {} levels", limit),
+            LimitViolation::ArrayTooLong { limit } => write!(f, "JSON array longer than {} elements", limit),
+        }
+    }
+}
+
+impl Error for LimitViolation {}
+
+/// An open object or array while scanning.
+struct Frame {
+    array: bool,
+    elements: usize,
+    /// At the start of an array or after a comma, so the next value is a new element
+    expecting: bool,
+}
+
+impl JsonLimits {
+    pub fn check(&self, payload: &[u8]) -> Result<(), LimitViolation> {
+        if self.max_bytes > 0 && payload.len() > self.max_bytes {
+            return Err(LimitViolation::TooLarge {
+                size: payload.len(),
+                limit: self.max_bytes,
+            });
+        }
+        // Only JSON documents are scanned; other payloads have only the size limit
+        match payload.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') | Some(b'[') => self.check_structure(payload),
+            _ => Ok(()),
+        }
+    }
+
+    /// Walk the document without building it. Malformed JSON is left for
+    /// the deserializer to reject.
+    fn check_structure(&self, payload: &[u8]) -> Result<(), LimitViolation> {
+        let mut open: Vec<Frame> = Vec::new();
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for &byte in payload {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if byte == b'\\' {
+                    escaped = true;
+                } else if byte == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+
+            if let Some(frame) = open.last_mut().filter(|frame| frame.array && frame.expecting) {
+                if byte != b']' {
+                    frame.elements += 1;
+                    frame.expecting = false;
+                    if self.max_array_len > 0 && frame.elements > self.max_array_len {
+                        return Err(LimitViolation::ArrayTooLong {
+                            limit: self.max_array_len,
+                        });
+                    }
+                }
+            }
+
+            match byte {
+                b'{' | b'[' => {
+                    if self.max_depth > 0 && open.len() >= self.max_depth {
+                        return Err(LimitViolation::TooDeep { limit: self.max_depth });
+                    }
+                    open.push(Frame {
+                        array: byte == b'[',
+                        elements: 0,
+                        expecting: true,
+                    });
+                }
+                b'}' | b']' => {
+                    open.pop();
+                }
+                b',' => {
+                    if let Some(frame) = open.last_mut() {
+                        frame.expecting = true;
+                    }
+                }
+                b'"' => in_string = true,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: JsonLimits = JsonLimits {
+        max_bytes: 0,
+        max_depth: 3,
+        max_array_len: 2,
+    };
+
+    #[test]
+    fn escaped_quotes_stay_inside_the_string() {
+        assert_eq!(LIMITS.check(br#"{"a": "x\"[[[[,,,", "b": [1, 2]}"#), Ok(()));
+        assert_eq!(LIMITS.check(br#"{"a": "x\\", "b": [[[[1]]]]}"#), Err(LimitViolation::TooDeep { limit: 3 }));
+    }
+
+    #[test]
+    fn empty_arrays_have_no_elements() {
+        let limits = JsonLimits { max_array_len: 1, ..LIMITS };
+        assert_eq!(limits.check(b"[]"), Ok(()));
+        assert_eq!(limits.check(b" [ ] "), Ok(()));
+        assert_eq!(limits.check(b"{\"a\": [], \"b\": []}"), Ok(()));
+    }
+
+    #[test]
+    fn nested_empty_arrays_count_as_elements() {
+        assert_eq!(LIMITS.check(b"[[], []]"), Ok(()));
+        assert_eq!(LIMITS.check(b"[[], [], []]"), Err(LimitViolation::ArrayTooLong { limit: 2 }));
+        assert_eq!(LIMITS.check(b"[[[]], [[]]]"), Ok(()));
+    }
+
+    #[test]
+    fn depth_limit_is_inclusive() {
+        assert_eq!(LIMITS.check(b"[{\"a\": [1]}]"), Ok(()));
+        assert_eq!(LIMITS.check(b"[{\"a\": [[1]]}]"), Err(LimitViolation::TooDeep { limit: 3 }));
+    }
+
+    #[test]
+    fn other_payloads_only_have_the_size_limit() {
+        assert_eq!(LIMITS.check(b"on [[[[[[1,2,3]]]]]]"), Ok(()));
+        assert_eq!(LIMITS.check(&[0xff, b'[', b'[', b'[', b'[']), Ok(()));
+        assert_eq!(LIMITS.check(b""), Ok(()));
+        let limits = JsonLimits { max_bytes: 4, ..LIMITS };
+        assert_eq!(limits.check(b"hello"), Err(LimitViolation::TooLarge { size: 5, limit: 4 }));
+    }
+
+    #[test]
+    fn zero_disables_a_limit() {
+        let limits = JsonLimits {
+            max_bytes: 0,
+            max_depth: 0,
+            max_array_len: 0,
+        };
+        assert_eq!(limits.check(b"[[[[[[[[1, 2, 3, 4, 5]]]]]]]]"), Ok(()));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+use std::ffi::{c_int, c_uchar, c_void, CStr, CString};
+use std::ptr;
+
+const KEY_KEY: &str = "dev_key";
+const CSR_KEY: &str = "dev_csr";
+const CERT_KEY: &str = "dev_cert";
+const PREVIOUS_CERT_KEY: &str = "dev_cert_prev";
+
+/// Device key pair generated on-device. The private key is only ever
+/// written to NVS; the CSR is what leaves the device.
+pub struct KeyMaterial {
+    pub private_key_pem: String,
+    pub csr_pem: String,
+}
+
+/// Load the device key and CSR from NVS, generating an ECDSA P-256 key and a
+/// CSR for `common_name` the first time.
+pub fn load_or_generate(
+    partition: EspDefaultNvsPartition,
+    common_name: &str,
+) -> Result<KeyMaterial, Box<dyn std::error::Error>> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+    if let (Some(private_key_pem), Some(csr_pem)) = (get_string(&nvs, KEY_KEY)?, get_string(&nvs, CSR_KEY)?) {
+        return Ok(KeyMaterial { private_key_pem, csr_pem });
+    }
+
+    log::info!("Generating ECDSA P-256 device key...");
+    let material = generate(common_name)?;
+    nvs.set_str(KEY_KEY, &material.private_key_pem)?;
+    nvs.set_str(CSR_KEY, &material.csr_pem)?;
+    log::info!("Device key generated, CSR:\n{}", material.csr_pem);
+
+    Ok(material)
+}
+
+/// Certificate issued for the on-device key, if one was installed.
+/// Whether an earlier boot generated the device key. Safe to call before WiFi.
+pub fn has_key(partition: EspDefaultNvsPartition) -> Result<bool, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    Ok(nvs.contains(KEY_KEY)? && nvs.contains(CSR_KEY)?)
+}
+
+pub fn load_certificate(partition: EspDefaultNvsPartition) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, false)?;
+    get_string(&nvs, CERT_KEY)
+}
+
+/// Store the certificate signed from our CSR (e.g. by `CreateCertificateFromCsr`).
+pub fn store_certificate(partition: EspDefaultNvsPartition, certificate_pem: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !certificate_pem.trim_start().starts_with("-----BEGIN CERTIFICATE-----") {
+        return Err("Payload is not a PEM certificate".into());
+    }
+
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    if get_string(&nvs, KEY_KEY)?.is_none() {
+        return Err("No on-device key to pair the certificate with".into());
+    }
+    // Kept as a fallback identity in case the new one is rejected
+    if let Some(current) = get_string(&nvs, CERT_KEY)?.filter(|current| current != certificate_pem) {
+        nvs.set_str(PREVIOUS_CERT_KEY, &current)?;
+    }
+    nvs.set_str(CERT_KEY, certificate_pem)?;
+    Ok(())
+}
+
+/// Device key generated by [`load_or_generate`], if there is one.
+pub fn load_private_key(partition: EspDefaultNvsPartition) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, false)?;
+    get_string(&nvs, KEY_KEY)
+}
+
+/// Replace the identity for a move to another account: `certificate` with
+/// `private_key`, or with the on-device key when there is none. The
+/// certificate replaced isn't kept as a fallback, the old account's broker
+/// is no longer used.
+pub fn replace_identity(
+    partition: EspDefaultNvsPartition,
+    certificate_pem: &str,
+    private_key_pem: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    match private_key_pem {
+        Some(private_key_pem) => {
+            nvs.set_str(KEY_KEY, private_key_pem)?;
+            // Signed by the key just replaced
+            nvs.remove(CSR_KEY)?;
+        }
+        None if get_string(&nvs, KEY_KEY)?.is_none() => {
+            return Err("No on-device key to pair the certificate with".into());
+        }
+        None => {}
+    }
+    nvs.remove(PREVIOUS_CERT_KEY)?;
+    nvs.set_str(CERT_KEY, certificate_pem)?;
+    Ok(())
+}
+
+/// On-device certificate and key, when both are available.
+pub fn load_credentials(partition: EspDefaultNvsPartition) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, false)?;
+    match (get_string(&nvs, CERT_KEY)?, get_string(&nvs, KEY_KEY)?) {
+        (Some(cert), Some(key)) => Ok(Some((cert, key))),
+        _ => Ok(None),
+    }
+}
+
+/// Certificate replaced by the last `store_certificate`, with the on-device key.
+pub fn load_previous_credentials(
+    partition: EspDefaultNvsPartition,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let nvs = EspNvs::new(partition, NAMESPACE, false)?;
+    match (get_string(&nvs, PREVIOUS_CERT_KEY)?, get_string(&nvs, KEY_KEY)?) {
+        (Some(cert), Some(key)) => Ok(Some((cert, key))),
+        _ => Ok(None),
+    }
+}
+
+fn get_string(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut buf = vec![0u8; 2048];
+    Ok(nvs.get_str(key, &mut buf)?.map(str::to_string))
+}
+
+fn generate(common_name: &str) -> Result<KeyMaterial, Box<dyn std::error::Error>> {
+    let subject = CString::new(format!("CN={}", common_name))?;
+    let mut key_buf = vec![0u8; 512];
+    let mut csr_buf = vec![0u8; 1024];
+
+    unsafe {
+        let mut pk: sys::mbedtls_pk_context = std::mem::zeroed();
+        let mut csr: sys::mbedtls_x509write_csr = std::mem::zeroed();
+        sys::mbedtls_pk_init(&mut pk);
+        sys::mbedtls_x509write_csr_init(&mut csr);
+
+        let result = (|| {
+            check(sys::mbedtls_pk_setup(
+                &mut pk,
+                sys::mbedtls_pk_info_from_type(sys::mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+            ), "pk_setup")?;
+            // mbedtls_pk_ec() is an inline accessor for this field
+            check(sys::mbedtls_ecp_gen_key(
+                sys::mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                pk.private_pk_ctx as *mut sys::mbedtls_ecp_keypair,
+                Some(hardware_rng),
+                ptr::null_mut(),
+            ), "ecp_gen_key")?;
+            check(sys::mbedtls_pk_write_key_pem(&mut pk, key_buf.as_mut_ptr(), key_buf.len()), "write_key_pem")?;
+
+            check(sys::mbedtls_x509write_csr_set_subject_name(&mut csr, subject.as_ptr()), "csr_set_subject_name")?;
+            sys::mbedtls_x509write_csr_set_key(&mut csr, &mut pk);
+            sys::mbedtls_x509write_csr_set_md_alg(&mut csr, sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            check(sys::mbedtls_x509write_csr_pem(
+                &mut csr,
+                csr_buf.as_mut_ptr(),
+                csr_buf.len(),
+                Some(hardware_rng),
+                ptr::null_mut(),
+            ), "csr_pem")
+        })();
+
+        sys::mbedtls_x509write_csr_free(&mut csr);
+        sys::mbedtls_pk_free(&mut pk);
+        result?;
+    }
+
+    Ok(KeyMaterial {
+        private_key_pem: pem_from_buf(&key_buf)?,
+        csr_pem: pem_from_buf(&csr_buf)?,
+    })
+}
+
+unsafe extern "C" fn hardware_rng(_ctx: *mut c_void, output: *mut c_uchar, len: usize) -> c_int {
+    sys::esp_fill_random(output as *mut c_void, len);
+    0
+}
+
+fn check(ret: c_int, operation: &str) -> Result<(), String> {
+    if ret != 0 {
+        return Err(format!("mbedtls {} failed: -0x{:04x}", operation, -ret));
+    }
+    Ok(())
+}
+
+fn pem_from_buf(buf: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(CStr::from_bytes_until_nul(buf)?.to_str()?.to_string())
+}
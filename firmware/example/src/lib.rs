@@ -0,0 +1,82 @@
+//! Shared library behind the firmware binaries: the subsystems, and the
+//! application profiles composed from them. Each binary only picks a profile:
+//! `example` runs the always-on loop (or the battery profile enabled in
+//! cfg.toml), `cold_chain` and `contact` run their battery profile directly.
+
+pub mod alarm_events;
+pub mod alarms;
+pub mod audio;
+pub mod auth;
+pub mod battery;
+pub mod bench;
+pub mod boot;
+pub mod bootstrap;
+pub mod bridge;
+pub mod build_info;
+#[cfg(feature = "camera")]
+pub mod camera;
+pub mod chaos;
+pub mod client;
+pub mod clock;
+pub mod cold_chain;
+pub mod console;
+pub mod contact;
+pub mod dead_letter;
+pub mod dedup;
+pub mod defender;
+pub mod delivery;
+pub mod device_advisor;
+pub mod diagnostics;
+pub mod doh;
+pub mod echo;
+pub mod efuse;
+pub mod energy;
+pub mod envelope;
+pub mod estop;
+pub mod events;
+pub mod factory;
+pub mod flags;
+pub mod gnss;
+pub mod greengrass;
+pub mod health;
+pub mod heartbeat;
+#[cfg(feature = "heap-trace")]
+pub mod heap_trace;
+pub mod identity;
+pub mod irrigation;
+pub mod jobs;
+pub mod json_limits;
+pub mod keygen;
+pub mod limits;
+pub mod middleware;
+pub mod migration_plan;
+pub mod migrations;
+pub mod motion;
+#[cfg(feature = "mqtt5")]
+pub mod mqtt5;
+pub mod netstats;
+pub mod offline_queue;
+pub mod ota;
+pub mod prometheus;
+pub mod provenance;
+pub mod publish_queue;
+pub mod reassembly;
+pub mod reconnect;
+pub mod reprovision;
+pub mod request;
+pub mod retry;
+pub mod router;
+pub mod rtc;
+pub mod schema;
+pub mod secrets;
+pub mod services;
+pub mod shadow;
+pub mod sigv4;
+pub mod soak;
+pub mod startup;
+pub mod template;
+pub mod template_rules;
+pub mod timer;
+pub mod tls_observer;
+pub mod topics;
+pub mod traffic;
@@ -0,0 +1,15 @@
+use crate::middleware::Middleware;
+use std::error::Error;
+
+pub use crate::json_limits::{JsonLimits, LimitViolation};
+
+impl Middleware for JsonLimits {
+    fn name(&self) -> &'static str {
+        "limits"
+    }
+
+    fn on_receive(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.check(&payload)?;
+        Ok(payload)
+    }
+}
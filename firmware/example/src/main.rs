@@ -1,88 +1,1634 @@
-pub mod client;
-pub mod startup;
+#[cfg(feature = "camera")]
+use example::camera;
+#[cfg(feature = "heap-trace")]
+use example::heap_trace;
+use example::{
+    alarms, audio, auth, bench, boot, bootstrap, bridge, build_info, chaos, client, clock,
+    cold_chain, console, contact, dead_letter, defender, delivery, device_advisor, diagnostics,
+    doh, energy, envelope, estop, events, gnss, greengrass, health, heartbeat, irrigation, jobs,
+    keygen, middleware, motion, ota, prometheus, provenance, reprovision, retry, schema, secrets,
+    services, shadow, soak, startup, timer, tls_observer, traffic,
+};
+use client::ConnState;
+use dead_letter::DeadLetter;
+use delivery::Outcome;
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+use events::Event;
+use health::HealthMonitor;
+use heartbeat::Heartbeat;
+use jobs::Jobs;
 use log::*;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use services::Service;
+use shadow::Shadow;
+use soak::Soak;
 use startup::App;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use timer::PeriodicTimer;
 
+/// A command, or the response to one. A `request_id` on the command is
+/// echoed on its response so the sender can pair them.
 #[derive(Serialize, Deserialize, Debug)]
 struct JsonMessage {
     message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Envelope fields of an incoming command other than the action itself.
+#[derive(Deserialize, Debug)]
+struct CommandMeta {
+    /// When the command was issued, in milliseconds since the Unix epoch
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+/// Response to a command that doesn't match its schema.
+#[derive(Serialize, Debug)]
+struct CommandRejected<'a> {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    errors: &'a [schema::FieldError],
+}
+
+#[derive(Serialize, Debug)]
+struct AuditEvent<'a> {
+    event: &'static str,
+    action: &'static str,
+    reason: String,
+    command: &'a str,
+}
+
+/// Whether a command is recent enough to run, with `command_max_age_secs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommandAge {
+    /// Within the max age, without a timestamp, or no max age set
+    Fresh,
+    /// Older than the max age, by this many milliseconds
+    Stale(u64),
+    /// Timestamped, but the clock isn't set yet to tell its age
+    Unknown,
+}
+
+fn command_age(raw_data: &[u8], max_age_secs: u64) -> CommandAge {
+    if max_age_secs == 0 {
+        return CommandAge::Fresh;
+    }
+    let Some(issued) = serde_json::from_slice::<CommandMeta>(raw_data).ok().and_then(|meta| meta.timestamp) else {
+        return CommandAge::Fresh;
+    };
+    let Some(now) = clock::now_ms() else {
+        return CommandAge::Unknown;
+    };
+    let age = now.saturating_sub(issued);
+    if age > max_age_secs * 1000 {
+        CommandAge::Stale(age)
+    } else {
+        CommandAge::Fresh
+    }
+}
+
+/// Timestamped commands held until SNTP sets the clock; past this the
+/// oldest is dropped.
+const MAX_HELD_COMMANDS: usize = 16;
+
+/// Topic reported for commands from the LAN console, e.g. in dead letters.
+const CONSOLE_TOPIC: &str = "console";
+
+/// Alarm raised while the hardware e-stop is tripped.
+const ESTOP_ALARM: &str = "estop";
+
+/// WiFi outage used to rebuild a connection whose heartbeats stopped
+/// getting through.
+const HEARTBEAT_RECOVERY_OUTAGE: Duration = Duration::from_secs(5);
+
+/// Longest fleet backoff a broadcast can ask for.
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 3600);
+const MAX_BACKOFF_FACTOR: u32 = 60;
+
+/// `{"message": "backoff", "factor": 4, "duration_secs": 1800}` on the
+/// broadcast topic: publish periodic reports `factor` times less often.
+/// A factor of 1 ends the backoff early.
+#[derive(Deserialize, Debug)]
+struct BackoffBroadcast {
+    factor: u32,
+    duration_secs: u64,
+    /// When the backend sent it, so a retained broadcast only covers what
+    /// is left of its duration
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IrrigateCommand {
+    zone: usize,
+    minutes: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct AlarmAckCommand {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CertificatePayload {
+    certificate: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServiceCommand {
+    name: String,
+    enabled: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Telemetry {
+    uptime_secs: u64,
+    /// Serial number burned into eFuse, so reports stay attributable after
+    /// a device is renamed or reflashed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial: Option<String>,
+    free_heap: u32,
+    retries: std::collections::BTreeMap<&'static str, u32>,
+    messages: middleware::MessageStats,
+    /// QoS 1 publishes not acknowledged since boot, with delivery tracking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    undelivered: Option<u32>,
+    /// Publishes the publish queue dropped on overflow since boot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_dropped: Option<u64>,
+    /// Telemetry publishes that failed since boot, e.g. timed out waiting
+    /// for room in the publish queue
+    #[serde(skip_serializing_if = "Option::is_none")]
+    telemetry_failed: Option<u32>,
+    /// Round trip of the last loopback ping, with the health check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broker_rtt_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<gnss::Fix>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    motion: Option<motion::MotionSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sound: Option<audio::LevelStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy: Option<energy::Reading>,
+    /// How far off `timestamp` may be, with clock drift compensation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_error_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct EnergyEvent {
+    event: &'static str,
+    #[serde(flatten)]
+    reading: energy::Reading,
+}
+
+#[derive(Serialize, Debug)]
+struct SoundAlert {
+    event: &'static str,
+    threshold_dbfs: f32,
+    #[serde(flatten)]
+    level: audio::Level,
+}
+
+#[derive(Serialize, Debug)]
+struct MetricsReport<'a> {
+    event: &'static str,
+    uptime_secs: u64,
+    endpoint: &'a str,
+    #[serde(flatten)]
+    traffic: traffic::TrafficStats,
+}
+
+#[derive(Serialize, Debug)]
+struct MotionAlert {
+    event: &'static str,
+    #[serde(flatten)]
+    motion: motion::MotionEvent,
+}
+
+#[derive(Serialize, Debug)]
+struct LocationEvent<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    fix: &'a gnss::Fix,
+}
+
+#[derive(Serialize, Debug)]
+struct GeofenceEvent<'a> {
+    event: &'static str,
+    transition: &'static str,
+    #[serde(flatten)]
+    fix: &'a gnss::Fix,
+}
+
+/// `led` in the shadow, desired and reported.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LedState {
+    On,
+    Off,
+}
+
+/// Device facts kept in the shadow's reported state.
+#[derive(Serialize, Debug)]
+struct ReportedDevice<'a> {
+    firmware_version: &'a str,
+    hardware_revision: &'a str,
+    device_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufactured: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct TlsCertEvent<'a> {
+    event: &'static str,
+    fingerprint: &'a str,
+    issuer: &'a str,
+    subject: &'a str,
+    change: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_fingerprint: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct IdentityFallbackEvent<'a> {
+    event: &'static str,
+    reason: &'static str,
+    /// Identity that connected
+    identity: &'static str,
+    /// Identity selected by `auth_mode`, which was rejected
+    primary: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct StartupEvent {
+    event: &'static str,
+    /// From the start of startup to the first broker connection
+    connected_ms: u32,
+    #[serde(flatten)]
+    report: boot::StartupReport,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // It is necessary to call this function once. Otherwise some patches to the runtime
-    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
-    esp_idf_svc::sys::link_patches();
+    startup::init_runtime();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Battery sensor profiles: sample, maybe upload, deep sleep
+    let config = startup::config();
+    if config.cold_chain_enabled {
+        let nvs = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
+        cold_chain::run(nvs, &config);
+    }
+    if config.contact_enabled {
+        let nvs = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
+        contact::run(nvs, &config);
+    }
 
     // This sets the wifi and creates MQTT client
     let mut app = App::new()?;
 
+    // Subscribe before starting the listener so no connection event is missed
+    let event_receiver = app.events.subscribe(16);
+
+    // Status LED lit while the broker connection is up
+    if app.config.status_led_pin >= 0 {
+        let mut led = PinDriver::output(unsafe { AnyOutputPin::new(app.config.status_led_pin) })?;
+        led.set_low()?;
+        app.client.lock().on_connection_change(move |state| {
+            let lit = match state {
+                ConnState::Connected => led.set_high(),
+                ConnState::Disconnected => led.set_low(),
+            };
+            if let Err(e) = lit {
+                warn!("Failed to set the status LED: {}", e);
+            }
+        });
+    }
+
+    // LED switched through the shadow, off until the desired state says otherwise
+    let mut led = match (app.config.led_pin >= 0, app.config.shadow_enabled) {
+        (true, true) => {
+            let mut led = PinDriver::output(unsafe { AnyOutputPin::new(app.config.led_pin) })?;
+            led.set_low()?;
+            Some(led)
+        }
+        (true, false) => {
+            warn!("led_pin needs shadow_enabled, leaving the LED off");
+            None
+        }
+        _ => None,
+    };
+
     // Start non-blocking message listener
-    let message_receiver = app.client.start_message_listener()?;
+    let message_receiver = app.client.lock().start_message_listener()?;
 
-    // Subscribe to topic
-    app.client.subscribe()?;
+    let reserved_receiver = app
+        .client
+        .lock()
+        .take_reserved_receiver()
+        .ok_or("Reserved topic receiver already taken")?;
+
+    // Subscribe to topic; the client restores its subscriptions after a reconnect
+    app.client.lock().subscribe()?;
+    if !app.config.broadcast_topic.is_empty() {
+        app.client.subscribe_topic(app.config.broadcast_topic)?;
+    }
+
+    let diff_reports = |shadow: Shadow| {
+        if app.config.shadow_diff_reports {
+            shadow.with_diff_reports(
+                Duration::from_secs(app.config.shadow_full_report_mins * 60),
+                app.config.mqtt_client_id,
+            )
+        } else {
+            shadow
+        }
+    };
+    let mut shadow = app.config.shadow_enabled.then(|| {
+        diff_reports(Shadow::new(
+            app.config.thing_name(),
+            Duration::from_millis(app.config.shadow_get_timeout_ms),
+            app.client.clone(),
+            app.events.clone(),
+        ))
+    });
+    let mut named_shadows: Vec<Shadow> = if app.config.shadow_enabled {
+        app.config
+            .shadow_names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                diff_reports(Shadow::named(
+                    app.config.thing_name(),
+                    name,
+                    Duration::from_millis(app.config.shadow_get_timeout_ms),
+                    app.client.clone(),
+                    app.events.clone(),
+                ))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let firmware_shadow = |app: &App| {
+        Shadow::named(
+            app.config.thing_name(),
+            "firmware",
+            Duration::from_millis(app.config.shadow_get_timeout_ms),
+            app.client.clone(),
+            app.events.clone(),
+        )
+    };
+    let rollout_shadow = app.config.firmware_shadow.then(|| firmware_shadow(&app));
+    // Only reported to, for dashboards
+    let alarm_shadow = (app.alarms.is_some() && app.config.shadow_enabled).then(|| {
+        Shadow::named(
+            app.config.thing_name(),
+            "alarms",
+            Duration::from_millis(app.config.shadow_get_timeout_ms),
+            app.client.clone(),
+            app.events.clone(),
+        )
+    });
+
+    let mut jobs = app.config.jobs_enabled.then(|| {
+        let mut jobs = Jobs::new(app.config.thing_name(), app.client.clone());
+        jobs.register(jobs::Reboot);
+        match ota::OtaUpdate::new(app.config.hardware_revision, app.events.clone())
+            .map(|ota| ota.with_retry_policy(app.config.retry_policy()))
+        {
+            Some(ota) if app.config.firmware_shadow => {
+                jobs.register(ota.with_rollout_shadow(firmware_shadow(&app)))
+            }
+            Some(ota) => jobs.register(ota),
+            None => warn!("Built without ota_public_key, OTA jobs will be rejected"),
+        }
+        jobs
+    });
+
+    let mut defender = (app.config.defender_interval_secs > 0)
+        .then(|| defender::Defender::new(app.config.thing_name(), app.client.clone()));
+    let mut defender_timer = PeriodicTimer::new(
+        Duration::from_secs(app.config.defender_interval_secs),
+        app.config.mqtt_client_id,
+        "defender",
+    );
+
+    let mut metrics_timer = PeriodicTimer::new(
+        Duration::from_secs(app.config.metrics_interval_mins * 60),
+        app.config.mqtt_client_id,
+        "metrics",
+    );
+    let metrics_topic = format!("{}/metrics", app.config.mqtt_topic_pub);
+
+    let started = Instant::now();
+    let mut telemetry_timer = PeriodicTimer::new(
+        Duration::from_secs(app.config.telemetry_interval_secs),
+        app.config.mqtt_client_id,
+        "telemetry",
+    );
+
+    let mut dead_letter = DeadLetter::new(
+        app.config.dead_letter_topic(),
+        app.config.dead_letter_max_per_min,
+    );
+
+    let mut gnss_timer = PeriodicTimer::new(
+        Duration::from_secs(app.config.gnss_report_interval_secs),
+        app.config.mqtt_client_id,
+        "gnss",
+    );
+    let mut movement = gnss::MovementFilter::new(app.config.gnss_min_move_m);
+    let mut geofence = (app.config.geofence_radius_m > 0.0).then(|| {
+        gnss::Geofence::new(
+            app.config.geofence_lat,
+            app.config.geofence_lon,
+            app.config.geofence_radius_m,
+        )
+    });
+
+    let mut energy_timer = PeriodicTimer::new(
+        Duration::from_secs(app.config.energy_interval_secs),
+        app.config.mqtt_client_id,
+        "energy",
+    );
+
+    let mut soak = app.config.soak_enabled.then(|| Soak::new(&app));
+    if soak.is_some() {
+        warn!("Soak test mode: publishing continuously and forcing reconnects");
+    }
+
+    let mut failover = (app.config.bridge_failover_after > 0).then(|| bridge::Failover::new(&app));
+    let mut doh = (!app.config.doh_url.is_empty())
+        .then(|| doh::DohFallback::new(app.config.doh_url, app.config.doh_after));
+
+    let delivery_timeout = Duration::from_secs(app.config.delivery_timeout_secs);
+    let mut heartbeat = (app.config.heartbeat_interval_secs > 0).then(|| {
+        Heartbeat::new(
+            app.config.heartbeat_topic(),
+            Duration::from_secs(app.config.heartbeat_interval_secs),
+            app.config.mqtt_client_id,
+            app.config.heartbeat_max_missed,
+        )
+    });
+
+    let mut health = (app.config.health_interval_secs > 0).then(|| {
+        HealthMonitor::new(
+            app.config.health_topic(),
+            Duration::from_secs(app.config.health_interval_secs),
+            Duration::from_millis(app.config.health_timeout_ms),
+            app.config.mqtt_client_id,
+        )
+    });
+    if let Some(health) = health.as_ref() {
+        health.subscribe(&app.client)?;
+    }
+
+    // Subscribed before the first connect, so the retained configuration
+    // arrives right after it
+    let mut bootstrap_pending = !app.config.bootstrap_topic.is_empty();
+    if bootstrap_pending {
+        bootstrap::subscribe(&app.client, app.config.bootstrap_topic, app.events.clone())?;
+    }
+
+    let mut device_advisor = app.config.device_advisor().then(|| {
+        device_advisor::DeviceAdvisor::new(
+            app.config.device_advisor_topic().to_string(),
+            Duration::from_secs(app.config.device_advisor_interval_secs),
+            app.config.mqtt_client_id,
+        )
+    });
+    if let Some(device_advisor) = device_advisor.as_ref() {
+        device_advisor.subscribe(&app.client)?;
+    }
+
+    // The heartbeat needs the reports too, to see its PUBACKs
+    let deliveries = (!delivery_timeout.is_zero() || heartbeat.is_some())
+        .then(|| app.client.lock().track_deliveries(16));
+    let mut undelivered = 0;
+    let mut telemetry_failed = 0;
+
+    // Retained commands replayed on the first connect arrive before SNTP has
+    // synced, so their age is only known later
+    let mut held_commands: VecDeque<(String, Vec<u8>)> = VecDeque::new();
+    let mut restart_pending = false;
+    let mut fallback_reported = false;
+    let mut startup_reported = false;
+    let mut provenance_published = app.config.provenance_topic.is_empty();
+    let mut flags_generation = app.flags.generation();
 
     info!("Starting main application loop");
 
     // Main application loop - non-blocking
     loop {
-        // Check for MQTT messages without blocking
-        match message_receiver.try_recv() {
-            Ok(raw_data) => {
-                // Try to parse as JSON first
-                match serde_json::from_slice::<JsonMessage>(&raw_data) {
-                    Ok(msg) => {
-                        info!("Received JSON message - action: {}", msg.message);
-
-                        // Handle specific actions
-                        let response = match msg.message.as_str() {
-                            "ping" => {
-                                info!("Ping received, sending pong");
-                                JsonMessage {
-                                    message: format!("pong from: {}", app.config.mqtt_client_id),
-                                }
-                            }
-                            _ => {
-                                warn!("Unknown action: {}", msg.message);
-                                JsonMessage {
-                                    message: format!("Unknown action: {}", msg.message),
-                                }
-                            }
-                        };
+        // Reserved topics first, so a shadow delta's event is handled below
+        // before commands are accepted again
+        while let Ok((topic, payload)) = reserved_receiver.try_recv() {
+            if topic == app.config.broadcast_topic {
+                let mut timers = [&mut telemetry_timer, &mut gnss_timer, &mut energy_timer];
+                if let Err(e) = apply_broadcast(&payload, &mut timers) {
+                    warn!("Invalid broadcast: {}", e);
+                }
+                continue;
+            }
+            let handled = shadow
+                .iter_mut()
+                .chain(named_shadows.iter_mut())
+                .any(|shadow| shadow.handle(&topic, &payload))
+                || jobs
+                    .as_mut()
+                    .is_some_and(|jobs| jobs.handle(&topic, &payload))
+                || defender
+                    .as_mut()
+                    .is_some_and(|defender| defender.handle(&topic, &payload));
+            if !handled {
+                debug!("Unhandled message on reserved topic \"{}\"", topic);
+            }
+        }
 
-                        // Send JSON response
-                        let json_response = serde_json::to_string(&response)?;
-                        app.client.publish(&json_response)?;
-                        info!("Sent response: {}", json_response);
-                    }
-                    Err(_) => {
-                        // Fallback for non-JSON messages
-                        let message_text = String::from_utf8_lossy(&raw_data);
-                        info!("Received non-JSON message: {}", message_text);
+        // Restore subscriptions after a reconnect, run topic handlers
+        app.client.lock().poll();
 
-                        let response = JsonMessage {
-                            message: format!("Received plain text: {}", message_text),
+        while let Ok(event) = event_receiver.try_recv() {
+            match event {
+                Event::MqttConnected => {
+                    info!("Broker connection is up on {}", app.client.lock().endpoint_address());
+                    if let Err(e) = app.client.lock().publish_online() {
+                        error!("Failed to publish presence: {}", e);
+                    }
+                    if app.tls_probe.is_some() {
+                        if let Err(e) = report_server_certificate(&mut app) {
+                            warn!("Failed to inspect broker certificate: {}", e);
+                        }
+                    }
+                    if !startup_reported {
+                        startup_reported = true;
+                        if let Err(e) = report_startup(&mut app) {
+                            error!("Failed to publish the startup report: {}", e);
+                        }
+                    }
+                    if !provenance_published {
+                        let topic = app.config.provenance_topic;
+                        match provenance::publish_once(&app.client, app.nvs.clone(), &app.device_id, topic) {
+                            Ok(()) => provenance_published = true,
+                            Err(e) => error!("Failed to publish the build provenance: {}", e),
+                        }
+                    }
+                    // Reaching the broker is what proves a new image good
+                    if let Err(e) = ota::mark_valid() {
+                        error!("Failed to confirm the running image: {}", e);
+                    }
+                    if app.identity.is_fallback() && !fallback_reported {
+                        match report_identity_fallback(&mut app) {
+                            Ok(()) => fallback_reported = true,
+                            Err(e) => error!("Failed to report fallback identity: {}", e),
+                        }
+                    }
+                    if !app.client.lock().topic_aliases().is_empty() {
+                        if let Err(e) = announce_topic_aliases(&mut app) {
+                            error!("Failed to announce topic aliases: {}", e);
+                        }
+                    }
+                    if let Some(soak) = soak.as_mut() {
+                        soak.record_connect();
+                    }
+                    if let Some(shadow) = shadow.as_mut() {
+                        if let Err(e) = shadow.bootstrap() {
+                            error!("Failed to request shadow: {}", e);
+                        }
+                        let device = ReportedDevice {
+                            firmware_version: ota::FIRMWARE_VERSION,
+                            hardware_revision: app.config.hardware_revision,
+                            device_id: &app.device_id,
+                            serial: app
+                                .hardware
+                                .as_ref()
+                                .map(|hardware| hardware.serial.as_str())
+                                .filter(|serial| !serial.is_empty()),
+                            manufactured: app.hardware.as_ref().and_then(|hardware| hardware.manufactured.as_deref()),
                         };
+                        if let Err(e) = shadow.report(&device) {
+                            error!("Failed to report device state: {}", e);
+                        }
+                        if let Err(e) = shadow.report(&serde_json::json!({ "flags": app.flags.values() })) {
+                            error!("Failed to report feature flags: {}", e);
+                        }
+                        if let Some(led) = led.as_ref() {
+                            if let Err(e) = shadow.report(&serde_json::json!({ "led": led_state(led) })) {
+                                error!("Failed to report the LED: {}", e);
+                            }
+                        }
+                    }
+                    for shadow in named_shadows.iter_mut() {
+                        if let Err(e) = shadow.bootstrap() {
+                            error!("Failed to request shadow \"{}\": {}", shadow.name().unwrap_or_default(), e);
+                        }
+                    }
+                    if let Some(rollout_shadow) = rollout_shadow.as_ref() {
+                        if let Err(e) = ota::report_running(rollout_shadow) {
+                            error!("Failed to report firmware version: {}", e);
+                        }
+                    }
+                    if let Err(e) = report_alarms(&app, alarm_shadow.as_ref()) {
+                        error!("Failed to report alarms: {}", e);
+                    }
+                    if let Some(jobs) = jobs.as_mut() {
+                        if let Err(e) = jobs.bootstrap() {
+                            error!("Failed to request pending jobs: {}", e);
+                        }
+                    }
+                    if let Some(defender) = defender.as_mut() {
+                        if let Err(e) = defender.bootstrap() {
+                            error!("Failed to subscribe to Device Defender responses: {}", e);
+                        }
+                    }
+                }
+                Event::MqttDisconnected => warn!("Broker connection lost, waiting for reconnect"),
+                Event::ShadowDelta(delta) => {
+                    info!("Shadow delta: {}", delta);
+                    apply_desired_state(&mut app, led.as_mut(), shadow.as_ref(), &delta);
+                }
+                // Once per boot: a redelivery after a reconnect would undo
+                // what the shadow changed since
+                Event::BootstrapConfig(config) if bootstrap_pending => {
+                    info!("Bootstrap configuration: {}", config);
+                    apply_desired_state(&mut app, led.as_mut(), shadow.as_ref(), &config);
+                    bootstrap_pending = false;
+                    if let Err(e) = app.client.unsubscribe(app.config.bootstrap_topic) {
+                        error!("Failed to unsubscribe from the bootstrap topic: {}", e);
+                    }
+                }
+                Event::NamedShadowDelta { name, delta } => info!("Shadow \"{}\" delta: {}", name, delta),
+                other => debug!("Event: {:?}", other),
+            }
+        }
+
+        // Hold commands until the shadow bootstrap has applied pending state
+        for shadow in shadow.iter_mut().chain(named_shadows.iter_mut()) {
+            shadow.poll();
+        }
+        let accepting_commands = shadow.iter().chain(named_shadows.iter()).all(Shadow::is_running);
+        let next_message = if !accepting_commands {
+            None
+        } else if let Some(held) = clock::now_ms().and_then(|_| held_commands.pop_front()) {
+            Some(held)
+        } else {
+            message_receiver.try_recv().ok().or_else(|| {
+                let console = app.console.as_ref()?;
+                console.try_recv().map(|frame| (CONSOLE_TOPIC.to_string(), frame))
+            })
+        };
 
-                        let json_response = serde_json::to_string(&response)?;
-                        app.client.publish(&json_response)?;
+        // Check for MQTT messages without blocking
+        match next_message {
+            Some(message) if command_age(&message.1, app.config.command_max_age_secs) == CommandAge::Unknown => {
+                debug!("Holding a command on \"{}\" until the clock is set", message.0);
+                if held_commands.len() >= MAX_HELD_COMMANDS {
+                    if let Some((_, raw_data)) = held_commands.pop_front() {
+                        if let Err(e) = audit_dropped_command(&mut app, &raw_data, "clock not set".to_string()) {
+                            error!("Failed to report a dropped command: {}", e);
+                        }
+                    }
+                }
+                held_commands.push_back(message);
+            }
+            Some((topic, raw_data)) => {
+                debug!("Message on \"{}\"", topic);
+                if let Err(e) = handle_message(&mut app, shadow.as_ref(), &raw_data, &mut restart_pending) {
+                    error!("Failed to handle message on \"{}\": {}", topic, e);
+                    if let Some(soak) = soak.as_mut() {
+                        soak.record_handler_error();
                     }
+                    dead_letter.record(&app.client, &app.device_id, &topic, &raw_data, &e.to_string());
                 }
             }
-            Err(_) => {
+            None => {
                 // No message received, continue with other tasks
             }
         }
 
+        // A shadow delta or the `service` command switched services
+        if app.flags.generation() != flags_generation {
+            flags_generation = app.flags.generation();
+            reconcile_services(&mut app, shadow.iter_mut().chain(named_shadows.iter_mut()));
+        }
+
+        // Never connected with this identity: try the next one after a restart
+        if app.config.cert_fallback_after > 0
+            && app.bridge.is_none()
+            && app.greengrass.is_none()
+            && app.identity.count > 1
+            && app.client.lock().failed_attempts() >= app.config.cert_fallback_after
+            && !restart_pending
+        {
+            warn!(
+                "Identity {} failed {} connection attempts, falling back",
+                app.identity.name,
+                app.client.lock().failed_attempts()
+            );
+            match auth::fall_back(app.nvs.clone(), &app.identity) {
+                Ok(()) => restart_pending = true,
+                Err(e) => error!("Failed to select the fallback identity: {}", e),
+            }
+        }
+
+        // Greengrass core unreachable: restart onto AWS IoT for one boot
+        if let (Some(core), false) = (app.greengrass.as_ref(), restart_pending) {
+            let failures = app.client.lock().failures_since_connect();
+            if failures >= app.config.greengrass_fallback_after.max(1) {
+                warn!("Greengrass core {} failed {} connection attempts, falling back to AWS IoT", core, failures);
+                match greengrass::fall_back(app.nvs.clone()) {
+                    Ok(()) => restart_pending = true,
+                    Err(e) => error!("Failed to fall back from the Greengrass core: {}", e),
+                }
+            }
+        }
+
+        // Broker keeps failing: resolve its host through DNS-over-HTTPS
+        if let Some(doh) = doh.as_mut() {
+            doh.poll(&app.client);
+        }
+
+        // AWS IoT unreachable: restart onto a local bridge, and back once it returns
+        if let (Some(failover), false) = (failover.as_mut(), restart_pending) {
+            match failover.poll(app.client.lock().failures_since_connect()) {
+                Ok(switch) => restart_pending = switch,
+                Err(e) => error!("Bridge failover failed: {}", e),
+            }
+        }
+
+        if restart_pending || jobs.as_ref().is_some_and(Jobs::restart_pending) {
+            // Give the response a moment to leave before rebooting
+            std::thread::sleep(Duration::from_secs(2));
+            unsafe { esp_idf_svc::sys::esp_restart() };
+        }
+
+        if let Some(deliveries) = deliveries.as_ref() {
+            if !delivery_timeout.is_zero() {
+                app.client.lock().expire_deliveries(delivery_timeout);
+            }
+            while let Ok(delivery) = deliveries.try_recv() {
+                if let Some(heartbeat) = heartbeat.as_mut() {
+                    heartbeat.delivered(&delivery);
+                }
+                if delivery.outcome == Outcome::Acked {
+                    debug!("Message {} acknowledged after {:?}", delivery.id, delivery.elapsed);
+                } else {
+                    undelivered += 1;
+                    warn!(
+                        "Message {} on \"{}\" not delivered ({:?} after {:?})",
+                        delivery.id, delivery.topic, delivery.outcome, delivery.elapsed
+                    );
+                }
+            }
+        }
+
+        if heartbeat
+            .as_mut()
+            .is_some_and(|heartbeat| heartbeat.poll(&app.client, &app.device_id))
+        {
+            warn!("Heartbeats aren't getting through although connected, cycling WiFi");
+            if let Err(e) = app.drop_wifi(HEARTBEAT_RECOVERY_OUTAGE) {
+                error!("Failed to recover the connection: {}", e);
+            }
+        }
+
+        if health.as_mut().is_some_and(|health| health.poll(&app.client)) {
+            warn!("Broker connection looks half-open, reconnecting");
+            if let Err(e) = app.client.lock().force_reconnect() {
+                error!("Failed to reconnect: {}", e);
+            }
+        }
+
+        if let Some(device_advisor) = device_advisor.as_mut() {
+            device_advisor.poll(&app.client);
+        }
+
+        // Periodic reports are skipped while offline, unless the offline
+        // queue keeps them for later
+        let online = app.client.can_publish();
+
+        if telemetry_timer.poll() && online && Service::Telemetry.wanted(&app.flags) {
+            let telemetry = Telemetry {
+                uptime_secs: started.elapsed().as_secs(),
+                serial: app
+                    .hardware
+                    .as_ref()
+                    .map(|hardware| hardware.serial.clone())
+                    .filter(|serial| !serial.is_empty()),
+                free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                retries: retry::retry_counts()
+                    .iter()
+                    .map(|(subsystem, count)| (subsystem.as_str(), *count))
+                    .collect(),
+                messages: app.metrics.stats(),
+                undelivered: (!delivery_timeout.is_zero()).then_some(undelivered),
+                queue_dropped: app.client.lock().publish_queue_dropped(),
+                telemetry_failed: (telemetry_failed > 0).then_some(telemetry_failed),
+                broker_rtt_ms: health
+                    .as_ref()
+                    .and_then(HealthMonitor::rtt)
+                    .map(|rtt| rtt.as_millis() as u32),
+                location: app.gnss.as_ref().and_then(|gnss| gnss.latest()),
+                motion: app.motion.as_ref().map(|motion| motion.summary()),
+                sound: app.microphone.as_mut().and_then(|microphone| microphone.take_stats()),
+                energy: app.energy.as_ref().and_then(|energy| energy.latest()),
+                clock_error_ms: clock::error_ms(),
+            };
+            let json_telemetry = app.template.render(envelope::to_json(&app.device_id, &telemetry)?)?;
+            let expiry = (app.config.telemetry_expiry_secs > 0)
+                .then(|| Duration::from_secs(app.config.telemetry_expiry_secs));
+            let sent = match app.config.telemetry_ingest_rule {
+                "" => app.client.publish_opts(
+                    app.config.mqtt_topic_pub,
+                    &json_telemetry,
+                    client::qos(app.config.mqtt_pub_qos)?,
+                    false,
+                    expiry,
+                ),
+                rule => app.client.publish_ingest(rule, app.config.mqtt_topic_pub, &json_telemetry, expiry),
+            };
+            match sent {
+                Ok(_) => info!("Sent telemetry: {}", json_telemetry),
+                Err(e) => {
+                    telemetry_failed += 1;
+                    error!("Failed to publish telemetry: {}", e);
+                }
+            }
+        }
+
+        if let Some(defender) = defender.as_mut().filter(|_| defender_timer.poll() && online) {
+            match defender.publish(&app.metrics.stats()) {
+                Ok(Some(report_id)) => info!("Sent Device Defender report {}", report_id),
+                Ok(None) => {}
+                Err(e) => error!("Failed to publish Device Defender report: {}", e),
+            }
+        }
+
+        if metrics_timer.poll() && online {
+            let json_metrics = {
+                let client = app.client.lock();
+                let report = MetricsReport {
+                    event: "metrics",
+                    uptime_secs: started.elapsed().as_secs(),
+                    endpoint: &client.endpoint_address(),
+                    traffic: client.traffic(),
+                };
+                envelope::to_json(&app.device_id, &report)?
+            };
+            match app.client.publish_to(&metrics_topic, &json_metrics) {
+                Ok(_) => info!("Sent metrics: {}", json_metrics),
+                Err(e) => error!("Failed to publish metrics: {}", e),
+            }
+        }
+
+        // Motion and tamper are published as soon as they happen rather than
+        // waiting for the next telemetry report
+        let motion_event = app.motion.as_mut().map(|motion| motion.poll()).transpose();
+        match motion_event {
+            Ok(Some(Some(motion))) => {
+                warn!("Motion detected: {:?}", motion);
+                let json_alert = envelope::to_json(&app.device_id, &MotionAlert { event: "motion", motion })?;
+                if let Err(e) = app.client.publish(&json_alert) {
+                    error!("Failed to publish motion event: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to read accelerometer: {}", e),
+        }
+
+        if let Some(level) = app.microphone.as_mut().and_then(|microphone| microphone.poll()) {
+            warn!("Sound level {:.1} dBFS above threshold", level.rms_dbfs);
+            let json_alert = envelope::to_json(
+                &app.device_id,
+                &SoundAlert {
+                    event: "sound_level",
+                    threshold_dbfs: app.config.audio_alert_dbfs,
+                    level,
+                },
+            )?;
+            if let Err(e) = app.client.publish(&json_alert) {
+                error!("Failed to publish sound alert: {}", e);
+            }
+        }
+
+        if let (true, Some(meter)) = (energy_timer.poll() && online, app.energy.as_mut()) {
+            match meter.read() {
+                Ok(reading) => {
+                    let json_event = envelope::to_json(&app.device_id, &EnergyEvent { event: "energy", reading })?;
+                    if let Err(e) = app.client.publish(&json_event) {
+                        error!("Failed to publish energy reading: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to read energy meter: {}", e),
+            }
+        }
+
+        // Before irrigation, so a trip is announced ahead of the zone closing
+        match app.estop.as_mut().map(|estop| estop.poll()).transpose() {
+            Ok(Some(true)) if online => {
+                if let Err(e) = publish_estop_event(&mut app, estop::EStopEvent::EstopTripped) {
+                    error!("Failed to publish e-stop alarm: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("E-stop step failed: {}", e),
+        }
+        // Cleared by estop_reset
+        if let (Some(estop), Some(alarms)) = (app.estop.as_ref(), app.alarms.as_mut()) {
+            if estop.is_tripped() && !alarms.is_active(ESTOP_ALARM) {
+                let raised = alarms.raise(ESTOP_ALARM, alarms::Severity::Critical, "estop", "Emergency stop tripped");
+                if let Err(e) = raised {
+                    error!("Failed to raise e-stop alarm: {}", e);
+                }
+            }
+        }
+
+        if online {
+            publish_alarm_events(&mut app, alarm_shadow.as_ref());
+        }
+
+        let irrigation_events = app.irrigation.as_mut().map(|irrigation| irrigation.poll()).transpose();
+        match irrigation_events {
+            Ok(Some(events)) => {
+                if let Err(e) = publish_irrigation_events(&mut app, shadow.as_ref(), &events) {
+                    error!("Failed to publish irrigation events: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("Irrigation step failed: {}", e),
+        }
+
+        let fix = app.gnss.as_ref().and_then(|gnss| gnss.latest());
+        if let (true, Some(fix)) = (gnss_timer.poll() && online, fix) {
+            if let Err(e) = report_location(&mut app, shadow.as_ref(), &mut movement, geofence.as_mut(), &fix) {
+                error!("Failed to report location: {}", e);
+            }
+        }
+
+        if let Some(soak) = soak.as_mut() {
+            if let Err(e) = soak.poll(&mut app) {
+                error!("Soak step failed: {}", e);
+            }
+        }
+
         // Add any other application logic here
 
         // Small delay to prevent busy waiting
         std::thread::sleep(Duration::from_millis(100));
     }
 }
+
+/// Publish an `audit` event for a command dropped unexecuted.
+fn audit_dropped_command(app: &mut App, raw_data: &[u8], reason: String) -> Result<(), Box<dyn std::error::Error>> {
+    let command = serde_json::from_slice::<JsonMessage>(raw_data).map(|msg| msg.message).unwrap_or_default();
+    warn!("Dropping stale command \"{}\": {}", command, reason);
+    let audit = AuditEvent {
+        event: "audit",
+        action: "drop_stale_command",
+        reason,
+        command: &command,
+    };
+    let json_audit = envelope::to_json(&app.device_id, &audit)?;
+    app.client.publish(&json_audit)?;
+    Ok(())
+}
+
+/// Handle one message from the command topic, publishing the response.
+fn handle_message(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    raw_data: &[u8],
+    restart_pending: &mut bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stale_age = match command_age(raw_data, app.config.command_max_age_secs) {
+        CommandAge::Stale(age) => Some(age),
+        _ => None,
+    };
+
+    // Commands are JSON objects; anything else falls through to the plain text reply
+    let command = serde_json::from_slice::<serde_json::Value>(raw_data).ok();
+    if let (None, Some(serde_json::Value::Object(command))) = (stale_age, &command) {
+        if let Err(errors) = schema::validate(command) {
+            let action = command.get("message").and_then(|action| action.as_str()).unwrap_or_default();
+            let reasons: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            warn!("Rejected command \"{}\": {}", action, reasons.join("; "));
+            let rejected = CommandRejected {
+                message: format!("Invalid command \"{}\": {}", action, reasons.join("; ")),
+                request_id: command.get("request_id").and_then(|id| id.as_str()),
+                errors: &errors,
+            };
+            app.client.publish(&envelope::to_json(&app.device_id, &rejected)?)?;
+            return Ok(());
+        }
+    }
+
+    // Try to parse as JSON first
+    match serde_json::from_slice::<JsonMessage>(raw_data) {
+        Ok(_) if stale_age.is_some() => {
+            // Typically a retained command replayed after reconnecting
+            let age = stale_age.unwrap_or_default();
+            let reason = format!("issued {} ms ago, max age {} s", age, app.config.command_max_age_secs);
+            audit_dropped_command(app, raw_data, reason)?;
+        }
+        Ok(msg) => {
+            info!("Received JSON message - action: {}", msg.message);
+
+            // Handle specific actions
+            let message = match msg.message.as_str() {
+                "ping" => {
+                    info!("Ping received, sending pong");
+                    format!("pong from: {}", app.config.mqtt_client_id)
+                }
+                "version" => build_info::report().to_string(),
+                "bench" => {
+                    let command = serde_json::from_slice::<bench::BenchCommand>(raw_data)?;
+                    let topic = format!("{}/bench", app.config.mqtt_topic_pub);
+                    let report = bench::run(&mut app.client.lock(), &topic, &command)?;
+                    info!("Benchmark finished: {:?}", report);
+                    serde_json::to_string(&report)?
+                }
+                "tasks.list" => serde_json::to_string(&diagnostics::tasks())?,
+                "conn.stats" => serde_json::to_string(&app.client.lock().connection_stats().snapshot())?,
+                #[cfg(feature = "camera")]
+                "snapshot" => take_snapshot(app, raw_data)?,
+                #[cfg(feature = "heap-trace")]
+                "heap_trace" => run_heap_trace(app, raw_data)?,
+                "irrigate" if app.irrigation.is_some() => {
+                    let command = serde_json::from_slice::<IrrigateCommand>(raw_data)?;
+                    let irrigation = app.irrigation.as_mut().ok_or("Irrigation is disabled")?;
+                    let events = irrigation.start(command.zone, command.minutes, "command")?;
+                    publish_irrigation_events(app, shadow, &events)?;
+                    format!("Zone {} open", command.zone)
+                }
+                "irrigate_stop" if app.irrigation.is_some() => {
+                    let irrigation = app.irrigation.as_mut().ok_or("Irrigation is disabled")?;
+                    let events: Vec<_> = irrigation.stop("command")?.into_iter().collect();
+                    publish_irrigation_events(app, shadow, &events)?;
+                    "All zones closed".to_string()
+                }
+                "alarm_ack" if app.alarms.is_some() => {
+                    let command = serde_json::from_slice::<AlarmAckCommand>(raw_data)?;
+                    let alarms = app.alarms.as_mut().ok_or("Alarms are disabled")?;
+                    alarms.acknowledge(&command.id)?;
+                    format!("Alarm {} acknowledged", command.id)
+                }
+                "estop_reset" if app.estop.is_some() => {
+                    let estop = app.estop.as_mut().ok_or("E-stop is not fitted")?;
+                    estop.reset()?;
+                    if let Some(alarms) = app.alarms.as_mut() {
+                        alarms.clear(ESTOP_ALARM)?;
+                    }
+                    publish_estop_event(app, estop::EStopEvent::EstopReset)?;
+                    "E-stop reset".to_string()
+                }
+                "csr" if app.config.key_on_device => {
+                    let material = keygen::load_or_generate(app.nvs.clone(), app.config.mqtt_client_id)?;
+                    material.csr_pem
+                }
+                "chaos" if app.config.chaos_enabled() => {
+                    let command = serde_json::from_slice::<chaos::ChaosCommand>(raw_data)?;
+                    let duration = Duration::from_secs(command.duration_secs);
+                    warn!("Injecting fault {:?} for {:?}", command.fault, duration);
+                    match command.fault {
+                        chaos::Fault::DropWifi => app.drop_wifi(duration)?,
+                        chaos::Fault::StallListener => app.chaos.stall_listener(duration),
+                        chaos::Fault::DelayPublish => app.chaos.delay_publish(duration),
+                        chaos::Fault::Oom => chaos::exhaust_heap(),
+                    }
+                    format!("Injected fault {:?}", command.fault)
+                }
+                "install_cert" if app.config.key_on_device => {
+                    let result = serde_json::from_slice::<CertificatePayload>(raw_data)
+                        .map_err(|e| e.into())
+                        .and_then(|payload| keygen::store_certificate(app.nvs.clone(), &payload.certificate));
+                    match result {
+                        Ok(()) => {
+                            info!("Certificate installed, restarting to use it");
+                            *restart_pending = true;
+                            "Certificate installed, restarting".to_string()
+                        }
+                        Err(e) => format!("Certificate rejected: {}", e),
+                    }
+                }
+                "reprovision" if app.config.reprovision_enabled => {
+                    let result = serde_json::from_slice::<reprovision::Bundle>(raw_data)
+                        .map_err(|e| e.into())
+                        .and_then(|bundle| {
+                            reprovision::apply(app.nvs.clone(), &app.config, &bundle).map(|()| bundle)
+                        });
+                    match result {
+                        Ok(bundle) => {
+                            info!("Reprovisioned to {}, restarting to use it", bundle.mqtt_url);
+                            *restart_pending = true;
+                            format!("Reprovisioned to {}, restarting", bundle.mqtt_url)
+                        }
+                        Err(e) => format!("Reprovisioning rejected: {}", e),
+                    }
+                }
+                "service" => {
+                    let command = serde_json::from_slice::<ServiceCommand>(raw_data)?;
+                    let service = Service::parse(&command.name).ok_or("Unknown service")?;
+                    let mut flags = serde_json::Map::new();
+                    flags.insert(service.flag(), command.enabled.into());
+                    app.flags.apply(&flags)?;
+                    if let Some(shadow) = shadow {
+                        shadow.report(&serde_json::json!({ "flags": app.flags.values() }))?;
+                    }
+                    // The main loop starts or stops it once the message is handled
+                    format!("Service {} {}", service.name(), if command.enabled { "enabled" } else { "disabled" })
+                }
+                // Known to the schema, but disabled in this build or configuration
+                _ => {
+                    warn!("Action not available: {}", msg.message);
+                    format!("Action not available: {}", msg.message)
+                }
+            };
+
+            let response = JsonMessage {
+                message,
+                request_id: msg.request_id,
+            };
+            let json_response = envelope::to_json(&app.device_id, &response)?;
+            app.client.publish(&json_response)?;
+            info!("Sent response: {}", json_response);
+        }
+        Err(_) => {
+            // Fallback for non-JSON messages
+            let message_text = String::from_utf8_lossy(raw_data);
+            info!("Received non-JSON message: {}", message_text);
+
+            let response = JsonMessage {
+                message: format!("Received plain text: {}", message_text),
+                request_id: None,
+            };
+
+            let json_response = envelope::to_json(&app.device_id, &response)?;
+            app.client.publish(&json_response)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle a fleet-wide message from the backend. Nothing is published in
+/// response, so a broadcast never triggers a burst of replies.
+fn apply_broadcast(payload: &[u8], timers: &mut [&mut PeriodicTimer]) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = serde_json::from_slice::<JsonMessage>(payload)?;
+    if msg.message != "backoff" {
+        debug!("Ignoring broadcast \"{}\"", msg.message);
+        return Ok(());
+    }
+    let backoff = serde_json::from_slice::<BackoffBroadcast>(payload)?;
+    let factor = backoff.factor.min(MAX_BACKOFF_FACTOR);
+    let mut duration = Duration::from_secs(backoff.duration_secs).min(MAX_BACKOFF);
+    if let (Some(issued), Some(now)) = (backoff.timestamp, clock::now_ms()) {
+        duration = duration.saturating_sub(Duration::from_millis(now.saturating_sub(issued)));
+    }
+    if factor > 1 && !duration.is_zero() {
+        warn!("Fleet backoff: reporting {}x less often for {:?}", factor, duration);
+    } else {
+        info!("Fleet backoff lifted");
+    }
+
+    let until = Instant::now() + duration;
+    for timer in timers.iter_mut() {
+        timer.slow_down(factor, until);
+    }
+    Ok(())
+}
+
+/// Start heap tracing, or stop it and upload the leak summary in chunks.
+/// Returns the summary's key once stopped.
+#[cfg(feature = "heap-trace")]
+fn run_heap_trace(app: &mut App, raw_data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let command = serde_json::from_slice::<heap_trace::HeapTraceCommand>(raw_data)?;
+    if command.action == heap_trace::HeapTraceAction::Start {
+        heap_trace::start()?;
+        return Ok("Heap tracing started".to_string());
+    }
+
+    let summary = serde_json::to_string(&heap_trace::stop()?)?;
+    let key = format!("heap-traces/{}/{}.json", app.device_id, clock::now_ms().unwrap_or_default());
+    let topic = format!("{}/heap-trace", app.config.mqtt_topic_pub);
+    for chunk in heap_trace::chunks(&key, &summary) {
+        app.client.publish_to(&topic, &envelope::to_json(&app.device_id, &chunk)?)?;
+    }
+    Ok(key)
+}
+
+/// Capture a JPEG and upload it to the presigned URL in the command, or
+/// in chunks over MQTT without one. Returns the object key.
+#[cfg(feature = "camera")]
+fn take_snapshot(app: &mut App, raw_data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let command = serde_json::from_slice::<camera::SnapshotCommand>(raw_data)?;
+    let frame = camera::capture()?;
+    let jpeg = frame.jpeg();
+    info!("Captured {} byte snapshot", jpeg.len());
+
+    let key = command.key.unwrap_or_else(|| {
+        format!("snapshots/{}/{}.jpg", app.device_id, clock::now_ms().unwrap_or_default())
+    });
+    match command.upload_url {
+        Some(url) => camera::upload(&url, jpeg)?,
+        None => {
+            let topic = format!("{}/snapshot", app.config.mqtt_topic_pub);
+            for chunk in camera::chunks(&key, jpeg) {
+                app.client.publish_to(&topic, &envelope::to_json(&app.device_id, &chunk)?)?;
+            }
+        }
+    }
+    Ok(key)
+}
+
+/// Apply `energy_calibration` from a shadow delta and report it back so the
+/// desired state is cleared.
+fn apply_energy_calibration(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    delta: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(meter) = app.energy.as_mut() else {
+        return Ok(());
+    };
+    let delta: serde_json::Value = serde_json::from_str(delta)?;
+    let Some(desired) = delta.get("energy_calibration") else {
+        return Ok(());
+    };
+    // Unmentioned gains keep their current value
+    let mut merged = serde_json::to_value(meter.calibration())?;
+    if let (Some(merged), Some(desired)) = (merged.as_object_mut(), desired.as_object()) {
+        merged.extend(desired.clone());
+    }
+    let calibration: energy::Calibration = serde_json::from_value(merged)?;
+    meter.set_calibration(calibration, app.nvs.clone())?;
+
+    if let Some(shadow) = shadow {
+        shadow.report(&serde_json::json!({ "energy_calibration": calibration }))?;
+    }
+    Ok(())
+}
+
+fn led_state(led: &PinDriver<'static, AnyOutputPin, Output>) -> LedState {
+    if led.is_set_high() {
+        LedState::On
+    } else {
+        LedState::Off
+    }
+}
+
+/// Apply every setting the device takes from the shadow's desired state,
+/// from a delta or the bootstrap configuration. A setting that fails doesn't
+/// keep the others from applying.
+fn apply_desired_state(
+    app: &mut App,
+    led: Option<&mut PinDriver<'static, AnyOutputPin, Output>>,
+    shadow: Option<&Shadow>,
+    state: &str,
+) {
+    if let Err(e) = apply_energy_calibration(app, shadow, state) {
+        error!("Failed to apply energy calibration: {}", e);
+    }
+    if let Err(e) = apply_irrigation_config(app, shadow, state) {
+        error!("Failed to apply irrigation config: {}", e);
+    }
+    if let Err(e) = apply_led(led, shadow, state) {
+        error!("Failed to switch the LED: {}", e);
+    }
+    if let Err(e) = apply_feature_flags(app, shadow, state) {
+        error!("Failed to apply feature flags: {}", e);
+    }
+    if let Err(e) = apply_telemetry_template(app, shadow, state) {
+        error!("Failed to apply telemetry template: {}", e);
+    }
+}
+
+/// Switch the LED to `led` from a shadow delta and report the new state, so
+/// the delta clears.
+fn apply_led(
+    led: Option<&mut PinDriver<'static, AnyOutputPin, Output>>,
+    shadow: Option<&Shadow>,
+    delta: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(led) = led else {
+        return Ok(());
+    };
+    let delta: serde_json::Value = serde_json::from_str(delta)?;
+    let Some(desired) = delta.get("led") else {
+        return Ok(());
+    };
+    match serde_json::from_value::<LedState>(desired.clone())? {
+        LedState::On => led.set_high()?,
+        LedState::Off => led.set_low()?,
+    }
+    info!("LED {:?}", led_state(led));
+    if let Some(shadow) = shadow {
+        shadow.report(&serde_json::json!({ "led": led_state(led) }))?;
+    }
+    Ok(())
+}
+
+/// Apply `flags` from a shadow delta and report every flag back.
+fn apply_feature_flags(app: &mut App, shadow: Option<&Shadow>, delta: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let delta: serde_json::Value = serde_json::from_str(delta)?;
+    let Some(desired) = delta.get("flags") else {
+        return Ok(());
+    };
+    let desired = desired.as_object().ok_or("flags must be an object")?;
+    app.flags.apply(desired)?;
+    if let Some(shadow) = shadow {
+        shadow.report(&serde_json::json!({ "flags": app.flags.values() }))?;
+    }
+    Ok(())
+}
+
+/// Start and stop services to match their flags. Shadow sync covers the
+/// classic and named shadows; the shadows only reported to keep reporting.
+fn reconcile_services<'a>(app: &mut App, shadows: impl Iterator<Item = &'a mut Shadow>) {
+    let console_wanted = app.config.console_enabled && Service::Console.wanted(&app.flags);
+    if console_wanted && app.console.is_none() {
+        let exporter = app
+            .config
+            .console_metrics
+            .then(|| prometheus::Exporter::new(app.metrics.clone(), app.traffic.clone()));
+        let started = secrets::settings::CONSOLE_TOKEN
+            .open()
+            .and_then(|token| console::Console::start(app.config.console_port, token, exporter));
+        match started {
+            Ok(console) => {
+                info!("Console started");
+                app.console = Some(console);
+            }
+            Err(e) => error!("Failed to start the console: {}", e),
+        }
+    } else if !console_wanted && app.console.take().is_some() {
+        info!("Console stopped");
+    }
+
+    let shadow_wanted = Service::Shadow.wanted(&app.flags);
+    let connected = app.client.is_connected();
+    for shadow in shadows {
+        let result = match (shadow_wanted, shadow.is_stopped()) {
+            (true, true) => shadow.start(connected),
+            (false, false) => shadow.stop(),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("Failed to switch shadow sync: {}", e);
+        }
+    }
+
+    info!(
+        "Telemetry {}",
+        if Service::Telemetry.wanted(&app.flags) { "enabled" } else { "disabled" }
+    );
+}
+
+/// Apply `telemetry_template` from a shadow delta and report the template
+/// in use back.
+fn apply_telemetry_template(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    delta: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let delta: serde_json::Value = serde_json::from_str(delta)?;
+    let Some(desired) = delta.get("telemetry_template") else {
+        return Ok(());
+    };
+    app.template.apply(desired)?;
+    if let Some(shadow) = shadow {
+        shadow.report(&serde_json::json!({ "telemetry_template": app.template.rules() }))?;
+    }
+    Ok(())
+}
+
+/// Publish the queued alarm transitions and mirror each in the alarm shadow:
+/// the alarm under its id, or `null` once it is closed.
+fn publish_alarm_events(app: &mut App, alarm_shadow: Option<&Shadow>) {
+    let events = app.alarms.as_mut().map(alarms::Alarms::take_events).unwrap_or_default();
+    for event in events {
+        let published = envelope::to_json(&app.device_id, &event)
+            .map_err(Into::into)
+            .and_then(|json| app.client.publish(&json));
+        if let Err(e) = published {
+            error!("Failed to publish alarm \"{}\": {}", event.alarm.id, e);
+        }
+        if let Some(shadow) = alarm_shadow {
+            let entry = (!event.closed).then_some(&event.alarm);
+            if let Err(e) = shadow.report(&serde_json::json!({ "alarms": { event.alarm.id.as_str(): entry } })) {
+                error!("Failed to mirror alarm \"{}\": {}", event.alarm.id, e);
+            }
+        }
+    }
+}
+
+/// Replace the alarms in the alarm shadow with the registry, dropping any
+/// closed while the device was offline.
+fn report_alarms(app: &App, alarm_shadow: Option<&Shadow>) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(alarms), Some(shadow)) = (app.alarms.as_ref(), alarm_shadow) else {
+        return Ok(());
+    };
+    let registry: serde_json::Map<String, serde_json::Value> = alarms
+        .iter()
+        .map(|alarm| serde_json::to_value(alarm).map(|value| (alarm.id.clone(), value)))
+        .collect::<Result<_, _>>()?;
+    // A shadow update merges, so entries only go away by being set to null
+    shadow.report(&serde_json::json!({ "alarms": null }))?;
+    shadow.report(&serde_json::json!({ "alarms": registry }))?;
+    Ok(())
+}
+
+/// Publish an e-stop event. A tripped alarm counts as sent once the client
+/// has taken it.
+fn publish_estop_event(app: &mut App, event: estop::EStopEvent) -> Result<(), Box<dyn std::error::Error>> {
+    app.client.publish(&envelope::to_json(&app.device_id, &event)?)?;
+    if let (estop::EStopEvent::EstopTripped, Some(estop)) = (event, app.estop.as_mut()) {
+        estop.alarm_sent();
+    }
+    Ok(())
+}
+
+/// Publish irrigation events and, when a zone opened or closed, report the
+/// new state to the shadow.
+fn publish_irrigation_events(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    events: &[irrigation::IrrigationEvent],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    for event in events {
+        app.client.publish(&envelope::to_json(&app.device_id, event)?)?;
+    }
+    report_irrigation_state(app, shadow)
+}
+
+fn report_irrigation_state(app: &mut App, shadow: Option<&Shadow>) -> Result<(), Box<dyn std::error::Error>> {
+    if let (Some(shadow), Some(irrigation)) = (shadow, app.irrigation.as_ref()) {
+        let state = serde_json::json!({ "irrigation": irrigation.state() });
+        shadow.report(&state)?;
+    }
+    Ok(())
+}
+
+/// Apply `irrigation` settings from a shadow delta and report them back.
+fn apply_irrigation_config(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    delta: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(irrigation) = app.irrigation.as_mut() else {
+        return Ok(());
+    };
+    let delta: serde_json::Value = serde_json::from_str(delta)?;
+    let Some(desired) = delta.pointer("/irrigation/config") else {
+        return Ok(());
+    };
+    // Unmentioned settings keep their current value
+    let mut merged = serde_json::to_value(irrigation.config())?;
+    if let (Some(merged), Some(desired)) = (merged.as_object_mut(), desired.as_object()) {
+        merged.extend(desired.clone());
+    }
+    irrigation.set_config(serde_json::from_value(merged)?, app.nvs.clone())?;
+    report_irrigation_state(app, shadow)
+}
+
+/// Publish the position once the device has moved far enough, keep the
+/// shadow's reported location current, and raise geofence crossings.
+fn report_location(
+    app: &mut App,
+    shadow: Option<&Shadow>,
+    movement: &mut gnss::MovementFilter,
+    geofence: Option<&mut gnss::Geofence>,
+    fix: &gnss::Fix,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(transition) = geofence.and_then(|geofence| geofence.update(fix)) {
+        warn!("Geofence {}", transition.as_str());
+        let json_event = envelope::to_json(
+            &app.device_id,
+            &GeofenceEvent {
+                event: "geofence",
+                transition: transition.as_str(),
+                fix,
+            },
+        )?;
+        app.client.publish(&json_event)?;
+    }
+
+    if !movement.should_report(fix) {
+        return Ok(());
+    }
+    let json_event = envelope::to_json(&app.device_id, &LocationEvent { event: "location", fix })?;
+    app.client.publish(&json_event)?;
+    if let Some(shadow) = shadow {
+        shadow.report(&serde_json::json!({ "location": fix }))?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct TopicAliasEvent<'a> {
+    event: &'static str,
+    aliases: std::collections::BTreeMap<&'a str, &'a str>,
+}
+
+/// Tell the cloud which wire topics stand for which logical topics, so
+/// rules can translate back. Sent on every connect.
+fn announce_topic_aliases(app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+    let json_event = envelope::to_json(
+        &app.device_id,
+        &TopicAliasEvent {
+            event: "topic_aliases",
+            aliases: app.client.lock().topic_aliases().reverse(),
+        },
+    )?;
+    let topic = format!("{}/topic-aliases", app.config.mqtt_topic_pub);
+    app.client.publish_to(&topic, &json_event)?;
+    Ok(())
+}
+
+/// Publish how long each startup step took, once per boot.
+fn report_startup(app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+    let startup = StartupEvent {
+        event: "startup",
+        connected_ms: app.boot.elapsed_ms(),
+        report: app.boot.report(),
+    };
+    info!("Connected {} ms after startup began", startup.connected_ms);
+    app.client.publish(&envelope::to_json(&app.device_id, &startup)?)?;
+    Ok(())
+}
+
+/// Raise a `security_alert` naming the identity that connected after the
+/// primary one was rejected.
+fn report_identity_fallback(app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+    error!("Connected with fallback identity {}, {} was rejected", app.identity.name, app.config.auth_mode);
+    let json_event = envelope::to_json(
+        &app.device_id,
+        &IdentityFallbackEvent {
+            event: "security_alert",
+            reason: "identity_fallback",
+            identity: app.identity.name,
+            primary: app.config.auth_mode,
+        },
+    )?;
+    app.client.publish(&json_event)?;
+    Ok(())
+}
+
+/// Publish the broker certificate observed after connecting, raising a
+/// security event when it changed outside the expected rotation window.
+fn report_server_certificate(app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(identity) = app.tls_probe.as_ref() else {
+        return Ok(());
+    };
+    // The endpoint connected to: backup URL, bridge or Greengrass core included
+    let url = app.client.lock().endpoint().to_string();
+    let (host, port) = tls_observer::endpoint_host_port(&url).ok_or("Cannot parse host from the broker URL")?;
+    // The client negotiates ALPN with AWS IoT on 443 only
+    let alpn = (app.config.use_alpn && app.greengrass.is_none() && port == 443).then_some(client::ALPN_MQTT_CA);
+    let info = tls_observer::probe(host, port, alpn, identity)?;
+    let change = tls_observer::record(app.nvs.clone(), &info, app.config.tls_rotation_window_days)?;
+    info!(
+        "Broker certificate {} issued by {} ({})",
+        info.fingerprint,
+        info.issuer,
+        change.as_str()
+    );
+
+    let previous_fingerprint = match &change {
+        tls_observer::CertChange::Rotated { previous }
+        | tls_observer::CertChange::Unexpected { previous } => Some(previous.as_str()),
+        _ => None,
+    };
+    let event = match change {
+        tls_observer::CertChange::Unexpected { .. } => {
+            error!("Broker certificate changed outside the rotation window!");
+            "security_alert"
+        }
+        _ => "tls_server_cert",
+    };
+
+    let json_event = envelope::to_json(
+        &app.device_id,
+        &TlsCertEvent {
+            event,
+            fingerprint: &info.fingerprint,
+            issuer: &info.issuer,
+            subject: &info.subject,
+            change: change.as_str(),
+            previous_fingerprint,
+        },
+    )?;
+    app.client.publish(&json_event)?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A stage on the publish and receive paths of the [`Client`](crate::client::Client).
+///
+/// Cross-cutting concerns (signing, compression, metrics, auditing) implement
+/// this instead of being added inline to the client. Both hooks pass the
+/// payload through unchanged by default.
+pub trait Middleware: Send {
+    fn name(&self) -> &'static str;
+
+    /// Transform an outgoing payload. An error aborts the publish.
+    fn on_publish(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(payload)
+    }
+
+    /// Transform an incoming payload. An error drops the message.
+    fn on_receive(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(payload)
+    }
+}
+
+/// Ordered set of middleware shared by the client and its listener thread.
+///
+/// Outgoing payloads pass through in registration order and incoming ones in
+/// reverse, so a stage registered after `compress` sees what `compress`
+/// produced on the way out and hands `decompress` its input on the way in.
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    stages: Arc<Mutex<Vec<Box<dyn Middleware>>>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, middleware: impl Middleware + 'static) {
+        log::info!("Registered {} middleware", middleware.name());
+        self.stages.lock().unwrap().push(Box::new(middleware));
+    }
+
+    pub fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut stages = self.stages.lock().unwrap();
+        stages.iter_mut().try_fold(payload, |payload, stage| {
+            stage
+                .on_publish(topic, payload)
+                .map_err(|e| format!("{} middleware: {}", stage.name(), e).into())
+        })
+    }
+
+    pub fn receive(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut stages = self.stages.lock().unwrap();
+        stages.iter_mut().rev().try_fold(payload, |payload, stage| {
+            stage
+                .on_receive(topic, payload)
+                .map_err(|e| format!("{} middleware: {}", stage.name(), e).into())
+        })
+    }
+}
+
+/// Message and byte counts, as reported in telemetry.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct MessageStats {
+    pub published: u64,
+    pub published_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+    /// Own publishes the broker delivered back, dropped by the echo filter
+    pub echoes: u64,
+    /// Redeliveries dropped by the dedup filter
+    pub duplicates: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    published_bytes: AtomicU64,
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+    echoes: AtomicU64,
+    duplicates: AtomicU64,
+}
+
+/// Counts traffic in both directions. Register a clone in the chain and
+/// keep the original to read [`Metrics::stats`].
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> MessageStats {
+        MessageStats {
+            published: self.counters.published.load(Ordering::Relaxed),
+            published_bytes: self.counters.published_bytes.load(Ordering::Relaxed),
+            received: self.counters.received.load(Ordering::Relaxed),
+            received_bytes: self.counters.received_bytes.load(Ordering::Relaxed),
+            echoes: self.counters.echoes.load(Ordering::Relaxed),
+            duplicates: self.counters.duplicates.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_echo(&self) {
+        self.counters.echoes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_duplicate(&self) {
+        self.counters.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Middleware for Metrics {
+    fn name(&self) -> &'static str {
+        "metrics"
+    }
+
+    fn on_publish(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.counters.published.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .published_bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        Ok(payload)
+    }
+
+    fn on_receive(&mut self, _topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .received_bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        Ok(payload)
+    }
+}
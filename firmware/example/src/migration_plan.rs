@@ -0,0 +1,85 @@
+//! Which NVS migrations to apply, apart from the NVS access in
+//! [`crate::migrations`], so the ordering runs in host tests (see
+//! `firmware/host-tests`).
+
+/// A registry entry, known by the schema version it upgrades to.
+pub trait Versioned {
+    fn version(&self) -> u16;
+}
+
+/// Latest schema version of `migrations`, 0 for an empty registry.
+pub fn latest_version<M: Versioned>(migrations: &[M]) -> u16 {
+    migrations.last().map(Versioned::version).unwrap_or(0)
+}
+
+/// Migrations to apply to move from `current` to the latest version.
+/// Fails if the registry isn't strictly ascending.
+pub fn pending<M: Versioned>(current: u16, migrations: &[M]) -> Result<&[M], String> {
+    if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version() >= pair[1].version()) {
+        return Err(format!(
+            "Migrations out of order: {} registered before {}",
+            pair[0].version(),
+            pair[1].version()
+        ));
+    }
+
+    let start = migrations.partition_point(|m| m.version() <= current);
+    Ok(&migrations[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Versioned for u16 {
+        fn version(&self) -> u16 {
+            *self
+        }
+    }
+
+    #[test]
+    fn fresh_device_runs_every_migration() {
+        assert_eq!(pending(0, &[1u16, 2, 3]), Ok(&[1u16, 2, 3][..]));
+        assert_eq!(latest_version(&[1u16, 2, 3]), 3);
+    }
+
+    #[test]
+    fn empty_registry_has_nothing_pending() {
+        let registry: [u16; 0] = [];
+        assert_eq!(pending(0, &registry), Ok(&registry[..]));
+        assert_eq!(pending(4, &registry), Ok(&registry[..]));
+        assert_eq!(latest_version(&registry), 0);
+    }
+
+    #[test]
+    fn partial_run_resumes_after_the_last_stored_version() {
+        // Interrupted after migration 2 had bumped the stored version
+        assert_eq!(pending(2, &[1u16, 2, 3, 4]), Ok(&[3u16, 4][..]));
+    }
+
+    #[test]
+    fn resume_skips_gaps_in_the_numbering() {
+        assert_eq!(pending(3, &[1u16, 2, 5, 8]), Ok(&[5u16, 8][..]));
+    }
+
+    #[test]
+    fn up_to_date_has_nothing_pending() {
+        assert_eq!(pending(3, &[1u16, 2, 3]), Ok(&[][..]));
+    }
+
+    #[test]
+    fn out_of_order_registry_is_rejected() {
+        let error = pending(0, &[1u16, 3, 2]).unwrap_err();
+        assert_eq!(error, "Migrations out of order: 3 registered before 2");
+    }
+
+    #[test]
+    fn repeated_version_is_rejected() {
+        assert!(pending(0, &[1u16, 2, 2]).is_err());
+    }
+
+    #[test]
+    fn out_of_order_registry_is_rejected_even_when_up_to_date() {
+        assert!(pending(5, &[2u16, 1]).is_err());
+    }
+}
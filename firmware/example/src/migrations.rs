@@ -0,0 +1,82 @@
+//! NVS schema versioning.
+//!
+//! The schema version of everything the application stores in NVS is kept
+//! under [`SCHEMA_VERSION_KEY`] in the [`NAMESPACE`] namespace. At boot,
+//! [`run`] applies every registered migration newer than the stored version,
+//! in order, bumping the stored version after each one so an interrupted
+//! upgrade resumes where it stopped.
+//!
+//! To change the layout of stored data, append a [`Migration`] to
+//! [`MIGRATIONS`] with the next version number:
+//!
+//! ```ignore
+//! Migration {
+//!     version: 2,
+//!     description: "rename wifi_ssid to ssid",
+//!     run: |nvs| {
+//!         let mut buf = [0u8; 64];
+//!         if let Some(ssid) = nvs.get_str("wifi_ssid", &mut buf)? {
+//!             let ssid = ssid.to_string();
+//!             nvs.set_str("ssid", &ssid)?;
+//!             nvs.remove("wifi_ssid")?;
+//!         }
+//!         Ok(())
+//!     },
+//! },
+//! ```
+//!
+//! A fresh device starts at version 0 and runs every migration, so migrations
+//! must tolerate keys that don't exist.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+
+pub use crate::migration_plan::{latest_version, pending, Versioned};
+
+pub const NAMESPACE: &str = "app";
+pub const SCHEMA_VERSION_KEY: &str = "schema_ver";
+
+pub struct Migration {
+    /// Schema version this migration upgrades to
+    pub version: u16,
+    pub description: &'static str,
+    pub run: fn(&mut EspNvs<NvsDefault>) -> Result<(), EspError>,
+}
+
+/// Registered migrations, in ascending version order.
+pub const MIGRATIONS: &[Migration] = &[];
+
+impl Versioned for Migration {
+    fn version(&self) -> u16 {
+        self.version
+    }
+}
+
+/// Upgrade the stored data to the latest schema, returning the resulting version.
+pub fn run(
+    partition: EspDefaultNvsPartition,
+    migrations: &[Migration],
+) -> Result<u16, Box<dyn std::error::Error>> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let current = nvs.get_u16(SCHEMA_VERSION_KEY)?.unwrap_or(0);
+    let latest = latest_version(migrations);
+
+    if current > latest {
+        // Running older firmware after a rollback: leave newer data untouched
+        log::warn!(
+            "NVS schema version {} is newer than this firmware supports ({}), skipping migrations",
+            current, latest
+        );
+        return Ok(current);
+    }
+
+    for migration in pending(current, migrations)? {
+        log::info!("Applying NVS migration {}: {}", migration.version, migration.description);
+        (migration.run)(&mut nvs)
+            .map_err(|e| format!("NVS migration {} failed: {}", migration.version, e))?;
+        nvs.set_u16(SCHEMA_VERSION_KEY, migration.version)?;
+    }
+
+    log::info!("NVS schema at version {}", latest);
+    Ok(latest)
+}
@@ -0,0 +1,154 @@
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C0};
+use esp_idf_svc::hal::units::Hertz;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// LIS3DH with SDO/SA0 pulled low.
+const ADDRESS: u8 = 0x18;
+const WHO_AM_I: u8 = 0x0F;
+const LIS3DH_ID: u8 = 0x33;
+const CTRL_REG1: u8 = 0x20;
+const CTRL_REG2: u8 = 0x21;
+const CTRL_REG3: u8 = 0x22;
+const CTRL_REG4: u8 = 0x23;
+const CTRL_REG5: u8 = 0x24;
+const OUT_X_L: u8 = 0x28;
+const INT1_CFG: u8 = 0x30;
+const INT1_SRC: u8 = 0x31;
+const INT1_THS: u8 = 0x32;
+const INT1_DURATION: u8 = 0x33;
+/// Set on a register address to read several registers in one transfer.
+const AUTO_INCREMENT: u8 = 0x80;
+/// INT1 threshold resolution at ±2 g.
+const THRESHOLD_MG_PER_LSB: u16 = 16;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionKind {
+    Motion,
+    /// Acceleration beyond the tamper threshold: the device was knocked or pried
+    Tamper,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct MotionEvent {
+    pub kind: MotionKind,
+    pub peak_mg: u32,
+}
+
+/// Motion activity since boot, as reported in telemetry.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct MotionSummary {
+    pub motion_events: u32,
+    pub tamper_events: u32,
+    pub max_peak_mg: u32,
+}
+
+/// LIS3DH accelerometer raising an interrupt when acceleration, with
+/// gravity filtered out, exceeds a threshold.
+pub struct MotionSensor {
+    i2c: I2cDriver<'static>,
+    interrupt: PinDriver<'static, AnyIOPin, Input>,
+    tamper_mg: u32,
+    summary: MotionSummary,
+}
+
+impl MotionSensor {
+    pub fn start(
+        i2c: I2C0,
+        sda_pin: i32,
+        scl_pin: i32,
+        int_pin: i32,
+        threshold_mg: u16,
+        tamper_mg: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = I2cConfig::new().baudrate(Hertz(400_000));
+        let mut i2c = I2cDriver::new(
+            i2c,
+            unsafe { AnyIOPin::new(sda_pin) },
+            unsafe { AnyIOPin::new(scl_pin) },
+            &config,
+        )?;
+
+        let mut id = [0u8];
+        i2c.write_read(ADDRESS, &[WHO_AM_I], &mut id, BLOCK)?;
+        if id[0] != LIS3DH_ID {
+            return Err(format!("No LIS3DH at 0x{:02x} (WHO_AM_I 0x{:02x})", ADDRESS, id[0]).into());
+        }
+
+        let threshold = (threshold_mg / THRESHOLD_MG_PER_LSB).clamp(1, 0x7F) as u8;
+        for (register, value) in [
+            // 100 Hz, X/Y/Z enabled
+            (CTRL_REG1, 0x57),
+            // High-pass filter on INT1 so gravity doesn't count as motion
+            (CTRL_REG2, 0x01),
+            // Route the INT1 generator to the INT1 pin
+            (CTRL_REG3, 0x40),
+            // ±2 g, high resolution
+            (CTRL_REG4, 0x08),
+            // Latch INT1 until INT1_SRC is read
+            (CTRL_REG5, 0x08),
+            (INT1_THS, threshold),
+            (INT1_DURATION, 0x00),
+            // Any axis above the threshold
+            (INT1_CFG, 0x2A),
+        ] {
+            i2c.write(ADDRESS, &[register, value], BLOCK)?;
+        }
+
+        let mut interrupt = PinDriver::input(unsafe { AnyIOPin::new(int_pin) })?;
+        interrupt.set_pull(Pull::Down)?;
+        interrupt.set_interrupt_type(InterruptType::PosEdge)?;
+        unsafe { interrupt.subscribe(|| INTERRUPTED.store(true, Ordering::Relaxed))? };
+        interrupt.enable_interrupt()?;
+
+        log::info!("LIS3DH motion detection armed at {} mg", threshold as u16 * THRESHOLD_MG_PER_LSB);
+        Ok(Self {
+            i2c,
+            interrupt,
+            tamper_mg,
+            summary: MotionSummary::default(),
+        })
+    }
+
+    /// The motion event behind a pending interrupt, if there is one.
+    pub fn poll(&mut self) -> Result<Option<MotionEvent>, Box<dyn std::error::Error>> {
+        if !INTERRUPTED.swap(false, Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let peak_mg = self.read_magnitude_mg()?;
+        // Reading INT1_SRC releases the latched interrupt
+        let mut source = [0u8];
+        self.i2c.write_read(ADDRESS, &[INT1_SRC], &mut source, BLOCK)?;
+        self.interrupt.enable_interrupt()?;
+
+        let kind = if peak_mg >= self.tamper_mg {
+            self.summary.tamper_events += 1;
+            MotionKind::Tamper
+        } else {
+            self.summary.motion_events += 1;
+            MotionKind::Motion
+        };
+        self.summary.max_peak_mg = self.summary.max_peak_mg.max(peak_mg);
+        Ok(Some(MotionEvent { kind, peak_mg }))
+    }
+
+    pub fn summary(&self) -> MotionSummary {
+        self.summary
+    }
+
+    fn read_magnitude_mg(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut raw = [0u8; 6];
+        self.i2c
+            .write_read(ADDRESS, &[OUT_X_L | AUTO_INCREMENT], &mut raw, BLOCK)?;
+        // 12-bit left-justified samples, 1 mg per digit at ±2 g
+        let axis = |i: usize| (i16::from_le_bytes([raw[i], raw[i + 1]]) >> 4) as f32;
+        let (x, y, z) = (axis(0), axis(2), axis(4));
+        Ok((x * x + y * y + z * z).sqrt() as u32)
+    }
+}
@@ -0,0 +1,191 @@
+//! Opt-in MQTT 5 (`--features mqtt5`, with `CONFIG_MQTT_PROTOCOL_5` from
+//! sdkconfig.mqtt5). esp-idf-svc only configures MQTT 3.1 and 3.1.1, and
+//! esp-mqtt only sets up its MQTT 5 state for a client created as one. So
+//! build.rs has the linker route esp-idf-svc's `esp_mqtt_client_init`
+//! through [`__wrap_esp_mqtt_client_init`], which sets `protocol_ver` in the
+//! raw configuration. Everything else goes through esp-mqtt's MQTT 5 C API
+//! on the client handle.
+
+use esp_idf_svc::sys::{self, esp_mqtt_client_config_t, esp_mqtt_client_handle_t};
+use std::ffi::{c_char, c_void, CString};
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Session expiry the next client connects with, see [`set_session_expiry`]
+static SESSION_EXPIRY_SECS: AtomicU32 = AtomicU32::new(0);
+/// Reason code of the last refused connection, 0 for none
+static REFUSED: AtomicU32 = AtomicU32::new(0);
+
+/// What the client publishes with when no properties were asked for. Kept
+/// for good, as esp-mqtt may hold on to the configuration it was given.
+struct NoProperties(sys::esp_mqtt5_publish_property_config_t);
+
+unsafe impl Sync for NoProperties {}
+
+static NO_PROPERTIES: NoProperties = NoProperties(unsafe { std::mem::zeroed() });
+
+extern "C" {
+    fn __real_esp_mqtt_client_init(config: *const esp_mqtt_client_config_t) -> esp_mqtt_client_handle_t;
+}
+
+/// Stands in for `esp_mqtt_client_init`: every esp-mqtt client of this
+/// firmware speaks MQTT 5. Set up before the client task starts, so nothing
+/// here races the first connection.
+#[no_mangle]
+pub unsafe extern "C" fn __wrap_esp_mqtt_client_init(config: *const esp_mqtt_client_config_t) -> esp_mqtt_client_handle_t {
+    if config.is_null() {
+        return __real_esp_mqtt_client_init(config);
+    }
+    let mut config = *config;
+    config.session.protocol_ver = sys::esp_mqtt_protocol_ver_t_MQTT_PROTOCOL_V_5;
+    let handle = __real_esp_mqtt_client_init(&config);
+    if !handle.is_null() {
+        if let Err(e) = set_up(handle) {
+            log::error!("Failed to set up MQTT 5: {}", e);
+        }
+    }
+    handle
+}
+
+unsafe fn set_up(handle: esp_mqtt_client_handle_t) -> Result<(), sys::EspError> {
+    let mut connect: sys::esp_mqtt5_connection_property_config_t = std::mem::zeroed();
+    connect.session_expiry_interval = SESSION_EXPIRY_SECS.load(Ordering::Relaxed);
+    sys::esp!(sys::esp_mqtt5_client_set_connect_property(handle, &connect))?;
+    sys::esp!(sys::esp_mqtt_client_register_event(
+        handle,
+        sys::esp_mqtt_event_id_t_MQTT_EVENT_ERROR,
+        Some(on_error),
+        ptr::null_mut(),
+    ))
+}
+
+/// How long the broker keeps a persistent session after the connection
+/// drops; `None` ends it with the connection. Applies to the client created
+/// next. MQTT 5 carries it on the wire, unlike 3.1.1.
+pub fn set_session_expiry(expiry: Option<Duration>) {
+    let secs = expiry.map_or(0, |expiry| expiry.as_secs().min(u32::MAX as u64) as u32);
+    SESSION_EXPIRY_SECS.store(secs, Ordering::Relaxed);
+}
+
+unsafe extern "C" fn on_error(_arg: *mut c_void, _base: sys::esp_event_base_t, _id: i32, data: *mut c_void) {
+    let Some(event) = (data as *const sys::esp_mqtt_event_t).as_ref() else {
+        return;
+    };
+    let Some(error) = event.error_handle.as_ref() else {
+        return;
+    };
+    if error.error_type == sys::esp_mqtt_error_type_t_MQTT_ERROR_TYPE_CONNECTION_REFUSED {
+        let reason = ReasonCode(error.connect_return_code as u8);
+        log::warn!("Broker refused the connection: {}", reason);
+        REFUSED.store(reason.0 as u32, Ordering::Relaxed);
+    }
+}
+
+/// Reason code of the last connection the broker refused.
+pub fn last_refusal() -> Option<ReasonCode> {
+    match REFUSED.load(Ordering::Relaxed) {
+        0 => None,
+        code => Some(ReasonCode(code as u8)),
+    }
+}
+
+/// An MQTT 5 reason code, as in a CONNACK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReasonCode(pub u8);
+
+impl ReasonCode {
+    pub fn name(&self) -> &'static str {
+        match self.0 {
+            0x00 => "Success",
+            0x80 => "Unspecified error",
+            0x81 => "Malformed packet",
+            0x82 => "Protocol error",
+            0x83 => "Implementation specific error",
+            0x84 => "Unsupported protocol version",
+            0x85 => "Client identifier not valid",
+            0x86 => "Bad user name or password",
+            0x87 => "Not authorized",
+            0x88 => "Server unavailable",
+            0x89 => "Server busy",
+            0x8A => "Banned",
+            0x8C => "Bad authentication method",
+            0x90 => "Topic name invalid",
+            0x95 => "Packet too large",
+            0x97 => "Quota exceeded",
+            0x99 => "Payload format invalid",
+            0x9A => "Retain not supported",
+            0x9B => "QoS not supported",
+            0x9C => "Use another server",
+            0x9D => "Server moved",
+            0x9F => "Connection rate exceeded",
+            _ => "Unknown reason",
+        }
+    }
+}
+
+impl fmt::Display for ReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (0x{:02x})", self.name(), self.0)
+    }
+}
+
+/// Properties of one MQTT 5 publish, see [`crate::client::Client::publish_with_properties`].
+#[derive(Debug, Clone, Default)]
+pub struct PublishProperties {
+    /// User properties, in order; a key may repeat
+    pub user: Vec<(String, String)>,
+    /// Where a responder should publish its reply
+    pub response_topic: Option<String>,
+    /// Echoed by a responder, so a reply can be matched to its request
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+/// Run `publish` with `properties` set on the client, and clear them again,
+/// so they go with exactly that one publish. esp-mqtt turns the publish
+/// into bytes before it returns.
+pub(crate) fn with_properties<T>(
+    handle: esp_mqtt_client_handle_t,
+    properties: &PublishProperties,
+    publish: impl FnOnce() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let strings = properties
+        .user
+        .iter()
+        .map(|(key, value)| Ok((CString::new(key.as_str())?, CString::new(value.as_str())?)))
+        .collect::<Result<Vec<_>, std::ffi::NulError>>()?;
+    let mut items: Vec<sys::esp_mqtt5_user_property_item_t> = strings
+        .iter()
+        .map(|(key, value)| sys::esp_mqtt5_user_property_item_t {
+            key: key.as_ptr(),
+            value: value.as_ptr(),
+        })
+        .collect();
+    let count = u8::try_from(items.len()).map_err(|_| "At most 255 user properties per publish")?;
+    let response_topic = properties.response_topic.as_deref().map(CString::new).transpose()?;
+
+    let mut config: sys::esp_mqtt5_publish_property_config_t = unsafe { std::mem::zeroed() };
+    if let Some(topic) = &response_topic {
+        config.response_topic = topic.as_ptr();
+    }
+    if let Some(data) = &properties.correlation_data {
+        config.correlation_data = data.as_ptr() as *const c_char;
+        config.correlation_data_len = u16::try_from(data.len()).map_err(|_| "Correlation data over 65535 bytes")?;
+    }
+    if count > 0 {
+        // Copies the strings into a list of its own
+        sys::esp!(unsafe { sys::esp_mqtt5_client_set_user_property(&mut config.user_property, items.as_mut_ptr(), count) })?;
+    }
+
+    let result = sys::esp!(unsafe { sys::esp_mqtt5_client_set_publish_property(handle, &config) })
+        .map_err(Into::into)
+        .and_then(|()| publish());
+    if let Err(e) = sys::esp!(unsafe { sys::esp_mqtt5_client_set_publish_property(handle, &NO_PROPERTIES.0) }) {
+        log::warn!("Failed to clear the MQTT 5 publish properties: {}", e);
+    }
+    if !config.user_property.is_null() {
+        unsafe { sys::esp_mqtt5_client_delete_user_property(config.user_property) };
+    }
+    result
+}
@@ -0,0 +1,318 @@
+//! Per-attempt broker connection statistics. esp-mqtt only says whether an
+//! attempt worked, so after a failed one a probe repeats the connection
+//! phase by phase (DNS, TCP, TLS) with a byte-counting socket to show where
+//! it breaks.
+
+use crate::client::{CLIENT_CERT, PRIVATE_KEY, SERVER_CERT};
+use esp_idf_svc::sys;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ffi::{c_int, c_uchar, c_void, CString};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Result of a probe: how long each phase took, `None` if it wasn't reached.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PhaseTimings {
+    pub dns_ms: Option<u32>,
+    pub tcp_ms: Option<u32>,
+    pub tls_ms: Option<u32>,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    /// Phase that failed and why
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConnectionAttempt {
+    /// Milliseconds since the epoch, if the clock was set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_ms: Option<u64>,
+    /// "pending", "connected" or "failed"
+    pub outcome: &'static str,
+    /// Time until esp-mqtt reported the outcome
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Phase-by-phase probe, run after a failed attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<PhaseTimings>,
+    /// Broker URL of the attempt
+    pub endpoint: String,
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// The last `capacity` attempts, shared by the client and its listener.
+#[derive(Clone)]
+pub struct ConnectionStats {
+    attempts: Arc<Mutex<VecDeque<ConnectionAttempt>>>,
+    capacity: usize,
+    /// Broker URL the attempts go to
+    endpoint: Arc<Mutex<String>>,
+}
+
+impl ConnectionStats {
+    /// `url` is the broker URL the client connects to; `capacity` 0
+    /// disables recording.
+    pub fn new(url: &str, capacity: usize) -> Self {
+        Self {
+            attempts: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            endpoint: Arc::new(Mutex::new(url.to_string())),
+        }
+    }
+
+    /// Record attempts from now on against `url`, after the client moved to
+    /// another endpoint.
+    pub fn set_endpoint(&self, url: &str) {
+        *self.endpoint.lock().unwrap() = url.to_string();
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionAttempt> {
+        self.attempts.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn begin(&self) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= self.capacity {
+            attempts.pop_front();
+        }
+        attempts.push_back(ConnectionAttempt {
+            started_ms: crate::clock::now_ms(),
+            outcome: "pending",
+            duration_ms: None,
+            error: None,
+            probe: None,
+            endpoint: self.endpoint.lock().unwrap().clone(),
+            started: Instant::now(),
+        });
+    }
+
+    pub fn connected(&self) {
+        self.finish("connected", None);
+    }
+
+    /// Record the failure and probe the broker in the background.
+    pub fn failed(&self, error: Option<String>) {
+        if !self.finish("failed", error) {
+            return;
+        }
+        let Some((host, port)) = broker_address(&self.endpoint.lock().unwrap()) else {
+            return;
+        };
+        let stats = self.clone();
+        let spawned = std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || {
+                let timings = probe(&host, port);
+                log::info!("Connection probe: {:?}", timings);
+                if let Some(attempt) = stats.attempts.lock().unwrap().back_mut() {
+                    attempt.probe = Some(timings);
+                }
+            });
+        if let Err(e) = spawned {
+            log::warn!("Failed to start connection probe: {}", e);
+        }
+    }
+
+    /// Close the pending attempt; false if there was none.
+    fn finish(&self, outcome: &'static str, error: Option<String>) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        match attempts.back_mut() {
+            Some(attempt) if attempt.outcome == "pending" => {
+                attempt.outcome = outcome;
+                attempt.duration_ms = Some(attempt.started.elapsed().as_millis() as u32);
+                attempt.error = error;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Host and port of an `mqtts://`, `ssl://` or `wss://` URL. The probe's
+/// TLS handshake would fail on a plain `mqtt://` or `ws://` broker.
+fn broker_address(url: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    if scheme == "mqtt" || scheme == "ws" {
+        return None;
+    }
+    let authority = rest.split(['/', '?']).next()?;
+    let default_port = if scheme == "wss" { 443 } else { 8883 };
+    Some(match authority.rsplit_once(':') {
+        Some((host, port)) => (host.to_string(), port.parse().ok()?),
+        None => (authority.to_string(), default_port),
+    })
+}
+
+/// Resolve, connect and handshake with the broker, timing each phase.
+pub fn probe(host: &str, port: u16) -> PhaseTimings {
+    let mut timings = PhaseTimings::default();
+
+    let started = Instant::now();
+    let address: SocketAddr = match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(address)) => address,
+        Ok(None) => {
+            timings.failure = Some("dns: no addresses".to_string());
+            return timings;
+        }
+        Err(e) => {
+            timings.failure = Some(format!("dns: {}", e));
+            return timings;
+        }
+    };
+    timings.dns_ms = Some(started.elapsed().as_millis() as u32);
+
+    let started = Instant::now();
+    let stream = match TcpStream::connect_timeout(&address, PROBE_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(e) => {
+            timings.failure = Some(format!("tcp: {}", e));
+            return timings;
+        }
+    };
+    timings.tcp_ms = Some(started.elapsed().as_millis() as u32);
+    let _ = stream.set_read_timeout(Some(PROBE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(PROBE_TIMEOUT));
+
+    let started = Instant::now();
+    let mut bio = CountingStream {
+        stream,
+        sent: 0,
+        received: 0,
+    };
+    let result = unsafe { tls_handshake(host, &mut bio) };
+    timings.bytes_sent = bio.sent;
+    timings.bytes_received = bio.received;
+    match result {
+        Ok(()) => timings.tls_ms = Some(started.elapsed().as_millis() as u32),
+        Err(e) => timings.failure = Some(format!("tls: {}", e)),
+    }
+    timings
+}
+
+struct CountingStream {
+    stream: TcpStream,
+    sent: usize,
+    received: usize,
+}
+
+unsafe extern "C" fn bio_send(ctx: *mut c_void, buf: *const c_uchar, len: usize) -> c_int {
+    let bio = &mut *(ctx as *mut CountingStream);
+    match bio.stream.write(std::slice::from_raw_parts(buf, len)) {
+        Ok(written) => {
+            bio.sent += written;
+            written as c_int
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => sys::MBEDTLS_ERR_SSL_WANT_WRITE,
+        Err(_) => sys::MBEDTLS_ERR_NET_SEND_FAILED,
+    }
+}
+
+unsafe extern "C" fn bio_recv(ctx: *mut c_void, buf: *mut c_uchar, len: usize) -> c_int {
+    let bio = &mut *(ctx as *mut CountingStream);
+    match bio.stream.read(std::slice::from_raw_parts_mut(buf, len)) {
+        Ok(read) => {
+            bio.received += read;
+            read as c_int
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => sys::MBEDTLS_ERR_SSL_WANT_READ,
+        Err(_) => sys::MBEDTLS_ERR_NET_RECV_FAILED,
+    }
+}
+
+/// mbedTLS client handshake over `bio` with the embedded certificates, so
+/// every byte passes through the counters.
+unsafe fn tls_handshake(host: &str, bio: &mut CountingStream) -> Result<(), String> {
+    let mut ssl: sys::mbedtls_ssl_context = core::mem::zeroed();
+    let mut conf: sys::mbedtls_ssl_config = core::mem::zeroed();
+    let mut ca: sys::mbedtls_x509_crt = core::mem::zeroed();
+    let mut cert: sys::mbedtls_x509_crt = core::mem::zeroed();
+    let mut key: sys::mbedtls_pk_context = core::mem::zeroed();
+    let mut entropy: sys::mbedtls_entropy_context = core::mem::zeroed();
+    let mut drbg: sys::mbedtls_ctr_drbg_context = core::mem::zeroed();
+    sys::mbedtls_ssl_init(&mut ssl);
+    sys::mbedtls_ssl_config_init(&mut conf);
+    sys::mbedtls_x509_crt_init(&mut ca);
+    sys::mbedtls_x509_crt_init(&mut cert);
+    sys::mbedtls_pk_init(&mut key);
+    sys::mbedtls_entropy_init(&mut entropy);
+    sys::mbedtls_ctr_drbg_init(&mut drbg);
+
+    let result = (|| {
+        let check = |step: &str, ret: c_int| match ret {
+            0 => Ok(()),
+            ret => Err(format!("{} failed (mbedTLS error -0x{:04x})", step, -ret)),
+        };
+        // PEM input must be NUL-terminated, with the NUL counted
+        let pem = |bytes: &[u8]| [bytes, &[0]].concat();
+        let private_key = PRIVATE_KEY.open().map_err(|e| e.to_string())?;
+        let (ca_pem, cert_pem, key_pem) = (pem(SERVER_CERT), pem(CLIENT_CERT), pem(&private_key));
+        let host = CString::new(host).map_err(|e| e.to_string())?;
+        let entropy_ptr = &mut entropy as *mut _ as *mut c_void;
+        let drbg_ptr = &mut drbg as *mut _ as *mut c_void;
+
+        check(
+            "seed",
+            sys::mbedtls_ctr_drbg_seed(&mut drbg, Some(sys::mbedtls_entropy_func), entropy_ptr, core::ptr::null(), 0),
+        )?;
+        check("ca", sys::mbedtls_x509_crt_parse(&mut ca, ca_pem.as_ptr(), ca_pem.len()))?;
+        check("certificate", sys::mbedtls_x509_crt_parse(&mut cert, cert_pem.as_ptr(), cert_pem.len()))?;
+        check(
+            "key",
+            sys::mbedtls_pk_parse_key(
+                &mut key,
+                key_pem.as_ptr(),
+                key_pem.len(),
+                core::ptr::null(),
+                0,
+                Some(sys::mbedtls_ctr_drbg_random),
+                drbg_ptr,
+            ),
+        )?;
+        check(
+            "config",
+            sys::mbedtls_ssl_config_defaults(
+                &mut conf,
+                sys::MBEDTLS_SSL_IS_CLIENT as c_int,
+                sys::MBEDTLS_SSL_TRANSPORT_STREAM as c_int,
+                sys::MBEDTLS_SSL_PRESET_DEFAULT as c_int,
+            ),
+        )?;
+        sys::mbedtls_ssl_conf_authmode(&mut conf, sys::MBEDTLS_SSL_VERIFY_REQUIRED as c_int);
+        sys::mbedtls_ssl_conf_ca_chain(&mut conf, &mut ca, core::ptr::null_mut());
+        check("own certificate", sys::mbedtls_ssl_conf_own_cert(&mut conf, &mut cert, &mut key))?;
+        sys::mbedtls_ssl_conf_rng(&mut conf, Some(sys::mbedtls_ctr_drbg_random), drbg_ptr);
+        check("setup", sys::mbedtls_ssl_setup(&mut ssl, &conf))?;
+        check("hostname", sys::mbedtls_ssl_set_hostname(&mut ssl, host.as_ptr()))?;
+        let bio_ptr = bio as *mut CountingStream as *mut c_void;
+        sys::mbedtls_ssl_set_bio(&mut ssl, bio_ptr, Some(bio_send), Some(bio_recv), None);
+
+        loop {
+            match sys::mbedtls_ssl_handshake(&mut ssl) {
+                0 => return Ok(()),
+                sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                ret => return check("handshake", ret),
+            }
+        }
+    })();
+
+    sys::mbedtls_ssl_free(&mut ssl);
+    sys::mbedtls_ssl_config_free(&mut conf);
+    sys::mbedtls_x509_crt_free(&mut ca);
+    sys::mbedtls_x509_crt_free(&mut cert);
+    sys::mbedtls_pk_free(&mut key);
+    sys::mbedtls_ctr_drbg_free(&mut drbg);
+    sys::mbedtls_entropy_free(&mut entropy);
+    result
+}
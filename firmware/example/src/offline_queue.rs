@@ -0,0 +1,202 @@
+//! Store-and-forward for publishes made while the broker is unreachable.
+//! Records go into a ring buffer in their own NVS namespace, so a device on
+//! flaky WiFi keeps its samples across an outage and even a reboot. The
+//! client flushes them in order once it is connected again.
+//!
+//! Each record is a blob under the key of its slot, `m<n>`. `head` and
+//! `tail` are running sequence numbers: the slot of a record is its sequence
+//! number modulo the capacity. When the buffer is full the oldest record is
+//! dropped for the new one.
+//!
+//! A record starts with a flags byte (QoS, retain, expiry) and the topic
+//! length. A publish with an expiry has its deadline, in milliseconds since
+//! the Unix epoch, between that header and the topic.
+
+use esp_idf_svc::mqtt::client::QoS;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+pub const QUEUE_NAMESPACE: &str = "offline_q";
+const HEAD_KEY: &str = "head";
+const TAIL_KEY: &str = "tail";
+const CAPACITY_KEY: &str = "capacity";
+
+/// Largest topic plus payload kept; NVS has little room to spare.
+pub const MAX_RECORD_BYTES: usize = 1024;
+const HEADER_BYTES: usize = 3;
+const RETAIN_FLAG: u8 = 0x04;
+const EXPIRY_FLAG: u8 = 0x08;
+const EXPIRY_BYTES: usize = 8;
+
+/// A publish stored while offline.
+#[derive(Debug, Clone)]
+pub struct QueuedPublish {
+    pub topic: String,
+    pub payload: String,
+    pub qos: QoS,
+    pub retain: bool,
+    /// Not to be sent after this, in milliseconds since the Unix epoch
+    pub expires_at_ms: Option<u64>,
+}
+
+pub struct OfflineQueue {
+    nvs: EspNvs<NvsDefault>,
+    capacity: u32,
+    /// Sequence number of the oldest record
+    head: u32,
+    /// Sequence number of the next record
+    tail: u32,
+}
+
+impl OfflineQueue {
+    /// Open the queue holding up to `capacity` records, keeping what an
+    /// earlier boot stored unless the capacity changed.
+    pub fn open(partition: EspDefaultNvsPartition, capacity: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        if capacity == 0 {
+            return Err("Offline queue capacity must be at least 1".into());
+        }
+        let nvs = EspNvs::new(partition, QUEUE_NAMESPACE, true)?;
+        let mut queue = Self {
+            head: nvs.get_u32(HEAD_KEY)?.unwrap_or(0),
+            tail: nvs.get_u32(TAIL_KEY)?.unwrap_or(0),
+            nvs,
+            capacity,
+        };
+
+        // Slots depend on the capacity, so records stored with another one can't be found
+        let stored_capacity = queue.nvs.get_u32(CAPACITY_KEY)?;
+        if stored_capacity.is_some_and(|stored| stored != capacity) || queue.len() > capacity {
+            log::warn!("Offline queue capacity changed, dropping {} stored publish(es)", queue.len());
+            queue.clear(stored_capacity.unwrap_or(capacity))?;
+        }
+        queue.nvs.set_u32(CAPACITY_KEY, capacity)?;
+
+        if !queue.is_empty() {
+            log::info!("{} publish(es) stored while offline", queue.len());
+        }
+        Ok(queue)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.tail.wrapping_sub(self.head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Store a publish after the others, dropping the oldest when full.
+    pub fn push(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        qos: QoS,
+        retain: bool,
+        expires_at_ms: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if topic.len() + payload.len() > MAX_RECORD_BYTES {
+            return Err(format!(
+                "Publish to \"{}\" is too large to store offline ({} bytes)",
+                topic,
+                payload.len()
+            )
+            .into());
+        }
+        if self.len() >= self.capacity {
+            log::warn!("Offline queue full, dropping the oldest publish");
+            self.pop_front()?;
+        }
+
+        let mut record = Vec::with_capacity(HEADER_BYTES + EXPIRY_BYTES + topic.len() + payload.len());
+        let flags = qos_level(qos)
+            | if retain { RETAIN_FLAG } else { 0 }
+            | if expires_at_ms.is_some() { EXPIRY_FLAG } else { 0 };
+        record.push(flags);
+        record.extend_from_slice(&(topic.len() as u16).to_le_bytes());
+        if let Some(expires_at_ms) = expires_at_ms {
+            record.extend_from_slice(&expires_at_ms.to_le_bytes());
+        }
+        record.extend_from_slice(topic.as_bytes());
+        record.extend_from_slice(payload.as_bytes());
+
+        // The record first, so a reset in between leaves the queue as it was
+        self.nvs.set_raw(&self.slot_key(self.tail), &record)?;
+        self.tail = self.tail.wrapping_add(1);
+        self.nvs.set_u32(TAIL_KEY, self.tail)?;
+        Ok(())
+    }
+
+    /// The oldest publish, skipping records that can't be read back.
+    pub fn front(&mut self) -> Result<Option<QueuedPublish>, Box<dyn std::error::Error>> {
+        let mut buf = vec![0u8; HEADER_BYTES + EXPIRY_BYTES + MAX_RECORD_BYTES];
+        while !self.is_empty() {
+            let record = self.nvs.get_raw(&self.slot_key(self.head), &mut buf)?.and_then(decode);
+            match record {
+                Some(record) => return Ok(Some(record)),
+                None => {
+                    log::warn!("Dropping unreadable offline publish {}", self.head);
+                    self.pop_front()?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Forget the oldest publish, once it has been sent.
+    pub fn pop_front(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        self.nvs.remove(&self.slot_key(self.head))?;
+        self.head = self.head.wrapping_add(1);
+        self.nvs.set_u32(HEAD_KEY, self.head)?;
+        Ok(())
+    }
+
+    /// Drop every record, laid out for `capacity`.
+    fn clear(&mut self, capacity: u32) -> Result<(), Box<dyn std::error::Error>> {
+        for slot in 0..capacity {
+            self.nvs.remove(&format!("m{}", slot))?;
+        }
+        self.head = 0;
+        self.tail = 0;
+        self.nvs.set_u32(HEAD_KEY, 0)?;
+        self.nvs.set_u32(TAIL_KEY, 0)?;
+        Ok(())
+    }
+
+    fn slot_key(&self, sequence: u32) -> String {
+        format!("m{}", sequence % self.capacity)
+    }
+}
+
+fn decode(record: &[u8]) -> Option<QueuedPublish> {
+    let header = record.get(..HEADER_BYTES)?;
+    let topic_len = u16::from_le_bytes([header[1], header[2]]) as usize;
+    let (expires_at_ms, start) = if header[0] & EXPIRY_FLAG != 0 {
+        let deadline = record.get(HEADER_BYTES..HEADER_BYTES + EXPIRY_BYTES)?;
+        (Some(u64::from_le_bytes(deadline.try_into().ok()?)), HEADER_BYTES + EXPIRY_BYTES)
+    } else {
+        (None, HEADER_BYTES)
+    };
+    let topic = record.get(start..start + topic_len)?;
+    let payload = &record[start + topic_len..];
+    Some(QueuedPublish {
+        topic: String::from_utf8(topic.to_vec()).ok()?,
+        payload: String::from_utf8(payload.to_vec()).ok()?,
+        qos: match header[0] & 0x03 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        },
+        retain: header[0] & RETAIN_FLAG != 0,
+        expires_at_ms,
+    })
+}
+
+fn qos_level(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
@@ -0,0 +1,374 @@
+use crate::client::SharedClient;
+use crate::events::{Event, EventBus};
+use crate::jobs::{JobExecutor, JobOutcome};
+use crate::retry::{self, RetryPolicy, Subsystem};
+use crate::shadow::Shadow;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::ota::EspOta;
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+
+include!(concat!(env!("OUT_DIR"), "/ota_key.rs"));
+
+/// Version of the firmware currently running.
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Download chunk, also the HTTP client's buffer size.
+const CHUNK_SIZE: usize = 4096;
+/// Progress steps, in percent, between rollout shadow updates.
+const REPORT_STEP: u8 = 10;
+/// Attempts to reach the image server before failing the job.
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// OTA manifest as produced by `tools/release`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub version: String,
+    pub size: u64,
+    pub sha256: String,
+    pub signature: String,
+    pub min_compatible_version: String,
+    #[serde(default)]
+    pub hardware_revision: Option<String>,
+}
+
+/// `major.minor.patch` version; missing components count as zero and any
+/// pre-release/build suffix is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(u32, u32, u32);
+
+impl Version {
+    pub fn parse(version: &str) -> Result<Version, String> {
+        let core = version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default();
+
+        let mut parts = [0u32; 3];
+        for (i, part) in core.split('.').enumerate() {
+            if i >= parts.len() {
+                return Err(format!("Invalid version \"{}\": too many components", version));
+            }
+            parts[i] = part
+                .parse()
+                .map_err(|_| format!("Invalid version \"{}\"", version))?;
+        }
+
+        Ok(Version(parts[0], parts[1], parts[2]))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Refuse images that can't safely be installed on this unit. The returned
+/// error is descriptive enough to be reported as the job failure reason.
+pub fn check_compatibility(
+    manifest: &Manifest,
+    running_version: &str,
+    hardware_revision: &str,
+) -> Result<(), String> {
+    let running = Version::parse(running_version)?;
+    let min_compatible = Version::parse(&manifest.min_compatible_version)?;
+
+    if running.cmp(&min_compatible) == Ordering::Less {
+        return Err(format!(
+            "Image {} requires firmware >= {} to migrate from, running {}",
+            manifest.version, min_compatible, running
+        ));
+    }
+
+    if let Some(required) = manifest.hardware_revision.as_deref() {
+        if required != hardware_revision {
+            return Err(format!(
+                "Image {} is built for hardware revision \"{}\", this unit is \"{}\"",
+                manifest.version, required, hardware_revision
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Rollout state merged into the `firmware` named shadow, so fleet indexing
+/// queries such as `shadow.name.firmware.reported.status:failed` can follow
+/// a rollout. `current_version` is reported on every connect; a unit that
+/// rolled back shows it differing from `target_version`.
+#[derive(Serialize, Debug)]
+struct RolloutReport<'a> {
+    target_version: &'a str,
+    /// "downloading", "installed" or "failed"
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<u8>,
+    /// Cleared (null) unless the install failed
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct RunningFirmware {
+    current_version: &'static str,
+}
+
+/// Report the running version in the `firmware` shadow.
+pub fn report_running(shadow: &Shadow) -> Result<(), Box<dyn Error>> {
+    shadow.report(&RunningFirmware {
+        current_version: FIRMWARE_VERSION,
+    })
+}
+
+/// Job document created by `tools/release`.
+#[derive(Deserialize, Debug)]
+struct OtaJob {
+    url: String,
+    manifest: Manifest,
+}
+
+/// `{"operation": "ota", "url": ..., "manifest": {...}}`: download the image
+/// into the next OTA partition, verify its size, SHA-256 and signature
+/// against the manifest, then boot into it.
+pub struct OtaUpdate {
+    hardware_revision: &'static str,
+    public_key: &'static [u8],
+    events: EventBus,
+    rollout: Option<Shadow>,
+    retry_policy: RetryPolicy,
+}
+
+impl OtaUpdate {
+    /// None if the firmware was built without `ota_public_key`: unsigned
+    /// images are never installed.
+    pub fn new(hardware_revision: &'static str, events: EventBus) -> Option<Self> {
+        Some(Self {
+            hardware_revision,
+            public_key: OTA_PUBLIC_KEY?,
+            events,
+            rollout: None,
+            retry_policy: RetryPolicy::default(),
+        })
+    }
+
+    /// Back off by `policy` between attempts to reach the image server
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Report the state of each install in `shadow` (the `firmware` named shadow).
+    pub fn with_rollout_shadow(mut self, shadow: Shadow) -> Self {
+        self.rollout = Some(shadow);
+        self
+    }
+
+    /// Best effort: a lost report must not fail the install.
+    fn report(&self, report: RolloutReport) {
+        if let Some(shadow) = &self.rollout {
+            if let Err(e) = shadow.report(&report) {
+                log::warn!("Failed to report rollout state: {}", e);
+            }
+        }
+    }
+
+    fn install(&self, job: &OtaJob) -> Result<(), Box<dyn Error>> {
+        check_compatibility(&job.manifest, FIRMWARE_VERSION, self.hardware_revision)?;
+        log::info!("Installing firmware {} ({} bytes)", job.manifest.version, job.manifest.size);
+
+        let mut ota = EspOta::new()?;
+        let mut update = ota.initiate_update()?;
+        match self.download(job, |chunk| Ok(update.write(chunk)?)) {
+            Ok(()) => update.complete()?,
+            Err(e) => {
+                update.abort()?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stream the image into `write`, hashing it on the way, and check it
+    /// against the manifest.
+    fn download(
+        &self,
+        job: &OtaJob,
+        mut write: impl FnMut(&[u8]) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let policy = self.retry_policy.with_max_attempts(CONNECT_ATTEMPTS);
+        let mut connection = retry::retry(Subsystem::Ota, &policy, || -> Result<_, Box<dyn Error>> {
+            let mut connection = EspHttpConnection::new(&HttpConfiguration {
+                buffer_size: Some(CHUNK_SIZE),
+                crt_bundle_attach: Some(sys::esp_crt_bundle_attach),
+                ..Default::default()
+            })?;
+            connection.initiate_request(Method::Get, &job.url, &[])?;
+            connection.initiate_response()?;
+            Ok(connection)
+        })?;
+        if connection.status() != 200 {
+            return Err(format!("Image download failed with HTTP {}", connection.status()).into());
+        }
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut received = 0u64;
+        let mut last_percent = None;
+        loop {
+            let read = connection.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            received += read as u64;
+            if received > job.manifest.size {
+                return Err(format!("Image is larger than the manifest's {} bytes", job.manifest.size).into());
+            }
+            hasher.update(&buf[..read]);
+            write(&buf[..read])?;
+
+            let percent = (received * 100 / job.manifest.size.max(1)) as u8;
+            if last_percent != Some(percent) {
+                if last_percent.is_some_and(|last| last / REPORT_STEP != percent / REPORT_STEP) {
+                    let report = RolloutReport {
+                        target_version: &job.manifest.version,
+                        status: "downloading",
+                        progress: Some(percent),
+                        error: None,
+                    };
+                    self.report(report);
+                }
+                last_percent = Some(percent);
+                self.events.publish(Event::OtaProgress { percent });
+            }
+        }
+        if received != job.manifest.size {
+            return Err(format!("Image is {} bytes, manifest says {}", received, job.manifest.size).into());
+        }
+
+        let digest = hasher.finish();
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        if !hex.eq_ignore_ascii_case(&job.manifest.sha256) {
+            return Err("Image SHA-256 doesn't match the manifest".into());
+        }
+        verify_signature(self.public_key, &digest, &job.manifest.signature)
+    }
+}
+
+impl JobExecutor for OtaUpdate {
+    fn operation(&self) -> &'static str {
+        "ota"
+    }
+
+    fn execute(&mut self, _client: &SharedClient, document: &Value) -> Result<JobOutcome, Box<dyn Error>> {
+        let job: OtaJob = serde_json::from_value(document.clone())?;
+        let started = RolloutReport {
+            target_version: &job.manifest.version,
+            status: "downloading",
+            progress: Some(0),
+            error: None,
+        };
+        self.report(started);
+
+        if let Err(e) = self.install(&job) {
+            let failed = RolloutReport {
+                target_version: &job.manifest.version,
+                status: "failed",
+                progress: None,
+                error: Some(e.to_string()),
+            };
+            self.report(failed);
+            return Err(e);
+        }
+        let installed = RolloutReport {
+            target_version: &job.manifest.version,
+            status: "installed",
+            progress: Some(100),
+            error: None,
+        };
+        self.report(installed);
+
+        let mut details = serde_json::Map::new();
+        details.insert("version".to_string(), Value::String(job.manifest.version));
+        Ok(JobOutcome { details, restart: true })
+    }
+}
+
+/// Confirm the running image so the bootloader doesn't roll it back. Call
+/// once the device has reached the broker.
+pub fn mark_valid() -> Result<(), Box<dyn Error>> {
+    EspOta::new()?.mark_running_slot_valid()?;
+    Ok(())
+}
+
+/// mbedTLS SHA-256, so the firmware doesn't need a hashing crate.
+struct Sha256(sys::mbedtls_sha256_context);
+
+impl Sha256 {
+    fn new() -> Self {
+        let mut context = unsafe { core::mem::zeroed() };
+        unsafe {
+            sys::mbedtls_sha256_init(&mut context);
+            sys::mbedtls_sha256_starts(&mut context, 0);
+        }
+        Self(context)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { sys::mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        unsafe { sys::mbedtls_sha256_finish(&mut self.0, digest.as_mut_ptr()) };
+        digest
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+/// Check the manifest's base64 DER ECDSA signature of the image against the
+/// PEM public key baked in at build time.
+fn verify_signature(public_key: &[u8], digest: &[u8; 32], signature: &str) -> Result<(), Box<dyn Error>> {
+    let mut der = [0u8; 80];
+    let mut der_len = 0;
+    let decoded = unsafe {
+        sys::mbedtls_base64_decode(der.as_mut_ptr(), der.len(), &mut der_len, signature.as_ptr(), signature.len())
+    };
+    if decoded != 0 {
+        return Err("Manifest signature isn't valid base64".into());
+    }
+
+    // mbedTLS wants PEM input NUL-terminated, with the NUL counted
+    let mut pem = public_key.to_vec();
+    pem.push(0);
+    unsafe {
+        let mut key: sys::mbedtls_pk_context = core::mem::zeroed();
+        sys::mbedtls_pk_init(&mut key);
+        let mut result = sys::mbedtls_pk_parse_public_key(&mut key, pem.as_ptr(), pem.len());
+        if result == 0 {
+            result = sys::mbedtls_pk_verify(
+                &mut key,
+                sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len(),
+                der.as_ptr(),
+                der_len,
+            );
+        }
+        sys::mbedtls_pk_free(&mut key);
+        if result != 0 {
+            return Err(format!("Image signature verification failed (mbedTLS error -0x{:04x})", -result).into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! The device's counters in the Prometheus text exposition format, served
+//! by the LAN console on `/metrics` so on-prem deployments can scrape
+//! devices directly.
+
+use crate::middleware::Metrics;
+use crate::retry;
+use crate::traffic::TrafficCounters;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Handles to the counters to expose. Cheap to clone, the counters are shared.
+#[derive(Clone)]
+pub struct Exporter {
+    metrics: Metrics,
+    traffic: TrafficCounters,
+    started: Instant,
+}
+
+impl Exporter {
+    pub fn new(metrics: Metrics, traffic: TrafficCounters) -> Self {
+        Self {
+            metrics,
+            traffic,
+            started: Instant::now(),
+        }
+    }
+
+    /// Every metric with its HELP and TYPE lines.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let traffic = self.traffic.stats();
+        let messages = self.metrics.stats();
+
+        let counters = [
+            ("mqtt_sent_messages_total", "MQTT messages handed to the connection", traffic.messages_sent),
+            ("mqtt_sent_bytes_total", "Payload bytes handed to the connection", traffic.bytes_sent),
+            ("mqtt_received_messages_total", "MQTT messages received", traffic.messages_received),
+            ("mqtt_received_bytes_total", "Payload bytes received", traffic.bytes_received),
+            ("mqtt_reconnects_total", "Broker connections after the first", traffic.reconnects),
+            ("mqtt_subscribe_failures_total", "Subscribe calls esp-mqtt refused", traffic.subscribe_failures),
+            ("mqtt_echoes_total", "Own messages delivered back and dropped", messages.echoes),
+            ("mqtt_duplicates_total", "Redelivered commands dropped", messages.duplicates),
+        ];
+        for (name, help, value) in counters {
+            metric(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        metric(&mut out, "retries_total", "Retries per subsystem", "counter");
+        for (subsystem, count) in retry::retry_counts() {
+            let _ = writeln!(out, "retries_total{{subsystem=\"{}\"}} {}", subsystem.as_str(), count);
+        }
+
+        metric(&mut out, "uptime_seconds", "Time since the firmware started", "gauge");
+        let _ = writeln!(out, "uptime_seconds {}", self.started.elapsed().as_secs());
+        metric(&mut out, "free_heap_bytes", "Free heap", "gauge");
+        let _ = writeln!(out, "free_heap_bytes {}", unsafe { esp_idf_svc::sys::esp_get_free_heap_size() });
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
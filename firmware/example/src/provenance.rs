@@ -0,0 +1,78 @@
+// Include the generated provenance record from build.rs
+include!(concat!(env!("OUT_DIR"), "/provenance.rs"));
+
+use crate::client::SharedClient;
+use crate::envelope;
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::mqtt::client::QoS;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use serde::{Deserialize, Serialize};
+
+/// Fingerprint of the last record published, so each build is published once
+const PUBLISHED_KEY: &str = "prov_published";
+
+#[derive(Deserialize, Debug, Default)]
+struct Summary {
+    version: String,
+    commit: String,
+    dirty: bool,
+    builder: String,
+    rustc: String,
+    esp_idf: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ProvenanceEvent {
+    event: &'static str,
+    #[serde(flatten)]
+    record: serde_json::Value,
+}
+
+/// JSON record of where this binary came from: source commit, builder,
+/// toolchain versions and Cargo.lock hash.
+pub fn record() -> &'static str {
+    std::str::from_utf8(&PROVENANCE).unwrap_or("{}")
+}
+
+/// One line naming the build, for the boot log.
+pub fn banner() -> String {
+    let summary: Summary = serde_json::from_str(record()).unwrap_or_default();
+    format!(
+        "Firmware {} from {}{}, built by {} with {} for ESP-IDF {}",
+        summary.version,
+        summary.commit,
+        if summary.dirty { " (modified)" } else { "" },
+        summary.builder,
+        summary.rustc,
+        summary.esp_idf
+    )
+}
+
+/// Publish the record to `topic` at QoS 1, unless this build was published
+/// before. Call once connected.
+pub fn publish_once(
+    client: &SharedClient,
+    nvs: EspDefaultNvsPartition,
+    device_id: &str,
+    topic: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = EspNvs::new(nvs, NAMESPACE, true)?;
+    // FNV-1a, stable across builds of the firmware
+    let fingerprint = record().bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let fingerprint = format!("{:016x}", fingerprint);
+    let mut buf = [0u8; 17];
+    if storage.get_str(PUBLISHED_KEY, &mut buf)? == Some(fingerprint.as_str()) {
+        return Ok(());
+    }
+
+    let event = ProvenanceEvent {
+        event: "provenance",
+        record: serde_json::from_str(record())?,
+    };
+    client.publish_with_qos(topic, &envelope::to_json(device_id, &event)?, QoS::AtLeastOnce)?;
+    storage.set_str(PUBLISHED_KEY, &fingerprint)?;
+    log::info!("Published the build provenance to \"{}\"", topic);
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Bounded queue in front of the esp-mqtt outbox. esp-mqtt keeps every
+//! enqueued message on the heap until it is sent (QoS 0) or acknowledged
+//! (QoS 1), so a bursty sensor on a slow link can exhaust the heap. With a
+//! queue, the client only enqueues while the outbox is below a byte limit
+//! and holds the rest here, up to `capacity` messages, handling overflow as
+//! configured.
+
+use esp_idf_svc::mqtt::client::QoS;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// What to do with a publish when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the oldest queued publish to make room
+    DropOldest,
+    /// Drop the new publish
+    DropNewest,
+    /// Wait for room, failing the publish after a timeout
+    Block,
+}
+
+impl Overflow {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "drop_oldest" => Ok(Overflow::DropOldest),
+            "drop_newest" => Ok(Overflow::DropNewest),
+            "block" => Ok(Overflow::Block),
+            other => Err(format!(
+                "Unknown publish queue overflow policy \"{}\" (drop_oldest, drop_newest or block)",
+                other
+            )),
+        }
+    }
+}
+
+/// A publish waiting for room in the outbox, after the middleware chain.
+pub(crate) struct Pending {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    /// Dropped instead of sent after this
+    pub expires: Option<Instant>,
+}
+
+pub struct PublishQueue {
+    capacity: usize,
+    overflow: Overflow,
+    outbox_limit: usize,
+    pending: VecDeque<Pending>,
+    dropped: u64,
+}
+
+impl PublishQueue {
+    /// Hold up to `capacity` publishes while the outbox holds `outbox_limit`
+    /// bytes or more.
+    pub fn new(capacity: usize, overflow: Overflow, outbox_limit: usize) -> Result<Self, String> {
+        if capacity == 0 {
+            return Err("Publish queue capacity must be at least 1".into());
+        }
+        Ok(Self {
+            capacity,
+            overflow,
+            outbox_limit,
+            pending: VecDeque::with_capacity(capacity),
+            dropped: 0,
+        })
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    pub fn outbox_limit(&self) -> usize {
+        self.outbox_limit
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.capacity
+    }
+
+    /// Publishes dropped on overflow since boot.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Queue `publish`, dropping one per the overflow policy when full.
+    /// With `Block` the caller waits for room first.
+    pub(crate) fn push(&mut self, publish: Pending) {
+        if self.is_full() {
+            self.dropped += 1;
+            match self.overflow {
+                Overflow::DropNewest => {
+                    log::warn!("Publish queue full, dropping publish to \"{}\"", publish.topic);
+                    return;
+                }
+                Overflow::DropOldest | Overflow::Block => {
+                    if let Some(oldest) = self.pending.pop_front() {
+                        log::warn!("Publish queue full, dropping oldest publish to \"{}\"", oldest.topic);
+                    }
+                }
+            }
+        }
+        self.pending.push_back(publish);
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Pending> {
+        self.pending.pop_front()
+    }
+
+    /// Put back a publish esp-mqtt refused, to retry it first.
+    pub(crate) fn push_front(&mut self, publish: Pending) {
+        self.pending.push_front(publish);
+    }
+}
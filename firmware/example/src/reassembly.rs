@@ -0,0 +1,248 @@
+//! Reassembly of incoming messages esp-mqtt delivers in pieces. A message
+//! larger than the client's receive buffer arrives as a first chunk carrying
+//! the topic and the total size, then as chunks carrying their offset and no
+//! topic. The listener feeds every piece through a [`Reassembler`] and only
+//! hands complete messages, in an owned buffer, to the middleware.
+//!
+//! Nothing here touches ESP-IDF, so the unit tests run on the host (see
+//! `firmware/host-tests`).
+
+use std::error::Error;
+use std::fmt;
+
+/// Cap when no inbound size limit is configured: AWS IoT's largest message.
+pub const DEFAULT_MAX_BYTES: usize = 128 * 1024;
+
+/// Where a piece belongs in its message, from esp-mqtt's event details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fragment {
+    /// The whole message in one piece
+    Complete,
+    /// The first piece of a message of `total` bytes
+    First { total: usize },
+    /// A later piece, starting `offset` bytes into the message
+    Next { offset: usize, total: usize },
+}
+
+/// Why a message was dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReassemblyError {
+    TooLarge { size: usize, limit: usize },
+    /// A later piece arrived without the first one
+    MissingStart { offset: usize },
+    /// A piece didn't continue where the message so far ends
+    OutOfOrder { offset: usize, expected: usize },
+    /// The pieces add up to more than the announced size
+    Overrun { size: usize, total: usize },
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReassemblyError::TooLarge { size, limit } => {
+                write!(f, "payload of {} bytes exceeds the {}-byte limit", size, limit)
+            }
+            ReassemblyError::MissingStart { offset } => {
+                write!(f, "piece at offset {} without the start of its message", offset)
+            }
+            ReassemblyError::OutOfOrder { offset, expected } => {
+                write!(f, "piece at offset {} where {} was expected", offset, expected)
+            }
+            ReassemblyError::Overrun { size, total } => {
+                write!(f, "pieces add up to {} bytes of a {}-byte message", size, total)
+            }
+        }
+    }
+}
+
+impl Error for ReassemblyError {}
+
+enum State {
+    Idle,
+    Receiving { topic: String, total: usize, data: Vec<u8> },
+    /// Dropping the rest of a message over the limit
+    Skipping { total: usize },
+}
+
+/// Collects the pieces of one message at a time, as esp-mqtt never
+/// interleaves two messages.
+pub struct Reassembler {
+    max_bytes: usize,
+    state: State,
+}
+
+impl Reassembler {
+    /// Drop messages over `max_bytes` before buffering them; `0` means
+    /// [`DEFAULT_MAX_BYTES`].
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes: if max_bytes == 0 { DEFAULT_MAX_BYTES } else { max_bytes },
+            state: State::Idle,
+        }
+    }
+
+    /// Add a piece. Returns the topic and payload once a message is
+    /// complete; a message without a topic gets an empty one. A piece that
+    /// starts a message abandons any message still incomplete.
+    pub fn push(
+        &mut self,
+        topic: Option<&str>,
+        data: &[u8],
+        fragment: Fragment,
+    ) -> Result<Option<(String, Vec<u8>)>, ReassemblyError> {
+        match fragment {
+            Fragment::Complete => {
+                self.state = State::Idle;
+                self.check_size(data.len())?;
+                Ok(Some((topic.unwrap_or_default().to_string(), data.to_vec())))
+            }
+            Fragment::First { total } => {
+                self.state = State::Idle;
+                if let Err(e) = self.check_size(total) {
+                    self.state = State::Skipping { total };
+                    return Err(e);
+                }
+                let mut buffer = Vec::with_capacity(total);
+                buffer.extend_from_slice(data);
+                self.state = State::Receiving {
+                    topic: topic.unwrap_or_default().to_string(),
+                    total,
+                    data: buffer,
+                };
+                self.finish()
+            }
+            Fragment::Next { offset, total } => match &mut self.state {
+                State::Idle => Err(ReassemblyError::MissingStart { offset }),
+                State::Skipping { total: skipping } => {
+                    if offset + data.len() >= *skipping {
+                        self.state = State::Idle;
+                    }
+                    Ok(None)
+                }
+                State::Receiving { total: expected_total, data: buffer, .. } => {
+                    if offset != buffer.len() || total != *expected_total {
+                        let expected = buffer.len();
+                        self.state = State::Idle;
+                        return Err(ReassemblyError::OutOfOrder { offset, expected });
+                    }
+                    buffer.extend_from_slice(data);
+                    self.finish()
+                }
+            },
+        }
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), ReassemblyError> {
+        if size > self.max_bytes {
+            return Err(ReassemblyError::TooLarge {
+                size,
+                limit: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Hand over the message being received if it is complete.
+    fn finish(&mut self) -> Result<Option<(String, Vec<u8>)>, ReassemblyError> {
+        let State::Receiving { total, data, .. } = &self.state else {
+            return Ok(None);
+        };
+        if data.len() < *total {
+            return Ok(None);
+        }
+        let (size, total) = (data.len(), *total);
+        match std::mem::replace(&mut self.state, State::Idle) {
+            State::Receiving { topic, data, .. } if size == total => Ok(Some((topic, data))),
+            _ => Err(ReassemblyError::Overrun { size, total }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, payload: &[u8]) -> Option<(String, Vec<u8>)> {
+        Some((topic.to_string(), payload.to_vec()))
+    }
+
+    #[test]
+    fn complete_message_passes_through() {
+        let mut reassembler = Reassembler::new(16);
+        let result = reassembler.push(Some("cmd"), b"{}", Fragment::Complete);
+        assert_eq!(result, Ok(message("cmd", b"{}")));
+    }
+
+    #[test]
+    fn chunks_are_joined_under_the_first_topic() {
+        let mut reassembler = Reassembler::new(0);
+        assert_eq!(reassembler.push(Some("cmd"), b"hel", Fragment::First { total: 8 }), Ok(None));
+        assert_eq!(reassembler.push(None, b"lo ", Fragment::Next { offset: 3, total: 8 }), Ok(None));
+        let result = reassembler.push(None, b"yo", Fragment::Next { offset: 6, total: 8 });
+        assert_eq!(result, Ok(message("cmd", b"hello yo")));
+    }
+
+    #[test]
+    fn message_over_the_limit_is_dropped_with_its_chunks() {
+        let mut reassembler = Reassembler::new(4);
+        let result = reassembler.push(Some("cmd"), b"abc", Fragment::First { total: 6 });
+        assert_eq!(result, Err(ReassemblyError::TooLarge { size: 6, limit: 4 }));
+        assert_eq!(reassembler.push(None, b"def", Fragment::Next { offset: 3, total: 6 }), Ok(None));
+        // The next message starts clean
+        let result = reassembler.push(Some("cmd"), b"ok", Fragment::Complete);
+        assert_eq!(result, Ok(message("cmd", b"ok")));
+    }
+
+    #[test]
+    fn complete_message_over_the_limit_is_dropped() {
+        let mut reassembler = Reassembler::new(2);
+        let result = reassembler.push(Some("cmd"), b"abc", Fragment::Complete);
+        assert_eq!(result, Err(ReassemblyError::TooLarge { size: 3, limit: 2 }));
+    }
+
+    #[test]
+    fn chunk_without_a_start_is_rejected() {
+        let mut reassembler = Reassembler::new(0);
+        let result = reassembler.push(None, b"def", Fragment::Next { offset: 3, total: 6 });
+        assert_eq!(result, Err(ReassemblyError::MissingStart { offset: 3 }));
+    }
+
+    #[test]
+    fn gap_between_chunks_drops_the_message() {
+        let mut reassembler = Reassembler::new(0);
+        reassembler.push(Some("cmd"), b"abc", Fragment::First { total: 9 }).unwrap();
+        let result = reassembler.push(None, b"ghi", Fragment::Next { offset: 6, total: 9 });
+        assert_eq!(result, Err(ReassemblyError::OutOfOrder { offset: 6, expected: 3 }));
+        let result = reassembler.push(None, b"def", Fragment::Next { offset: 3, total: 9 });
+        assert_eq!(result, Err(ReassemblyError::MissingStart { offset: 3 }));
+    }
+
+    #[test]
+    fn chunks_past_the_announced_size_are_rejected() {
+        let mut reassembler = Reassembler::new(0);
+        reassembler.push(Some("cmd"), b"abc", Fragment::First { total: 4 }).unwrap();
+        let result = reassembler.push(None, b"def", Fragment::Next { offset: 3, total: 4 });
+        assert_eq!(result, Err(ReassemblyError::Overrun { size: 6, total: 4 }));
+    }
+
+    #[test]
+    fn new_message_abandons_an_incomplete_one() {
+        let mut reassembler = Reassembler::new(0);
+        reassembler.push(Some("old"), b"abc", Fragment::First { total: 9 }).unwrap();
+        let result = reassembler.push(Some("new"), b"xy", Fragment::Complete);
+        assert_eq!(result, Ok(message("new", b"xy")));
+        let result = reassembler.push(None, b"def", Fragment::Next { offset: 3, total: 9 });
+        assert_eq!(result, Err(ReassemblyError::MissingStart { offset: 3 }));
+    }
+
+    #[test]
+    fn payload_is_an_owned_copy() {
+        let mut reassembler = Reassembler::new(0);
+        let mut event_buffer = b"abc".to_vec();
+        reassembler.push(Some("cmd"), &event_buffer, Fragment::First { total: 6 }).unwrap();
+        // esp-mqtt reuses its buffer for the next piece
+        event_buffer.copy_from_slice(b"def");
+        let result = reassembler.push(None, &event_buffer, Fragment::Next { offset: 3, total: 6 });
+        assert_eq!(result, Ok(message("cmd", b"abcdef")));
+    }
+}
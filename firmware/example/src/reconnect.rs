@@ -0,0 +1,138 @@
+//! Broker reconnection with jittered exponential backoff. esp-mqtt on its
+//! own retries at the fixed `reconnect_timeout`, which hammers the broker
+//! during an outage and synchronizes a fleet that lost it at the same time.
+//! Here esp-mqtt's own timer is only a backstop at twice the cap; a manager
+//! thread triggers each attempt with `esp_mqtt_client_reconnect` once the
+//! backoff delay has passed. A URL carrying expiring credentials, SigV4's,
+//! is signed again before each attempt.
+
+use crate::retry::{RetryPolicy, Subsystem};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use esp_idf_svc::sys::{self, esp_mqtt_client_handle_t};
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the reconnection stands, as reported to status callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectStatus {
+    Connected,
+    /// Connection lost; attempt number `attempt` starts after `delay`
+    Waiting { attempt: u32, delay: Duration },
+    /// Attempt number `attempt` is under way
+    Reconnecting { attempt: u32 },
+}
+
+/// What the listener thread tells the manager.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LinkEvent {
+    Connected,
+    /// A disconnect or a failed attempt. One failure may bring more than one
+    Lost,
+}
+
+pub type StatusCallback = Box<dyn Fn(&ReconnectStatus) + Send>;
+
+/// Signs the URL of the endpoint the next attempt goes to.
+pub(crate) type Signer = Box<dyn Fn() -> Result<String, Box<dyn std::error::Error>> + Send>;
+
+#[derive(Clone, Default)]
+pub(crate) struct StatusCallbacks(Arc<Mutex<Vec<StatusCallback>>>);
+
+impl StatusCallbacks {
+    pub fn register(&self, callback: StatusCallback) {
+        self.0.lock().unwrap().push(callback);
+    }
+
+    fn report(&self, status: ReconnectStatus) {
+        for callback in self.0.lock().unwrap().iter() {
+            callback(&status);
+        }
+    }
+}
+
+/// The esp-mqtt handle, only used through its thread-safe C API.
+struct Handle(esp_mqtt_client_handle_t);
+
+unsafe impl Send for Handle {}
+
+/// Start the manager thread. It runs until the listener drops its sender.
+pub(crate) fn spawn(
+    handle: esp_mqtt_client_handle_t,
+    policy: RetryPolicy,
+    events: Receiver<LinkEvent>,
+    signer: Option<Signer>,
+    callbacks: StatusCallbacks,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let handle = Handle(handle);
+    // Signing fetches credentials over HTTPS
+    let stack_size = if signer.is_some() { 12 * 1024 } else { 4096 };
+    thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(move || run(handle, policy.with_max_attempts(0), events, signer, callbacks))
+        .map_err(|e| format!("Failed to spawn reconnect manager thread: {}", e))?;
+    Ok(())
+}
+
+fn run(
+    handle: Handle,
+    policy: RetryPolicy,
+    events: Receiver<LinkEvent>,
+    signer: Option<Signer>,
+    callbacks: StatusCallbacks,
+) {
+    let mut backoff = policy.backoff(Subsystem::Mqtt);
+    while let Ok(event) = events.recv() {
+        if let LinkEvent::Connected = event {
+            if backoff.attempt() > 0 {
+                log::info!("MQTT reconnected after {} attempt(s)", backoff.attempt());
+            }
+            backoff.reset();
+            callbacks.report(ReconnectStatus::Connected);
+            continue;
+        }
+
+        let delay = backoff.next_delay().unwrap_or(policy.max_delay);
+        let attempt = backoff.attempt();
+        log::warn!("MQTT connection lost, reconnect attempt {} in {:?}", attempt, delay);
+        callbacks.report(ReconnectStatus::Waiting { attempt, delay });
+
+        // More losses from the same failure are absorbed by the wait; a
+        // connect in the meantime ends it
+        let deadline = Instant::now() + delay;
+        let connected = loop {
+            match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(LinkEvent::Lost) => {}
+                Ok(LinkEvent::Connected) => break true,
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        };
+        if connected {
+            backoff.reset();
+            callbacks.report(ReconnectStatus::Connected);
+            continue;
+        }
+
+        callbacks.report(ReconnectStatus::Reconnecting { attempt });
+        if let Some(signer) = &signer {
+            // On failure the attempt still goes ahead, with the last URL
+            if let Err(e) = resign(&handle, signer) {
+                log::warn!("Failed to sign the broker URL for attempt {}: {}", attempt, e);
+            }
+        }
+        if let Err(e) = sys::esp!(unsafe { sys::esp_mqtt_client_reconnect(handle.0) }) {
+            // Not waiting to reconnect, e.g. already connecting
+            log::debug!("esp-mqtt ignored reconnect attempt {}: {}", attempt, e);
+        }
+    }
+}
+
+/// Point esp-mqtt at a freshly signed URL. Never logs the URL, it carries
+/// the signature.
+fn resign(handle: &Handle, signer: &Signer) -> Result<(), Box<dyn std::error::Error>> {
+    let uri = CString::new(signer()?)?;
+    sys::esp!(unsafe { sys::esp_mqtt_client_set_uri(handle.0, uri.as_ptr()) })?;
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Moving a device to another endpoint and thing, e.g. when a fleet migrates
+//! between AWS accounts. The `reprovision` command brings the new endpoint,
+//! thing and certificate. Before anything is stored, the device opens a
+//! second connection with them next to the live one; only when the new
+//! broker accepts it are they written to NVS and the device restarts onto
+//! them. A bundle that doesn't work leaves the device where it was, and it
+//! never has to go back to the production line.
+
+use crate::auth::NvsX509;
+use crate::client::{Client, ConnectOptions};
+use crate::startup::Config;
+use crate::{factory, keygen};
+use crossbeam_channel::{bounded, RecvTimeoutError};
+use embedded_svc::mqtt::client::EventPayload;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use serde::Deserialize;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the new broker has to accept the test connection
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const PEM_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Payload of the `reprovision` command.
+#[derive(Deserialize, Debug)]
+pub struct Bundle {
+    pub mqtt_url: String,
+    pub thing_name: String,
+    /// Defaults to the thing name
+    #[serde(default)]
+    pub client_id: Option<String>,
+    pub certificate: String,
+    /// Without one, the certificate must be issued for the on-device key
+    #[serde(default)]
+    pub private_key: Option<String>,
+}
+
+impl Bundle {
+    fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or(&self.thing_name)
+    }
+}
+
+/// Check `bundle` with a test connection and store it for the next boot.
+/// On error nothing is stored.
+pub fn apply(nvs: EspDefaultNvsPartition, config: &Config, bundle: &Bundle) -> Result<(), Box<dyn Error>> {
+    if !bundle.mqtt_url.starts_with("mqtts://") {
+        return Err(format!("Needs an mqtts:// URL, not \"{}\"", bundle.mqtt_url).into());
+    }
+    if !bundle.certificate.trim_start().starts_with(PEM_CERTIFICATE) {
+        return Err("certificate is not a PEM certificate".into());
+    }
+    // The test connection would take over the live one
+    if bundle.mqtt_url == config.mqtt_url && bundle.client_id() == config.mqtt_client_id {
+        return Err("Same endpoint and client id as the current connection".into());
+    }
+    let private_key = match &bundle.private_key {
+        Some(private_key) => private_key.clone(),
+        None => keygen::load_private_key(nvs.clone())?.ok_or("No private_key and no on-device key")?,
+    };
+
+    test_connection(config, bundle, private_key)?;
+    log::info!("{} accepted the new identity, storing it", bundle.mqtt_url);
+
+    keygen::replace_identity(nvs.clone(), &bundle.certificate, bundle.private_key.as_deref())?;
+    factory::store_endpoint(nvs, &bundle.mqtt_url, bundle.client_id(), &bundle.thing_name)?;
+    Ok(())
+}
+
+/// Connect to the new broker with the new identity, alongside the live
+/// connection, and disconnect again.
+fn test_connection(config: &Config, bundle: &Bundle, private_key: String) -> Result<(), Box<dyn Error>> {
+    let identity = NvsX509::new(bundle.certificate.clone(), private_key);
+    let mut client = Client::new(
+        &bundle.mqtt_url,
+        bundle.client_id(),
+        config.mqtt_topic_pub,
+        config.mqtt_topic_sub,
+        ConnectOptions::default().with_alpn(config.use_alpn),
+        &identity,
+    )?;
+    let mut connection = client.mqtt_connection.take().ok_or("MQTT connection already taken")?;
+
+    let (tx, rx) = bounded::<Result<(), String>>(4);
+    thread::Builder::new().stack_size(6000).spawn(move || {
+        // Ends once the client is dropped
+        while let Ok(event) = connection.next() {
+            let outcome = match event.payload() {
+                EventPayload::Connected(_) => Ok(()),
+                EventPayload::Error(e) => Err(e.to_string()),
+                EventPayload::Disconnected => Err("Disconnected by the broker".to_string()),
+                _ => continue,
+            };
+            let _ = tx.try_send(outcome);
+        }
+    })?;
+
+    // esp-mqtt keeps retrying, so an error only counts if nothing better
+    // follows before the timeout
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    let mut last_error = None;
+    let result = loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(())) => break Ok(()),
+            Ok(Err(e)) => {
+                log::warn!("Test connection to {} failed: {}", bundle.mqtt_url, e);
+                last_error = Some(e);
+            }
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                break Err(match last_error {
+                    Some(e) => format!("Test connection failed: {}", e),
+                    None => format!("No connection within {:?}", TEST_TIMEOUT),
+                });
+            }
+        }
+    };
+    drop(client);
+    Ok(result?)
+}
@@ -0,0 +1,135 @@
+//! Requests from the device to the cloud that expect a reply, made with
+//! [`Client::request`](crate::client::Client::request). Each request carries
+//! a `request_id` and a `reply_to` topic, the command topic; the responder
+//! publishes its reply there with the same `request_id`. The listener hands
+//! replies to the waiting caller before they could be taken for commands.
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Ids of requests that timed out, so a late reply is still recognized
+const MAX_EXPIRED: usize = 8;
+
+#[derive(Deserialize)]
+struct Correlation {
+    request_id: Option<String>,
+}
+
+struct Pending {
+    sender: Sender<Vec<u8>>,
+    /// The request itself, in case the broker echoes it back
+    request: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    pending: BTreeMap<String, Pending>,
+    expired: VecDeque<String>,
+}
+
+/// Requests waiting for a reply, shared by the client and its listener.
+#[derive(Clone, Default)]
+pub struct Requests {
+    state: Arc<Mutex<State>>,
+    counter: Arc<AtomicU32>,
+}
+
+impl Requests {
+    /// A fresh request id: random per boot, counting up within it.
+    pub fn next_id(&self) -> String {
+        let boot = unsafe { esp_idf_svc::sys::esp_random() };
+        format!("{:08x}-{}", boot, self.counter.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Wait for the reply to `request`, which carries `id`.
+    pub fn register(&self, id: &str, request: &[u8]) -> Reply {
+        let (sender, receiver) = bounded(1);
+        self.state.lock().unwrap().pending.insert(
+            id.to_string(),
+            Pending {
+                sender,
+                request: request.to_vec(),
+            },
+        );
+        Reply {
+            id: id.to_string(),
+            receiver,
+            requests: self.clone(),
+        }
+    }
+
+    /// Called by the listener for messages on the command topic. Returns
+    /// true if `payload` is the reply to a request, waited for or expired.
+    pub fn resolve(&self, payload: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.is_empty() && state.expired.is_empty() {
+            return false;
+        }
+        let Some(id) = serde_json::from_slice::<Correlation>(payload)
+            .ok()
+            .and_then(|correlation| correlation.request_id)
+        else {
+            return false;
+        };
+        if state.expired.contains(&id) {
+            log::warn!("Reply to request {} arrived after the timeout, dropping it", id);
+            return true;
+        }
+        match state.pending.get(&id) {
+            Some(pending) if pending.request != payload => {
+                let _ = pending.sender.try_send(payload.to_vec());
+                state.pending.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expire(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.remove(id).is_none() {
+            return;
+        }
+        if state.expired.len() >= MAX_EXPIRED {
+            state.expired.pop_front();
+        }
+        state.expired.push_back(id.to_string());
+    }
+}
+
+/// The reply to one request, once it arrives.
+pub struct Reply {
+    id: String,
+    receiver: Receiver<Vec<u8>>,
+    requests: Requests,
+}
+
+impl Reply {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Block until the reply arrives, for up to `timeout`.
+    pub fn wait(self, timeout: Duration) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                self.requests.expire(&self.id);
+                Err(format!("No reply to request {} within {:?}", self.id, timeout).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(format!("Request {} abandoned", self.id).into()),
+        }
+    }
+}
+
+impl Drop for Reply {
+    /// Nobody waits for the reply anymore, e.g. because publishing failed
+    fn drop(&mut self) {
+        self.requests.state.lock().unwrap().pending.remove(&self.id);
+    }
+}
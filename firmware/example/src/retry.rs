@@ -0,0 +1,158 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Subsystems that retry, used to attribute retry counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Wifi,
+    Mqtt,
+    Subscribe,
+    Ota,
+    Sntp,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::Wifi,
+        Subsystem::Mqtt,
+        Subsystem::Subscribe,
+        Subsystem::Ota,
+        Subsystem::Sntp,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subsystem::Wifi => "wifi",
+            Subsystem::Mqtt => "mqtt",
+            Subsystem::Subscribe => "subscribe",
+            Subsystem::Ota => "ota",
+            Subsystem::Sntp => "sntp",
+        }
+    }
+}
+
+static RETRIES: [AtomicU32; Subsystem::ALL.len()] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Total retries since boot, per subsystem.
+pub fn retry_counts() -> [(Subsystem, u32); Subsystem::ALL.len()] {
+    Subsystem::ALL.map(|subsystem| (subsystem, RETRIES[subsystem as usize].load(Ordering::Relaxed)))
+}
+
+/// Exponential backoff, optionally jittered, capped at `max_delay` and
+/// limited to `max_attempts` (0 = retry forever).
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+    pub jitter: bool,
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2,
+            jitter: true,
+            max_attempts: 0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn backoff(&self, subsystem: Subsystem) -> Backoff {
+        Backoff {
+            policy: *self,
+            subsystem,
+            attempt: 0,
+        }
+    }
+}
+
+/// Retry state for one operation.
+pub struct Backoff {
+    policy: RetryPolicy,
+    subsystem: Subsystem,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt, or `None` once the budget is spent.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempt += 1;
+        if self.policy.max_attempts != 0 && self.attempt >= self.policy.max_attempts {
+            return None;
+        }
+        RETRIES[self.subsystem as usize].fetch_add(1, Ordering::Relaxed);
+
+        let factor = self.policy.multiplier.saturating_pow(self.attempt - 1);
+        let delay = self
+            .policy
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.policy.max_delay);
+
+        if !self.policy.jitter {
+            return Some(delay);
+        }
+        // Equal jitter: somewhere between half and all of the delay
+        let half = delay / 2;
+        let spread = half.as_millis() as u32;
+        let random = unsafe { esp_idf_svc::sys::esp_random() };
+        Some(half + Duration::from_millis((random % spread.max(1)) as u64))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Run `operation` until it succeeds or the policy's budget is exhausted,
+/// returning the last error in that case.
+pub fn retry<T, E: Display>(
+    subsystem: Subsystem,
+    policy: &RetryPolicy,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = policy.backoff(subsystem);
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) => match backoff.next_delay() {
+                Some(delay) => {
+                    log::warn!(
+                        "{} attempt {} failed: {}, retrying in {:?}",
+                        subsystem.as_str(),
+                        backoff.attempt(),
+                        e,
+                        delay
+                    );
+                    thread::sleep(delay);
+                }
+                None => {
+                    log::error!("{} failed after {} attempts: {}", subsystem.as_str(), backoff.attempt(), e);
+                    return Err(e);
+                }
+            },
+        }
+    }
+}
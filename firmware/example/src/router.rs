@@ -0,0 +1,141 @@
+//! Per-topic message handlers registered with
+//! [`Client::subscribe_with_handler`](crate::client::Client::subscribe_with_handler).
+//!
+//! The listener thread only sorts messages: those on a routed topic go to
+//! their own channel instead of the command or reserved channels. The
+//! handlers run on the main loop from `Client::dispatch`, so they can
+//! publish through the client they are given.
+
+use crate::client::Client;
+use std::sync::{Arc, Mutex};
+
+/// Handles messages on the topics it was registered for. Implemented for
+/// closures taking the client, the logical topic and the payload. `Send`
+/// so the client can be shared as a [`SharedClient`].
+///
+/// [`SharedClient`]: crate::client::SharedClient
+pub trait Handler: Send {
+    fn handle(&mut self, client: &mut Client, topic: &str, payload: &[u8]);
+}
+
+impl<F: FnMut(&mut Client, &str, &[u8]) + Send> Handler for F {
+    fn handle(&mut self, client: &mut Client, topic: &str, payload: &[u8]) {
+        self(client, topic, payload)
+    }
+}
+
+struct Route {
+    filter: String,
+    handler: Box<dyn Handler>,
+}
+
+/// Routing table from topic filters to handlers. A message goes to the
+/// first handler whose filter matches, in registration order.
+#[derive(Default)]
+pub struct Router {
+    /// Shared with the listener thread, which only needs to know whether a
+    /// topic is routed
+    filters: Arc<Mutex<Vec<String>>>,
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn register(&mut self, filter: &str, handler: impl Handler + 'static) -> Result<(), String> {
+        validate(filter)?;
+        self.filters.lock().unwrap().push(filter.to_string());
+        self.routes.push(Route {
+            filter: filter.to_string(),
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Drop the routes registered for `filter`. Returns false if there were none.
+    pub fn unregister(&mut self, filter: &str) -> bool {
+        let mut filters = self.filters.lock().unwrap();
+        let before = filters.len();
+        filters.retain(|registered| registered != filter);
+        self.routes.retain(|route| route.filter != filter);
+        filters.len() != before
+    }
+
+    pub fn matcher(&self) -> RouteMatcher {
+        RouteMatcher(self.filters.clone())
+    }
+
+    /// Remove the routes to dispatch them while the client is borrowed by
+    /// the handlers; `restore` puts them back.
+    pub(crate) fn take(&mut self) -> Router {
+        Router {
+            filters: self.filters.clone(),
+            routes: std::mem::take(&mut self.routes),
+        }
+    }
+
+    /// Put back routes taken by `take`, ahead of any registered meanwhile,
+    /// except those a handler unregistered.
+    pub(crate) fn restore(&mut self, mut taken: Router) {
+        let filters = self.filters.lock().unwrap();
+        taken.routes.retain(|route| filters.contains(&route.filter));
+        drop(filters);
+        taken.routes.append(&mut self.routes);
+        self.routes = taken.routes;
+    }
+
+    /// Run the handler for `topic`. Returns false if no route matches.
+    pub fn dispatch(&mut self, client: &mut Client, topic: &str, payload: &[u8]) -> bool {
+        match self.routes.iter_mut().find(|route| matches(&route.filter, topic)) {
+            Some(route) => {
+                route.handler.handle(client, topic, payload);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The listener thread's view of the routing table.
+#[derive(Clone)]
+pub struct RouteMatcher(Arc<Mutex<Vec<String>>>);
+
+impl RouteMatcher {
+    pub fn is_routed(&self, topic: &str) -> bool {
+        self.0.lock().unwrap().iter().any(|filter| matches(filter, topic))
+    }
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`, with `+` for one
+/// level and a trailing `#` for any number of levels, including none.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // Wildcards in the first level don't match reserved topics like $aws/...
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+fn validate(filter: &str) -> Result<(), String> {
+    if filter.is_empty() {
+        return Err("Empty topic filter".to_string());
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard = level.contains(['+', '#']);
+        if wildcard && level.len() > 1 {
+            return Err(format!("Topic filter \"{}\": a wildcard must fill a whole level", filter));
+        }
+        if *level == "#" && i + 1 != levels.len() {
+            return Err(format!("Topic filter \"{}\": # must be the last level", filter));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Typed state in RTC slow memory. It keeps its contents through deep sleep,
+//! so a battery profile's wake cycle can pick up where the last one left off
+//! without reading NVS, and only writes NVS when something worth keeping
+//! across a power loss changed. A reset or power cycle starts it over.
+//!
+//! Each slot is a static in `.rtc.data` holding one value, serialized with a
+//! length and a CRC-32. A slot that was never written, or was torn by a
+//! brownout in the middle of a store, reads as empty, and the caller falls
+//! back to NVS or defaults:
+//!
+//! ```ignore
+//! #[link_section = ".rtc.data"]
+//! static STATE: RtcSlot<State, 128> = RtcSlot::new();
+//! ```
+//!
+//! Without the `link_section` the slot is ordinary RAM and always empty
+//! after a wake. Slots are for the wake cycle's own thread; they aren't
+//! locked.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::marker::PhantomData;
+
+/// Length (u16) and CRC-32 (u32) ahead of the value
+const HEADER: usize = 6;
+
+/// Room for one `T` in `N` bytes, header included.
+pub struct RtcSlot<T, const N: usize> {
+    bytes: UnsafeCell<[u8; N]>,
+    value: PhantomData<fn() -> T>,
+}
+
+// Only used from the thread running the wake cycle, see the module docs
+unsafe impl<T, const N: usize> Sync for RtcSlot<T, N> {}
+
+impl<T: Serialize + DeserializeOwned, const N: usize> Default for RtcSlot<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, const N: usize> RtcSlot<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: UnsafeCell::new([0; N]),
+            value: PhantomData,
+        }
+    }
+
+    /// The value last stored, if it survived intact.
+    pub fn load(&self) -> Option<T> {
+        let bytes = unsafe { &*self.bytes.get() };
+        let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if len == 0 || len > N - HEADER {
+            return None;
+        }
+        let crc = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+        let payload = &bytes[HEADER..HEADER + len];
+        if crc32(payload) != crc {
+            log::warn!("RTC slot of {} bytes failed its checksum", len);
+            return None;
+        }
+        match serde_json::from_slice(payload) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("RTC slot doesn't hold the expected value: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, value: &T) -> Result<(), Box<dyn Error>> {
+        let payload = serde_json::to_vec(value)?;
+        if payload.len() > N - HEADER {
+            return Err(format!("{} bytes don't fit an RTC slot of {}", payload.len(), N).into());
+        }
+        let bytes = unsafe { &mut *self.bytes.get() };
+        // Length last, so a store cut short doesn't pass for a whole one
+        bytes[..2].fill(0);
+        bytes[2..HEADER].copy_from_slice(&crc32(&payload).to_le_bytes());
+        bytes[HEADER..HEADER + payload.len()].copy_from_slice(&payload);
+        bytes[..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        let bytes = unsafe { &mut *self.bytes.get() };
+        bytes[..2].fill(0);
+    }
+}
+
+/// CRC-32 (IEEE, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
@@ -0,0 +1,191 @@
+//! Schemas of the commands accepted on the command topic, embedded in the
+//! firmware. A command is checked against the schema of its action before it
+//! runs, so a malformed one is answered with what is wrong with each field
+//! instead of a parse error or "Unknown action".
+//!
+//! Adding a command means adding its schema to [`COMMANDS`]. Fields not in
+//! the schema are ignored, as serde does.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    String,
+    /// Whole number within an inclusive range
+    Integer { min: i64, max: i64 },
+    /// One of a fixed set of strings
+    OneOf(&'static [&'static str]),
+    Bool,
+}
+
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+pub struct CommandSchema {
+    /// Value of the `message` field
+    pub action: &'static str,
+    pub fields: &'static [Field],
+}
+
+const fn required(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: true }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: false }
+}
+
+/// Fields any command may carry.
+const COMMON: &[Field] = &[
+    optional("timestamp", Kind::Integer { min: 0, max: i64::MAX }),
+    optional("request_id", Kind::String),
+];
+
+pub const COMMANDS: &[CommandSchema] = &[
+    CommandSchema { action: "ping", fields: &[] },
+    CommandSchema { action: "version", fields: &[] },
+    CommandSchema {
+        action: "bench",
+        fields: &[
+            optional("count", Kind::Integer { min: 1, max: 1000 }),
+            optional("size", Kind::Integer { min: 0, max: 8192 }),
+            optional("qos", Kind::Integer { min: 0, max: 1 }),
+        ],
+    },
+    CommandSchema { action: "tasks.list", fields: &[] },
+    CommandSchema { action: "conn.stats", fields: &[] },
+    CommandSchema {
+        action: "snapshot",
+        fields: &[optional("upload_url", Kind::String), optional("key", Kind::String)],
+    },
+    CommandSchema {
+        action: "heap_trace",
+        fields: &[required("action", Kind::OneOf(&["start", "stop"]))],
+    },
+    CommandSchema {
+        action: "irrigate",
+        fields: &[
+            required("zone", Kind::Integer { min: 0, max: 31 }),
+            required("minutes", Kind::Integer { min: 1, max: 1440 }),
+        ],
+    },
+    CommandSchema { action: "irrigate_stop", fields: &[] },
+    CommandSchema { action: "estop_reset", fields: &[] },
+    CommandSchema {
+        action: "alarm_ack",
+        fields: &[required("id", Kind::String)],
+    },
+    CommandSchema { action: "csr", fields: &[] },
+    CommandSchema {
+        action: "chaos",
+        fields: &[
+            required("fault", Kind::OneOf(&["drop_wifi", "stall_listener", "delay_publish", "oom"])),
+            optional("duration_secs", Kind::Integer { min: 1, max: 3600 }),
+        ],
+    },
+    CommandSchema {
+        action: "install_cert",
+        fields: &[required("certificate", Kind::String)],
+    },
+    CommandSchema {
+        action: "reprovision",
+        fields: &[
+            required("mqtt_url", Kind::String),
+            required("thing_name", Kind::String),
+            optional("client_id", Kind::String),
+            required("certificate", Kind::String),
+            optional("private_key", Kind::String),
+        ],
+    },
+    CommandSchema {
+        action: "service",
+        fields: &[
+            required("name", Kind::OneOf(&["telemetry", "shadow", "console"])),
+            required("enabled", Kind::Bool),
+        ],
+    },
+];
+
+/// What is wrong with one field.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum Problem {
+    Missing,
+    WrongType { expected: &'static str },
+    OutOfRange { min: i64, max: i64 },
+    NotOneOf { allowed: Vec<&'static str> },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: &'static str,
+    #[serde(flatten)]
+    pub problem: Problem,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            Problem::Missing => write!(f, "{}: missing", self.field),
+            Problem::WrongType { expected } => write!(f, "{}: expected {}", self.field, expected),
+            Problem::OutOfRange { min, max } => write!(f, "{}: must be between {} and {}", self.field, min, max),
+            Problem::NotOneOf { allowed } => write!(f, "{}: must be one of {}", self.field, allowed.join(", ")),
+        }
+    }
+}
+
+/// Check a command object against the schema of its action. Every field
+/// is checked, so all problems are reported at once.
+pub fn validate(command: &serde_json::Map<String, Value>) -> Result<(), Vec<FieldError>> {
+    let schema = match command.get("message") {
+        None => return Err(vec![error("message", Problem::Missing)]),
+        Some(Value::String(action)) => COMMANDS.iter().find(|schema| schema.action == action),
+        Some(_) => return Err(vec![error("message", Problem::WrongType { expected: "a string" })]),
+    };
+    let Some(schema) = schema else {
+        let allowed = COMMANDS.iter().map(|schema| schema.action).collect();
+        return Err(vec![error("message", Problem::NotOneOf { allowed })]);
+    };
+
+    let errors: Vec<FieldError> = schema
+        .fields
+        .iter()
+        .chain(COMMON)
+        .filter_map(|field| check(field, command.get(field.name)))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check(field: &Field, value: Option<&Value>) -> Option<FieldError> {
+    let problem = match (field.kind, value) {
+        (_, None) | (_, Some(Value::Null)) if !field.required => return None,
+        (_, None) => Problem::Missing,
+        (Kind::String, Some(Value::String(_))) => return None,
+        (Kind::String, Some(_)) => Problem::WrongType { expected: "a string" },
+        (Kind::Integer { min, max }, Some(value)) => match value.as_i64() {
+            Some(number) if (min..=max).contains(&number) => return None,
+            Some(_) => Problem::OutOfRange { min, max },
+            None => Problem::WrongType { expected: "an integer" },
+        },
+        (Kind::OneOf(allowed), Some(Value::String(choice))) if allowed.contains(&choice.as_str()) => return None,
+        (Kind::OneOf(allowed), Some(_)) => Problem::NotOneOf {
+            allowed: allowed.to_vec(),
+        },
+        (Kind::Bool, Some(Value::Bool(_))) => return None,
+        (Kind::Bool, Some(_)) => Problem::WrongType { expected: "a boolean" },
+    };
+    Some(error(field.name, problem))
+}
+
+fn error(field: &'static str, problem: Problem) -> FieldError {
+    FieldError { field, problem }
+}
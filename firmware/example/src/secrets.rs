@@ -0,0 +1,148 @@
+//! Compile-time secrets encrypted at rest. With `secrets_key` set, build.rs
+//! encrypts the embedded private keys and the secret cfg.toml settings (see
+//! [`settings`]) with AES-256-GCM, so they don't sit in flash as plaintext. The AES key is never in the image: it is derived
+//! as HMAC-SHA256(device-class key, [`CONTEXT`]), where the device-class key
+//! is burned into an eFuse key block with purpose `HMAC_UP` and only the HMAC
+//! peripheral can use it.
+
+use esp_idf_svc::sys;
+use std::borrow::Cow;
+use std::ffi::c_void;
+use std::sync::OnceLock;
+
+/// HMAC message the AES key is derived from; build.rs uses the same one.
+pub const CONTEXT: &[u8] = b"aws-iot-esp32 cfg secrets v1";
+const TAG_LEN: usize = 16;
+
+/// A secret embedded by build.rs.
+pub enum Sealed {
+    Plain(&'static [u8]),
+    Encrypted {
+        /// eFuse key block holding the device-class key, 0 for BLOCK_KEY0
+        key_block: u32,
+        nonce: [u8; 12],
+        /// Ciphertext followed by the 16-byte GCM tag
+        data: &'static [u8],
+    },
+}
+
+impl Sealed {
+    pub fn len(&self) -> usize {
+        match self {
+            Sealed::Plain(data) => data.len(),
+            Sealed::Encrypted { data, .. } => data.len().saturating_sub(TAG_LEN),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The plaintext, decrypting it first if it was encrypted. A device
+    /// without the right eFuse key fails here rather than leaking anything.
+    pub fn open(&self) -> Result<Cow<'static, [u8]>, Box<dyn std::error::Error>> {
+        match self {
+            Sealed::Plain(data) => Ok(Cow::Borrowed(data)),
+            Sealed::Encrypted { key_block, nonce, data } => {
+                let mut key = derive_key(*key_block)?;
+                let plaintext = decrypt(&key, nonce, data);
+                key.fill(0);
+                Ok(Cow::Owned(plaintext?))
+            }
+        }
+    }
+}
+
+/// A secret cfg.toml setting. Opened on first use and kept for the life of
+/// the firmware, like the plain settings.
+pub struct Setting {
+    sealed: Sealed,
+    text: OnceLock<&'static str>,
+}
+
+impl Setting {
+    pub const fn new(sealed: Sealed) -> Self {
+        Self {
+            sealed,
+            text: OnceLock::new(),
+        }
+    }
+
+    /// Whether the setting was left empty, without decrypting it.
+    pub fn is_empty(&self) -> bool {
+        self.sealed.is_empty()
+    }
+
+    pub fn open(&self) -> Result<&'static str, Box<dyn std::error::Error>> {
+        if let Some(text) = self.text.get() {
+            return Ok(text);
+        }
+        let text = String::from_utf8(self.sealed.open()?.into_owned())?;
+        Ok(self.text.get_or_init(|| Box::leak(text.into_boxed_str())))
+    }
+}
+
+/// The cfg.toml settings build.rs keeps out of [`crate::startup::Config`]:
+/// `wifi_pass`, `aws_secret_access_key`, `aws_session_token`,
+/// `custom_auth_password`, `custom_auth_token`, `custom_auth_signature` and
+/// `console_token`, by their names in capitals.
+pub mod settings {
+    include!(concat!(env!("OUT_DIR"), "/sealed_settings.rs"));
+}
+
+fn derive_key(key_block: u32) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut key = [0u8; 32];
+    sys::esp!(unsafe {
+        sys::esp_hmac_calculate(
+            sys::hmac_key_id_t_HMAC_KEY0 + key_block,
+            CONTEXT.as_ptr() as *const c_void,
+            CONTEXT.len(),
+            key.as_mut_ptr(),
+        )
+    })
+    .map_err(|e| format!("No usable HMAC key in eFuse BLOCK_KEY{}: {}", key_block, e))?;
+    Ok(key)
+}
+
+fn decrypt(key: &[u8; 32], nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if data.len() < TAG_LEN {
+        return Err("Encrypted secret is truncated".into());
+    }
+    let (ciphertext, tag) = data.split_at(data.len() - TAG_LEN);
+    let mut plaintext = vec![0u8; ciphertext.len()];
+
+    let ret = unsafe {
+        let mut gcm: sys::mbedtls_gcm_context = std::mem::zeroed();
+        sys::mbedtls_gcm_init(&mut gcm);
+        let mut ret = sys::mbedtls_gcm_setkey(
+            &mut gcm,
+            sys::mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+            key.as_ptr(),
+            256,
+        );
+        if ret == 0 {
+            ret = sys::mbedtls_gcm_auth_decrypt(
+                &mut gcm,
+                ciphertext.len(),
+                nonce.as_ptr(),
+                nonce.len(),
+                core::ptr::null(),
+                0,
+                tag.as_ptr(),
+                TAG_LEN,
+                ciphertext.as_ptr(),
+                plaintext.as_mut_ptr(),
+            );
+        }
+        sys::mbedtls_gcm_free(&mut gcm);
+        ret
+    };
+    if ret != 0 {
+        return Err(format!(
+            "Failed to decrypt secret (mbedTLS error -0x{:04x}): wrong device-class key?",
+            -ret
+        )
+        .into());
+    }
+    Ok(plaintext)
+}
@@ -0,0 +1,48 @@
+//! Services that can be stopped and started while the device runs, to shed
+//! load or silence a misbehaving feature without an OTA. Each is switched by
+//! the feature flag `service.<name>`, which defaults to on: through
+//! `desired.flags` in the shadow, or the `service` command. Like any flag
+//! the choice is kept in NVS, so a stopped service stays stopped across
+//! reboots until it is switched on again.
+//!
+//! Only services that cfg.toml enables can be started; the flag can't turn
+//! on what the configuration left off.
+
+use crate::flags::FeatureFlags;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// Periodic telemetry publishes
+    Telemetry,
+    /// Shadow sync: the classic and named shadows, unsubscribed while
+    /// stopped. Only the `service` command can start it again
+    Shadow,
+    /// The LAN console's HTTP server
+    Console,
+}
+
+impl Service {
+    pub const ALL: [Service; 3] = [Service::Telemetry, Service::Shadow, Service::Console];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Service::Telemetry => "telemetry",
+            Service::Shadow => "shadow",
+            Service::Console => "console",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|service| service.name() == name)
+    }
+
+    /// The feature flag switching it.
+    pub fn flag(self) -> String {
+        format!("service.{}", self.name())
+    }
+
+    /// Whether the flags want it running.
+    pub fn wanted(self, flags: &FeatureFlags) -> bool {
+        flags.get(&self.flag()).unwrap_or(true)
+    }
+}
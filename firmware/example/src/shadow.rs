@@ -0,0 +1,409 @@
+use crate::client::SharedClient;
+use crate::events::{Event, EventBus};
+use crate::timer::PeriodicTimer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The `state` section of a shadow document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct ShadowState<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reported: Option<T>,
+    /// Desired values that differ from the reported ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<T>,
+}
+
+/// Document published on `get/accepted`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct ShadowDocument<T> {
+    pub state: ShadowState<T>,
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// Message published on `update/delta`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct ShadowDelta<T> {
+    pub state: T,
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// Request published on `update` to merge `reported` into the shadow.
+#[derive(Serialize, Debug)]
+pub struct ShadowUpdate<'a, T: Serialize> {
+    pub state: ShadowState<&'a T>,
+}
+
+/// Called with the `state` object of every delta a shadow applies.
+pub type DeltaCallback = Box<dyn FnMut(&Value) + Send>;
+
+/// Topics of one of a thing's shadows.
+pub struct ShadowTopics {
+    pub get: String,
+    pub get_accepted: String,
+    pub get_rejected: String,
+    pub update: String,
+    pub update_rejected: String,
+    pub update_delta: String,
+}
+
+impl ShadowTopics {
+    pub fn classic(thing_name: &str) -> Self {
+        Self::with_prefix(format!("$aws/things/{}/shadow", thing_name))
+    }
+
+    pub fn named(thing_name: &str, shadow_name: &str) -> Self {
+        Self::with_prefix(format!("$aws/things/{}/shadow/name/{}", thing_name, shadow_name))
+    }
+
+    fn with_prefix(prefix: String) -> Self {
+        Self {
+            get: format!("{}/get", prefix),
+            get_accepted: format!("{}/get/accepted", prefix),
+            get_rejected: format!("{}/get/rejected", prefix),
+            update: format!("{}/update", prefix),
+            update_rejected: format!("{}/update/rejected", prefix),
+            update_delta: format!("{}/update/delta", prefix),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootstrapState {
+    /// `shadow/get` sent, waiting for the document
+    Pending { requested: Instant },
+    /// Any delta pending at connect time has been applied
+    Running,
+}
+
+/// Reported state kept by a shadow that publishes only what changed.
+struct ReportDiff {
+    /// Everything reported since boot, merged the way the shadow merges it
+    reported: Value,
+    /// Phased per device, so a fleet doesn't send full reports in lockstep
+    full_every: PeriodicTimer,
+    /// Set by a rejected update, cleared by the next full report
+    forced: bool,
+}
+
+/// Fetches the shadow on every (re)connect so desired state set while the
+/// device was offline is applied before it starts accepting commands.
+///
+/// Deltas go to the callback set with [`Shadow::on_delta`], or otherwise onto
+/// the event bus as [`Event::ShadowDelta`] (classic shadow) or
+/// [`Event::NamedShadowDelta`].
+pub struct Shadow {
+    pub topics: ShadowTopics,
+    /// None for the classic shadow
+    name: Option<String>,
+    on_delta: Option<DeltaCallback>,
+    state: BootstrapState,
+    /// Version of the newest document or delta applied
+    version: Option<u64>,
+    timeout: Duration,
+    /// Unsubscribed and not reporting, see [`Shadow::stop`]
+    stopped: bool,
+    /// Set by [`Shadow::with_diff_reports`]
+    diff: Option<Mutex<ReportDiff>>,
+    client: SharedClient,
+    events: EventBus,
+}
+
+impl Shadow {
+    pub fn new(thing_name: &str, timeout: Duration, client: SharedClient, events: EventBus) -> Self {
+        Self {
+            topics: ShadowTopics::classic(thing_name),
+            name: None,
+            on_delta: None,
+            state: BootstrapState::Running,
+            version: None,
+            timeout,
+            stopped: false,
+            diff: None,
+            client,
+            events,
+        }
+    }
+
+    /// The named shadow `shadow_name` of `thing_name`.
+    pub fn named(
+        thing_name: &str,
+        shadow_name: &str,
+        timeout: Duration,
+        client: SharedClient,
+        events: EventBus,
+    ) -> Self {
+        Self {
+            topics: ShadowTopics::named(thing_name, shadow_name),
+            name: Some(shadow_name.to_string()),
+            ..Self::new(thing_name, timeout, client, events)
+        }
+    }
+
+    /// Hand this shadow's deltas to `callback` instead of the event bus.
+    pub fn on_delta(mut self, callback: impl FnMut(&Value) + Send + 'static) -> Self {
+        self.on_delta = Some(Box::new(callback));
+        self
+    }
+
+    /// Report only the fields that differ from what was reported before,
+    /// and the whole reported state every `full_every` in case an update
+    /// was lost. A rejected update makes the next report a full one too.
+    /// `client_id` sets the phase of the full reports.
+    pub fn with_diff_reports(mut self, full_every: Duration, client_id: &str) -> Self {
+        let name = match &self.name {
+            Some(name) => format!("shadow/{}", name),
+            None => "shadow".to_string(),
+        };
+        self.diff = Some(Mutex::new(ReportDiff {
+            reported: Value::Object(Default::default()),
+            full_every: PeriodicTimer::new(full_every, client_id, &name),
+            forced: false,
+        }));
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == BootstrapState::Running
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Stop syncing: unsubscribe from the shadow topics and drop reports
+    /// until [`Shadow::start`].
+    pub fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.stopped = true;
+        self.state = BootstrapState::Running;
+        let mut client = self.client.lock();
+        client.unsubscribe(&self.topics.get_accepted)?;
+        client.unsubscribe(&self.topics.get_rejected)?;
+        client.unsubscribe(&self.topics.update_delta)?;
+        if self.diff.is_some() {
+            client.unsubscribe(&self.topics.update_rejected)?;
+        }
+        log::info!("Stopped syncing {}", self.label());
+        Ok(())
+    }
+
+    /// Sync again after [`Shadow::stop`], fetching the document right away
+    /// when `connected`.
+    pub fn start(&mut self, connected: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.stopped = false;
+        // The shadow may have changed while nothing was reported
+        self.force_full_report();
+        log::info!("Syncing {} again", self.label());
+        if connected {
+            self.bootstrap()?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the shadow responses and request the current document.
+    /// Call on every connect. The client restores subscriptions after a
+    /// reconnect too, but not necessarily before the request goes out.
+    pub fn bootstrap(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.stopped {
+            return Ok(());
+        }
+        let mut client = self.client.lock();
+        client.subscribe_topic(&self.topics.get_accepted)?;
+        client.subscribe_topic(&self.topics.get_rejected)?;
+        client.subscribe_topic(&self.topics.update_delta)?;
+        if self.diff.is_some() {
+            client.subscribe_topic(&self.topics.update_rejected)?;
+        }
+
+        client.publish_to(&self.topics.get, "")?;
+        self.state = BootstrapState::Pending {
+            requested: Instant::now(),
+        };
+        log::info!("Requested {} document, holding commands until it's applied", self.label());
+        Ok(())
+    }
+
+    /// Give up waiting for the shadow document after the timeout so a
+    /// missing response can't stall the device forever.
+    pub fn poll(&mut self) {
+        if let BootstrapState::Pending { requested } = self.state {
+            if requested.elapsed() >= self.timeout {
+                log::warn!("No {} document after {:?}, continuing without it", self.label(), self.timeout);
+                self.state = BootstrapState::Running;
+            }
+        }
+    }
+
+    /// Handle a message on a shadow topic. Returns false if the topic isn't ours.
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> bool {
+        if topic == self.topics.get_accepted {
+            match serde_json::from_slice::<ShadowDocument<Value>>(payload) {
+                Ok(document) => {
+                    self.version = document.version;
+                    if let Some(delta) = document.state.delta {
+                        log::info!("Applying {} delta pending since last connect", self.label());
+                        self.deliver(&delta);
+                    }
+                }
+                Err(e) => log::warn!("Invalid {} document: {}", self.label(), e),
+            }
+            self.enter_running();
+        } else if topic == self.topics.get_rejected {
+            // 404 simply means no shadow exists yet
+            log::info!("Get rejected for {}: {}", self.label(), String::from_utf8_lossy(payload));
+            self.enter_running();
+        } else if topic == self.topics.update_delta {
+            match serde_json::from_slice::<ShadowDelta<Value>>(payload) {
+                // Deltas can arrive out of order; an older one is already superseded
+                Ok(delta) if delta.version.is_some() && delta.version <= self.version => {
+                    log::info!("Ignoring stale {} delta version {:?}", self.label(), delta.version);
+                }
+                Ok(delta) => {
+                    self.version = delta.version.or(self.version);
+                    self.deliver(&delta.state);
+                }
+                Err(e) => log::warn!("Invalid {} delta: {}", self.label(), e),
+            }
+        } else if topic == self.topics.update_rejected && self.diff.is_some() {
+            log::warn!("Update rejected for {}: {}", self.label(), String::from_utf8_lossy(payload));
+            self.force_full_report();
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Merge `reported` into the shadow's reported state. With
+    /// [`Shadow::with_diff_reports`], unchanged fields are left out and
+    /// nothing is published when nothing changed.
+    pub fn report<T: Serialize>(&self, reported: &T) -> Result<(), Box<dyn std::error::Error>> {
+        if self.stopped {
+            return Ok(());
+        }
+        let Some(diff) = &self.diff else {
+            return self.publish_reported(reported);
+        };
+        let reported = serde_json::to_value(reported)?;
+        let mut diff = diff.lock().unwrap();
+        let due = diff.full_every.poll();
+        if due || diff.forced {
+            let mut document = diff.reported.clone();
+            merge(&mut document, &reported);
+            self.publish_reported(&document)?;
+            diff.reported = document;
+            diff.forced = false;
+            return Ok(());
+        }
+        match changes(&diff.reported, &reported) {
+            Some(changed) => {
+                self.publish_reported(&changed)?;
+                merge(&mut diff.reported, &changed);
+            }
+            None => log::debug!("Nothing changed in {} report", self.label()),
+        }
+        Ok(())
+    }
+
+    fn publish_reported<T: Serialize>(&self, reported: &T) -> Result<(), Box<dyn std::error::Error>> {
+        let update = ShadowUpdate {
+            state: ShadowState {
+                desired: None,
+                reported: Some(reported),
+                delta: None,
+            },
+        };
+        self.client.publish_to(&self.topics.update, &serde_json::to_string(&update)?)?;
+        Ok(())
+    }
+
+    fn force_full_report(&self) {
+        if let Some(diff) = &self.diff {
+            diff.lock().unwrap().forced = true;
+        }
+    }
+
+    fn deliver(&mut self, delta: &Value) {
+        match (self.on_delta.as_mut(), &self.name) {
+            (Some(callback), _) => callback(delta),
+            (None, Some(name)) => self.events.publish(Event::NamedShadowDelta {
+                name: name.clone(),
+                delta: delta.to_string(),
+            }),
+            (None, None) => self.events.publish(Event::ShadowDelta(delta.to_string())),
+        }
+    }
+
+    /// For log messages: "shadow" or "shadow \"<name>\"".
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("shadow \"{}\"", name),
+            None => "shadow".to_string(),
+        }
+    }
+
+    fn enter_running(&mut self) {
+        if !self.is_running() {
+            log::info!("Bootstrap of {} complete", self.label());
+        }
+        self.state = BootstrapState::Running;
+    }
+}
+
+/// The parts of `next` that would change `previous` when merged into it, or
+/// None if merging it changes nothing. Objects are compared field by field,
+/// anything else as a whole, as the shadow service merges them.
+fn changes(previous: &Value, next: &Value) -> Option<Value> {
+    match (previous, next) {
+        (Value::Object(previous), Value::Object(next)) => {
+            let changed: serde_json::Map<String, Value> = next
+                .iter()
+                .filter_map(|(key, value)| match previous.get(key) {
+                    Some(old) => changes(old, value).map(|value| (key.clone(), value)),
+                    // Deleting a field that isn't there is a no-op
+                    None => (!value.is_null()).then(|| (key.clone(), value.clone())),
+                })
+                .collect();
+            (!changed.is_empty()).then_some(Value::Object(changed))
+        }
+        _ => (previous != next).then(|| next.clone()),
+    }
+}
+
+/// Merge `patch` into `target` the way the shadow service merges reported
+/// state: objects field by field, null deletes a field.
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(target) = target else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! AWS Signature Version 4 for the AWS IoT WebSocket endpoint. The
+//! signature travels in the query string of the `wss://` URL, which is how
+//! AWS IoT accepts SigV4 from clients that can't set upgrade headers.
+
+use esp_idf_svc::sys;
+use serde::Deserialize;
+
+const SERVICE: &str = "iotdevicegateway";
+/// How long the signed URL is accepted for a new connection.
+const EXPIRES_SECS: u32 = 86_400;
+
+/// Long-term IAM keys, or temporary ones with a session token.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
+/// Presign `wss://<host>/mqtt` for `region` at `now_secs` (seconds since
+/// the epoch).
+pub fn presign_mqtt_url(host: &str, region: &str, credentials: &Credentials, now_secs: u64) -> String {
+    let (date, amz_date) = format_time(now_secs);
+    let scope = format!("{}/{}/{}/aws4_request", date, region, SERVICE);
+
+    let query = format!(
+        "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+        uri_encode(&format!("{}/{}", credentials.access_key_id, scope)),
+        amz_date,
+        EXPIRES_SECS
+    );
+    let canonical_request = format!(
+        "GET\n/mqtt\n{}\nhost:{}\n\nhost\n{}",
+        query,
+        host,
+        hex(&sha256(b""))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&sha256(canonical_request.as_bytes()))
+    );
+
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac(key.as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, SERVICE.as_bytes());
+    let key = hmac(&key, b"aws4_request");
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    let mut url = format!("wss://{}/mqtt?{}&X-Amz-Signature={}", host, query, signature);
+    // AWS IoT wants the token outside the signed part
+    if let Some(token) = &credentials.session_token {
+        url.push_str("&X-Amz-Security-Token=");
+        url.push_str(&uri_encode(token));
+    }
+    url
+}
+
+/// Region of an AWS IoT endpoint such as `abc123-ats.iot.us-east-1.amazonaws.com`.
+pub fn region_of(host: &str) -> Option<&str> {
+    let mut labels = host.split('.');
+    labels.find(|label| *label == "iot")?;
+    labels.next()
+}
+
+/// `(YYYYMMDD, YYYYMMDDTHHMMSSZ)` in UTC.
+fn format_time(secs: u64) -> (String, String) {
+    let days = (secs / 86_400) as i64;
+    let time = secs % 86_400;
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let amz_date = format!("{}T{:02}{:02}{:02}Z", date, time / 3600, time / 60 % 60, time % 60);
+    (date, amz_date)
+}
+
+/// Percent-encode everything but the RFC 3986 unreserved characters.
+pub(crate) fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    unsafe {
+        let info = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        sys::mbedtls_md(info, data.as_ptr(), data.len(), digest.as_mut_ptr());
+    }
+    digest
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = [0u8; 32];
+    unsafe {
+        let info = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        sys::mbedtls_md_hmac(info, key.as_ptr(), key.len(), data.as_ptr(), data.len(), mac.as_mut_ptr());
+    }
+    mac
+}
@@ -0,0 +1,151 @@
+use crate::envelope;
+use crate::startup::App;
+use crate::timer::PeriodicTimer;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Soak statistics are published this often.
+const REPORT_INTERVAL: Duration = Duration::from_secs(3600);
+/// WiFi outage used for forced reconnects.
+const RECONNECT_OUTAGE: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+struct SoakMessage {
+    event: &'static str,
+    seq: u64,
+    padding: String,
+}
+
+#[derive(Serialize, Debug)]
+struct SoakReport {
+    event: &'static str,
+    uptime_secs: u64,
+    publishes: u64,
+    publish_errors: u64,
+    bytes_published: u64,
+    reconnects: u32,
+    forced_reconnects: u32,
+    handler_errors: u32,
+    free_heap: u32,
+    min_free_heap: u32,
+    largest_free_block: usize,
+}
+
+/// Long-running stability run used to validate a build before release:
+/// publishes random-size payloads, forces periodic reconnects and reports
+/// memory, reconnect and error statistics every hour.
+pub struct Soak {
+    topic: String,
+    max_payload: usize,
+    started: Instant,
+    publish_timer: PeriodicTimer,
+    reconnect_timer: PeriodicTimer,
+    report_timer: PeriodicTimer,
+    seq: u64,
+    publish_errors: u64,
+    bytes_published: u64,
+    connected_once: bool,
+    reconnects: u32,
+    forced_reconnects: u32,
+    handler_errors: u32,
+}
+
+impl Soak {
+    pub fn new(app: &App) -> Self {
+        let config = &app.config;
+        let client_id = config.mqtt_client_id;
+        Self {
+            topic: format!("{}/soak", config.mqtt_topic_pub),
+            max_payload: config.soak_max_payload_bytes,
+            started: Instant::now(),
+            publish_timer: PeriodicTimer::new(
+                Duration::from_millis(config.soak_publish_interval_ms),
+                client_id,
+                "soak-publish",
+            ),
+            reconnect_timer: PeriodicTimer::new(
+                Duration::from_secs(config.soak_reconnect_interval_secs),
+                client_id,
+                "soak-reconnect",
+            ),
+            report_timer: PeriodicTimer::new(REPORT_INTERVAL, client_id, "soak-report"),
+            seq: 0,
+            publish_errors: 0,
+            bytes_published: 0,
+            connected_once: false,
+            reconnects: 0,
+            forced_reconnects: 0,
+            handler_errors: 0,
+        }
+    }
+
+    /// Count a broker (re)connect. The first connect isn't a reconnect.
+    pub fn record_connect(&mut self) {
+        if self.connected_once {
+            self.reconnects += 1;
+        }
+        self.connected_once = true;
+    }
+
+    pub fn record_handler_error(&mut self) {
+        self.handler_errors += 1;
+    }
+
+    /// Run whatever soak step is due. Call once per main loop iteration.
+    pub fn poll(&mut self, app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+        if self.publish_timer.poll() {
+            self.publish_random(app)?;
+        }
+
+        if self.reconnect_timer.poll() {
+            log::info!("Soak: forcing a reconnect");
+            self.forced_reconnects += 1;
+            app.drop_wifi(RECONNECT_OUTAGE)?;
+        }
+
+        if self.report_timer.poll() {
+            let report = self.report();
+            log::info!("Soak report: {:?}", report);
+            app.client
+                .publish_to(&self.topic, &envelope::to_json(&app.device_id, &report)?)?;
+        }
+        Ok(())
+    }
+
+    fn publish_random(&mut self, app: &mut App) -> Result<(), Box<dyn std::error::Error>> {
+        self.seq += 1;
+        let random = unsafe { esp_idf_svc::sys::esp_random() } as usize;
+        let message = SoakMessage {
+            event: "soak",
+            seq: self.seq,
+            padding: "x".repeat(random % (self.max_payload + 1)),
+        };
+        let json = envelope::to_json(&app.device_id, &message)?;
+        match app.client.publish_to(&self.topic, &json) {
+            Ok(_) => self.bytes_published += json.len() as u64,
+            Err(e) => {
+                self.publish_errors += 1;
+                log::warn!("Soak publish {} failed: {}", self.seq, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self) -> SoakReport {
+        SoakReport {
+            event: "soak_report",
+            uptime_secs: self.started.elapsed().as_secs(),
+            publishes: self.seq,
+            publish_errors: self.publish_errors,
+            bytes_published: self.bytes_published,
+            reconnects: self.reconnects,
+            forced_reconnects: self.forced_reconnects,
+            handler_errors: self.handler_errors,
+            free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+            min_free_heap: unsafe { esp_idf_svc::sys::esp_get_minimum_free_heap_size() },
+            largest_free_block: unsafe {
+                esp_idf_svc::sys::heap_caps_get_largest_free_block(esp_idf_svc::sys::MALLOC_CAP_DEFAULT)
+            },
+        }
+    }
+}
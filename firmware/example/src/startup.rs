@@ -1,31 +1,345 @@
-use crate::client::Client;
+use crate::alarms::{Alarms, Severity};
+use crate::client::{self, Client, ConnectOptions, SharedClient};
 use embedded_svc::wifi::{ClientConfiguration, Configuration as wifiConfiguration};
+use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, wifi::EspWifi};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, sntp::EspSntp, wifi::EspWifi};
+use crate::events::{Event, EventBus};
+use crate::flags::FeatureFlags;
+use crate::limits::JsonLimits;
+use crate::middleware::{Metrics, MiddlewareChain};
+use crate::offline_queue::OfflineQueue;
+use crate::publish_queue::{Overflow, PublishQueue};
+use crate::retry::{self, RetryPolicy, Subsystem};
+use crate::audio::Microphone;
+use crate::chaos::Chaos;
+use crate::console::Console;
+use crate::prometheus::Exporter;
+use crate::energy::{self, EnergyMonitor};
+use crate::estop::EStop;
+use crate::gnss::Gnss;
+use crate::irrigation::Irrigation;
+use crate::motion::MotionSensor;
+use crate::services::Service;
+use crate::template::Template;
+use crate::topics::{self, TopicAliases};
+use crate::traffic::TrafficCounters;
+use crate::dedup::DedupFilter;
+use crate::echo::EchoFilter;
+use crate::efuse::HardwareInfo;
+use crate::boot::Boot;
+use crate::secrets::settings;
+use crate::{
+    auth, bridge, clock, defender, efuse, envelope, factory, greengrass, identity, keygen, migrations,
+    tls_observer,
+};
 use std::time::Duration;
+use std::sync::mpsc;
 use std::thread;
 
-//Add your wifi credentials in the cfg.toml file
+/// Stack of the thread bringing up WiFi and SNTP during startup
+const NETWORK_STACK_SIZE: usize = 8192;
+
+//Add your wifi credentials in the cfg.toml file. The secret settings, like
+//wifi_pass, are read through secrets::settings instead
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
     wifi_ssid: &'static str,
     #[default("")]
-    wifi_pass: &'static str,
-    #[default("")]
     mqtt_url: &'static str,
     #[default("")]
     mqtt_client_id: &'static str,
+    #[default(false)]
+    client_id_from_cert: bool,
     #[default("")]
     mqtt_topic_pub: &'static str,
     #[default("")]
     mqtt_topic_sub: &'static str,
+    #[default(0)]
+    mqtt_pub_qos: u8,
+    #[default(0)]
+    mqtt_sub_qos: u8,
+    #[default(30)]
+    delivery_timeout_secs: u64,
+    #[default(0)]
+    heartbeat_interval_secs: u64,
+    #[default(3)]
+    heartbeat_max_missed: u32,
+    #[default("")]
+    heartbeat_topic: &'static str,
+    #[default(0)]
+    health_interval_secs: u64,
+    #[default(5000)]
+    health_timeout_ms: u64,
+    #[default("")]
+    health_topic: &'static str,
     #[default("")]
     cert_ca: &'static str,
     #[default("")]
     cert_crt: &'static str,
     #[default("")]
     cert_key: &'static str,
+    #[default(0)]
+    telemetry_interval_secs: u64,
+    #[default(false)]
+    jitp_enabled: bool,
+    #[default(false)]
+    use_alpn: bool,
+    #[default("")]
+    presence_topic: &'static str,
+    #[default("")]
+    broadcast_topic: &'static str,
+    #[default("")]
+    cert_jitp_ca: &'static str,
+    #[default("")]
+    hardware_revision: &'static str,
+    #[default("")]
+    cert_backup_crt: &'static str,
+    #[default("")]
+    cert_backup_key: &'static str,
+    #[default("")]
+    secrets_key: &'static str,
+    #[default(0)]
+    secrets_key_block: u8,
+    #[default(0)]
+    cert_fallback_after: u32,
+    #[default("")]
+    mqtt_backup_urls: &'static str,
+    #[default(3)]
+    mqtt_failover_after: u32,
+    #[default("")]
+    doh_url: &'static str,
+    #[default(3)]
+    doh_after: u32,
+    #[default(0)]
+    bridge_failover_after: u32,
+    #[default("")]
+    bridge_instance: &'static str,
+    #[default(300)]
+    bridge_direct_check_secs: u64,
+    #[default(false)]
+    greengrass_discovery: bool,
+    #[default(5)]
+    greengrass_fallback_after: u32,
+    #[default(false)]
+    tls_observe: bool,
+    #[default(60)]
+    tls_rotation_window_days: i64,
+    #[default(false)]
+    key_on_device: bool,
+    #[default(false)]
+    reprovision_enabled: bool,
+    #[default("x509_embedded")]
+    auth_mode: &'static str,
+    #[default("")]
+    aws_access_key_id: &'static str,
+    #[default("")]
+    credentials_endpoint: &'static str,
+    #[default("")]
+    credentials_role_alias: &'static str,
+    #[default("")]
+    custom_authorizer_name: &'static str,
+    #[default("")]
+    custom_auth_username: &'static str,
+    #[default("")]
+    custom_auth_token_key: &'static str,
+    #[default(500)]
+    retry_initial_ms: u64,
+    #[default(30000)]
+    retry_max_ms: u64,
+    #[default(0)]
+    retry_max_attempts: u32,
+    #[default(60000)]
+    mqtt_reconnect_max_ms: u64,
+    #[default(true)]
+    mqtt_clean_session: bool,
+    #[default(3600)]
+    mqtt_session_expiry_secs: u64,
+    #[default(-1)]
+    status_led_pin: i32,
+    #[default(-1)]
+    led_pin: i32,
+    #[default(0)]
+    offline_queue_len: u32,
+    #[default(0)]
+    publish_queue_len: usize,
+    #[default("drop_oldest")]
+    publish_queue_overflow: &'static str,
+    #[default(16384)]
+    publish_outbox_max_bytes: usize,
+    #[default("")]
+    thing_name: &'static str,
+    #[default(false)]
+    shadow_enabled: bool,
+    #[default(5000)]
+    shadow_get_timeout_ms: u64,
+    #[default("")]
+    shadow_names: &'static str,
+    #[default(false)]
+    shadow_diff_reports: bool,
+    #[default(60)]
+    shadow_full_report_mins: u64,
+    #[default(false)]
+    jobs_enabled: bool,
+    #[default(0)]
+    defender_interval_secs: u64,
+    #[default(0)]
+    metrics_interval_mins: u64,
+    #[default("")]
+    device_advisor_endpoint: &'static str,
+    #[default("")]
+    device_advisor_topic: &'static str,
+    #[default(10)]
+    device_advisor_interval_secs: u64,
+    #[default(false)]
+    firmware_shadow: bool,
+    #[default(false)]
+    alarms_enabled: bool,
+    #[default("critical")]
+    alarms_ack_severity: &'static str,
+    #[default(0)]
+    alarms_suppress_secs: u64,
+    #[default("")]
+    feature_flags: &'static str,
+    #[default(10)]
+    conn_stats_history: usize,
+    #[default("")]
+    ota_public_key: &'static str,
+    #[default(0)]
+    command_max_age_secs: u64,
+    #[default(8192)]
+    inbound_max_bytes: usize,
+    #[default(16)]
+    inbound_max_depth: usize,
+    #[default(256)]
+    inbound_max_array_len: usize,
+    #[default(32)]
+    dedup_window: usize,
+    #[default("")]
+    dead_letter_topic: &'static str,
+    #[default(6)]
+    dead_letter_max_per_min: u32,
+    #[default("")]
+    provenance_topic: &'static str,
+    #[default("")]
+    bootstrap_topic: &'static str,
+    #[default(false)]
+    chaos_enabled: bool,
+    #[default("")]
+    topic_aliases: &'static str,
+    #[default("")]
+    telemetry_ingest_rule: &'static str,
+    #[default(0)]
+    telemetry_expiry_secs: u64,
+    #[default(false)]
+    clock_drift_compensation: bool,
+    #[default(false)]
+    gnss_enabled: bool,
+    #[default(17)]
+    gnss_uart_tx_pin: i32,
+    #[default(18)]
+    gnss_uart_rx_pin: i32,
+    #[default(9600)]
+    gnss_baud: u32,
+    #[default(10)]
+    gnss_report_interval_secs: u64,
+    #[default(25.0)]
+    gnss_min_move_m: f64,
+    #[default(0.0)]
+    geofence_lat: f64,
+    #[default(0.0)]
+    geofence_lon: f64,
+    #[default(0.0)]
+    geofence_radius_m: f64,
+    #[default(false)]
+    motion_enabled: bool,
+    #[default(8)]
+    motion_sda_pin: i32,
+    #[default(9)]
+    motion_scl_pin: i32,
+    #[default(10)]
+    motion_int_pin: i32,
+    #[default(250)]
+    motion_threshold_mg: u16,
+    #[default(1500)]
+    motion_tamper_mg: u32,
+    #[default(false)]
+    audio_enabled: bool,
+    #[default(14)]
+    audio_bclk_pin: i32,
+    #[default(21)]
+    audio_ws_pin: i32,
+    #[default(47)]
+    audio_din_pin: i32,
+    #[default(16000)]
+    audio_sample_rate: u32,
+    #[default(-20.0)]
+    audio_alert_dbfs: f32,
+    #[default("")]
+    energy_meter: &'static str,
+    #[default(43)]
+    energy_tx_pin: i32,
+    #[default(44)]
+    energy_rx_pin: i32,
+    #[default(1)]
+    energy_sda_pin: i32,
+    #[default(2)]
+    energy_scl_pin: i32,
+    #[default(30)]
+    energy_interval_secs: u64,
+    #[default(false)]
+    irrigation_enabled: bool,
+    #[default("")]
+    irrigation_valve_pins: &'static str,
+    #[default(-1)]
+    irrigation_pump_pin: i32,
+    #[default(-1)]
+    irrigation_flow_pin: i32,
+    #[default(450.0)]
+    irrigation_pulses_per_liter: f32,
+    #[default(-1)]
+    estop_pin: i32,
+    #[default(false)]
+    cold_chain_enabled: bool,
+    #[default(4)]
+    cold_chain_sensor_pin: i32,
+    #[default(-1)]
+    cold_chain_buzzer_pin: i32,
+    #[default(2.0)]
+    cold_chain_min_c: f32,
+    #[default(8.0)]
+    cold_chain_max_c: f32,
+    #[default(300)]
+    cold_chain_sample_secs: u64,
+    #[default(12)]
+    cold_chain_upload_every: u32,
+    #[default(2)]
+    cold_chain_escalate_after: u32,
+    #[default(false)]
+    contact_enabled: bool,
+    #[default(5)]
+    contact_pin: i32,
+    #[default(-1)]
+    contact_tamper_pin: i32,
+    #[default(50)]
+    contact_debounce_ms: u64,
+    #[default(3600)]
+    contact_heartbeat_secs: u64,
+    #[default(false)]
+    console_enabled: bool,
+    #[default(80)]
+    console_port: u16,
+    #[default(false)]
+    console_metrics: bool,
+    #[default(false)]
+    soak_enabled: bool,
+    #[default(1000)]
+    soak_publish_interval_ms: u64,
+    #[default(2048)]
+    soak_max_payload_bytes: usize,
+    #[default(1800)]
+    soak_reconnect_interval_secs: u64,
 }
 
 // Add debug logging for config values
@@ -33,23 +347,209 @@ impl Config {
     pub fn debug_print(&self) {
         log::info!("Config values:");
         log::info!("  wifi_ssid: '{}'", self.wifi_ssid);
-        log::info!("  wifi_pass: '{}'", if self.wifi_pass.is_empty() { "EMPTY" } else { "SET" });
+        log::info!("  wifi_pass: '{}'", if settings::WIFI_PASS.is_empty() { "EMPTY" } else { "SET" });
         log::info!("  mqtt_url: '{}'", self.mqtt_url);
         log::info!("  mqtt_client_id: '{}'", self.mqtt_client_id);
+        log::info!("  client_id_from_cert: {}", self.client_id_from_cert);
         log::info!("  mqtt_topic_pub: '{}'", self.mqtt_topic_pub);
         log::info!("  mqtt_topic_sub: '{}'", self.mqtt_topic_sub);
+        log::info!("  mqtt_pub_qos / mqtt_sub_qos: {} / {}", self.mqtt_pub_qos, self.mqtt_sub_qos);
+        log::info!("  delivery_timeout_secs: {}", self.delivery_timeout_secs);
+        log::info!("  heartbeat_interval_secs: {}", self.heartbeat_interval_secs);
+        if self.heartbeat_interval_secs > 0 {
+            log::info!("  heartbeat_max_missed: {}", self.heartbeat_max_missed);
+            log::info!("  heartbeat_topic: '{}'", self.heartbeat_topic());
+        }
+        log::info!("  health_interval_secs: {}", self.health_interval_secs);
+        if self.health_interval_secs > 0 {
+            log::info!("  health_timeout_ms: {}", self.health_timeout_ms);
+            log::info!("  health_topic: '{}'", self.health_topic());
+        }
         log::info!("  cert_ca: '{}'", self.cert_ca);
         log::info!("  cert_crt: '{}'", self.cert_crt);
         log::info!("  cert_key: '{}'", self.cert_key);
+        log::info!("  telemetry_interval_secs: {}", self.telemetry_interval_secs);
+        log::info!("  jitp_enabled: {}", self.jitp_enabled);
+        log::info!("  use_alpn: {}", self.use_alpn);
+        log::info!("  presence_topic: '{}'", self.presence_topic);
+        log::info!("  broadcast_topic: '{}'", self.broadcast_topic);
+        log::info!("  cert_jitp_ca: '{}'", self.cert_jitp_ca);
+        log::info!("  hardware_revision: '{}'", self.hardware_revision);
+        log::info!("  cert_backup_crt: '{}'", self.cert_backup_crt);
+        log::info!("  cert_backup_key: '{}'", self.cert_backup_key);
+        log::info!("  secrets_key: '{}'", if self.secrets_key.is_empty() { "EMPTY" } else { "SET" });
+        if !self.secrets_key.is_empty() {
+            log::info!("  secrets_key_block: {}", self.secrets_key_block);
+        }
+        log::info!("  cert_fallback_after: {}", self.cert_fallback_after);
+        log::info!("  mqtt_backup_urls: '{}'", self.mqtt_backup_urls);
+        if !self.mqtt_backup_urls.is_empty() {
+            log::info!("  mqtt_failover_after: {}", self.mqtt_failover_after);
+        }
+        log::info!("  doh_url: '{}'", self.doh_url);
+        if !self.doh_url.is_empty() {
+            log::info!("  doh_after: {}", self.doh_after);
+        }
+        log::info!("  bridge_failover_after: {}", self.bridge_failover_after);
+        if self.bridge_failover_after > 0 {
+            log::info!("  bridge_instance: '{}'", self.bridge_instance);
+            log::info!("  bridge_direct_check_secs: {}", self.bridge_direct_check_secs);
+        }
+        log::info!("  greengrass_discovery: {}", self.greengrass_discovery);
+        if self.greengrass_discovery {
+            log::info!("  greengrass_fallback_after: {}", self.greengrass_fallback_after);
+        }
+        log::info!("  tls_observe: {}", self.tls_observe);
+        log::info!("  tls_rotation_window_days: {}", self.tls_rotation_window_days);
+        log::info!("  key_on_device: {}", self.key_on_device);
+        log::info!("  reprovision_enabled: {}", self.reprovision_enabled);
+        log::info!("  auth_mode: '{}'", self.auth_mode);
+        if self.auth_mode == "sigv4_websocket" {
+            // Never log the secret key or token
+            log::info!("  aws_access_key_id: '{}'", self.aws_access_key_id);
+            log::info!("  credentials_endpoint: '{}'", self.credentials_endpoint);
+            log::info!("  credentials_role_alias: '{}'", self.credentials_role_alias);
+        }
+        if self.auth_mode == "custom_authorizer" {
+            // Never log the password, token or signature
+            log::info!("  custom_authorizer_name: '{}'", self.custom_authorizer_name);
+            log::info!("  custom_auth_username: '{}'", self.custom_auth_username);
+            log::info!("  custom_auth_token_key: '{}'", self.custom_auth_token_key);
+        }
+        log::info!("  retry_initial_ms: {}", self.retry_initial_ms);
+        log::info!("  retry_max_ms: {}", self.retry_max_ms);
+        log::info!("  retry_max_attempts: {}", self.retry_max_attempts);
+        log::info!("  mqtt_reconnect_max_ms: {}", self.mqtt_reconnect_max_ms);
+        log::info!("  mqtt_clean_session: {}", self.mqtt_clean_session);
+        if !self.mqtt_clean_session {
+            log::info!("  mqtt_session_expiry_secs: {}", self.mqtt_session_expiry_secs);
+        }
+        log::info!("  status_led_pin: {}", self.status_led_pin);
+        log::info!("  led_pin: {}", self.led_pin);
+        log::info!("  offline_queue_len: {}", self.offline_queue_len);
+        log::info!("  publish_queue_len: {}", self.publish_queue_len);
+        if self.publish_queue_len > 0 {
+            log::info!("  publish_queue_overflow: '{}'", self.publish_queue_overflow);
+            log::info!("  publish_outbox_max_bytes: {}", self.publish_outbox_max_bytes);
+        }
+        log::info!("  thing_name: '{}'", self.thing_name());
+        log::info!("  shadow_enabled: {}", self.shadow_enabled);
+        log::info!("  shadow_get_timeout_ms: {}", self.shadow_get_timeout_ms);
+        log::info!("  shadow_names: {}", self.shadow_names);
+        log::info!("  shadow_diff_reports: {}", self.shadow_diff_reports);
+        log::info!("  shadow_full_report_mins: {}", self.shadow_full_report_mins);
+        log::info!("  jobs_enabled: {}", self.jobs_enabled);
+        log::info!("  defender_interval_secs: {}", self.defender_interval_secs);
+        log::info!("  metrics_interval_mins: {}", self.metrics_interval_mins);
+        log::info!("  device_advisor_endpoint: '{}'", self.device_advisor_endpoint);
+        if self.device_advisor() {
+            log::info!("  device_advisor_topic: '{}'", self.device_advisor_topic());
+            log::info!("  device_advisor_interval_secs: {}", self.device_advisor_interval_secs);
+        }
+        log::info!("  firmware_shadow: {}", self.firmware_shadow);
+        log::info!("  alarms_enabled: {}", self.alarms_enabled);
+        if self.alarms_enabled {
+            log::info!("  alarms_ack_severity: '{}'", self.alarms_ack_severity);
+            log::info!("  alarms_suppress_secs: {}", self.alarms_suppress_secs);
+        }
+        log::info!("  feature_flags: '{}'", self.feature_flags);
+        log::info!("  conn_stats_history: {}", self.conn_stats_history);
+        log::info!("  ota_public_key: '{}'", self.ota_public_key);
+        log::info!("  command_max_age_secs: {}", self.command_max_age_secs);
+        log::info!(
+            "  inbound max bytes/depth/array_len: {} / {} / {}",
+            self.inbound_max_bytes,
+            self.inbound_max_depth,
+            self.inbound_max_array_len
+        );
+        log::info!("  dedup_window: {}", self.dedup_window);
+        log::info!("  dead_letter_topic: '{}'", self.dead_letter_topic());
+        log::info!("  dead_letter_max_per_min: {}", self.dead_letter_max_per_min);
+        log::info!("  provenance_topic: '{}'", self.provenance_topic);
+        log::info!("  bootstrap_topic: '{}'", self.bootstrap_topic);
+        log::info!("  topic_aliases: '{}'", self.topic_aliases);
+        log::info!("  telemetry_ingest_rule: '{}'", self.telemetry_ingest_rule);
+        log::info!("  telemetry_expiry_secs: {}", self.telemetry_expiry_secs);
+        log::info!("  clock_drift_compensation: {}", self.clock_drift_compensation);
+        log::info!("  gnss_enabled: {}", self.gnss_enabled);
+        if self.gnss_enabled {
+            log::info!("  gnss_uart_tx_pin / rx_pin: {} / {}", self.gnss_uart_tx_pin, self.gnss_uart_rx_pin);
+            log::info!("  gnss_baud: {}", self.gnss_baud);
+            log::info!("  gnss_report_interval_secs: {}", self.gnss_report_interval_secs);
+            log::info!("  gnss_min_move_m: {}", self.gnss_min_move_m);
+            log::info!("  geofence: {}, {} r={} m", self.geofence_lat, self.geofence_lon, self.geofence_radius_m);
+        }
+        log::info!("  motion_enabled: {}", self.motion_enabled);
+        if self.motion_enabled {
+            log::info!(
+                "  motion pins sda/scl/int: {} / {} / {}",
+                self.motion_sda_pin,
+                self.motion_scl_pin,
+                self.motion_int_pin
+            );
+            log::info!("  motion_threshold_mg: {}", self.motion_threshold_mg);
+            log::info!("  motion_tamper_mg: {}", self.motion_tamper_mg);
+        }
+        log::info!("  audio_enabled: {}", self.audio_enabled);
+        if self.audio_enabled {
+            log::info!(
+                "  audio pins bclk/ws/din: {} / {} / {}",
+                self.audio_bclk_pin,
+                self.audio_ws_pin,
+                self.audio_din_pin
+            );
+            log::info!("  audio_sample_rate: {}", self.audio_sample_rate);
+            log::info!("  audio_alert_dbfs: {}", self.audio_alert_dbfs);
+        }
+        log::info!("  energy_meter: '{}'", self.energy_meter);
+        if !self.energy_meter.is_empty() {
+            log::info!("  energy uart tx/rx: {} / {}", self.energy_tx_pin, self.energy_rx_pin);
+            log::info!("  energy i2c sda/scl: {} / {}", self.energy_sda_pin, self.energy_scl_pin);
+            log::info!("  energy_interval_secs: {}", self.energy_interval_secs);
+        }
+        log::info!("  irrigation_enabled: {}", self.irrigation_enabled);
+        if self.irrigation_enabled {
+            log::info!("  irrigation_valve_pins: '{}'", self.irrigation_valve_pins);
+            log::info!("  irrigation_pump_pin: {}", self.irrigation_pump_pin);
+            log::info!("  irrigation_flow_pin: {}", self.irrigation_flow_pin);
+            log::info!("  irrigation_pulses_per_liter: {}", self.irrigation_pulses_per_liter);
+            log::info!("  estop_pin: {}", self.estop_pin);
+        }
+        log::info!("  cold_chain_enabled: {}", self.cold_chain_enabled);
+        if self.cold_chain_enabled {
+            log::info!("  cold_chain_sensor_pin: {}", self.cold_chain_sensor_pin);
+            log::info!("  cold_chain_buzzer_pin: {}", self.cold_chain_buzzer_pin);
+            log::info!("  cold_chain range: {} .. {} C", self.cold_chain_min_c, self.cold_chain_max_c);
+            log::info!("  cold_chain_sample_secs: {}", self.cold_chain_sample_secs);
+            log::info!("  cold_chain_upload_every: {}", self.cold_chain_upload_every);
+            log::info!("  cold_chain_escalate_after: {}", self.cold_chain_escalate_after);
+        }
+        log::info!("  contact_enabled: {}", self.contact_enabled);
+        if self.contact_enabled {
+            log::info!("  contact_pin: {}", self.contact_pin);
+            log::info!("  contact_tamper_pin: {}", self.contact_tamper_pin);
+            log::info!("  contact_debounce_ms: {}", self.contact_debounce_ms);
+            log::info!("  contact_heartbeat_secs: {}", self.contact_heartbeat_secs);
+        }
+        log::info!("  console_enabled: {}", self.console_enabled);
+        if self.console_enabled {
+            log::info!("  console_port: {}", self.console_port);
+            log::info!("  console_token: '{}'", if settings::CONSOLE_TOKEN.is_empty() { "EMPTY" } else { "SET" });
+            log::info!("  console_metrics: {}", self.console_metrics);
+        }
+        log::info!("  chaos_enabled: {}", self.chaos_enabled());
+        log::info!("  soak_enabled: {}", self.soak_enabled);
+        if self.soak_enabled {
+            log::info!("  soak_publish_interval_ms: {}", self.soak_publish_interval_ms);
+            log::info!("  soak_max_payload_bytes: {}", self.soak_max_payload_bytes);
+            log::info!("  soak_reconnect_interval_secs: {}", self.soak_reconnect_interval_secs);
+        }
     }
     
     pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
         if self.wifi_ssid.is_empty() {
             return Err("WiFi SSID is empty! Please configure wifi_ssid in cfg.toml".into());
         }
-        if self.wifi_pass.is_empty() {
-            return Err("WiFi password is empty! Please configure wifi_pass in cfg.toml".into());
-        }
         if self.mqtt_url.is_empty() {
             return Err("MQTT URL is empty! Please configure mqtt_url in cfg.toml".into());
         }
@@ -62,60 +562,528 @@ impl Config {
         if self.mqtt_topic_sub.is_empty() {
             return Err("MQTT subscribe topic is empty! Please configure mqtt_topic_sub in cfg.toml".into());
         }
+        if !self.mqtt_backup_urls.is_empty() && !self.auth_mode.starts_with("x509_") {
+            return Err(format!("mqtt_backup_urls needs an x509 auth_mode, not \"{}\"", self.auth_mode).into());
+        }
+        let backup_urls = self.backup_urls();
+        if let Some(url) = backup_urls.iter().find(|url| !url.starts_with("mqtts://") && !url.starts_with("mqtt://")) {
+            return Err(format!("mqtt_backup_urls: \"{}\" is not an mqtts:// or mqtt:// URL", url).into());
+        }
+        if !self.doh_url.is_empty() && !self.doh_url.starts_with("https://") {
+            return Err(format!("doh_url must be an https:// URL, not \"{}\"", self.doh_url).into());
+        }
+        if self.device_advisor() && self.mqtt_reconnect_max_ms == 0 {
+            return Err("Device Advisor's reconnect tests need the backoff, set mqtt_reconnect_max_ms".into());
+        }
+        if self.jitp_enabled && self.cert_jitp_ca.is_empty() {
+            return Err("JITP is enabled but cert_jitp_ca is empty! Please configure cert_jitp_ca in cfg.toml".into());
+        }
+        // Console commands include reprovision, install_cert and chaos
+        if self.console_enabled && settings::CONSOLE_TOKEN.is_empty() {
+            return Err("The console is enabled but console_token is empty! Please configure console_token in cfg.toml".into());
+        }
         
         log::info!("Configuration validation passed!");
         Ok(())
     }
+
+    /// Brokers to fail over to, in order, after `mqtt_failover_after` failed
+    /// attempts on the current one. With `use_alpn`, TLS ones default to 443
+    /// like `mqtt_url`.
+    pub fn backup_urls(&self) -> Vec<String> {
+        self.mqtt_backup_urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| {
+                if self.use_alpn && url.starts_with("mqtts://") {
+                    client::with_default_port(url, 443)
+                } else {
+                    url.to_string()
+                }
+            })
+            .collect()
+    }
+
+    /// Whether this boot runs the Device Advisor test mode.
+    pub fn device_advisor(&self) -> bool {
+        !self.device_advisor_endpoint.is_empty()
+    }
+
+    /// Topic the Device Advisor suites watch, by default the publish topic.
+    pub fn device_advisor_topic(&self) -> &'static str {
+        if self.device_advisor_topic.is_empty() {
+            self.mqtt_topic_pub
+        } else {
+            self.device_advisor_topic
+        }
+    }
+
+    /// Connect to the Device Advisor test endpoint instead of `mqtt_url`, and
+    /// turn off what would move the device to another broker mid-test.
+    pub fn apply_device_advisor(&mut self) {
+        if !self.device_advisor() {
+            return;
+        }
+        log::warn!("Device Advisor test mode, connecting to {}", self.device_advisor_endpoint);
+        self.mqtt_url = self.device_advisor_endpoint;
+        self.mqtt_backup_urls = "";
+        self.bridge_failover_after = 0;
+        self.greengrass_discovery = false;
+    }
+
+    /// Fill in `{client_id}` and `{thing_name}` in the topic settings, so one
+    /// cfg.toml serves the whole fleet. Call once the client id and thing
+    /// name are final.
+    pub fn expand_topics(&mut self) -> Result<(), String> {
+        let (client_id, thing_name) = (self.mqtt_client_id, self.thing_name());
+        let fields = [
+            &mut self.mqtt_topic_pub,
+            &mut self.mqtt_topic_sub,
+            &mut self.heartbeat_topic,
+            &mut self.health_topic,
+            &mut self.presence_topic,
+            &mut self.broadcast_topic,
+            &mut self.dead_letter_topic,
+            &mut self.provenance_topic,
+            &mut self.bootstrap_topic,
+            &mut self.device_advisor_topic,
+            &mut self.topic_aliases,
+        ];
+        for topic in fields {
+            if topic.contains(['{', '}']) {
+                // Expanded once at boot and kept for the life of the firmware
+                *topic = Box::leak(topics::expand(topic, client_id, thing_name)?.into_boxed_str());
+            }
+        }
+        Ok(())
+    }
+
+    /// Thing name, defaulting to the MQTT client id as created by terraform.
+    pub fn thing_name(&self) -> &'static str {
+        if self.thing_name.is_empty() {
+            self.mqtt_client_id
+        } else {
+            self.thing_name
+        }
+    }
+
+    pub fn heartbeat_topic(&self) -> String {
+        if self.heartbeat_topic.is_empty() {
+            format!("{}/heartbeat", self.mqtt_topic_pub)
+        } else {
+            self.heartbeat_topic.to_string()
+        }
+    }
+
+    /// Loopback topic of the health check. The device both publishes and
+    /// subscribes to it, so it must be its own.
+    pub fn health_topic(&self) -> String {
+        if self.health_topic.is_empty() {
+            format!("{}/loopback", self.mqtt_topic_pub)
+        } else {
+            self.health_topic.to_string()
+        }
+    }
+
+    /// Topic for messages that failed processing, by default next to the
+    /// publish topic so the same policy allows it.
+    pub fn dead_letter_topic(&self) -> String {
+        if self.dead_letter_topic.is_empty() {
+            format!("{}/dead-letter", self.mqtt_topic_pub)
+        } else {
+            self.dead_letter_topic.to_string()
+        }
+    }
+
+    /// Fault injection is never available in release builds, whatever cfg.toml says.
+    pub fn chaos_enabled(&self) -> bool {
+        self.chaos_enabled && cfg!(debug_assertions)
+    }
+
+    /// Backoff shared by every subsystem that retries.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(self.retry_initial_ms),
+            max_delay: Duration::from_millis(self.retry_max_ms),
+            max_attempts: self.retry_max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// Bounds on incoming payloads.
+    pub fn inbound_limits(&self) -> JsonLimits {
+        JsonLimits {
+            max_bytes: self.inbound_max_bytes,
+            max_depth: self.inbound_max_depth,
+            max_array_len: self.inbound_max_array_len,
+        }
+    }
+
+    /// Broker reconnect backoff, starting like the shared policy and capped
+    /// at `mqtt_reconnect_max_ms`. `None` leaves reconnecting to esp-mqtt.
+    pub fn reconnect_policy(&self) -> Option<RetryPolicy> {
+        (self.mqtt_reconnect_max_ms > 0).then(|| RetryPolicy {
+            max_delay: Duration::from_millis(self.mqtt_reconnect_max_ms),
+            ..self.retry_policy()
+        })
+    }
+
+    /// Severity from which alarms need a cloud acknowledgment; `None` when none do.
+    pub fn alarm_ack_severity(&self) -> Result<Option<Severity>, String> {
+        Some(self.alarms_ack_severity)
+            .filter(|name| !name.is_empty())
+            .map(Severity::parse)
+            .transpose()
+    }
+
+    pub fn session(&self) -> client::Session {
+        if self.mqtt_clean_session {
+            client::Session::Clean
+        } else {
+            client::Session::Persistent {
+                expiry: Duration::from_secs(self.mqtt_session_expiry_secs),
+            }
+        }
+    }
+}
+
+/// The configuration baked in from cfg.toml, for code that runs before [`App`].
+pub fn config() -> Config {
+    CONFIG
+}
+
+/// First thing every binary does: runtime patches, logging, build report.
+pub fn init_runtime() {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities, copying to the LAN console
+    crate::console::LOGGER.initialize();
+
+    log::info!("{}", crate::provenance::banner());
+    log::info!("Build: {}", crate::build_info::report());
 }
 
 pub struct App {
     pub wifi: EspWifi<'static>,
+    pub sntp: EspSntp<'static>,
+    pub nvs: EspDefaultNvsPartition,
     pub config: Config,
-    pub client: Client,
+    pub device_id: String,
+    pub events: EventBus,
+    pub metrics: Metrics,
+    /// The client's traffic counters
+    pub traffic: TrafficCounters,
+    pub alarms: Option<Alarms>,
+    pub flags: FeatureFlags,
+    pub template: Template,
+    pub chaos: Chaos,
+    pub gnss: Option<Gnss>,
+    pub motion: Option<MotionSensor>,
+    pub microphone: Option<Microphone>,
+    pub energy: Option<EnergyMonitor>,
+    pub irrigation: Option<Irrigation>,
+    /// Hardware e-stop of the irrigation profile
+    pub estop: Option<EStop>,
+    pub client: SharedClient,
+    pub identity: auth::Identity,
+    /// Identity the broker certificate probe connects with, with
+    /// `tls_observe` over X.509
+    pub tls_probe: Option<tls_observer::ProbeIdentity>,
+    /// Broker URL of the local bridge when failed over to one
+    pub bridge: Option<String>,
+    /// Broker URL of the Greengrass core when connected through one
+    pub greengrass: Option<String>,
+    pub console: Option<Console>,
+    /// Revision, serial and manufacture date burned into eFuse
+    pub hardware: Option<HardwareInfo>,
+    /// Timing of the startup steps
+    pub boot: Boot,
 }
 
 impl App {
     pub fn new() -> Result<App, Box<dyn std::error::Error>> {
+        Self::with_partition(EspDefaultNvsPartition::take()?)
+    }
+
+    /// Like `new`, for callers that needed NVS before bringing the network up
+    pub fn with_partition(nvs: EspDefaultNvsPartition) -> Result<App, Box<dyn std::error::Error>> {
+        let boot = Boot::new();
         let peripherals = unsafe { Peripherals::new() };
         let sys_loop = EspSystemEventLoop::take()?;
-        let nvs = EspDefaultNvsPartition::take()?;
-        let app_config: Config = CONFIG;
+
+        let step = boot.start("nvs")?;
+        let mut app_config: Config = factory::apply(nvs.clone(), CONFIG)?;
+        let hardware = efuse::read().unwrap_or_else(|e| {
+            log::warn!("Failed to read hardware info from eFuse: {}", e);
+            None
+        });
+        // Burned during manufacturing, so it outranks cfg.toml and factory NVS
+        if let Some(hardware) = hardware.as_ref().filter(|hardware| !hardware.revision.is_empty()) {
+            log::info!("Hardware from eFuse: {:?}", hardware);
+            app_config.hardware_revision = Box::leak(hardware.revision.clone().into_boxed_str());
+        }
+        // The certificate outranks cfg.toml and factory NVS: it is what the
+        // broker and the thing policy see
+        if app_config.client_id_from_cert {
+            let certificate = auth::client_certificate(&app_config, nvs.clone())?;
+            let common_name = identity::certificate_common_name(&certificate)
+                .map_err(|e| format!("client_id_from_cert: {}", e))?;
+            log::info!("Client id and thing name from the certificate: {}", common_name);
+            let common_name: &'static str = Box::leak(common_name.into_boxed_str());
+            app_config.mqtt_client_id = common_name;
+            app_config.thing_name = common_name;
+        }
+        app_config.apply_device_advisor();
+        app_config.expand_topics()?;
         app_config.debug_print();
         app_config.validate()?;
+        let wifi_pass: &'static str = match factory::wifi_pass(nvs.clone())? {
+            Some(pass) => Box::leak(pass.into_boxed_str()),
+            None => settings::WIFI_PASS.open()?,
+        };
+        if wifi_pass.is_empty() {
+            return Err("WiFi password is empty! Please configure wifi_pass in cfg.toml".into());
+        }
 
-        let mut wifi_driver = EspWifi::new(peripherals.modem, sys_loop, Some(nvs))?;
+        migrations::run(nvs.clone(), migrations::MIGRATIONS)?;
+        step.finish();
 
-        wifi_driver.set_configuration(&wifiConfiguration::Client(ClientConfiguration {
-            ssid: app_config.wifi_ssid.try_into().unwrap(),
-            password: app_config.wifi_pass.try_into().unwrap(),
-            ..Default::default()
-        }))?;
+        // Before SNTP can replace what the clock reads now
+        if app_config.clock_drift_compensation {
+            clock::compensate_drift(nvs.clone())?;
+        }
 
-        wifi_driver.start()?;
-        log::info!("WiFi started, attempting connection...");
-        wifi_driver.connect()?;
+        // The network comes up on its own thread while storage and sensors
+        // initialize here; see `boot` for the graph
+        let (radio_on, radio_started) = mpsc::sync_channel(1);
+        let network = {
+            let (boot, modem, nvs) = (boot.clone(), peripherals.modem, nvs.clone());
+            let credentials = (app_config.wifi_ssid, wifi_pass);
+            let retry_policy = app_config.retry_policy();
+            thread::Builder::new()
+                .name("startup-net".into())
+                .stack_size(NETWORK_STACK_SIZE)
+                .spawn(move || {
+                    start_network(&boot, modem, sys_loop, nvs, credentials, retry_policy, radio_on)
+                        .map_err(|e| e.to_string())
+                })?
+        };
 
-        let mut retry_count = 0;
-        const MAX_RETRIES: u32 = 30; // 30 seconds timeout
-        
-        while !wifi_driver.is_connected()? {
-            if retry_count >= MAX_RETRIES {
-                return Err(format!("WiFi connection timeout after {} seconds", MAX_RETRIES).into());
+        let step = boot.start("storage")?;
+        // Only reads run alongside WiFi: generating the device id or key
+        // needs the hardware RNG, which is only a true entropy source once
+        // the radio is on
+        let needs_entropy = identity::load(nvs.clone())?.is_none()
+            || (app_config.key_on_device && !keygen::has_key(nvs.clone())?);
+        if needs_entropy && radio_started.recv().is_err() {
+            // The network thread gave up before starting WiFi; its error says why
+            network.join().map_err(|_| "Network bring-up panicked")??;
+            return Err("Network bring-up ended before WiFi started".into());
+        }
+        let device_id = identity::load_or_create(nvs.clone())?;
+        log::info!("Device id: {}", device_id);
+
+        if app_config.key_on_device {
+            keygen::load_or_generate(nvs.clone(), app_config.mqtt_client_id)?;
+        }
+        let flags = FeatureFlags::load(nvs.clone(), app_config.feature_flags)?;
+        let template = Template::load(nvs.clone())?;
+
+        let alarms = if app_config.alarms_enabled {
+            Some(Alarms::open(
+                nvs.clone(),
+                app_config.alarm_ack_severity()?,
+                Duration::from_secs(app_config.alarms_suppress_secs),
+            )?)
+        } else {
+            None
+        };
+        step.finish();
+
+        let step = boot.start("sensors")?;
+        let gnss = if app_config.gnss_enabled {
+            Some(Gnss::start(
+                peripherals.uart1,
+                app_config.gnss_uart_tx_pin,
+                app_config.gnss_uart_rx_pin,
+                app_config.gnss_baud,
+            )?)
+        } else {
+            None
+        };
+
+        let motion = if app_config.motion_enabled {
+            Some(MotionSensor::start(
+                peripherals.i2c0,
+                app_config.motion_sda_pin,
+                app_config.motion_scl_pin,
+                app_config.motion_int_pin,
+                app_config.motion_threshold_mg,
+                app_config.motion_tamper_mg,
+            )?)
+        } else {
+            None
+        };
+
+        let microphone = if app_config.audio_enabled {
+            Some(Microphone::start(
+                peripherals.i2s0,
+                app_config.audio_bclk_pin,
+                app_config.audio_ws_pin,
+                app_config.audio_din_pin,
+                app_config.audio_sample_rate,
+                app_config.audio_alert_dbfs,
+            )?)
+        } else {
+            None
+        };
+
+        let energy = match app_config.energy_meter {
+            "" => None,
+            "pzem004t" => Some(EnergyMonitor::new(
+                Box::new(energy::Pzem004t::new(
+                    peripherals.uart2,
+                    app_config.energy_tx_pin,
+                    app_config.energy_rx_pin,
+                )?),
+                nvs.clone(),
+            )?),
+            "ade7953" => Some(EnergyMonitor::new(
+                Box::new(energy::Ade7953::new(
+                    peripherals.i2c1,
+                    app_config.energy_sda_pin,
+                    app_config.energy_scl_pin,
+                )?),
+                nvs.clone(),
+            )?),
+            other => return Err(format!("Unknown energy_meter \"{}\"", other).into()),
+        };
+
+        let mut irrigation = if app_config.irrigation_enabled {
+            Some(Irrigation::new(
+                app_config.irrigation_valve_pins,
+                app_config.irrigation_pump_pin,
+                app_config.irrigation_flow_pin,
+                app_config.irrigation_pulses_per_liter,
+                nvs.clone(),
+            )?)
+        } else {
+            None
+        };
+
+        let estop = match irrigation.as_mut() {
+            Some(irrigation) if app_config.estop_pin >= 0 => {
+                let estop = EStop::start(app_config.estop_pin, irrigation.output_pins(), nvs.clone())?;
+                irrigation.set_estop(estop.latch());
+                Some(estop)
             }
-            
-            let config = wifi_driver.get_configuration()?;
-            log::info!("Waiting for station (attempt {}): {:?}", retry_count + 1, config);
-            
-            // Feed the watchdog and add delay
-            unsafe {
-                esp_idf_svc::hal::sys::esp_task_wdt_reset();
+            _ => None,
+        };
+        step.finish();
+
+        let (wifi_driver, sntp) = network.join().map_err(|_| "Network bring-up panicked")??;
+        let events = EventBus::new();
+        events.publish(Event::NetworkUp);
+
+        let metrics = Metrics::new();
+        let traffic = TrafficCounters::default();
+
+        let step = boot.start("console")?;
+        let console = if app_config.console_enabled && Service::Console.wanted(&flags) {
+            let exporter = app_config.console_metrics.then(|| Exporter::new(metrics.clone(), traffic.clone()));
+            Some(Console::start(app_config.console_port, settings::CONSOLE_TOKEN.open()?, exporter)?)
+        } else {
+            None
+        };
+        step.finish();
+
+        let step = boot.start("mqtt")?;
+        let retry_policy = app_config.retry_policy();
+        let (auth_provider, identity) = auth::select(&app_config, nvs.clone())?;
+        if identity.is_fallback() {
+            log::warn!(
+                "Connecting with fallback identity {} ({} of {})",
+                identity.name,
+                identity.index + 1,
+                identity.count
+            );
+        }
+        let bridge = if app_config.bridge_failover_after > 0 {
+            bridge::selected(nvs.clone())?
+        } else {
+            None
+        };
+        // A bridge only comes into play when AWS IoT and any core failed
+        let core = if app_config.greengrass_discovery && bridge.is_none() {
+            greengrass::select(nvs.clone(), app_config.mqtt_url, app_config.thing_name())?
+        } else {
+            None
+        };
+        let greengrass = core.as_ref().map(|core| core.url.clone());
+        // Bridge and core replace the endpoint; their own fallbacks apply
+        let backup_urls = match (&bridge, &greengrass) {
+            (None, None) => app_config.backup_urls(),
+            _ => Vec::new(),
+        };
+        let auth_provider: Box<dyn auth::AuthProvider> = match (&bridge, core) {
+            (Some(url), _) => {
+                log::warn!("Failed over to local bridge {}, messages are marked bridged", url);
+                envelope::set_bridged(true);
+                Box::new(bridge::Bridge::new(url))
+            }
+            (None, Some(core)) => {
+                log::info!("Connecting through Greengrass core {}, messages are marked bridged", core.url);
+                envelope::set_bridged(true);
+                Box::new(greengrass::GreengrassCore::new(core, auth_provider))
             }
-            thread::sleep(Duration::from_secs(1));
-            retry_count += 1;
+            (None, None) => auth_provider,
+        };
+        let tls_probe = match auth_provider.client_identity()? {
+            Some((certificate_pem, private_key_pem)) if app_config.tls_observe => Some(tls_observer::ProbeIdentity {
+                ca_pem: auth_provider.server_ca().unwrap_or_else(|| client::SERVER_CERT.to_vec()),
+                certificate_pem,
+                private_key_pem,
+            }),
+            None if app_config.tls_observe => {
+                log::warn!("{} sends no client certificate, tls_observe is skipped", auth_provider.name());
+                None
+            }
+            _ => None,
+        };
+
+        let middleware = MiddlewareChain::new();
+        // First, so they are the last stages incoming payloads pass: the
+        // filters only parse what the limits let through, and echoes never
+        // take a place in the dedup window
+        if app_config.dedup_window > 0 {
+            middleware.register(DedupFilter::new(app_config.dedup_window, metrics.clone()));
+        }
+        middleware.register(EchoFilter::new(&device_id, metrics.clone()));
+        middleware.register(app_config.inbound_limits());
+        middleware.register(metrics.clone());
+        let chaos = Chaos::new();
+        if app_config.chaos_enabled() {
+            log::warn!("Fault injection enabled");
+            middleware.register(chaos.clone());
         }
 
-        println!("IP info: {:?}", wifi_driver.sta_netif().get_ip_info()?);
-        log::info!("Should be connected now with credentials: ");
+        let topic_aliases = TopicAliases::parse(app_config.topic_aliases)?;
+        if !app_config.telemetry_ingest_rule.is_empty() {
+            topics::validate_rule_name(app_config.telemetry_ingest_rule)?;
+        }
+        if (1..defender::MIN_INTERVAL_SECS).contains(&app_config.defender_interval_secs) {
+            return Err(format!(
+                "defender_interval_secs must be 0 or at least {}",
+                defender::MIN_INTERVAL_SECS
+            )
+            .into());
+        }
+        let publish_qos = client::qos(app_config.mqtt_pub_qos)?;
+        let subscribe_qos = client::qos(app_config.mqtt_sub_qos)?;
+        let broadcast_topic = Some(app_config.broadcast_topic).filter(|topic| !topic.is_empty());
 
         log::info!("Creating MQTT client...");
         let client = match Client::new(
@@ -123,21 +1091,157 @@ impl App {
             app_config.mqtt_client_id,
             app_config.mqtt_topic_pub,
             app_config.mqtt_topic_sub,
+            ConnectOptions::default()
+                .with_jitp(app_config.jitp_enabled)
+                // The core's broker doesn't speak the AWS IoT ALPN protocol
+                .with_alpn(app_config.use_alpn && greengrass.is_none())
+                .with_presence_topic(Some(app_config.presence_topic).filter(|topic| !topic.is_empty()))
+                .with_reconnect_policy(app_config.reconnect_policy())
+                .with_session(app_config.session()),
+            auth_provider.as_ref(),
         ) {
             Ok(client) => {
                 log::info!("MQTT client created successfully");
                 client
+                    .with_retry_policy(retry_policy)
+                    .with_event_bus(events.clone())
+                    .with_middleware(middleware)
+                    .with_topic_aliases(topic_aliases)
+                    .with_connection_history(app_config.conn_stats_history)
+                    .with_qos(publish_qos, subscribe_qos)
+                    .with_broadcast_topic(broadcast_topic)
+                    .with_backup_endpoints(backup_urls, app_config.mqtt_failover_after)
+                    .with_traffic_counters(traffic.clone())
+                    .with_max_message_bytes(app_config.inbound_max_bytes)
             }
             Err(e) => {
                 log::error!("Failed to create MQTT client: {:?}", e);
                 return Err(e);
             }
         };
+        let client = match app_config.offline_queue_len {
+            0 => client,
+            capacity => client.with_offline_queue(OfflineQueue::open(nvs.clone(), capacity)?),
+        };
+        let client = match app_config.publish_queue_len {
+            0 => client,
+            capacity => client.with_publish_queue(PublishQueue::new(
+                capacity,
+                Overflow::parse(app_config.publish_queue_overflow)?,
+                app_config.publish_outbox_max_bytes,
+            )?),
+        };
+        step.finish();
+
+        let report = boot.report();
+        log::info!(
+            "Startup took {} ms, {} ms one step after another",
+            report.total_ms,
+            report.sequential_ms
+        );
 
         Ok(App {
             wifi: wifi_driver,
+            sntp,
+            nvs,
             config: app_config,
-            client,
+            device_id,
+            events,
+            metrics,
+            traffic,
+            alarms,
+            flags,
+            template,
+            chaos,
+            gnss,
+            motion,
+            microphone,
+            energy,
+            irrigation,
+            estop,
+            client: SharedClient::new(client),
+            identity,
+            tls_probe,
+            bridge,
+            greengrass,
+            console,
+            hardware,
+            boot,
         })
     }
+
+    /// Take WiFi down for `outage`, then bring it back through the regular
+    /// retry path. The MQTT client reconnects on its own.
+    pub fn drop_wifi(&mut self, outage: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        self.wifi.disconnect()?;
+        self.events.publish(Event::NetworkDown);
+        thread::sleep(outage);
+
+        let retry_policy = self.config.retry_policy();
+        retry::retry(Subsystem::Wifi, &retry_policy, || connect_wifi(&mut self.wifi))?;
+        self.events.publish(Event::NetworkUp);
+        Ok(())
+    }
+}
+
+/// WiFi, then SNTP: the network branch of the startup graph.
+fn start_network(
+    boot: &Boot,
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    (ssid, pass): (&'static str, &'static str),
+    retry_policy: RetryPolicy,
+    radio_on: mpsc::SyncSender<()>,
+) -> Result<(EspWifi<'static>, EspSntp<'static>), Box<dyn std::error::Error>> {
+    let step = boot.start("wifi")?;
+    let mut wifi_driver = EspWifi::new(modem, sys_loop, Some(nvs))?;
+
+    wifi_driver.set_configuration(&wifiConfiguration::Client(ClientConfiguration {
+        ssid: ssid.try_into().unwrap(),
+        password: pass.try_into().unwrap(),
+        ..Default::default()
+    }))?;
+
+    wifi_driver.start()?;
+    // Nobody waits for it unless the device id or key is still to be generated
+    let _ = radio_on.send(());
+    log::info!("WiFi started, attempting connection...");
+    retry::retry(Subsystem::Wifi, &retry_policy, || connect_wifi(&mut wifi_driver))?;
+
+    println!("IP info: {:?}", wifi_driver.sta_netif().get_ip_info()?);
+    log::info!("Should be connected now with credentials: ");
+    step.finish();
+
+    let step = boot.start("sntp")?;
+    let sntp = clock::start_sntp(&retry_policy)?;
+    step.finish();
+    Ok((wifi_driver, sntp))
+}
+
+/// Associate with the access point and wait up to 30 seconds for the link.
+fn connect_wifi(wifi_driver: &mut EspWifi<'static>) -> Result<(), Box<dyn std::error::Error>> {
+    wifi_driver.connect()?;
+
+    let mut retry_count = 0;
+    const MAX_WAIT_SECS: u32 = 30;
+
+    while !wifi_driver.is_connected()? {
+        if retry_count >= MAX_WAIT_SECS {
+            let _ = wifi_driver.disconnect();
+            return Err(format!("WiFi connection timeout after {} seconds", MAX_WAIT_SECS).into());
+        }
+
+        let config = wifi_driver.get_configuration()?;
+        log::info!("Waiting for station (attempt {}): {:?}", retry_count + 1, config);
+
+        // Feed the watchdog and add delay
+        unsafe {
+            esp_idf_svc::hal::sys::esp_task_wdt_reset();
+        }
+        thread::sleep(Duration::from_secs(1));
+        retry_count += 1;
+    }
+
+    Ok(())
 }
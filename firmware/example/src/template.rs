@@ -0,0 +1,81 @@
+//! Output template for telemetry, so a downstream consumer that wants other
+//! field names doesn't need a firmware update. The template is set through
+//! `desired.telemetry_template` in the shadow and kept in NVS:
+//!
+//! ```json
+//! {"rename": {"free_heap": "heap.free", "location": "gps"},
+//!  "drop": ["retries"],
+//!  "flatten": "_"}
+//! ```
+//!
+//! Fields are addressed by their dotted path in the message. A rename moves
+//! the field, or the whole object, to a new dotted path; a dropped field is
+//! left out. With `flatten`, nested objects become top-level fields whose
+//! names are the path joined by that separator. Arrays are kept as they are.
+//!
+//! A delta only carries what changed, so it is merged into the template:
+//! a rename to `""` and a `flatten` of `""` switch that rule off again.
+
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde_json::Value;
+
+pub use crate::template_rules::Rules;
+
+const TEMPLATE_KEY: &str = "template";
+/// NVS strings top out at 4000 bytes
+const MAX_TEMPLATE_BYTES: usize = 4000;
+
+/// The template in use and where it is stored.
+pub struct Template {
+    rules: Rules,
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Template {
+    /// The template stored before the last reboot.
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Self, Box<dyn std::error::Error>> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = vec![0u8; MAX_TEMPLATE_BYTES];
+        let rules = match nvs.get_str(TEMPLATE_KEY, &mut buf)? {
+            Some(json) => match serde_json::from_str::<Rules>(json) {
+                Ok(rules) => {
+                    log::info!("Telemetry template: {:?}", rules);
+                    rules
+                }
+                Err(e) => {
+                    log::warn!("Dropping unreadable telemetry template: {}", e);
+                    Rules::default()
+                }
+            },
+            None => Rules::default(),
+        };
+        Ok(Self { rules, nvs })
+    }
+
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Merge `telemetry_template` from a shadow delta into the template and
+    /// store it. Nothing changes if the result isn't a valid template.
+    pub fn apply(&mut self, desired: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        let rules = self.rules.merged(desired)?;
+        let json = serde_json::to_string(&rules)?;
+        if json.len() >= MAX_TEMPLATE_BYTES {
+            return Err(format!("Telemetry template over {} bytes", MAX_TEMPLATE_BYTES).into());
+        }
+        self.nvs.set_str(TEMPLATE_KEY, &json)?;
+        log::info!("Telemetry template updated: {:?}", rules);
+        self.rules = rules;
+        Ok(())
+    }
+
+    /// `json` with the template applied, or unchanged without one.
+    pub fn render(&self, json: String) -> Result<String, serde_json::Error> {
+        if self.rules == Rules::default() {
+            return Ok(json);
+        }
+        serde_json::to_string(&self.rules.apply(serde_json::from_str(&json)?))
+    }
+}
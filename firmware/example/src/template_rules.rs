@@ -0,0 +1,183 @@
+//! The rules of a telemetry template, apart from the NVS access in
+//! [`crate::template`], so they run in host tests (see
+//! `firmware/host-tests`).
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// Dotted path in the message → dotted path in the output; `""` keeps it
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    /// Dotted paths left out of the output
+    #[serde(default)]
+    pub drop: Vec<String>,
+    /// Separator for flattening nested objects; empty keeps them nested
+    #[serde(default)]
+    pub flatten: String,
+}
+
+impl Rules {
+    /// The rules with `desired`, from a shadow delta, merged in: maps are
+    /// extended, anything else replaced. Fails if the result isn't a valid
+    /// template.
+    pub fn merged(&self, desired: &Value) -> Result<Rules, Box<dyn std::error::Error>> {
+        let desired = desired.as_object().ok_or("telemetry_template must be an object")?;
+        let mut merged = serde_json::to_value(self)?;
+        if let Some(merged) = merged.as_object_mut() {
+            for (name, value) in desired {
+                match (merged.get_mut(name), value) {
+                    (Some(Value::Object(current)), Value::Object(changes)) => current.extend(changes.clone()),
+                    _ => {
+                        merged.insert(name.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        let rules: Rules = serde_json::from_value(merged)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (from, to) in &self.rename {
+            if from.is_empty() || (!to.is_empty() && to.split('.').any(str::is_empty)) {
+                return Err(format!("Can't rename \"{}\" to \"{}\"", from, to));
+            }
+        }
+        Ok(())
+    }
+
+    /// The message with the rules applied. Anything but an object is
+    /// returned as it is.
+    pub fn apply(&self, message: Value) -> Value {
+        let fields = match message {
+            Value::Object(fields) if !fields.is_empty() => fields,
+            other => return other,
+        };
+        let mut leaves = Vec::new();
+        collect_leaves(String::new(), Value::Object(fields), &mut leaves);
+
+        let mut output = Map::new();
+        for (path, value) in leaves {
+            if self.drop.iter().any(|dropped| covers(dropped, &path)) {
+                continue;
+            }
+            let path = self.renamed(&path);
+            if self.flatten.is_empty() {
+                insert_nested(&mut output, &path, value);
+            } else {
+                output.insert(path.replace('.', &self.flatten), value);
+            }
+        }
+        Value::Object(output)
+    }
+
+    /// `path` after the most specific rename covering it.
+    fn renamed(&self, path: &str) -> String {
+        self.rename
+            .iter()
+            .filter(|(from, to)| !to.is_empty() && covers(from, path))
+            .max_by_key(|(from, _)| from.len())
+            .map(|(from, to)| format!("{}{}", to, &path[from.len()..]))
+            .unwrap_or_else(|| path.to_string())
+    }
+}
+
+/// `prefix` is `path` itself or one of the objects containing it.
+fn covers(prefix: &str, path: &str) -> bool {
+    path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Every value that isn't a non-empty object, with its dotted path.
+fn collect_leaves(path: String, value: Value, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (name, value) in fields {
+                let child = if path.is_empty() { name } else { format!("{}.{}", path, name) };
+                collect_leaves(child, value, leaves);
+            }
+        }
+        value => leaves.push((path, value)),
+    }
+}
+
+/// Insert `value` at dotted `path`, creating the objects on the way. A value
+/// already in the way is replaced: with clashing renames the last one wins.
+fn insert_nested(output: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        None => {
+            output.insert(path.to_string(), value);
+        }
+        Some((name, rest)) => {
+            let child = output
+                .entry(name.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                insert_nested(child, rest, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules(template: Value) -> Rules {
+        Rules::default().merged(&template).unwrap()
+    }
+
+    #[test]
+    fn renames_nested_fields_by_most_specific_rule() {
+        let rules = rules(json!({"rename": {"net": "network", "net.rssi": "signal.rssi"}}));
+        let message = json!({"net": {"rssi": -60, "ip": "10.0.0.2"}, "uptime": 5});
+        assert_eq!(
+            rules.apply(message),
+            json!({"network": {"ip": "10.0.0.2"}, "signal": {"rssi": -60}, "uptime": 5})
+        );
+    }
+
+    #[test]
+    fn dropping_an_object_drops_its_fields() {
+        let rules = rules(json!({"drop": ["net"]}));
+        let message = json!({"net": {"rssi": -60, "ip": "10.0.0.2"}, "netmask": 24});
+        assert_eq!(rules.apply(message), json!({"netmask": 24}));
+    }
+
+    #[test]
+    fn flattens_objects_but_keeps_arrays() {
+        let rules = rules(json!({"flatten": "_"}));
+        let message = json!({"gps": {"fix": [1.5, 2.5], "sats": [{"id": 3}]}, "empty": {}});
+        assert_eq!(
+            rules.apply(message),
+            json!({"gps_fix": [1.5, 2.5], "gps_sats": [{"id": 3}], "empty": {}})
+        );
+    }
+
+    #[test]
+    fn a_delta_clearing_a_rename_switches_it_off() {
+        let rules = rules(json!({"rename": {"free_heap": "heap.free", "location": "gps"}, "flatten": "_"}));
+        let rules = rules.merged(&json!({"rename": {"free_heap": ""}, "flatten": ""})).unwrap();
+        assert_eq!(rules.rename.get("location").map(String::as_str), Some("gps"));
+        assert_eq!(
+            rules.apply(json!({"free_heap": 1, "location": {"lat": 2}})),
+            json!({"free_heap": 1, "gps": {"lat": 2}})
+        );
+    }
+
+    #[test]
+    fn rejects_an_invalid_merge() {
+        let rules = rules(json!({"drop": ["retries"]}));
+        assert!(rules.merged(&json!({"rename": {"a": "b..c"}})).is_err());
+        assert!(rules.merged(&json!({"unknown": 1})).is_err());
+        assert!(rules.merged(&json!("drop")).is_err());
+    }
+}
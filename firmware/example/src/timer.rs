@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+/// Periodic timer whose first deadline is shifted by a per-device phase.
+///
+/// Devices sharing the same interval would otherwise fire in lockstep after a
+/// fleet-wide reconnect (e.g. recovery from a regional outage). The phase is
+/// derived from the client id, so it is stable across reboots for a device but
+/// spread uniformly across the fleet.
+pub struct PeriodicTimer {
+    period: Duration,
+    next: Instant,
+    /// Period multiplier and when it expires, set by a fleet backoff
+    slowdown: Option<(u32, Instant)>,
+}
+
+impl PeriodicTimer {
+    /// Create a timer firing every `period`. `name` distinguishes timers of the
+    /// same device so they don't all fire at the same instant either.
+    /// A zero period yields a disabled timer.
+    pub fn new(period: Duration, client_id: &str, name: &str) -> Self {
+        let phase = phase_offset(period, client_id, name);
+        log::info!("Timer '{}': period {:?}, phase {:?}", name, period, phase);
+
+        Self {
+            period,
+            next: Instant::now() + phase,
+            slowdown: None,
+        }
+    }
+
+    /// Fire `factor` times less often until `until`. A factor of 1 or less
+    /// cancels a slowdown in progress.
+    pub fn slow_down(&mut self, factor: u32, until: Instant) {
+        self.slowdown = (factor > 1).then_some((factor, until));
+    }
+
+    /// The multiplier in effect, 1 when running at the normal rate.
+    pub fn slowdown_factor(&self) -> u32 {
+        match self.slowdown {
+            Some((factor, until)) if Instant::now() < until => factor,
+            _ => 1,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.period.is_zero()
+    }
+
+    /// Returns true once the deadline has passed and schedules the next one.
+    /// Missed periods are skipped rather than fired in a burst.
+    pub fn poll(&mut self) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now < self.next {
+            return false;
+        }
+
+        let period = self.period * self.slowdown_factor();
+        while self.next <= now {
+            self.next += period;
+        }
+        true
+    }
+}
+
+/// Deterministic offset in `[0, period)` computed from an FNV-1a hash of the
+/// client id and timer name.
+pub fn phase_offset(period: Duration, client_id: &str, name: &str) -> Duration {
+    let period_ms = period.as_millis() as u64;
+    if period_ms == 0 {
+        return Duration::ZERO;
+    }
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in client_id.bytes().chain(name.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    Duration::from_millis(hash % period_ms)
+}
@@ -0,0 +1,173 @@
+use crate::migrations::NAMESPACE;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys;
+use esp_idf_svc::tls::{self, EspTls, X509};
+use std::ffi::CStr;
+
+const FINGERPRINT_KEY: &str = "tls_fp";
+const VALID_TO_KEY: &str = "tls_valid_to";
+
+/// What the probe's handshake trusts and presents: the same as the MQTT
+/// connection's, taken from its auth provider.
+pub struct ProbeIdentity {
+    pub ca_pem: Vec<u8>,
+    pub certificate_pem: Vec<u8>,
+    pub private_key_pem: Vec<u8>,
+}
+
+/// Server certificate actually presented by the broker.
+#[derive(Debug, Clone)]
+pub struct ServerCertInfo {
+    /// SHA-256 of the DER certificate, lowercase hex
+    pub fingerprint: String,
+    pub issuer: String,
+    pub subject: String,
+    /// Validity bounds, in days since the Unix epoch
+    pub valid_from_days: i64,
+    pub valid_to_days: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertChange {
+    FirstSeen,
+    Unchanged,
+    /// Changed while the previous certificate was close to expiry
+    Rotated { previous: String },
+    /// Changed outside the rotation window: possible MITM or misconfiguration
+    Unexpected { previous: String },
+}
+
+impl CertChange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CertChange::FirstSeen => "first_seen",
+            CertChange::Unchanged => "unchanged",
+            CertChange::Rotated { .. } => "rotated",
+            CertChange::Unexpected { .. } => "unexpected",
+        }
+    }
+}
+
+/// Split `mqtts://host[:port]` into host and port. `wss://` defaults to 443.
+pub fn endpoint_host_port(url: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority = rest.split(['/', '?']).next()?;
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None if scheme == "wss" => Some((authority, 443)),
+        None => Some((authority, 8883)),
+    }
+}
+
+/// Open a separate TLS session to the broker with the device identity and
+/// inspect the leaf certificate it presents. esp-mqtt doesn't expose its own
+/// session, so this observes the same endpoint through a second handshake.
+pub fn probe(
+    host: &str,
+    port: u16,
+    alpn: Option<&str>,
+    identity: &ProbeIdentity,
+) -> Result<ServerCertInfo, Box<dyn std::error::Error>> {
+    let ca = nul_terminated(&identity.ca_pem);
+    let cert = nul_terminated(&identity.certificate_pem);
+    let key = nul_terminated(&identity.private_key_pem);
+
+    let alpn_protos = alpn.map(|protocol| [protocol]);
+    let mut session = EspTls::new()?;
+    session.connect(
+        host,
+        port,
+        &tls::Config {
+            ca_cert: Some(X509::pem_until_nul(&ca)),
+            client_cert: Some(X509::pem_until_nul(&cert)),
+            private_key: Some(X509::pem_until_nul(&key)),
+            timeout_ms: 10_000,
+            alpn_protos: alpn_protos.as_ref().map(|protos| protos.as_slice()),
+            ..Default::default()
+        },
+    )?;
+
+    unsafe {
+        let ssl = sys::esp_tls_get_ssl_context(session.context_handle()) as *const sys::mbedtls_ssl_context;
+        let peer = sys::mbedtls_ssl_get_peer_cert(ssl);
+        if peer.is_null() {
+            return Err("Broker presented no certificate".into());
+        }
+        let peer = &*peer;
+
+        let der = std::slice::from_raw_parts(peer.raw.p, peer.raw.len);
+        let mut digest = [0u8; 32];
+        sys::mbedtls_sha256(der.as_ptr(), der.len(), digest.as_mut_ptr(), 0);
+
+        Ok(ServerCertInfo {
+            fingerprint: digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            issuer: dn_to_string(&peer.issuer),
+            subject: dn_to_string(&peer.subject),
+            valid_from_days: x509_time_to_days(&peer.valid_from),
+            valid_to_days: x509_time_to_days(&peer.valid_to),
+        })
+    }
+}
+
+/// Compare with the certificate seen on the previous connect and remember
+/// the current one. A change counts as a rotation only if the new
+/// certificate was issued within `window_days` of the old one's expiry.
+pub fn record(
+    partition: EspDefaultNvsPartition,
+    info: &ServerCertInfo,
+    window_days: i64,
+) -> Result<CertChange, Box<dyn std::error::Error>> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+    let mut buf = [0u8; 65];
+    let previous = nvs.get_str(FINGERPRINT_KEY, &mut buf)?.map(str::to_string);
+    let previous_valid_to = nvs.get_i64(VALID_TO_KEY)?;
+
+    let change = match (previous, previous_valid_to) {
+        (Some(previous), _) if previous == info.fingerprint => CertChange::Unchanged,
+        (Some(previous), Some(valid_to)) if info.valid_from_days >= valid_to - window_days => {
+            CertChange::Rotated { previous }
+        }
+        (Some(previous), _) => CertChange::Unexpected { previous },
+        (None, _) => CertChange::FirstSeen,
+    };
+
+    if change != CertChange::Unchanged {
+        nvs.set_str(FINGERPRINT_KEY, &info.fingerprint)?;
+        nvs.set_i64(VALID_TO_KEY, info.valid_to_days)?;
+    }
+
+    Ok(change)
+}
+
+fn nul_terminated(pem: &[u8]) -> Vec<u8> {
+    let mut buf = pem.to_vec();
+    buf.push(0);
+    buf
+}
+
+unsafe fn dn_to_string(dn: &sys::mbedtls_x509_name) -> String {
+    let mut buf = [0u8; 256];
+    let len = sys::mbedtls_x509_dn_gets(buf.as_mut_ptr() as *mut _, buf.len(), dn);
+    if len < 0 {
+        return String::new();
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn x509_time_to_days(time: &sys::mbedtls_x509_time) -> i64 {
+    days_from_civil(time.year as i64, time.mon as i64, time.day as i64)
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
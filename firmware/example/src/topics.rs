@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+/// Prefix of Basic Ingest topics. A publish on `$aws/rules/<rule>/<topic>`
+/// goes straight to the IoT rule, skipping the message broker and its
+/// messaging charge; the rule sees `<topic>` as the topic.
+pub const BASIC_INGEST_PREFIX: &str = "$aws/rules";
+
+/// IoT rule names are letters, digits and underscores.
+pub fn validate_rule_name(rule_name: &str) -> Result<(), String> {
+    if rule_name.is_empty() || !rule_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid IoT rule name \"{}\" (letters, digits and _ only)", rule_name));
+    }
+    Ok(())
+}
+
+/// The Basic Ingest topic that delivers a publish on `topic` to the rule
+/// `rule_name` only.
+pub fn basic_ingest(rule_name: &str, topic: &str) -> Result<String, String> {
+    validate_rule_name(rule_name)?;
+    Ok(format!("{}/{}/{}", BASIC_INGEST_PREFIX, rule_name, topic))
+}
+
+/// Placeholders allowed in topics from cfg.toml.
+pub const PLACEHOLDERS: [&str; 2] = ["{client_id}", "{thing_name}"];
+
+/// `template` with `{client_id}` and `{thing_name}` filled in. Any other
+/// brace is an error, so a mistyped placeholder doesn't end up as a literal
+/// topic level.
+pub fn expand(template: &str, client_id: &str, thing_name: &str) -> Result<String, String> {
+    let topic = template.replace("{client_id}", client_id).replace("{thing_name}", thing_name);
+    if topic.contains(['{', '}']) {
+        return Err(format!(
+            "Unknown placeholder in topic \"{}\" (only {})",
+            template,
+            PLACEHOLDERS.join(" and ")
+        ));
+    }
+    Ok(topic)
+}
+
+/// Mapping from logical topics to shorter on-the-wire topics.
+///
+/// Every publish and subscribe goes through [`TopicAliases::wire`], so the
+/// rest of the firmware keeps using descriptive topic names while the link
+/// carries the short ones. The mapping is announced on connect so cloud
+/// rules can translate back.
+#[derive(Debug, Clone, Default)]
+pub struct TopicAliases {
+    aliases: BTreeMap<String, String>,
+}
+
+impl TopicAliases {
+    /// Parse `logical=wire` pairs separated by commas, e.g.
+    /// `"sensors/esp32/telemetry=s/t,sensors/esp32/dead-letter=s/d"`.
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut aliases = BTreeMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (logical, wire) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Topic alias \"{}\" is not logical=wire", pair))?;
+            let (logical, wire) = (logical.trim(), wire.trim());
+            if logical.is_empty() || wire.is_empty() {
+                return Err(format!("Topic alias \"{}\" has an empty side", pair).into());
+            }
+            if aliases.values().any(|existing| existing == wire) {
+                return Err(format!("Wire topic \"{}\" is used by more than one alias", wire).into());
+            }
+            aliases.insert(logical.to_string(), wire.to_string());
+        }
+        Ok(Self { aliases })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// The topic to use on the wire for `logical`.
+    pub fn wire<'a>(&'a self, logical: &'a str) -> &'a str {
+        self.aliases.get(logical).map_or(logical, String::as_str)
+    }
+
+    /// The logical topic for `wire`, as received.
+    pub fn logical<'a>(&'a self, wire: &'a str) -> &'a str {
+        self.aliases
+            .iter()
+            .find(|(_, alias)| alias.as_str() == wire)
+            .map_or(wire, |(logical, _)| logical.as_str())
+    }
+
+    /// Wire topic to logical topic, as announced to the cloud.
+    pub fn reverse(&self) -> BTreeMap<&str, &str> {
+        self.aliases
+            .iter()
+            .map(|(logical, wire)| (wire.as_str(), logical.as_str()))
+            .collect()
+    }
+}
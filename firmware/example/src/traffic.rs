@@ -0,0 +1,68 @@
+//! MQTT traffic counters kept by the [`Client`](crate::client::Client) and
+//! published as periodic metrics. Unlike the metrics middleware, which sees
+//! payloads before signing or compression, these count what crosses the
+//! connection.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Totals since boot.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct TrafficStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Connections after the first
+    pub reconnects: u64,
+    /// Subscribe and resubscribe calls esp-mqtt refused
+    pub subscribe_failures: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    reconnects: AtomicU64,
+    subscribe_failures: AtomicU64,
+}
+
+/// Shared by the client and its listener.
+#[derive(Clone, Default)]
+pub struct TrafficCounters {
+    counters: Arc<Counters>,
+}
+
+impl TrafficCounters {
+    pub fn sent(&self, bytes: usize) {
+        self.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.counters.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn subscribe_failed(&self) {
+        self.counters.subscribe_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            messages_sent: self.counters.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.counters.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            reconnects: self.counters.reconnects.load(Ordering::Relaxed),
+            subscribe_failures: self.counters.subscribe_failures.load(Ordering::Relaxed),
+        }
+    }
+}
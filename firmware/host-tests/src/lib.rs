@@ -0,0 +1,15 @@
+//! The firmware's hardware-independent modules, built for the host so their
+//! unit tests run with `cargo xtask test`. The firmware crate itself only
+//! builds for ESP-IDF targets. A module listed here may only use std, serde
+//! and serde_json.
+
+#[path = "../../example/src/alarm_events.rs"]
+pub mod alarm_events;
+#[path = "../../example/src/json_limits.rs"]
+pub mod json_limits;
+#[path = "../../example/src/migration_plan.rs"]
+pub mod migration_plan;
+#[path = "../../example/src/reassembly.rs"]
+pub mod reassembly;
+#[path = "../../example/src/template_rules.rs"]
+pub mod template_rules;
@@ -5,6 +5,10 @@ provider "aws" {
 # Data source to get current AWS account ID
 data "aws_caller_identity" "current" {}
 
+locals {
+  account_id = var.account_id != "" ? var.account_id : data.aws_caller_identity.current.account_id
+}
+
 # Create IoT Things using the module
 module "iot_things" {
   for_each = { for thing in var.things : thing.name => thing }
@@ -13,8 +17,199 @@ module "iot_things" {
   thing_name         = each.value.name
   topic_prefix       = each.value.topic_prefix
   region             = var.region
-  account_id         = var.account_id != "" ? var.account_id : data.aws_caller_identity.current.account_id
+  account_id         = local.account_id
   policy_name        = var.policy_name
   certificate_active = var.certificate_active
   tags               = var.tags
 }
+
+# Static group containing every thing created above
+resource "aws_iot_thing_group" "fleet" {
+  name = var.thing_group_name
+
+  properties {
+    description = "All ESP32 devices managed by this configuration"
+  }
+
+  tags = var.tags
+}
+
+resource "aws_iot_thing_group_membership" "fleet" {
+  for_each = module.iot_things
+
+  thing_name       = each.value.thing_name
+  thing_group_name = aws_iot_thing_group.fleet.name
+}
+
+# Fleet indexing is an account/region wide setting, required by dynamic groups
+resource "aws_iot_indexing_configuration" "fleet" {
+  count = var.enable_fleet_indexing ? 1 : 0
+
+  thing_indexing_configuration {
+    thing_indexing_mode              = "REGISTRY_AND_SHADOW"
+    thing_connectivity_indexing_mode = "STATUS"
+
+    # Rollout state reported by firmware_shadow
+    named_shadow_indexing_mode = "ON"
+
+    filter {
+      named_shadow_names = ["firmware"]
+    }
+
+    # The firmware reports its semver string, e.g. "1.4.0"
+    custom_field {
+      name = "shadow.reported.firmware_version"
+      type = "String"
+    }
+  }
+
+  thing_group_indexing_configuration {
+    thing_group_indexing_mode = "ON"
+  }
+}
+
+# The AWS provider has no dynamic thing group resource, so use the CLI.
+# Every query is limited to the static fleet group.
+resource "terraform_data" "dynamic_thing_groups" {
+  for_each = var.enable_fleet_indexing ? var.dynamic_thing_groups : {}
+
+  input = {
+    group_name = each.key
+    query_string = format(
+      "thingGroupNames:%s AND (%s)",
+      aws_iot_thing_group.fleet.name,
+      replace(each.value, "{firmware_version}", var.current_firmware_version)
+    )
+    region = var.region
+  }
+
+  provisioner "local-exec" {
+    command = <<-EOT
+      aws iot create-dynamic-thing-group \
+        --region ${self.input.region} \
+        --thing-group-name ${self.input.group_name} \
+        --query-string '${self.input.query_string}'
+    EOT
+  }
+
+  provisioner "local-exec" {
+    when    = destroy
+    command = <<-EOT
+      aws iot delete-dynamic-thing-group \
+        --region ${self.input.region} \
+        --thing-group-name ${self.input.group_name}
+    EOT
+  }
+
+  depends_on = [aws_iot_indexing_configuration.fleet]
+}
+
+# Just-in-time provisioning: devices with a certificate signed by this CA are
+# registered as things on their first connection attempt
+resource "aws_iam_role" "jitp" {
+  count = var.enable_jitp ? 1 : 0
+
+  name = "esp32-jitp-role"
+  assume_role_policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      {
+        Effect    = "Allow"
+        Action    = "sts:AssumeRole"
+        Principal = { Service = "iot.amazonaws.com" }
+      }
+    ]
+  })
+
+  tags = var.tags
+}
+
+resource "aws_iam_role_policy_attachment" "jitp" {
+  count = var.enable_jitp ? 1 : 0
+
+  role       = aws_iam_role.jitp[0].name
+  policy_arn = "arn:aws:iam::aws:policy/service-role/AWSIoTThingsRegistration"
+}
+
+resource "aws_iot_policy" "jitp" {
+  count = var.enable_jitp ? 1 : 0
+
+  name = "esp32-jitp-policy"
+  policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      {
+        Effect = "Allow"
+        Action = [
+          "iot:Publish",
+          "iot:Receive",
+          "iot:PublishRetain"
+        ]
+        Resource = [
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/${var.jitp_topic_prefix}/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/$aws/things/$${iot:Connection.Thing.ThingName}/shadow/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/$aws/things/$${iot:Connection.Thing.ThingName}/jobs/*"
+        ]
+      },
+      {
+        Effect = "Allow"
+        Action = "iot:Subscribe"
+        Resource = [
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/${var.jitp_topic_prefix}/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/$aws/things/$${iot:Connection.Thing.ThingName}/shadow/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/$aws/things/$${iot:Connection.Thing.ThingName}/jobs/*"
+        ]
+      },
+      {
+        Effect   = "Allow"
+        Action   = "iot:Connect"
+        Resource = "arn:aws:iot:${var.region}:${local.account_id}:client/$${iot:Connection.Thing.ThingName}"
+      }
+    ]
+  })
+
+  tags = var.tags
+}
+
+resource "aws_iot_ca_certificate" "jitp" {
+  count = var.enable_jitp ? 1 : 0
+
+  active                       = true
+  allow_auto_registration      = true
+  ca_certificate_pem           = file(var.jitp_ca_certificate_path)
+  verification_certificate_pem = file(var.jitp_verification_certificate_path)
+
+  registration_config {
+    role_arn = aws_iam_role.jitp[0].arn
+    template_body = jsonencode({
+      Parameters = {
+        "AWS::IoT::Certificate::CommonName" = { Type = "String" }
+        "AWS::IoT::Certificate::Id"         = { Type = "String" }
+      }
+      Resources = {
+        thing = {
+          Type = "AWS::IoT::Thing"
+          Properties = {
+            ThingName   = { Ref = "AWS::IoT::Certificate::CommonName" }
+            ThingGroups = [aws_iot_thing_group.fleet.name]
+          }
+        }
+        certificate = {
+          Type = "AWS::IoT::Certificate"
+          Properties = {
+            CertificateId = { Ref = "AWS::IoT::Certificate::Id" }
+            Status        = "ACTIVE"
+          }
+        }
+        policy = {
+          Type       = "AWS::IoT::Policy"
+          Properties = { PolicyName = aws_iot_policy.jitp[0].name }
+        }
+      }
+    })
+  }
+
+  tags = var.tags
+
+  depends_on = [aws_iam_role_policy_attachment.jitp]
+}
@@ -23,6 +23,34 @@ output "iot_endpoint" {
   value       = length(module.iot_things) > 0 ? values(module.iot_things)[0].iot_endpoint : null
 }
 
+# Thing groups, e.g. as OTA job targets
+output "thing_group" {
+  description = "Static thing group containing all created things"
+  value = {
+    name = aws_iot_thing_group.fleet.name
+    arn  = aws_iot_thing_group.fleet.arn
+  }
+}
+
+output "dynamic_thing_groups" {
+  description = "Dynamic thing groups with their query and ARN"
+  value = {
+    for name, group in terraform_data.dynamic_thing_groups : name => {
+      query_string = group.output.query_string
+      arn          = "arn:aws:iot:${var.region}:${local.account_id}:thinggroup/${name}"
+    }
+  }
+}
+
+output "jitp_ca_certificate" {
+  description = "CA registered for just-in-time provisioning (null when disabled)"
+  value = var.enable_jitp ? {
+    id          = aws_iot_ca_certificate.jitp[0].id
+    arn         = aws_iot_ca_certificate.jitp[0].arn
+    policy_name = aws_iot_policy.jitp[0].name
+  } : null
+}
+
 # Instructions for next steps
 output "next_steps" {
   description = "Instructions for using the created resources"
@@ -47,6 +75,8 @@ ${join("\n", [for name, thing in module.iot_things : "   cd ../firmware/${name}
   - Endpoint: ${length(module.iot_things) > 0 ? values(module.iot_things)[0].iot_endpoint : "N/A"}
   - Things Created: ${join(", ", keys(module.iot_things))}
   - Topics: esp32/* (shared across all devices)
+  - Thing group: ${aws_iot_thing_group.fleet.name}
+  - Dynamic groups: ${join(", ", keys(terraform_data.dynamic_thing_groups))}
 
 EOT
 }
\ No newline at end of file
@@ -17,12 +17,20 @@ locals {
           "iot:Receive",
           "iot:PublishRetain"
         ]
-        Resource = "arn:aws:iot:${var.region}:${local.account_id}:topic/${var.topic_prefix}/*"
+        Resource = [
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/${var.topic_prefix}/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/$aws/things/${var.thing_name}/shadow/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topic/$aws/things/${var.thing_name}/jobs/*"
+        ]
       },
       {
-        Effect   = "Allow"
-        Action   = "iot:Subscribe"
-        Resource = "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/${var.topic_prefix}/*"
+        Effect = "Allow"
+        Action = "iot:Subscribe"
+        Resource = [
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/${var.topic_prefix}/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/$aws/things/${var.thing_name}/shadow/*",
+          "arn:aws:iot:${var.region}:${local.account_id}:topicfilter/$aws/things/${var.thing_name}/jobs/*"
+        ]
       },
       {
         Effect   = "Allow"
@@ -45,4 +45,55 @@ variable "tags" {
     Environment = "development"
     ManagedBy   = "terraform"
   }
-}
\ No newline at end of file
+}
+
+variable "thing_group_name" {
+  description = "Name of the static thing group every created thing is added to"
+  type        = string
+  default     = "esp32-fleet"
+}
+
+variable "enable_fleet_indexing" {
+  description = "Whether to enable fleet indexing (registry, shadow and connectivity status)"
+  type        = bool
+  default     = true
+}
+
+variable "dynamic_thing_groups" {
+  description = "Dynamic thing groups to create, keyed by name, with their fleet indexing query (usable as OTA job targets). Queries only match things in thing_group_name; {firmware_version} stands for current_firmware_version"
+  type        = map(string)
+  default = {
+    "esp32-outdated-firmware" = "NOT shadow.reported.firmware_version:{firmware_version}"
+    "esp32-disconnected"      = "connectivity.connected:false"
+  }
+}
+
+variable "current_firmware_version" {
+  description = "Firmware version of the latest release; devices reporting any other version are outdated"
+  type        = string
+  default     = "0.1.0"
+}
+
+variable "enable_jitp" {
+  description = "Whether to register a CA for just-in-time provisioning (JITP)"
+  type        = bool
+  default     = false
+}
+
+variable "jitp_ca_certificate_path" {
+  description = "Path to the PEM CA certificate that signs device certificates for JITP"
+  type        = string
+  default     = "certs/jitp/rootCA.pem"
+}
+
+variable "jitp_verification_certificate_path" {
+  description = "Path to the PEM verification certificate (signed by the CA, CN set to the account registration code)"
+  type        = string
+  default     = "certs/jitp/verificationCert.pem"
+}
+
+variable "jitp_topic_prefix" {
+  description = "Topic prefix granted to devices provisioned through JITP"
+  type        = string
+  default     = "esp32"
+}
@@ -0,0 +1,214 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// NVS namespaces and keys read by the firmware: `factory.rs` for the
+/// settings, `keygen.rs` for the certificate used by `auth_mode = "x509_nvs"`.
+const FACTORY_NAMESPACE: &str = "factory";
+const APP_NAMESPACE: &str = "app";
+const CERT_KEY: &str = "dev_cert";
+const KEY_KEY: &str = "dev_key";
+
+/// Generate the NVS partition image of each device on a production line:
+/// WiFi credentials, broker, thing name and certificate, flashed next to the
+/// generic firmware image. Devices come from a CSV file, or from the flags
+/// for a single device.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// CSV with a header row and the columns of the device flags below,
+    /// e.g. `thing_name,wifi_ssid,wifi_pass,cert,key`
+    #[arg(long)]
+    devices: Option<PathBuf>,
+
+    #[command(flatten)]
+    device: Device,
+
+    /// Create the certificate of devices without `cert`/`key` through the
+    /// AWS IoT API, attaching this policy to it
+    #[arg(long)]
+    policy: Option<String>,
+
+    /// Images, generator input and created certificates go here
+    #[arg(long, default_value = "provisioning")]
+    out_dir: PathBuf,
+
+    /// Size of the nvs partition in partitions.csv
+    #[arg(long, default_value = "0x6000")]
+    size: String,
+}
+
+/// One device. Empty settings are left out of the image, so the device
+/// keeps the value compiled in from cfg.toml.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+struct Device {
+    /// Thing name, also the image's file name
+    #[arg(long = "thing")]
+    thing_name: Option<String>,
+    #[arg(long)]
+    client_id: Option<String>,
+    #[arg(long)]
+    wifi_ssid: Option<String>,
+    #[arg(long)]
+    wifi_pass: Option<String>,
+    #[arg(long)]
+    mqtt_url: Option<String>,
+    /// Defaults to `x509_nvs` when the image carries a certificate
+    #[arg(long)]
+    auth_mode: Option<String>,
+    #[arg(long)]
+    hardware_revision: Option<String>,
+    /// Device certificate PEM
+    #[arg(long)]
+    cert: Option<PathBuf>,
+    /// Private key PEM of the certificate
+    #[arg(long)]
+    key: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let devices = match &args.devices {
+        Some(path) => csv::Reader::from_path(path)?
+            .deserialize::<Device>()
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![args.device.clone()],
+    };
+    std::fs::create_dir_all(&args.out_dir)?;
+
+    let iot = match &args.policy {
+        Some(_) => Some(aws_sdk_iot::Client::new(&aws_config::load_from_env().await)),
+        None => None,
+    };
+
+    let mut failed = 0;
+    for (row, mut device) in devices.into_iter().enumerate() {
+        let Some(thing) = non_empty(&device.thing_name).map(str::to_string) else {
+            eprintln!("FAIL device {}: no thing_name", row + 1);
+            failed += 1;
+            continue;
+        };
+
+        let needs_certificate = device.cert.is_none() && device.key.is_none();
+        if let (true, Some(iot), Some(policy)) = (needs_certificate, &iot, &args.policy) {
+            match create_certificate(iot, &thing, policy, &args.out_dir).await {
+                Ok((cert, key)) => {
+                    device.cert = Some(cert);
+                    device.key = Some(key);
+                }
+                Err(e) => {
+                    eprintln!("FAIL {}: creating the certificate: {}", thing, e);
+                    failed += 1;
+                    continue;
+                }
+            }
+        }
+
+        match generate(&device, &thing, &args.out_dir, &args.size) {
+            Ok(image) => println!("{}: {}", thing, image.display()),
+            Err(e) => {
+                eprintln!("FAIL {}: {}", thing, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("Flash an image with `espflash write-bin 0x9000 <image>` after the firmware");
+    if failed > 0 {
+        eprintln!("{} device(s) failed", failed);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Write the generator input for `device` and run ESP-IDF's NVS partition
+/// generator on it, returning the image.
+fn generate(device: &Device, thing: &str, out_dir: &Path, size: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let certificate = match (&device.cert, &device.key) {
+        (Some(cert), Some(key)) => Some((std::fs::canonicalize(cert)?, std::fs::canonicalize(key)?)),
+        (None, None) => None,
+        _ => return Err("cert and key go together".into()),
+    };
+    let auth_mode = non_empty(&device.auth_mode).or(certificate.as_ref().map(|_| "x509_nvs"));
+
+    let input = out_dir.join(format!("{}.csv", thing));
+    let image = out_dir.join(format!("{}.bin", thing));
+
+    let mut csv = csv::Writer::from_path(&input)?;
+    csv.write_record(["key", "type", "encoding", "value"])?;
+    csv.write_record([FACTORY_NAMESPACE, "namespace", "", ""])?;
+    let settings = [
+        ("wifi_ssid", non_empty(&device.wifi_ssid)),
+        ("wifi_pass", non_empty(&device.wifi_pass)),
+        ("mqtt_url", non_empty(&device.mqtt_url)),
+        ("client_id", non_empty(&device.client_id)),
+        ("thing_name", Some(thing)),
+        ("auth_mode", auth_mode),
+        ("hw_rev", non_empty(&device.hardware_revision)),
+    ];
+    for (key, value) in settings {
+        if let Some(value) = value {
+            csv.write_record([key, "data", "string", value])?;
+        }
+    }
+    if let Some((cert, key)) = &certificate {
+        csv.write_record([APP_NAMESPACE, "namespace", "", ""])?;
+        csv.write_record([CERT_KEY, "file", "string", &cert.to_string_lossy()])?;
+        csv.write_record([KEY_KEY, "file", "string", &key.to_string_lossy()])?;
+    }
+    csv.flush()?;
+
+    let status = Command::new("python3")
+        .args(["-m", "esp_idf_nvs_partition_gen", "generate"])
+        .arg(&input)
+        .arg(&image)
+        .arg(size)
+        .status()
+        .map_err(|e| format!("running python3: {}", e))?;
+    if !status.success() {
+        return Err(format!(
+            "NVS partition generator failed ({}); install it with `pip install esp-idf-nvs-partition-gen`",
+            status
+        )
+        .into());
+    }
+    Ok(image)
+}
+
+/// Create `thing` if needed and a certificate for it with `policy`, saving
+/// the PEMs under `out_dir/thing`.
+async fn create_certificate(
+    iot: &aws_sdk_iot::Client,
+    thing: &str,
+    policy: &str,
+    out_dir: &Path,
+) -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
+    iot.create_thing().thing_name(thing).send().await?;
+    let created = iot.create_keys_and_certificate().set_as_active(true).send().await?;
+    let arn = created.certificate_arn().ok_or("no certificate ARN")?;
+    let certificate_pem = created.certificate_pem().ok_or("no certificate")?;
+    let private_key = created
+        .key_pair()
+        .and_then(|pair| pair.private_key())
+        .ok_or("no private key")?;
+
+    // Saved before attaching, so a failure below doesn't lose the only copy of the key
+    let dir = out_dir.join(thing);
+    std::fs::create_dir_all(&dir)?;
+    let cert = dir.join("certificate.pem.crt");
+    let key = dir.join("private.pem.key");
+    std::fs::write(&cert, certificate_pem)?;
+    std::fs::write(&key, private_key)?;
+
+    iot.attach_policy().policy_name(policy).target(arn).send().await?;
+    iot.attach_thing_principal().thing_name(thing).principal(arn).send().await?;
+    println!("{}: created certificate {}", thing, arn);
+    Ok((cert, key))
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
@@ -0,0 +1,160 @@
+use aws_sdk_iot::types::{PresignedUrlConfig, TargetSelection};
+use aws_sdk_s3::primitives::ByteStream;
+use base64::Engine;
+use clap::Parser;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use p256::pkcs8::DecodePrivateKey;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// Build the OTA manifest for a firmware image, optionally upload both to S3
+/// and create the AWS IoT job that rolls it out.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Firmware image produced by `espflash save-image`
+    #[arg(long)]
+    bin: PathBuf,
+
+    /// Version of the image (e.g. 1.4.0)
+    #[arg(long)]
+    version: String,
+
+    /// Oldest running firmware version allowed to install this image
+    #[arg(long)]
+    min_compatible_version: String,
+
+    /// Restrict the image to one hardware revision
+    #[arg(long)]
+    hardware_revision: Option<String>,
+
+    /// PKCS#8 PEM ECDSA P-256 key used to sign the image
+    #[arg(long)]
+    signing_key: PathBuf,
+
+    /// Where to write the manifest locally
+    #[arg(long, default_value = "manifest.json")]
+    out: PathBuf,
+
+    /// S3 bucket to upload the image and manifest to
+    #[arg(long)]
+    bucket: Option<String>,
+
+    /// Key prefix inside the bucket
+    #[arg(long, default_value = "firmware")]
+    prefix: String,
+
+    /// Create an IoT job targeting these thing or thing group ARNs
+    #[arg(long, value_delimiter = ',', requires_all = ["bucket", "role_arn"])]
+    targets: Vec<String>,
+
+    /// Role AWS IoT assumes to presign the S3 download URL
+    #[arg(long)]
+    role_arn: Option<String>,
+
+    /// Lifetime of the presigned download URL
+    #[arg(long, default_value_t = 3600)]
+    url_expiry_secs: i64,
+}
+
+/// OTA manifest, mirrored by the firmware OTA handler.
+#[derive(Serialize, Debug)]
+struct Manifest {
+    version: String,
+    size: u64,
+    sha256: String,
+    /// Base64 DER ECDSA P-256 signature over the image bytes
+    signature: String,
+    min_compatible_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hardware_revision: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let image = std::fs::read(&args.bin)?;
+    let manifest = build_manifest(&args, &image)?;
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    std::fs::write(&args.out, &manifest_json)?;
+    println!("Wrote manifest to {}:\n{}", args.out.display(), manifest_json);
+
+    let Some(bucket) = &args.bucket else {
+        return Ok(());
+    };
+
+    let shared_config = aws_config::load_from_env().await;
+    let s3 = aws_sdk_s3::Client::new(&shared_config);
+
+    let image_key = format!("{}/{}/firmware.bin", args.prefix, args.version);
+    let manifest_key = format!("{}/{}/manifest.json", args.prefix, args.version);
+
+    s3.put_object()
+        .bucket(bucket)
+        .key(&image_key)
+        .body(ByteStream::from(image))
+        .send()
+        .await?;
+    s3.put_object()
+        .bucket(bucket)
+        .key(&manifest_key)
+        .content_type("application/json")
+        .body(ByteStream::from(manifest_json.into_bytes()))
+        .send()
+        .await?;
+    println!("Uploaded s3://{}/{} and s3://{}/{}", bucket, image_key, bucket, manifest_key);
+
+    if args.targets.is_empty() {
+        return Ok(());
+    }
+
+    // AWS IoT replaces the placeholder with a presigned URL when delivering
+    // the job document, so the device never needs S3 credentials
+    let document = serde_json::json!({
+        "operation": "ota",
+        "url": format!("${{aws:iot:s3-presigned-url:https://{}.s3.amazonaws.com/{}}}", bucket, image_key),
+        "manifest": manifest,
+    });
+    let job_id = format!("ota-{}", args.version.replace('.', "_"));
+
+    let iot = aws_sdk_iot::Client::new(&shared_config);
+    iot.create_job()
+        .job_id(&job_id)
+        .set_targets(Some(args.targets.clone()))
+        .target_selection(TargetSelection::Snapshot)
+        .document(document.to_string())
+        .presigned_url_config(
+            PresignedUrlConfig::builder()
+                .set_role_arn(args.role_arn.clone())
+                .expires_in_sec(args.url_expiry_secs)
+                .build(),
+        )
+        .send()
+        .await?;
+    println!("Created job {} for {} target(s)", job_id, args.targets.len());
+
+    Ok(())
+}
+
+fn build_manifest(args: &Args, image: &[u8]) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let key_pem = std::fs::read_to_string(&args.signing_key)?;
+    let signing_key = SigningKey::from_pkcs8_pem(&key_pem)
+        .map_err(|e| format!("Failed to load signing key: {}", e))?;
+    let signature: Signature = signing_key.sign(image);
+
+    let sha256 = Sha256::digest(image)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    Ok(Manifest {
+        version: args.version.clone(),
+        size: image.len() as u64,
+        sha256,
+        signature: base64::engine::general_purpose::STANDARD.encode(signature.to_der()),
+        min_compatible_version: args.min_compatible_version.clone(),
+        hardware_revision: args.hardware_revision.clone(),
+    })
+}
@@ -0,0 +1,164 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Fail when a firmware image no longer fits its app partition, or eats
+/// into the headroom kept by the size budget, and show which crates take
+/// the space. Run after `espflash save-image`, before flashing or releasing.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Firmware image produced by `espflash save-image`
+    #[arg(long)]
+    bin: PathBuf,
+
+    /// Partition table the image is flashed with
+    #[arg(long, default_value = "partitions.csv")]
+    partitions: PathBuf,
+
+    /// App partition to check against; defaults to the smallest one, which
+    /// every OTA slot has to fit
+    #[arg(long)]
+    partition: Option<String>,
+
+    /// Share of the partition the image may use, leaving room for the
+    /// updates still to come
+    #[arg(long, default_value_t = 90.0)]
+    budget_percent: f64,
+
+    /// Output of `cargo bloat --release --crates --message-format json`,
+    /// for the breakdown printed on failure
+    #[arg(long)]
+    bloat: Option<PathBuf>,
+
+    /// Crates listed in the breakdown
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+}
+
+#[derive(Debug)]
+struct Partition {
+    name: String,
+    size: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Bloat {
+    text_section_size: u64,
+    crates: Vec<CrateSize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CrateSize {
+    name: String,
+    size: u64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let image_size = std::fs::metadata(&args.bin)?.len();
+    let partitions = app_partitions(&std::fs::read_to_string(&args.partitions)?)?;
+    let partition = match &args.partition {
+        Some(name) => partitions
+            .into_iter()
+            .find(|partition| &partition.name == name)
+            .ok_or_else(|| format!("No app partition \"{}\" in {}", name, args.partitions.display()))?,
+        None => partitions
+            .into_iter()
+            .min_by_key(|partition| partition.size)
+            .ok_or_else(|| format!("No app partition in {}", args.partitions.display()))?,
+    };
+    let budget = (partition.size as f64 * args.budget_percent / 100.0) as u64;
+
+    println!(
+        "{}: {} bytes, {:.1}% of {} ({} bytes), budget {} bytes ({}%)",
+        args.bin.display(),
+        image_size,
+        image_size as f64 * 100.0 / partition.size as f64,
+        partition.name,
+        partition.size,
+        budget,
+        args.budget_percent
+    );
+
+    let failure = if image_size > partition.size {
+        Some(format!(
+            "image doesn't fit {}: {} bytes too large",
+            partition.name,
+            image_size - partition.size
+        ))
+    } else if image_size > budget {
+        Some(format!("image is {} bytes over budget", image_size - budget))
+    } else {
+        None
+    };
+
+    let Some(failure) = failure else {
+        println!("PASS {} bytes left in the budget", budget - image_size);
+        return Ok(());
+    };
+
+    eprintln!("FAIL {}", failure);
+    match &args.bloat {
+        Some(path) => {
+            let bloat: Bloat = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+            print_breakdown(&bloat, args.top);
+        }
+        None => eprintln!(
+            "Pass --bloat with the output of `cargo bloat --release --crates --message-format json` for a breakdown"
+        ),
+    }
+    std::process::exit(1);
+}
+
+/// App partitions of an ESP-IDF partition table CSV.
+fn app_partitions(csv: &str) -> Result<Vec<Partition>, Box<dyn std::error::Error>> {
+    let mut partitions = Vec::new();
+    for line in csv.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() < 5 || fields[1] != "app" {
+            continue;
+        }
+        let size = parse_size(fields[4]).ok_or_else(|| format!("Bad size in partition line \"{}\"", line))?;
+        partitions.push(Partition {
+            name: fields[0].to_string(),
+            size,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Sizes as the partition table tool accepts them: `0x1e0000`, `1966080`,
+/// `64K` or `2M`.
+fn parse_size(value: &str) -> Option<u64> {
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    let (digits, unit) = match value.char_indices().last()? {
+        (i, 'K' | 'k') => (&value[..i], 1024),
+        (i, 'M' | 'm') => (&value[..i], 1024 * 1024),
+        _ => (value, 1),
+    };
+    Some(digits.parse::<u64>().ok()? * unit)
+}
+
+fn print_breakdown(bloat: &Bloat, top: usize) {
+    eprintln!("Largest crates in .text ({} bytes):", bloat.text_section_size);
+    for krate in bloat.crates.iter().take(top) {
+        eprintln!(
+            "  {:>9} bytes  {:>5.1}%  {}",
+            krate.size,
+            krate.size as f64 * 100.0 / bloat.text_section_size.max(1) as f64,
+            krate.name
+        );
+    }
+    let rest: u64 = bloat.crates.iter().skip(top).map(|krate| krate.size).sum();
+    if rest > 0 {
+        eprintln!("  {:>9} bytes  in {} more crates", rest, bloat.crates.len().saturating_sub(top));
+    }
+}
@@ -0,0 +1,204 @@
+use aws_sdk_iotdataplane::primitives::Blob;
+use clap::Parser;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{timeout, Instant};
+
+/// Exercise a live device through AWS IoT Core: send a ping on its command
+/// topic, wait for the pong on its response topic and optionally check that
+/// the reported shadow state is fresh. Exits nonzero on any failure.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// AWS IoT data endpoint (terraform output `iot_endpoint`)
+    #[arg(long)]
+    endpoint: String,
+
+    /// Thing name of the device under test
+    #[arg(long)]
+    thing: String,
+
+    /// MQTT client id of the device under test (`mqtt_client_id`), which it
+    /// names in its pong; defaults to the thing name
+    #[arg(long)]
+    client_id: Option<String>,
+
+    /// Topic the device subscribes to (`mqtt_topic_sub`)
+    #[arg(long)]
+    command_topic: String,
+
+    /// Topic the device publishes responses to (`mqtt_topic_pub`)
+    #[arg(long)]
+    response_topic: String,
+
+    /// Root CA used to listen for the response over MQTT
+    #[arg(long, default_value = "certs/AmazonRootCA1.pem")]
+    ca: PathBuf,
+
+    /// Certificate of a test identity allowed to subscribe to the response topic
+    #[arg(long)]
+    cert: PathBuf,
+
+    /// Private key of the test identity
+    #[arg(long)]
+    key: PathBuf,
+
+    /// Seconds to wait for the pong
+    #[arg(long, default_value_t = 15)]
+    timeout_secs: u64,
+
+    /// Fail if the newest reported shadow field is older than this; skipped when unset
+    #[arg(long)]
+    shadow_max_age_secs: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let shared_config = aws_config::load_from_env().await;
+    let data_plane_config = aws_sdk_iotdataplane::config::Builder::from(&shared_config)
+        .endpoint_url(format!("https://{}", args.endpoint))
+        .build();
+    let data_plane = aws_sdk_iotdataplane::Client::from_conf(data_plane_config);
+
+    let mut failures = Vec::new();
+
+    match check_ping(&args, &data_plane).await {
+        Ok(rtt) => println!("PASS ping: pong received after {:?}", rtt),
+        Err(e) => failures.push(format!("ping: {}", e)),
+    }
+
+    if let Some(max_age) = args.shadow_max_age_secs {
+        match check_shadow(&args, &data_plane, max_age).await {
+            Ok(age) => println!("PASS shadow: reported state updated {}s ago", age),
+            Err(e) => failures.push(format!("shadow: {}", e)),
+        }
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("FAIL {}", failure);
+        }
+        std::process::exit(1);
+    }
+
+    println!("All smoke checks passed for {}", args.thing);
+    Ok(())
+}
+
+/// Publish a ping through the data plane and wait for the device's pong on
+/// the response topic, returning the round-trip time.
+async fn check_ping(
+    args: &Args,
+    data_plane: &aws_sdk_iotdataplane::Client,
+) -> Result<Duration, Box<dyn std::error::Error>> {
+    let mut options = MqttOptions::new(format!("smoke-{}", std::process::id()), &args.endpoint, 8883);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_transport(Transport::tls(
+        std::fs::read(&args.ca)?,
+        Some((std::fs::read(&args.cert)?, std::fs::read(&args.key)?)),
+        None,
+    ));
+
+    let (mqtt, mut eventloop) = AsyncClient::new(options, 10);
+    mqtt.subscribe(&args.response_topic, QoS::AtLeastOnce).await?;
+
+    // Only send the ping once the subscription is active, or the pong may be missed
+    let deadline = Duration::from_secs(args.timeout_secs);
+    timeout(deadline, async {
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = eventloop.poll().await? {
+                return Ok::<(), rumqttc::ConnectionError>(());
+            }
+        }
+    })
+    .await
+    .map_err(|_| "timed out subscribing to the response topic")??;
+
+    // Other devices on a shared command topic answer too, so only the pong
+    // naming this device and echoing this ping's request_id counts
+    let request_id = format!(
+        "smoke-{}-{}",
+        std::process::id(),
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis()
+    );
+    let expected_pong = format!("pong from: {}", args.client_id.as_deref().unwrap_or(&args.thing));
+
+    let sent = Instant::now();
+    data_plane
+        .publish()
+        .topic(&args.command_topic)
+        .qos(1)
+        .payload(Blob::new(
+            json!({ "message": "ping", "request_id": request_id }).to_string(),
+        ))
+        .send()
+        .await?;
+
+    let remaining = deadline.saturating_sub(sent.elapsed());
+    timeout(remaining, async {
+        loop {
+            if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await? {
+                let body: Value = match serde_json::from_slice(&publish.payload) {
+                    Ok(body) => body,
+                    Err(_) => continue,
+                };
+                let is_pong = body["message"].as_str() == Some(expected_pong.as_str())
+                    && body["request_id"].as_str() == Some(request_id.as_str());
+                if is_pong {
+                    return Ok::<(), rumqttc::ConnectionError>(());
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        format!(
+            "no \"{}\" for request {} on \"{}\" within {}s",
+            expected_pong, request_id, args.response_topic, args.timeout_secs
+        )
+    })??;
+
+    mqtt.disconnect().await.ok();
+    Ok(sent.elapsed())
+}
+
+/// Fetch the classic shadow and return the age in seconds of the most
+/// recently reported field.
+async fn check_shadow(
+    args: &Args,
+    data_plane: &aws_sdk_iotdataplane::Client,
+    max_age: u64,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let output = data_plane.get_thing_shadow().thing_name(&args.thing).send().await?;
+    let payload = output.payload.ok_or("shadow response has no payload")?;
+    let shadow: Value = serde_json::from_slice(payload.as_ref())?;
+
+    let newest = newest_timestamp(&shadow["metadata"]["reported"])
+        .ok_or("shadow has no reported state")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let age = now.saturating_sub(newest);
+
+    if age > max_age {
+        return Err(format!("reported state is {}s old (max {}s)", age, max_age).into());
+    }
+    Ok(age)
+}
+
+/// Shadow metadata mirrors the state document with a `timestamp` per leaf.
+fn newest_timestamp(metadata: &Value) -> Option<u64> {
+    match metadata {
+        Value::Object(fields) => fields
+            .iter()
+            .filter_map(|(key, value)| match key.as_str() {
+                "timestamp" => value.as_u64(),
+                _ => newest_timestamp(value),
+            })
+            .max(),
+        Value::Array(items) => items.iter().filter_map(newest_timestamp).max(),
+        _ => None,
+    }
+}
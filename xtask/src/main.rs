@@ -0,0 +1,502 @@
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Firmware crate, relative to the repository root.
+const FIRMWARE_DIR: &str = "firmware/example";
+/// Host build of the firmware's hardware-independent modules.
+const HOST_TESTS_DIR: &str = "firmware/host-tests";
+/// The user's cfg.toml while `flash --env` builds with another one.
+const CFG_BACKUP: &str = "cfg.backup.toml";
+/// Where `terraform apply` writes each thing's certificates.
+const TERRAFORM_CERTS: &str = "terraform/certs";
+/// NVS namespace and keys read by the `x509_nvs` auth mode (see keygen.rs).
+const NVS_NAMESPACE: &str = "app";
+const NVS_CERT_KEY: &str = "dev_cert";
+const NVS_KEY_KEY: &str = "dev_key";
+/// Offset and size of the `nvs` partition in partitions.csv.
+const NVS_OFFSET: &str = "0x9000";
+const NVS_SIZE: &str = "0x6000";
+/// Layout of the user data eFuse block read by efuse.rs.
+const EFUSE_BLOCK: &str = "BLOCK_USR_DATA";
+const EFUSE_LAYOUT_VERSION: u8 = 1;
+const EFUSE_BLOCK_BYTES: usize = 32;
+
+/// Build, flash, monitor and provision the firmware with single commands.
+/// Run from the repository root: `cargo xtask <command>`.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Task,
+}
+
+#[derive(Subcommand, Debug)]
+enum Task {
+    /// Validate a configuration without building
+    CheckConfig(EnvArgs),
+    /// Copy a thing's certificates from the Terraform output into the
+    /// firmware and point the configuration at them
+    Certs {
+        /// Thing name, i.e. the directory under terraform/certs
+        #[arg(long)]
+        thing: String,
+        #[command(flatten)]
+        env: EnvArgs,
+    },
+    /// Generate an NVS image holding the device certificate and key, for
+    /// `auth_mode = "x509_nvs"`
+    Nvs(NvsArgs),
+    /// Validate, build and flash, then open the serial monitor
+    Flash(FlashArgs),
+    /// Burn hardware revision, serial number and manufacture date into the
+    /// user data eFuse block. Irreversible
+    Efuse(EfuseArgs),
+    /// Run the firmware's unit tests on the host
+    Test,
+    /// Open the serial monitor
+    Monitor {
+        #[arg(long, value_enum, default_value_t = Board::S3)]
+        board: Board,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct EnvArgs {
+    /// Use firmware/example/cfg.<env>.toml instead of cfg.toml
+    #[arg(long)]
+    env: Option<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct NvsArgs {
+    /// Device certificate (PEM)
+    #[arg(long)]
+    cert: PathBuf,
+    /// Device private key (PEM)
+    #[arg(long)]
+    key: PathBuf,
+    /// Image to write
+    #[arg(long, default_value = "firmware/example/target/nvs.bin")]
+    out: PathBuf,
+}
+
+#[derive(ClapArgs, Debug)]
+struct FlashArgs {
+    #[arg(long, value_enum, default_value_t = Board::S3)]
+    board: Board,
+    #[command(flatten)]
+    env: EnvArgs,
+    /// Debug build instead of release
+    #[arg(long)]
+    debug: bool,
+    /// Binary (profile) to flash: example, cold_chain or contact
+    #[arg(long, default_value = "example")]
+    bin: String,
+    /// Cargo features, e.g. camera
+    #[arg(long)]
+    features: Option<String>,
+    /// Also write an NVS image made by `cargo xtask nvs`. Replaces everything
+    /// in NVS, including the device id
+    #[arg(long)]
+    nvs: Option<PathBuf>,
+    /// Don't open the serial monitor after flashing
+    #[arg(long)]
+    no_monitor: bool,
+}
+
+#[derive(ClapArgs, Debug)]
+struct EfuseArgs {
+    #[arg(long, value_enum, default_value_t = Board::S3)]
+    board: Board,
+    /// Hardware revision, up to 7 ASCII characters, e.g. B2
+    #[arg(long)]
+    revision: String,
+    /// Serial number, up to 16 ASCII characters
+    #[arg(long)]
+    serial: String,
+    /// Manufacture date, YYYY-MM-DD
+    #[arg(long)]
+    date: String,
+    /// Serial port, if espefuse can't find the device
+    #[arg(long)]
+    port: Option<String>,
+    /// Only write the block image, without burning it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Board {
+    S3,
+    C3,
+}
+
+impl Board {
+    fn target(self) -> &'static str {
+        match self {
+            Board::S3 => "xtensa-esp32s3-espidf",
+            Board::C3 => "riscv32imc-esp-espidf",
+        }
+    }
+
+    fn mcu(self) -> &'static str {
+        match self {
+            Board::S3 => "esp32s3",
+            Board::C3 => "esp32c3",
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let firmware = Path::new(FIRMWARE_DIR);
+    if !firmware.is_dir() {
+        return Err(format!("{} not found, run cargo xtask from the repository root", FIRMWARE_DIR).into());
+    }
+
+    match cli.command {
+        Task::CheckConfig(env) => check_config(&config_path(&env)),
+        Task::Certs { thing, env } => inject_certs(&thing, &config_path(&env)),
+        Task::Nvs(args) => generate_nvs(&args),
+        Task::Flash(args) => flash(&args),
+        Task::Efuse(args) => burn_efuse(&args),
+        Task::Test => run(Command::new("cargo").args(["test", "--manifest-path"]).arg(Path::new(HOST_TESTS_DIR).join("Cargo.toml"))),
+        Task::Monitor { board } => run(Command::new("espflash").args(["monitor", "--chip", board.mcu()])),
+    }
+}
+
+fn config_path(env: &EnvArgs) -> PathBuf {
+    match &env.env {
+        Some(env) => Path::new(FIRMWARE_DIR).join(format!("cfg.{}.toml", env)),
+        None => Path::new(FIRMWARE_DIR).join("cfg.toml"),
+    }
+}
+
+/// The `[example]` table of a cfg.toml.
+fn load_config(path: &Path) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut cfg: toml::Table = content.parse()?;
+    match cfg.remove("example") {
+        Some(toml::Value::Table(table)) => Ok(table),
+        _ => Err(format!("{} has no [example] section", path.display()).into()),
+    }
+}
+
+/// Catch what would otherwise fail the build script or only show up on the
+/// device: missing settings, placeholders, missing files, bad enum values.
+fn check_config(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let cfg = load_config(path)?;
+    let text = |key: &str| cfg.get(key).and_then(toml::Value::as_str).unwrap_or("");
+    let mut errors = Vec::new();
+
+    for key in [
+        "wifi_ssid",
+        "wifi_pass",
+        "mqtt_url",
+        "mqtt_client_id",
+        "mqtt_topic_pub",
+        "mqtt_topic_sub",
+    ] {
+        if text(key).is_empty() {
+            errors.push(format!("{} is empty", key));
+        }
+    }
+    for (key, value) in &cfg {
+        if value
+            .as_str()
+            .is_some_and(|value| value.contains("YOUR_") || value.contains("your-"))
+        {
+            errors.push(format!("{} still holds the example placeholder", key));
+        }
+    }
+    if !text("mqtt_url").is_empty() && !text("mqtt_url").starts_with("mqtts://") {
+        errors.push("mqtt_url must start with mqtts://".to_string());
+    }
+
+    let mut files = vec!["cert_ca", "cert_crt", "cert_key"];
+    files.extend(
+        [
+            "cert_jitp_ca",
+            "cert_backup_crt",
+            "cert_backup_key",
+            "ota_public_key",
+            "secrets_key",
+        ]
+        .iter()
+        .filter(|key| !text(key).is_empty()),
+    );
+    for key in files {
+        if !Path::new(FIRMWARE_DIR).join(text(key)).is_file() {
+            errors.push(format!("{} \"{}\" not found in {}", key, text(key), FIRMWARE_DIR));
+        }
+    }
+
+    for key in ["mqtt_pub_qos", "mqtt_sub_qos"] {
+        if let Some(qos) = cfg
+            .get(key)
+            .and_then(toml::Value::as_integer)
+            .filter(|qos| !(0..=1).contains(qos))
+        {
+            errors.push(format!("{} is {}, AWS IoT supports QoS 0 and 1", key, qos));
+        }
+    }
+    let auth_mode = cfg
+        .get("auth_mode")
+        .and_then(toml::Value::as_str)
+        .unwrap_or("x509_embedded");
+    match auth_mode {
+        "x509_embedded" | "x509_nvs" => {}
+        "sigv4_websocket" if !text("aws_access_key_id").is_empty() || !text("credentials_endpoint").is_empty() => {}
+        "sigv4_websocket" => errors.push("sigv4_websocket needs aws_access_key_id or credentials_endpoint".to_string()),
+        "custom_authorizer" if !text("custom_authorizer_name").is_empty() => {}
+        "custom_authorizer" => errors.push("custom_authorizer needs custom_authorizer_name".to_string()),
+        other => errors.push(format!("unsupported auth_mode \"{}\"", other)),
+    }
+    if cfg.get("jitp_enabled").and_then(toml::Value::as_bool) == Some(true) && text("cert_jitp_ca").is_empty() {
+        errors.push("jitp_enabled needs cert_jitp_ca".to_string());
+    }
+    if cfg.get("console_enabled").and_then(toml::Value::as_bool) == Some(true) && text("console_token").is_empty() {
+        errors.push("console_enabled needs console_token".to_string());
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("FAIL {}: {}", path.display(), error);
+        }
+        return Err(format!("{} configuration error(s)", errors.len()).into());
+    }
+    println!("PASS {}", path.display());
+    Ok(())
+}
+
+/// Copy terraform/certs/<thing> to firmware/example/certs/<thing> and set
+/// the certificate paths in the configuration to the copied files.
+fn inject_certs(thing: &str, config: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let source = Path::new(TERRAFORM_CERTS).join(thing);
+    let relative = Path::new("certs").join(thing);
+    let destination = Path::new(FIRMWARE_DIR).join(&relative);
+    fs::create_dir_all(&destination)?;
+
+    let (mut certificate, mut private_key) = (None, None);
+    for entry in fs::read_dir(&source).map_err(|e| format!("Failed to read {}: {}", source.display(), e))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        fs::copy(source.join(&name), destination.join(&name))?;
+        if name.ends_with("-certificate.pem.crt") {
+            certificate = Some(name);
+        } else if name.ends_with("-private.pem.key") {
+            private_key = Some(name);
+        }
+    }
+    let certificate = certificate.ok_or_else(|| format!("No *-certificate.pem.crt in {}", source.display()))?;
+    let private_key = private_key.ok_or_else(|| format!("No *-private.pem.key in {}", source.display()))?;
+    println!("Copied {} to {}", source.display(), destination.display());
+
+    let path = |name: &str| relative.join(name).to_string_lossy().replace('\\', "/");
+    set_config_values(
+        config,
+        &[
+            ("cert_ca", path("AmazonRootCA1.pem")),
+            ("cert_crt", path(&certificate)),
+            ("cert_key", path(&private_key)),
+        ],
+    )
+}
+
+/// Rewrite `key = ...` lines in place, so comments and layout survive.
+fn set_config_values(config: &Path, values: &[(&str, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(config).map_err(|e| format!("Failed to read {}: {}", config.display(), e))?;
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    for (key, value) in values {
+        let line = format!("{} = \"{}\"", key, value);
+        match lines
+            .iter_mut()
+            .find(|line| line.split('=').next().map(str::trim) == Some(*key))
+        {
+            Some(existing) => *existing = line.clone(),
+            None => return Err(format!("{} has no {} setting", config.display(), key).into()),
+        }
+        println!("{}: {}", config.display(), line);
+    }
+    fs::write(config, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Build the NVS partition image with ESP-IDF's generator
+/// (`pip install esp-idf-nvs-partition-gen`).
+fn generate_nvs(args: &NvsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    for file in [&args.cert, &args.key] {
+        if !file.is_file() {
+            return Err(format!("{} not found", file.display()).into());
+        }
+    }
+    if let Some(parent) = args.out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let absolute = |path: &Path| fs::canonicalize(path).map(|path| path.display().to_string());
+    let csv = format!(
+        "key,type,encoding,value\n{},namespace,,\n{},file,string,{}\n{},file,string,{}\n",
+        NVS_NAMESPACE,
+        NVS_CERT_KEY,
+        absolute(&args.cert)?,
+        NVS_KEY_KEY,
+        absolute(&args.key)?
+    );
+    let csv_path = args.out.with_extension("csv");
+    fs::write(&csv_path, csv)?;
+
+    run(Command::new("python3")
+        .args(["-m", "esp_idf_nvs_partition_gen", "generate"])
+        .arg(&csv_path)
+        .arg(&args.out)
+        .arg(NVS_SIZE))?;
+    println!(
+        "Wrote {}, flash it with --nvs or: espflash write-bin {} {}",
+        args.out.display(),
+        NVS_OFFSET,
+        args.out.display()
+    );
+    Ok(())
+}
+
+fn build_firmware(args: &FlashArgs, firmware: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut build = Command::new("cargo");
+    build.current_dir(firmware).env("MCU", args.board.mcu()).args([
+        "build",
+        "--target",
+        args.board.target(),
+        "--bin",
+        args.bin.as_str(),
+    ]);
+    if !args.debug {
+        build.arg("--release");
+    }
+    if let Some(features) = &args.features {
+        build.args(["--features", features]);
+    }
+    run(&mut build)
+}
+
+fn flash(args: &FlashArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let firmware = Path::new(FIRMWARE_DIR);
+    let config = config_path(&args.env);
+    check_config(&config)?;
+    // toml_cfg and build.rs only read cfg.toml, so swap it for the build
+    let cfg_toml = firmware.join("cfg.toml");
+    let backup = firmware.join(CFG_BACKUP);
+    let swapped = args.env.env.is_some();
+    if swapped {
+        if backup.exists() {
+            return Err(format!(
+                "{} is left from an interrupted flash, restore it to cfg.toml or delete it",
+                backup.display()
+            )
+            .into());
+        }
+        if cfg_toml.exists() {
+            fs::copy(&cfg_toml, &backup)?;
+        }
+        fs::copy(&config, &cfg_toml)?;
+        println!("Using {} as cfg.toml", config.display());
+    }
+    let built = build_firmware(args, firmware);
+    if swapped {
+        if backup.exists() {
+            fs::rename(&backup, &cfg_toml)?;
+        } else {
+            fs::remove_file(&cfg_toml)?;
+        }
+        println!("Restored cfg.toml");
+    }
+    built?;
+
+    if let Some(nvs) = &args.nvs {
+        run(Command::new("espflash")
+            .args(["write-bin", "--chip", args.board.mcu(), NVS_OFFSET])
+            .arg(nvs))?;
+    }
+
+    let profile = if args.debug { "debug" } else { "release" };
+    let elf = Path::new("target")
+        .join(args.board.target())
+        .join(profile)
+        .join(&args.bin);
+    let mut espflash = Command::new("espflash");
+    espflash.current_dir(firmware).args([
+        "flash",
+        "--chip",
+        args.board.mcu(),
+        "--partition-table",
+        "partitions.csv",
+    ]);
+    if !args.no_monitor {
+        espflash.arg("--monitor");
+    }
+    run(espflash.arg(elf))
+}
+
+/// Burn the hardware facts with espefuse (part of esptool), which asks to
+/// type BURN before it writes anything.
+fn burn_efuse(args: &EfuseArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let block = efuse_block(args)?;
+    let path = Path::new(FIRMWARE_DIR).join("target").join("efuse.bin");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, block)?;
+    println!(
+        "Wrote {} for revision {}, serial {}, date {}",
+        path.display(),
+        args.revision,
+        args.serial,
+        args.date
+    );
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let mut espefuse = Command::new("python3");
+    espefuse.args(["-m", "espefuse", "--chip", args.board.mcu()]);
+    if let Some(port) = &args.port {
+        espefuse.args(["--port", port]);
+    }
+    run(espefuse.args(["burn_block_data", EFUSE_BLOCK]).arg(&path))
+}
+
+fn efuse_block(args: &EfuseArgs) -> Result<[u8; EFUSE_BLOCK_BYTES], Box<dyn std::error::Error>> {
+    let mut block = [0u8; EFUSE_BLOCK_BYTES];
+    block[0] = EFUSE_LAYOUT_VERSION;
+    for (value, field, name) in [(&args.revision, 1..8, "revision"), (&args.serial, 8..24, "serial")] {
+        if !value.is_ascii() || value.contains('\0') || value.len() > field.len() {
+            return Err(format!("{} must be at most {} ASCII characters", name, field.len()).into());
+        }
+        block[field.start..field.start + value.len()].copy_from_slice(value.as_bytes());
+    }
+
+    let date: Vec<u32> = args
+        .date
+        .split('-')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("date \"{}\" is not YYYY-MM-DD", args.date))?;
+    let [year, month, day] = date[..] else {
+        return Err(format!("date \"{}\" is not YYYY-MM-DD", args.date).into());
+    };
+    if !(2000..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(format!("date \"{}\" is out of range", args.date).into());
+    }
+    block[24..28].copy_from_slice(&(year * 10000 + month * 100 + day).to_le_bytes());
+    Ok(block)
+}
+
+fn run(command: &mut Command) -> Result<(), Box<dyn std::error::Error>> {
+    println!("$ {:?}", command);
+    let status = command
+        .status()
+        .map_err(|e| format!("Failed to start {:?}: {}", command.get_program(), e))?;
+    if !status.success() {
+        return Err(format!("{:?} failed with {}", command.get_program(), status).into());
+    }
+    Ok(())
+}